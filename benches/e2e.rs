@@ -0,0 +1,155 @@
+//! End-to-end benchmark with the kernel's stack as the peer: a TUN device
+//! is set up, and plain `std::net` sockets talk to listeners of the stack
+//! through it. Needs `CAP_NET_ADMIN` for the device.
+//!
+//! ```text
+//! cargo bench --features bench-e2e --bench e2e -- [round trips] [MiB]
+//! ```
+//!
+//! Reports the latency distribution of request/response round trips, the
+//! buckets of [`Histogram`], and the throughput of bulk transfers both ways.
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use tcp_rust::{stats::Histogram, Interface, InterfaceConfig, TunSetup};
+
+/// Address of the host's end of the device, the stack answers on the rest
+const HOST: Ipv4Addr = Ipv4Addr::new(192, 168, 77, 1);
+const STACK: Ipv4Addr = Ipv4Addr::new(192, 168, 77, 2);
+
+const ECHO: u16 = 7000;
+const SINK: u16 = 7001;
+const SOURCE: u16 = 7002;
+
+/// Size of the requests and responses of the round trips
+const MESSAGE: usize = 64;
+
+fn main() -> io::Result<()> {
+    // cargo passes --bench along
+    let mut args = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"));
+    let round_trips = args
+        .next()
+        .map_or(Ok(10_000), |n| n.parse())
+        .map_err(invalid)?;
+    let mib = args.next().map_or(Ok(4), |n| n.parse()).map_err(invalid)?;
+
+    let setup = TunSetup {
+        name: String::from("tunbench0"),
+        address: Some((HOST, 24)),
+        ..Default::default()
+    };
+    let mut iface = Interface::with_setup(&setup, InterfaceConfig::default())?;
+
+    let _echo = iface.serve(ECHO, |stream| {
+        let mut buf = [0; MESSAGE];
+        loop {
+            match stream.read(&mut buf)? {
+                0 => return stream.shutdown(Shutdown::Write),
+                n => stream.write_all(&buf[..n])?,
+            }
+        }
+    })?;
+    let _sink = iface.serve(SINK, |stream| {
+        io::copy(stream, &mut io::sink())?;
+        // Everything arrived
+        stream.write_all(b"k")?;
+        stream.shutdown(Shutdown::Write)
+    })?;
+    let _source = iface.serve(SOURCE, move |stream| {
+        let chunk = [0xa5; 64 * 1024];
+        for _ in 0..mib * 16 {
+            stream.write_all(&chunk)?;
+        }
+        stream.shutdown(Shutdown::Write)
+    })?;
+
+    println!("rr/{}B: {}", MESSAGE, report(&round_trip(round_trips)?));
+    let bytes = mib * 1024 * 1024;
+    println!("bulk/upload:   {}", throughput(bytes, upload(bytes)?));
+    println!("bulk/download: {}", throughput(bytes, download(bytes)?));
+    Ok(())
+}
+
+fn connect(port: u16) -> io::Result<TcpStream> {
+    let stream =
+        TcpStream::connect_timeout(&SocketAddr::from((STACK, port)), Duration::from_secs(5))?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    Ok(stream)
+}
+
+/// Times `count` requests, each waiting for the whole response
+fn round_trip(count: usize) -> io::Result<Histogram> {
+    let mut stream = connect(ECHO)?;
+    let request = [0x5a; MESSAGE];
+    let mut response = [0; MESSAGE];
+    let mut latency = Histogram::default();
+    for _ in 0..count {
+        let sent = Instant::now();
+        stream.write_all(&request)?;
+        stream.read_exact(&mut response)?;
+        latency.record(sent.elapsed());
+    }
+    Ok(latency)
+}
+
+fn upload(bytes: usize) -> io::Result<Duration> {
+    let mut stream = connect(SINK)?;
+    let chunk = [0xa5; 64 * 1024];
+    let start = Instant::now();
+    for _ in 0..bytes / chunk.len() {
+        stream.write_all(&chunk)?;
+    }
+    stream.shutdown(Shutdown::Write)?;
+    stream.read_exact(&mut [0])?;
+    Ok(start.elapsed())
+}
+
+fn download(bytes: usize) -> io::Result<Duration> {
+    let mut stream = connect(SOURCE)?;
+    let start = Instant::now();
+    let n = io::copy(&mut stream, &mut io::sink())?;
+    let elapsed = start.elapsed();
+    if n as usize != bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} of {} bytes downloaded", n, bytes),
+        ));
+    }
+    Ok(elapsed)
+}
+
+fn report(h: &Histogram) -> String {
+    let us = |d: Option<Duration>| d.unwrap_or_default().as_micros();
+    let mut report = format!(
+        "{} samples, mean {}us, p50 <{}us, p99 <{}us, max {}us",
+        h.count(),
+        us(h.mean()),
+        us(h.quantile(0.5)),
+        us(h.quantile(0.99)),
+        h.max().as_micros(),
+    );
+    for (bound, n) in h.buckets().filter(|&(_, n)| n > 0) {
+        report += &format!("\n  <{:>8}us {:>8}", bound.as_micros(), n);
+    }
+    report
+}
+
+fn throughput(bytes: usize, elapsed: Duration) -> String {
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    format!(
+        "{:.0} MiB in {:.2?}, {:.1} MiB/s",
+        mib,
+        elapsed,
+        mib / elapsed.as_secs_f64()
+    )
+}
+
+fn invalid(e: std::num::ParseIntError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
@@ -0,0 +1,373 @@
+//! Model-based check of the connection state machine.
+//!
+//! A reference model of the synchronized states, written straight from
+//! RFC 9293 S3.10.7.4 and knowing nothing of [`Connection`], is fed the same
+//! randomized segments as a real connection. After every segment their
+//! state, RCV.NXT and SND.UNA must agree. A divergence is shrunk to the
+//! fewest and shortest segments still showing it before being reported.
+//!
+//! ```text
+//! cargo run --release --example model_check --features testing -- [cases] [seed]
+//! ```
+use std::{
+    fs::File,
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+};
+
+use etherparse::PacketBuilder;
+use tcp_rust::{
+    device::Device,
+    tcp::{Connection, State},
+    InterfaceConfig, StreamOptions,
+};
+
+/// Receive buffer of both the model and the connection
+const RECV_BUFFER: usize = 1024;
+const MAX_SEGMENTS: usize = 24;
+
+/// Discards whatever the connection sends back
+struct Sink(File);
+
+impl AsRawFd for Sink {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Device for Sink {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        Ok(packet.len())
+    }
+
+    fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "Nothing to receive",
+        ))
+    }
+}
+
+/// xorshift64*, so that a seed is all it takes to replay a run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Uniform in `[-spread, spread]`
+    fn around(&mut self, spread: u32) -> u32 {
+        (self.below(2 * spread as u64 + 1) as i64 - spread as i64) as u32
+    }
+}
+
+/// Where the connection starts from
+#[derive(Clone, Debug)]
+struct Start {
+    state: State,
+    iss: u32,
+    irs: u32,
+    /// Data sent and not acknowledged yet
+    in_flight: usize,
+}
+
+/// Segment from the peer, always carrying an ACK
+#[derive(Clone, Debug)]
+struct Segment {
+    seq: u32,
+    ack: u32,
+    len: usize,
+    fin: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Observed {
+    state: State,
+    rcv_nxt: u32,
+    snd_una: u32,
+}
+
+struct Model {
+    state: State,
+    snd_una: u32,
+    snd_nxt: u32,
+    /// Sequence number of our FIN, if we sent one
+    fin_seq: Option<u32>,
+    rcv_nxt: u32,
+    /// Received bytes, nobody ever reads them
+    buffered: usize,
+}
+
+impl Model {
+    fn new(start: &Start) -> Self {
+        let closing = fin_sent(start.state);
+        let snd_una = start.iss.wrapping_add(1);
+        let sent = start.in_flight as u32 + closing as u32;
+        Self {
+            state: start.state,
+            snd_una,
+            snd_nxt: snd_una.wrapping_add(sent),
+            fin_seq: closing.then(|| snd_una.wrapping_add(start.in_flight as u32)),
+            rcv_nxt: start.irs.wrapping_add(1),
+            buffered: 0,
+        }
+    }
+
+    fn rcv_wnd(&self) -> u32 {
+        (RECV_BUFFER - self.buffered) as u32
+    }
+
+    /// Whether `x` falls within `[from, from + len)`
+    fn within(x: u32, from: u32, len: u32) -> bool {
+        x.wrapping_sub(from) < len
+    }
+
+    fn step(&mut self, seg: &Segment) {
+        let (nxt, wnd) = (self.rcv_nxt, self.rcv_wnd());
+        let seg_len = seg.len as u32 + seg.fin as u32;
+
+        // Sequence number check
+        let acceptable = match (seg_len, wnd) {
+            (0, 0) => seg.seq == nxt,
+            (0, _) => Self::within(seg.seq, nxt, wnd),
+            (_, 0) => false,
+            (_, _) => {
+                Self::within(seg.seq, nxt, wnd)
+                    || Self::within(seg.seq.wrapping_add(seg_len - 1), nxt, wnd)
+                    // Starts before the window and ends past it
+                    || Self::within(nxt, seg.seq, seg_len)
+            }
+        };
+        // In TIME-WAIT, only a retransmitted FIN can still arrive
+        if !acceptable || matches!(self.state, State::TimeWait | State::Closed) {
+            return;
+        }
+
+        // SND.UNA < SEG.ACK <= SND.NXT
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+        let acked = seg.ack.wrapping_sub(self.snd_una);
+        if acked > 0 && acked <= in_flight {
+            self.snd_una = seg.ack;
+        }
+        if self.state == State::FinWait1
+            && self.fin_seq.map(|f| f.wrapping_add(1)) == Some(self.snd_una)
+        {
+            self.state = State::FinWait2;
+        }
+        if self.state == State::Closing
+            && self.fin_seq.map(|f| f.wrapping_add(1)) == Some(self.snd_una)
+        {
+            self.state = State::TimeWait;
+        }
+        if self.state == State::LastAck
+            && self.fin_seq.map(|f| f.wrapping_add(1)) == Some(self.snd_una)
+        {
+            self.state = State::Closed;
+        }
+
+        // Only data starting at or before RCV.NXT can be taken, up to the
+        // end of the window, and none past the peer's FIN
+        let receiving = matches!(self.state, State::Estab | State::FinWait1 | State::FinWait2);
+        if receiving && seg.len > 0 && Self::within(nxt, seg.seq, seg.len as u32 + 1) {
+            let end = seg.seq.wrapping_add(seg.len as u32);
+            let new = std::cmp::min(end.wrapping_sub(nxt), wnd);
+            self.rcv_nxt = nxt.wrapping_add(new);
+            self.buffered += new as usize;
+        }
+
+        if seg.fin && seg.seq.wrapping_add(seg.len as u32) == self.rcv_nxt {
+            let next = match self.state {
+                State::Estab => Some(State::CloseWait),
+                State::FinWait1 => Some(State::Closing),
+                State::FinWait2 => Some(State::TimeWait),
+                _ => None,
+            };
+            if let Some(next) = next {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.state = next;
+            }
+        }
+    }
+
+    fn observe(&self) -> Observed {
+        Observed {
+            state: self.state,
+            rcv_nxt: self.rcv_nxt,
+            snd_una: self.snd_una,
+        }
+    }
+}
+
+/// Whether our FIN is out already when starting from `state`
+fn fin_sent(state: State) -> bool {
+    matches!(
+        state,
+        State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck
+    )
+}
+
+fn connection(start: &Start) -> Connection {
+    let closing = fin_sent(start.state);
+    let una = start.iss.wrapping_add(1);
+    let sent = start.in_flight as u32 + closing as u32;
+    Connection::builder(
+        "10.0.0.1:80".parse().unwrap(),
+        "10.0.0.2:4000".parse().unwrap(),
+    )
+    .state(start.state)
+    .iss(start.iss)
+    .snd_nxt(una.wrapping_add(sent))
+    .irs(start.irs)
+    .unacked(&vec![0; start.in_flight])
+    .options(StreamOptions {
+        recv_buffer: RECV_BUFFER,
+        ..Default::default()
+    })
+    .build()
+}
+
+fn packet(seg: &Segment) -> Vec<u8> {
+    let mut tcp = PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64)
+        .tcp(4000, 80, seg.seq, u16::MAX)
+        .ack(seg.ack);
+    if seg.fin {
+        tcp = tcp.fin();
+    }
+    let mut packet = Vec::new();
+    tcp.write(&mut packet, &vec![0xaa; seg.len]).unwrap();
+    packet
+}
+
+/// Runs both sides, returning the first step where they disagree
+fn run(
+    nic: &dyn Device,
+    start: &Start,
+    segments: &[Segment],
+) -> Option<(usize, Observed, Observed)> {
+    let config = InterfaceConfig::default();
+    let mut model = Model::new(start);
+    let mut c = connection(start);
+
+    for (i, seg) in segments.iter().enumerate() {
+        model.step(seg);
+        c.on_segment(nic, &config, &packet(seg)).unwrap();
+        let actual = Observed {
+            state: c.info().state,
+            rcv_nxt: c.rcv_nxt(),
+            snd_una: c.snd_una(),
+        };
+        if actual != model.observe() {
+            return Some((i, model.observe(), actual));
+        }
+    }
+    None
+}
+
+fn generate(rng: &mut Rng) -> (Start, Vec<Segment>) {
+    let start = Start {
+        state: [
+            State::Estab,
+            State::FinWait1,
+            State::FinWait2,
+            State::Closing,
+            State::CloseWait,
+            State::LastAck,
+        ][rng.below(6) as usize],
+        iss: rng.next() as u32,
+        irs: rng.next() as u32,
+        in_flight: rng.below(3000) as usize,
+    };
+
+    // Aim around what the model expects, so that most segments hit the
+    // window and acknowledge something
+    let mut model = Model::new(&start);
+    let segments = (0..1 + rng.below(MAX_SEGMENTS as u64))
+        .map(|_| {
+            let in_flight = model.snd_nxt.wrapping_sub(model.snd_una);
+            let seg = Segment {
+                seq: model.rcv_nxt.wrapping_add(rng.around(1500)),
+                ack: model
+                    .snd_una
+                    .wrapping_add(in_flight / 2)
+                    .wrapping_add(rng.around(in_flight / 2 + 500)),
+                len: [0, 1, rng.below(1400) as usize][rng.below(3) as usize],
+                fin: rng.below(8) == 0,
+            };
+            model.step(&seg);
+            seg
+        })
+        .collect();
+    (start, segments)
+}
+
+/// Drops and shortens segments as long as the divergence remains
+fn shrink(nic: &dyn Device, start: &Start, mut segments: Vec<Segment>) -> Vec<Segment> {
+    let fails = |segments: &[Segment]| run(nic, start, segments).is_some();
+
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for i in (0..segments.len()).rev() {
+            let mut fewer = segments.clone();
+            fewer.remove(i);
+            if fails(&fewer) {
+                segments = fewer;
+                progress = true;
+            }
+        }
+        for i in 0..segments.len() {
+            while segments[i].len > 0 {
+                let mut shorter = segments.clone();
+                shorter[i].len /= 2;
+                if !fails(&shorter) {
+                    break;
+                }
+                segments = shorter;
+                progress = true;
+            }
+        }
+    }
+    segments
+}
+
+fn main() -> io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let cases: u64 = args
+        .next()
+        .map_or(10_000, |a| a.parse().expect("number of cases"));
+    let seed: u64 = args.next().map_or(0x7c9_5eed, |a| a.parse().expect("seed"));
+
+    let nic = Sink(File::open("/dev/null")?);
+    // xorshift never leaves zero
+    let mut rng = Rng(seed | 1);
+
+    for case in 0..cases {
+        let (start, segments) = generate(&mut rng);
+        if run(&nic, &start, &segments).is_none() {
+            continue;
+        }
+
+        let segments = shrink(&nic, &start, segments);
+        let (step, expected, actual) = run(&nic, &start, &segments).unwrap();
+        eprintln!("case {} of seed {} diverged from the model", case, seed);
+        eprintln!("start: {:?}", start);
+        for (i, seg) in segments.iter().enumerate() {
+            eprintln!("  {:>2}: {:?}", i, seg);
+        }
+        eprintln!("after segment {}:", step);
+        eprintln!("  model:      {:?}", expected);
+        eprintln!("  connection: {:?}", actual);
+        std::process::exit(1);
+    }
+
+    println!("{} cases agreed with the model", cases);
+    Ok(())
+}
@@ -0,0 +1,649 @@
+//! Classic BPF programs run by the kernel on every packet before the
+//! stack reads it, see [`crate::Interface::attach_filter`]. What they
+//! drop never wakes up the packet loop, which matters on a device shared
+//! with a lot of traffic the stack has no use for.
+//!
+//! Besides [`Program::tcp_ports`], any program fits, e.g. one compiled by
+//! `tcpdump -y RAW -dd '<filter>'`, whose lines are the arguments of
+//! [`Instruction::new`]. Ancillary loads (`SKF_AD_*`) are not supported.
+//!
+//! A TUN device only takes eBPF, the program is translated the way the
+//! kernel does it for sockets. Loading it needs `CAP_BPF` unless the
+//! `kernel.unprivileged_bpf_disabled` sysctl allows it, and attaching it
+//! `CAP_NET_ADMIN`.
+//!
+//! ```
+//! # use tcp_rust::{bpf::Program, device::Loopback, Interface, InterfaceConfig};
+//! # fn main() -> std::io::Result<()> {
+//! # let (nic, _peer) = Loopback::pair()?;
+//! let mut iface = Interface::with_device(nic, InterfaceConfig::default())?;
+//! let _http = iface.bind(80)?;
+//! // Nothing but port 80 wakes the packet loop
+//! iface.attach_filter(&Program::tcp_ports(&[80])?)?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{fs::File, io, os::unix::prelude::FromRawFd};
+
+// linux/filter.h
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MSH: u16 = 0xa0;
+const BPF_RSH: u16 = 0x70;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JSET: u16 = 0x40;
+const BPF_K: u16 = 0x00;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_MISC: u16 = 0x07;
+const BPF_W: u16 = 0x00;
+const BPF_IMM: u16 = 0x00;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+const BPF_JA: u16 = 0x00;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_NEG: u16 = 0x80;
+const BPF_DIV: u16 = 0x30;
+const BPF_MOD: u16 = 0x90;
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+/// Words of scratch memory, `M[]`
+const BPF_MEMWORDS: u32 = 16;
+
+// linux/bpf.h
+const BPF_JMP32: u8 = 0x06;
+const BPF_ALU64: u8 = 0x07;
+const BPF_MOV: u8 = 0xb0;
+const BPF_JNE: u8 = 0x50;
+const BPF_JLT: u8 = 0xa0;
+const BPF_JLE: u8 = 0xb0;
+const BPF_EXIT: u8 = 0x90;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_TYPE_SOCKET_FILTER: u32 = 1;
+/// First of the offsets standing for ancillary data rather than the packet
+const SKF_AD_OFF: u32 = -0x1000i32 as u32;
+
+/// Registers of the translated program: the accumulator and index of the
+/// classic machine, the context loads need, a scratch one and the frame
+const A: u8 = 0;
+const CTX: u8 = 1;
+const CTX_SAVED: u8 = 6;
+const X: u8 = 7;
+const TMP: u8 = 8;
+const FP: u8 = 10;
+
+/// Most instructions the kernel takes in a program
+const BPF_MAXINSNS: usize = 4096;
+
+/// Most ports [`Program::tcp_ports`] can look for
+const MAX_PORTS: usize = 240;
+
+/// `struct sock_filter`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub code: u16,
+    /// Instructions skipped if the condition holds
+    pub jt: u8,
+    /// Instructions skipped if it doesn't
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl Instruction {
+    pub const fn new(code: u16, jt: u8, jf: u8, k: u32) -> Self {
+        Self { code, jt, jf, k }
+    }
+}
+
+/// `struct sock_fprog`
+#[repr(C)]
+pub(crate) struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const Instruction,
+}
+
+/// Filter keeping the packets it returns a non-zero length for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program(Vec<Instruction>);
+
+impl Program {
+    pub fn new(instructions: Vec<Instruction>) -> io::Result<Self> {
+        if instructions.is_empty() || instructions.len() > BPF_MAXINSNS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A program has 1 to 4096 instructions",
+            ));
+        }
+        Ok(Self(instructions))
+    }
+
+    /// Keeps the TCP segments over IPv4 to one of `ports`, or all of them
+    /// if there are none. Fragments past the first one have no TCP header
+    /// to tell, they are dropped.
+    pub fn tcp_ports(ports: &[u16]) -> io::Result<Self> {
+        // The jumps over the remaining comparisons must fit in a byte
+        if ports.len() > MAX_PORTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too many ports for one program",
+            ));
+        }
+
+        // Checks, then port comparisons ending with drop and keep, or keep
+        // and drop when anything goes
+        let drop_at = if ports.is_empty() { 8 } else { 9 + ports.len() };
+        let drop = |at: usize| (drop_at - at - 1) as u8;
+        let mut program = vec![
+            // IPv4
+            Instruction::new(BPF_LD | BPF_B | BPF_ABS, 0, 0, 0),
+            Instruction::new(BPF_ALU | BPF_RSH | BPF_K, 0, 0, 4),
+            Instruction::new(BPF_JMP | BPF_JEQ | BPF_K, 0, drop(2), 4),
+            // TCP
+            Instruction::new(BPF_LD | BPF_B | BPF_ABS, 0, 0, 9),
+            Instruction::new(BPF_JMP | BPF_JEQ | BPF_K, 0, drop(4), 6),
+            // First fragment
+            Instruction::new(BPF_LD | BPF_H | BPF_ABS, 0, 0, 6),
+            Instruction::new(BPF_JMP | BPF_JSET | BPF_K, drop(6), 0, 0x1fff),
+        ];
+        let drop = Instruction::new(BPF_RET | BPF_K, 0, 0, 0);
+        let keep = Instruction::new(BPF_RET | BPF_K, 0, 0, u32::MAX);
+        if ports.is_empty() {
+            program.extend([keep, drop]);
+            return Ok(Self(program));
+        }
+
+        // X = length of the IP header, then the destination port
+        program.push(Instruction::new(BPF_LDX | BPF_B | BPF_MSH, 0, 0, 0));
+        program.push(Instruction::new(BPF_LD | BPF_H | BPF_IND, 0, 0, 2));
+        for (i, &port) in ports.iter().enumerate() {
+            let to_keep = (ports.len() - i) as u8;
+            program.push(Instruction::new(
+                BPF_JMP | BPF_JEQ | BPF_K,
+                to_keep,
+                0,
+                port as u32,
+            ));
+        }
+        program.extend([drop, keep]);
+        Ok(Self(program))
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.0
+    }
+
+    /// What the kernel takes, valid as long as the program is
+    pub(crate) fn as_fprog(&self) -> SockFprog {
+        SockFprog {
+            len: self.0.len() as libc::c_ushort,
+            filter: self.0.as_ptr(),
+        }
+    }
+}
+
+/// `struct bpf_insn`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Insn {
+    code: u8,
+    /// Destination register in the low nibble, source in the high one
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl Insn {
+    fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Self {
+            code,
+            regs: dst | (src << 4),
+            off,
+            imm,
+        }
+    }
+}
+
+/// The first fields of `union bpf_attr`, as `BPF_PROG_LOAD` takes them
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+impl Program {
+    /// Loads the eBPF translation of the program into the kernel, for a
+    /// device to attach it by the returned descriptor
+    pub(crate) fn load(&self) -> io::Result<File> {
+        let insns = self.translate()?;
+        let license = b"GPL\0";
+        let mut log = vec![0u8; 1 << 16];
+        let mut attr = ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_SOCKET_FILTER,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: 0,
+            log_size: 0,
+            log_buf: 0,
+            kern_version: 0,
+            prog_flags: 0,
+        };
+
+        for verbose in [false, true] {
+            if verbose {
+                // Again, for the verifier to tell what it didn't like
+                attr.log_level = 1;
+                attr.log_size = log.len() as u32;
+                attr.log_buf = log.as_mut_ptr() as u64;
+            }
+            // SAFETY: `attr` and the buffers it points to outlive the call
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_bpf,
+                    BPF_PROG_LOAD,
+                    &attr as *const ProgLoadAttr,
+                    std::mem::size_of::<ProgLoadAttr>(),
+                )
+            };
+            if fd >= 0 {
+                // SAFETY: `fd` was just opened and nothing else owns it
+                return Ok(unsafe { File::from_raw_fd(fd as libc::c_int) });
+            }
+
+            let err = io::Error::last_os_error();
+            if verbose || err.raw_os_error() != Some(libc::EINVAL) {
+                let len = log.iter().position(|&b| b == 0).unwrap_or(0);
+                if len == 0 {
+                    return Err(err);
+                }
+                let reason = String::from_utf8_lossy(&log[..len]);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Program rejected: {}", reason.trim_end()),
+                ));
+            }
+        }
+        unreachable!()
+    }
+
+    /// Same program for the eBPF machine, whose instructions don't line up
+    /// with the classic ones, so the jumps are resolved once the length of
+    /// every translated instruction is known
+    fn translate(&self) -> io::Result<Vec<Insn>> {
+        // The verifier refuses dead code, the classic checker doesn't
+        let live = self.reachable();
+        let translate = |i: usize, starts| match live[i] {
+            true => translate(&self.0[i], i, starts),
+            false => Ok(Vec::new()),
+        };
+
+        let mut insns = prologue();
+        let mut starts = Vec::with_capacity(self.0.len() + 1);
+        let mut len = insns.len();
+        for i in 0..self.0.len() {
+            starts.push(len);
+            len += translate(i, None)?.len();
+        }
+        starts.push(len);
+
+        for i in 0..self.0.len() {
+            insns.extend(translate(i, Some(&starts))?);
+        }
+        Ok(insns)
+    }
+
+    /// Which instructions some path from the first one goes through
+    fn reachable(&self) -> Vec<bool> {
+        let mut live = vec![false; self.0.len()];
+        let mut next = vec![0];
+        while let Some(i) = next.pop() {
+            if i >= live.len() || live[i] {
+                continue;
+            }
+            live[i] = true;
+            let insn = &self.0[i];
+            match (insn.code & 0x07, insn.code & 0xf0) {
+                (BPF_RET, _) => {}
+                (BPF_JMP, BPF_JA) => next.push(i + 1 + insn.k as usize),
+                (BPF_JMP, _) => {
+                    next.push(i + 1 + insn.jt as usize);
+                    next.push(i + 1 + insn.jf as usize);
+                }
+                _ => next.push(i + 1),
+            }
+        }
+        live
+    }
+}
+
+/// Start of every translated program: the loads of packet data want the
+/// context in r6, and the verifier refuses to read registers or stack slots
+/// that were never written, so A, X and the scratch memory are zeroed
+fn prologue() -> Vec<Insn> {
+    let mut insns = vec![
+        Insn::new(BPF_ALU64 | BPF_MOV | BPF_X as u8, CTX_SAVED, CTX, 0, 0),
+        Insn::new(BPF_ALU as u8 | BPF_MOV, A, 0, 0, 0),
+        Insn::new(BPF_ALU as u8 | BPF_MOV, X, 0, 0, 0),
+    ];
+    insns.extend((0..BPF_MEMWORDS).map(|k| {
+        let off = -(((BPF_MEMWORDS - k) * 4) as i16);
+        Insn::new((BPF_ST | BPF_MEM | BPF_W) as u8, FP, 0, off, 0)
+    }));
+    insns
+}
+
+/// Translation of the `i`th classic instruction, with jumps going nowhere
+/// until `starts`, where each translated instruction begins, is given
+fn translate(insn: &Instruction, i: usize, starts: Option<&[usize]>) -> io::Result<Vec<Insn>> {
+    let invalid = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Instruction {}: {}", i, what),
+        )
+    };
+    let k = insn.k as i32;
+    let size = (insn.code & 0x18) as u8;
+    let mode = insn.code & 0xe0;
+    let op = (insn.code & 0xf0) as u8;
+    let src = insn.code & 0x08;
+    // Frame offset of a word of scratch memory
+    let mem = || {
+        if insn.k >= BPF_MEMWORDS {
+            return Err(invalid("no such scratch memory word"));
+        }
+        Ok(-(((BPF_MEMWORDS - insn.k) * 4) as i16))
+    };
+    let packet = || {
+        if insn.k >= SKF_AD_OFF {
+            return Err(invalid("ancillary data not supported"));
+        }
+        Ok(k)
+    };
+
+    let mut out = Vec::new();
+    match insn.code & 0x07 {
+        class @ (BPF_LD | BPF_LDX) => {
+            let dst = if class == BPF_LD { A } else { X };
+            match (class, mode) {
+                (_, BPF_IMM) => out.push(Insn::new(BPF_ALU as u8 | BPF_MOV, dst, 0, 0, k)),
+                (_, BPF_MEM) => out.push(Insn::new(
+                    (BPF_LDX | BPF_MEM | BPF_W) as u8,
+                    dst,
+                    FP,
+                    mem()?,
+                    0,
+                )),
+                // `struct __sk_buff` starts with the length
+                (_, BPF_LEN) => out.push(Insn::new(
+                    (BPF_LDX | BPF_MEM | BPF_W) as u8,
+                    dst,
+                    CTX_SAVED,
+                    0,
+                    0,
+                )),
+                (BPF_LD, BPF_ABS) => {
+                    out.push(Insn::new((BPF_LD | mode) as u8 | size, 0, 0, 0, packet()?))
+                }
+                (BPF_LD, BPF_IND) => {
+                    out.push(Insn::new((BPF_LD | mode) as u8 | size, 0, X, 0, packet()?))
+                }
+                // X = 4 * (P[k] & 0xf), the packet load clobbers A
+                (BPF_LDX, BPF_MSH) => out.extend([
+                    Insn::new(BPF_ALU64 | BPF_MOV | BPF_X as u8, TMP, A, 0, 0),
+                    Insn::new((BPF_LD | BPF_ABS | BPF_B) as u8, 0, 0, 0, packet()?),
+                    Insn::new((BPF_ALU | BPF_AND) as u8, A, 0, 0, 0xf),
+                    Insn::new((BPF_ALU | BPF_LSH) as u8, A, 0, 0, 2),
+                    Insn::new((BPF_ALU as u8) | BPF_MOV | BPF_X as u8, X, A, 0, 0),
+                    Insn::new(BPF_ALU64 | BPF_MOV | BPF_X as u8, A, TMP, 0, 0),
+                ]),
+                _ => return Err(invalid("unknown load")),
+            }
+        }
+        class @ (BPF_ST | BPF_STX) => {
+            let from = if class == BPF_ST { A } else { X };
+            out.push(Insn::new(
+                (BPF_STX | BPF_MEM | BPF_W) as u8,
+                FP,
+                from,
+                mem()?,
+                0,
+            ));
+        }
+        BPF_ALU => {
+            if op == BPF_NEG as u8 {
+                out.push(Insn::new(BPF_ALU as u8 | op, A, 0, 0, 0));
+            } else if src == BPF_X {
+                if op == BPF_DIV as u8 || op == BPF_MOD as u8 {
+                    // Dividing by zero ends a classic program, returning 0
+                    out.extend([
+                        Insn::new(BPF_JMP32 | BPF_JNE, X, 0, 2, 0),
+                        Insn::new(BPF_ALU as u8 | BPF_MOV, A, 0, 0, 0),
+                        Insn::new(BPF_JMP as u8 | BPF_EXIT, 0, 0, 0, 0),
+                    ]);
+                }
+                out.push(Insn::new(BPF_ALU as u8 | op | BPF_X as u8, A, X, 0, 0));
+            } else {
+                if (op == BPF_DIV as u8 || op == BPF_MOD as u8) && k == 0 {
+                    return Err(invalid("division by zero"));
+                }
+                out.push(Insn::new(BPF_ALU as u8 | op, A, 0, 0, k));
+            }
+        }
+        BPF_JMP => {
+            let at = |n: usize| starts.map_or(0, |s| s[n]);
+            // Offset from the instruction about to be pushed to the start
+            // of the classic one `skip` instructions past this one
+            let jump = |out: &Vec<Insn>, skip: u32| -> io::Result<i16> {
+                let target = i + 1 + skip as usize;
+                if starts.is_some_and(|s| target >= s.len() - 1) {
+                    return Err(invalid("jump past the end"));
+                }
+                let from = at(i) + out.len() + 1;
+                Ok((at(target) as i64 - from as i64) as i16)
+            };
+            if op == BPF_JA as u8 {
+                let off = jump(&out, insn.k)?;
+                out.push(Insn::new(BPF_JMP as u8 | BPF_JA as u8, 0, 0, off, 0));
+                return Ok(out);
+            }
+
+            let (jt, jf) = (insn.jt as u32, insn.jf as u32);
+            let (rhs, imm) = if src == BPF_X { (X, 0) } else { (0, k) };
+            let code = |op: u8| BPF_JMP32 | op | src as u8;
+            let inverse = match op as u16 {
+                BPF_JEQ => Some(BPF_JNE),
+                BPF_JGT => Some(BPF_JLE),
+                BPF_JGE => Some(BPF_JLT),
+                BPF_JSET => None,
+                _ => return Err(invalid("unknown jump")),
+            };
+            match (jt, jf, inverse) {
+                (0, 0, _) => {}
+                (_, 0, _) => {
+                    let off = jump(&out, jt)?;
+                    out.push(Insn::new(code(op), A, rhs, off, imm));
+                }
+                (0, _, Some(inverse)) => {
+                    let off = jump(&out, jf)?;
+                    out.push(Insn::new(code(inverse), A, rhs, off, imm));
+                }
+                _ => {
+                    let off = jump(&out, jt)?;
+                    out.push(Insn::new(code(op), A, rhs, off, imm));
+                    let off = jump(&out, jf)?;
+                    out.push(Insn::new(BPF_JMP as u8 | BPF_JA as u8, 0, 0, off, 0));
+                }
+            }
+        }
+        BPF_RET => {
+            match insn.code & 0x18 {
+                BPF_K => out.push(Insn::new(BPF_ALU as u8 | BPF_MOV, A, 0, 0, k)),
+                BPF_X => out.push(Insn::new(BPF_ALU as u8 | BPF_MOV | BPF_X as u8, A, X, 0, 0)),
+                BPF_A => {}
+                _ => return Err(invalid("unknown return")),
+            }
+            out.push(Insn::new(BPF_JMP as u8 | BPF_EXIT, 0, 0, 0, 0));
+        }
+        BPF_MISC => match insn.code & 0xf8 {
+            BPF_TAX => out.push(Insn::new(BPF_ALU as u8 | BPF_MOV | BPF_X as u8, X, A, 0, 0)),
+            BPF_TXA => out.push(Insn::new(BPF_ALU as u8 | BPF_MOV | BPF_X as u8, A, X, 0, 0)),
+            _ => return Err(invalid("unknown instruction")),
+        },
+        _ => unreachable!(),
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXIT: u8 = BPF_JMP as u8 | BPF_EXIT;
+
+    fn translated(instructions: Vec<Instruction>) -> Vec<Insn> {
+        Program::new(instructions).unwrap().translate().unwrap()
+    }
+
+    /// Where the jump at `at` lands
+    fn target(insns: &[Insn], at: usize) -> usize {
+        (at as i64 + 1 + insns[at].off as i64) as usize
+    }
+
+    /// Every jump lands inside the program, and it ends with an exit
+    fn assert_jumps_in_bounds(insns: &[Insn]) {
+        for (at, insn) in insns.iter().enumerate() {
+            let class = insn.code & 0x07;
+            if (class == BPF_JMP as u8 || class == BPF_JMP32) && insn.code != EXIT {
+                assert!(
+                    target(insns, at) < insns.len(),
+                    "jump at {} out of bounds",
+                    at
+                );
+            }
+        }
+        assert_eq!(insns.last().unwrap().code, EXIT);
+    }
+
+    #[test]
+    fn prologue_zeroes_the_scratch_memory() {
+        let insns = translated(vec![Instruction::new(BPF_RET | BPF_A, 0, 0, 0)]);
+        let p = prologue().len();
+        assert_eq!(p, 3 + BPF_MEMWORDS as usize);
+        for (k, insn) in insns[3..p].iter().enumerate() {
+            assert_eq!(insn.code, 0x62);
+            assert_eq!(insn.regs, FP);
+            assert_eq!(insn.off, -(64 - 4 * k as i16));
+            assert_eq!(insn.imm, 0);
+        }
+        // RET A is the exit alone
+        assert_eq!(insns.len(), p + 1);
+        assert_eq!(insns[p].code, EXIT);
+    }
+
+    #[test]
+    fn jumps_land_past_the_msh_expansion() {
+        let insns = translated(vec![
+            Instruction::new(BPF_LDX | BPF_B | BPF_MSH, 0, 0, 0),
+            Instruction::new(BPF_JMP | BPF_JEQ | BPF_K, 1, 0, 1),
+            Instruction::new(BPF_RET | BPF_K, 0, 0, 0),
+            Instruction::new(BPF_RET | BPF_K, 0, 0, 1),
+        ]);
+        let p = prologue().len();
+        // Six for MSH, then the jump to the second return
+        let jeq = p + 6;
+        assert_eq!(insns[jeq].code, BPF_JMP32 | BPF_JEQ as u8);
+        assert_eq!(target(&insns, jeq), jeq + 3);
+        assert_eq!(insns[jeq + 3].imm, 1);
+        assert_jumps_in_bounds(&insns);
+    }
+
+    #[test]
+    fn division_by_x_is_guarded() {
+        let insns = translated(vec![
+            Instruction::new(BPF_ALU | BPF_DIV | BPF_X, 0, 0, 0),
+            Instruction::new(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 7),
+            Instruction::new(BPF_RET | BPF_K, 0, 0, 0),
+            Instruction::new(BPF_RET | BPF_A, 0, 0, 0),
+        ]);
+        let p = prologue().len();
+        // X != 0 skips the return of 0 to the division
+        assert_eq!(insns[p].code, BPF_JMP32 | BPF_JNE);
+        assert_eq!(target(&insns, p), p + 3);
+        assert_eq!(insns[p + 2].code, EXIT);
+        assert_eq!(insns[p + 3].code, (BPF_ALU | BPF_DIV | BPF_X) as u8);
+        // Only jf is taken, on the inverse condition
+        let jne = p + 4;
+        assert_eq!(insns[jne].code, BPF_JMP32 | BPF_JNE);
+        assert_eq!(target(&insns, jne), jne + 3);
+        assert_eq!(insns[jne + 3].code, EXIT);
+        assert_jumps_in_bounds(&insns);
+    }
+
+    #[test]
+    fn modulo_by_x_is_guarded() {
+        let insns = translated(vec![
+            Instruction::new(BPF_ALU | BPF_MOD | BPF_X, 0, 0, 0),
+            Instruction::new(BPF_RET | BPF_A, 0, 0, 0),
+        ]);
+        let p = prologue().len();
+        assert_eq!(target(&insns, p), p + 3);
+        assert_eq!(insns[p + 3].code, (BPF_ALU | BPF_MOD | BPF_X) as u8);
+        assert_eq!(insns.len(), p + 5);
+    }
+
+    #[test]
+    fn jset_takes_both_branches() {
+        let insns = translated(vec![
+            Instruction::new(BPF_JMP | BPF_JSET | BPF_K, 1, 2, 0x10),
+            Instruction::new(BPF_RET | BPF_K, 0, 0, 0),
+            Instruction::new(BPF_RET | BPF_X, 0, 0, 0),
+            Instruction::new(BPF_RET | BPF_A, 0, 0, 0),
+        ]);
+        let p = prologue().len();
+        // The condition for jt, then a jump for jf, over the return of 0
+        // that no path reaches and so isn't translated
+        assert_eq!(insns[p].code, BPF_JMP32 | BPF_JSET as u8);
+        assert_eq!(target(&insns, p), p + 2);
+        assert_eq!(insns[p + 1].code, BPF_JMP as u8 | BPF_JA as u8);
+        assert_eq!(target(&insns, p + 1), p + 4);
+        // RET X moves X to A first
+        let mov = insns[p + 2];
+        assert_eq!(mov.code, BPF_ALU as u8 | BPF_MOV | BPF_X as u8);
+        assert_eq!(mov.regs, A | (X << 4));
+        assert_eq!(insns[p + 3].code, EXIT);
+        assert_eq!(insns[p + 4].code, EXIT);
+        assert_eq!(insns.len(), p + 5);
+        assert_jumps_in_bounds(&insns);
+    }
+
+    #[test]
+    fn port_programs_translate() {
+        let ports: Vec<u16> = (1..=MAX_PORTS as u16).collect();
+        for ports in [&[][..], &[80], &ports] {
+            let program = Program::tcp_ports(ports).unwrap();
+            assert_jumps_in_bounds(&program.translate().unwrap());
+        }
+        let ports: Vec<u16> = (0..=MAX_PORTS as u16).collect();
+        assert!(Program::tcp_ports(&ports).is_err());
+    }
+}
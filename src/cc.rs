@@ -0,0 +1,582 @@
+//! Congestion control (RFC 5681) and the controllers to pick from, see
+//! [`crate::InterfaceConfig::congestion_control`].
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::InterfaceConfig;
+
+/// Congestion control algorithm of the connections
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CongestionAlgorithm {
+    /// Loss based slow start and congestion avoidance (RFC 5681)
+    Reno,
+    /// Model based, paced from the estimated bottleneck bandwidth and
+    /// round-trip time (draft-cardwell-iccrg-bbr-congestion-control)
+    Bbr,
+}
+
+/// What an ACK told about the path
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AckSample {
+    pub now: Instant,
+    /// Bytes newly acknowledged
+    pub acked: usize,
+    /// Bytes still in flight after this ACK
+    pub in_flight: usize,
+    /// Round-trip time of the most recent segment acknowledged
+    pub rtt: Option<Duration>,
+    /// Bytes delivered per second while that segment was in flight
+    pub delivery_rate: Option<f64>,
+    /// Bytes delivered to the peer so far, this ACK included
+    pub delivered: u64,
+    /// Value of `delivered` when that segment was sent
+    pub prior_delivered: u64,
+    /// In the fast recovery of a loss the SACK blocks told about
+    pub recovering: bool,
+}
+
+/// Per-connection congestion controller
+#[derive(Clone, Debug)]
+pub(crate) enum Controller {
+    Reno(Reno),
+    Bbr(Bbr),
+}
+
+impl Controller {
+    pub(crate) fn new(config: &InterfaceConfig, mss: usize) -> Self {
+        match config.congestion_control {
+            CongestionAlgorithm::Reno => Self::Reno(Reno::new(mss, config.hystart)),
+            CongestionAlgorithm::Bbr => Self::Bbr(Bbr::new(mss)),
+        }
+    }
+
+    pub(crate) fn on_ack(&mut self, sample: &AckSample) {
+        match self {
+            Self::Reno(cc) => cc.on_ack(sample),
+            Self::Bbr(cc) => cc.on_ack(sample),
+        }
+    }
+
+    /// The retransmission timer went off with `in_flight` bytes unacknowledged
+    pub(crate) fn on_timeout(&mut self, in_flight: usize) {
+        match self {
+            Self::Reno(cc) => cc.on_timeout(in_flight),
+            Self::Bbr(cc) => cc.on_timeout(in_flight),
+        }
+    }
+
+    /// The SACK blocks told about a loss with `in_flight` bytes
+    /// unacknowledged, the holes are being retransmitted
+    pub(crate) fn on_loss(&mut self, in_flight: usize) {
+        match self {
+            Self::Reno(cc) => cc.on_loss(in_flight),
+            // Its model takes losses in through the delivery rate
+            Self::Bbr(_) => {}
+        }
+    }
+
+    /// Bytes allowed in flight
+    pub(crate) fn cwnd(&self) -> usize {
+        match self {
+            Self::Reno(cc) => cc.cwnd,
+            Self::Bbr(cc) => cc.cwnd(),
+        }
+    }
+
+    /// Slow start threshold, once there is one
+    pub(crate) fn ssthresh(&self) -> Option<usize> {
+        match self {
+            Self::Reno(cc) => Some(cc.ssthresh).filter(|&t| t != usize::MAX),
+            Self::Bbr(_) => None,
+        }
+    }
+
+    /// Bytes per second to space the segments out at, if any
+    pub(crate) fn pacing_rate(&self) -> Option<f64> {
+        match self {
+            Self::Reno(_) => None,
+            Self::Bbr(cc) => cc.pacing_rate(),
+        }
+    }
+}
+
+/// Initial window (RFC 6928)
+fn initial_window(mss: usize) -> usize {
+    10 * mss
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Reno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// Delay based slow start exit, if enabled
+    hystart: Option<HyStart>,
+}
+
+impl Reno {
+    fn new(mss: usize, hystart: bool) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            hystart: if hystart {
+                Some(HyStart::default())
+            } else {
+                None
+            },
+        }
+    }
+
+    fn on_ack(&mut self, sample: &AckSample) {
+        if sample.recovering {
+            // Held until the recovery is over (RFC 6675 S5)
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            let growth = sample.acked.min(self.mss);
+            match self.hystart.as_mut().map(|h| h.on_ack(sample)) {
+                Some(SlowStart::Exit) => {
+                    self.ssthresh = self.cwnd;
+                    self.hystart = None;
+                }
+                Some(SlowStart::Conservative) => {
+                    self.cwnd += (growth / HYSTART_CSS_GROWTH_DIVISOR).max(1);
+                }
+                // Slow start (RFC 5681 S3.1)
+                _ => self.cwnd += growth,
+            }
+        } else {
+            // Congestion avoidance, about one MSS per RTT
+            self.cwnd += (self.mss * self.mss / self.cwnd).max(1);
+        }
+    }
+
+    fn on_loss(&mut self, in_flight: usize) {
+        // RFC 5681 S3.2 (2) and (3), without the inflation: the SACK blocks
+        // tell what left the network
+        self.ssthresh = (in_flight / 2).max(2 * self.mss);
+        self.cwnd = self.ssthresh;
+        self.hystart = None;
+    }
+
+    fn on_timeout(&mut self, in_flight: usize) {
+        // RFC 5681 S3.1 (4) and the loss window
+        self.ssthresh = (in_flight / 2).max(2 * self.mss);
+        self.cwnd = self.mss;
+        // Slow start after a loss is bounded by ssthresh already
+        self.hystart = None;
+    }
+}
+
+// RFC 9406 S4.3
+const HYSTART_MIN_RTT_THRESH: Duration = Duration::from_millis(4);
+const HYSTART_MAX_RTT_THRESH: Duration = Duration::from_millis(16);
+const HYSTART_MIN_RTT_DIVISOR: u32 = 8;
+const HYSTART_N_RTT_SAMPLE: u32 = 8;
+const HYSTART_CSS_GROWTH_DIVISOR: usize = 4;
+const HYSTART_CSS_ROUNDS: u32 = 5;
+
+/// How the congestion window should grow on this ACK
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SlowStart {
+    Standard,
+    /// Conservative slow start, the RTT went up
+    Conservative,
+    /// Move on to congestion avoidance
+    Exit,
+}
+
+/// HyStart++ (RFC 9406): leaves slow start once the RTT starts growing,
+/// instead of overshooting until a loss.
+#[derive(Clone, Debug, Default)]
+struct HyStart {
+    /// Rounds end once a segment sent after their start gets acknowledged
+    next_round_delivered: u64,
+    last_round_min_rtt: Option<Duration>,
+    current_round_min_rtt: Option<Duration>,
+    rtt_sample_count: u32,
+    /// Minimum RTT of the round that triggered conservative slow start
+    css_baseline_min_rtt: Option<Duration>,
+    css_rounds: u32,
+}
+
+impl HyStart {
+    fn on_ack(&mut self, sample: &AckSample) -> SlowStart {
+        if sample.prior_delivered >= self.next_round_delivered {
+            self.next_round_delivered = sample.delivered;
+            self.last_round_min_rtt = self.current_round_min_rtt.take();
+            self.rtt_sample_count = 0;
+
+            if self.css_baseline_min_rtt.is_some() {
+                self.css_rounds += 1;
+                if self.css_rounds >= HYSTART_CSS_ROUNDS {
+                    return SlowStart::Exit;
+                }
+            }
+        }
+
+        if let Some(rtt) = sample.rtt {
+            self.rtt_sample_count += 1;
+            let min = self.current_round_min_rtt.map_or(rtt, |min| min.min(rtt));
+            self.current_round_min_rtt = Some(min);
+        }
+
+        let current = match self.current_round_min_rtt {
+            Some(current) => current,
+            None => return self.mode(),
+        };
+
+        match self.css_baseline_min_rtt {
+            None => {
+                if let Some(last) = self.last_round_min_rtt {
+                    if self.rtt_sample_count >= HYSTART_N_RTT_SAMPLE {
+                        let thresh = (last / HYSTART_MIN_RTT_DIVISOR)
+                            .clamp(HYSTART_MIN_RTT_THRESH, HYSTART_MAX_RTT_THRESH);
+                        if current >= last + thresh {
+                            self.css_baseline_min_rtt = Some(current);
+                            self.css_rounds = 0;
+                        }
+                    }
+                }
+            }
+            Some(baseline) => {
+                // The RTT went back down, that was a false alarm
+                if current < baseline {
+                    self.css_baseline_min_rtt = None;
+                }
+            }
+        }
+        self.mode()
+    }
+
+    fn mode(&self) -> SlowStart {
+        if self.css_baseline_min_rtt.is_some() {
+            SlowStart::Conservative
+        } else {
+            SlowStart::Standard
+        }
+    }
+}
+
+/// 2/ln(2), the smallest gain doubling the delivery rate every round
+const BBR_HIGH_GAIN: f64 = 2.885;
+/// Gains cycled through while probing for bandwidth, one per round
+const BBR_PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// Rounds over which the maximum delivery rate is kept
+const BBR_BW_WINDOW: u64 = 10;
+/// How long a minimum RTT stays valid without being seen again
+const BBR_MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+/// Time spent with a minimal window to measure the RTT again
+const BBR_PROBE_RTT_TIME: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BbrMode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Bbr {
+    mss: usize,
+    mode: BbrMode,
+    /// Delivery rate samples of the last rounds, for the max filter
+    bw_samples: VecDeque<(u64, f64)>,
+    min_rtt: Option<Duration>,
+    min_rtt_at: Option<Instant>,
+    /// Round-trip counting: a round ends once a segment sent after its
+    /// start gets acknowledged
+    round: u64,
+    next_round_delivered: u64,
+    /// Bandwidth when we last saw it grow, and rounds since then
+    full_bw: f64,
+    full_bw_rounds: u32,
+    cycle: usize,
+    probe_rtt_done: Option<Instant>,
+}
+
+impl Bbr {
+    fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            mode: BbrMode::Startup,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_at: None,
+            round: 0,
+            next_round_delivered: 0,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            cycle: 0,
+            probe_rtt_done: None,
+        }
+    }
+
+    /// Estimated bottleneck bandwidth, in bytes per second
+    fn btl_bw(&self) -> Option<f64> {
+        self.bw_samples.iter().map(|&(_, bw)| bw).reduce(f64::max)
+    }
+
+    /// Estimated bandwidth-delay product
+    fn bdp(&self) -> Option<usize> {
+        Some((self.btl_bw()? * self.min_rtt?.as_secs_f64()) as usize)
+    }
+
+    fn min_cwnd(&self) -> usize {
+        4 * self.mss
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            BbrMode::Startup => BBR_HIGH_GAIN,
+            BbrMode::Drain => 1.0 / BBR_HIGH_GAIN,
+            BbrMode::ProbeBw => BBR_PROBE_BW_GAINS[self.cycle],
+            BbrMode::ProbeRtt => 1.0,
+        }
+    }
+
+    fn cwnd(&self) -> usize {
+        if let BbrMode::ProbeRtt = self.mode {
+            return self.min_cwnd();
+        }
+
+        let gain = match self.mode {
+            BbrMode::Startup | BbrMode::Drain => BBR_HIGH_GAIN,
+            _ => 2.0,
+        };
+        match self.bdp() {
+            Some(bdp) => ((gain * bdp as f64) as usize).max(self.min_cwnd()),
+            None => initial_window(self.mss),
+        }
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+        Some(self.pacing_gain() * self.btl_bw()?)
+    }
+
+    fn on_ack(&mut self, sample: &AckSample) {
+        let round_start = sample.prior_delivered >= self.next_round_delivered;
+        if round_start {
+            self.round += 1;
+            self.next_round_delivered = sample.delivered;
+            if let BbrMode::ProbeBw = self.mode {
+                self.cycle = (self.cycle + 1) % BBR_PROBE_BW_GAINS.len();
+            }
+        }
+
+        if let Some(rate) = sample.delivery_rate {
+            self.bw_samples.push_back((self.round, rate));
+            let round = self.round;
+            self.bw_samples
+                .retain(|&(r, _)| round.saturating_sub(r) < BBR_BW_WINDOW);
+        }
+
+        let min_rtt_expired = self
+            .min_rtt_at
+            .is_some_and(|at| sample.now.duration_since(at) > BBR_MIN_RTT_WINDOW);
+        if let Some(rtt) = sample.rtt {
+            if self.min_rtt.is_none_or(|min| rtt <= min) || min_rtt_expired {
+                self.min_rtt = Some(rtt);
+                self.min_rtt_at = Some(sample.now);
+            }
+        }
+
+        match self.mode {
+            BbrMode::Startup => {
+                if round_start {
+                    self.check_full_pipe();
+                }
+            }
+            BbrMode::Drain => {
+                if self.bdp().is_none_or(|bdp| sample.in_flight <= bdp) {
+                    self.mode = BbrMode::ProbeBw;
+                    self.cycle = 0;
+                }
+            }
+            BbrMode::ProbeBw => {}
+            BbrMode::ProbeRtt => {
+                let done = *self
+                    .probe_rtt_done
+                    .get_or_insert(sample.now + BBR_PROBE_RTT_TIME);
+                if sample.now >= done {
+                    self.probe_rtt_done = None;
+                    self.min_rtt_at = Some(sample.now);
+                    self.mode = BbrMode::ProbeBw;
+                    self.cycle = 0;
+                }
+            }
+        }
+
+        if min_rtt_expired && self.mode != BbrMode::ProbeRtt {
+            self.mode = BbrMode::ProbeRtt;
+        }
+    }
+
+    /// Leaves startup once the bandwidth stopped growing by 25% for 3 rounds
+    fn check_full_pipe(&mut self) {
+        let bw = match self.btl_bw() {
+            Some(bw) => bw,
+            None => return,
+        };
+
+        if bw >= self.full_bw * 1.25 {
+            self.full_bw = bw;
+            self.full_bw_rounds = 0;
+            return;
+        }
+
+        self.full_bw_rounds += 1;
+        if self.full_bw_rounds >= 3 {
+            self.mode = BbrMode::Drain;
+        }
+    }
+
+    fn on_timeout(&mut self, _in_flight: usize) {
+        // The model no longer matches the path, start probing from scratch
+        self.bw_samples.clear();
+        self.full_bw = 0.0;
+        self.full_bw_rounds = 0;
+        self.mode = BbrMode::Startup;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    fn controller(congestion_control: CongestionAlgorithm) -> Controller {
+        let config = InterfaceConfig {
+            congestion_control,
+            hystart: false,
+            ..Default::default()
+        };
+        Controller::new(&config, MSS)
+    }
+
+    /// ACK starting a new round, `delivered` counting them
+    fn ack(now: Instant, delivered: u64, in_flight: usize, rate: f64) -> AckSample {
+        AckSample {
+            now,
+            acked: MSS,
+            in_flight,
+            rtt: Some(Duration::from_millis(10)),
+            delivery_rate: Some(rate),
+            delivered,
+            prior_delivered: delivered,
+            recovering: false,
+        }
+    }
+
+    fn mode(cc: &Controller) -> BbrMode {
+        match cc {
+            Controller::Bbr(bbr) => bbr.mode,
+            Controller::Reno(_) => unreachable!(),
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() <= b * 1e-9 + 1.0, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn reno_halves_on_loss_and_restarts_on_timeout() {
+        let now = Instant::now();
+        let mut cc = controller(CongestionAlgorithm::Reno);
+        assert_eq!(cc.cwnd(), 10 * MSS);
+        assert_eq!(cc.ssthresh(), None);
+        assert_eq!(cc.pacing_rate(), None);
+
+        // Slow start, by at most an MSS per ACK
+        cc.on_ack(&AckSample {
+            acked: 3 * MSS,
+            ..ack(now, 1, 0, 0.0)
+        });
+        assert_eq!(cc.cwnd(), 11 * MSS);
+
+        cc.on_loss(20 * MSS);
+        assert_eq!(cc.ssthresh(), Some(10 * MSS));
+        assert_eq!(cc.cwnd(), 10 * MSS);
+        // Held through the recovery
+        cc.on_ack(&AckSample {
+            recovering: true,
+            ..ack(now, 2, 0, 0.0)
+        });
+        assert_eq!(cc.cwnd(), 10 * MSS);
+        // Then congestion avoidance, MSS * MSS / cwnd per ACK
+        cc.on_ack(&ack(now, 3, 0, 0.0));
+        assert_eq!(cc.cwnd(), 10 * MSS + MSS / 10);
+
+        cc.on_timeout(8 * MSS);
+        assert_eq!(cc.ssthresh(), Some(4 * MSS));
+        assert_eq!(cc.cwnd(), MSS);
+        cc.on_ack(&ack(now, 4, 0, 0.0));
+        assert_eq!(cc.cwnd(), 2 * MSS);
+    }
+
+    #[test]
+    fn bbr_goes_through_its_phases() {
+        let start = Instant::now();
+        let mut cc = controller(CongestionAlgorithm::Bbr);
+        assert_eq!(cc.cwnd(), 10 * MSS);
+        assert_eq!(cc.pacing_rate(), None);
+        // Losses only reach it through the delivery rate
+        cc.on_loss(MSS);
+        assert_eq!(cc.cwnd(), 10 * MSS);
+
+        // Startup while the bandwidth keeps growing, 4 MB/s in the end with
+        // a BDP of 40 kB
+        let mut round = 0;
+        let mut next = |cc: &mut Controller, now, in_flight, rate| {
+            round += 1;
+            cc.on_ack(&ack(now, round, in_flight, rate));
+        };
+        for rate in [1e6, 2e6, 4e6] {
+            next(&mut cc, start, 0, rate);
+            assert_eq!(mode(&cc), BbrMode::Startup);
+        }
+        assert_close(cc.pacing_rate().unwrap(), BBR_HIGH_GAIN * 4e6);
+        assert_close(cc.cwnd() as f64, BBR_HIGH_GAIN * 40e3);
+
+        // Until it stops growing for 3 rounds
+        for _ in 0..3 {
+            next(&mut cc, start, 0, 4e6);
+        }
+        assert_eq!(mode(&cc), BbrMode::Drain);
+        assert_close(cc.pacing_rate().unwrap(), 4e6 / BBR_HIGH_GAIN);
+        // The queue isn't drained while more than the BDP is in flight
+        next(&mut cc, start, 50_000, 4e6);
+        assert_eq!(mode(&cc), BbrMode::Drain);
+        next(&mut cc, start, 30_000, 4e6);
+        assert_eq!(mode(&cc), BbrMode::ProbeBw);
+
+        // Probing up, then down, one round each
+        assert_close(cc.pacing_rate().unwrap(), 1.25 * 4e6);
+        assert_close(cc.cwnd() as f64, 2.0 * 40e3);
+        next(&mut cc, start, 0, 4e6);
+        assert_close(cc.pacing_rate().unwrap(), 0.75 * 4e6);
+
+        // No lower RTT in 10s, the window shrinks for a while to measure it
+        let later = start + BBR_MIN_RTT_WINDOW + Duration::from_secs(1);
+        next(&mut cc, later, 0, 4e6);
+        assert_eq!(mode(&cc), BbrMode::ProbeRtt);
+        assert_eq!(cc.cwnd(), 4 * MSS);
+        assert_close(cc.pacing_rate().unwrap(), 4e6);
+        next(&mut cc, later, 0, 4e6);
+        assert_eq!(mode(&cc), BbrMode::ProbeRtt);
+        next(&mut cc, later + BBR_PROBE_RTT_TIME, 0, 4e6);
+        assert_eq!(mode(&cc), BbrMode::ProbeBw);
+        assert_close(cc.cwnd() as f64, 2.0 * 40e3);
+
+        // A timeout throws the model away
+        cc.on_timeout(MSS);
+        assert_eq!(mode(&cc), BbrMode::Startup);
+        assert_eq!(cc.cwnd(), 10 * MSS);
+        assert_eq!(cc.pacing_rate(), None);
+    }
+}
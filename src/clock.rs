@@ -0,0 +1,89 @@
+//! Time as the connection timers see it, see [`InterfaceConfig::clock`].
+//!
+//! A [`VirtualClock`] only moves when told to, so that a test can walk a
+//! connection through its retransmissions or TIME-WAIT in milliseconds:
+//!
+//! ```
+//! # use tcp_rust::{clock::VirtualClock, device::Loopback, EventLoop, Interface, InterfaceConfig};
+//! # use std::{io, sync::Arc, time::Duration};
+//! # fn main() -> io::Result<()> {
+//! let clock = VirtualClock::new();
+//! let config = InterfaceConfig {
+//!     clock: Arc::new(clock.clone()),
+//!     event_loop: EventLoop::Manual,
+//!     ..Default::default()
+//! };
+//! # let (nic, _peer) = Loopback::pair()?;
+//! let iface = Interface::with_device(nic, config.clone())?;
+//! // Four minutes of TIME-WAIT, in a blink
+//! clock.advance(&iface, config.msl * 2)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`InterfaceConfig::clock`]: crate::InterfaceConfig::clock
+use std::{
+    fmt::Debug,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::Interface;
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Time since `earlier`, zero if it's in the future
+    fn since(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+/// The clock on the wall
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that stands still unless advanced, its clones sharing the time.
+#[derive(Clone, Debug)]
+pub struct VirtualClock(Arc<Mutex<Instant>>);
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Moves time forward by `by`, one tick of the packet loop at a time,
+    /// running the timers of `iface` synchronously at every tick, so the
+    /// segments they send come out in the order they would in real time.
+    /// The interface must be driven by hand, see
+    /// [`crate::EventLoop::Manual`].
+    pub fn advance(&self, iface: &Interface, by: Duration) -> io::Result<()> {
+        let mut left = by;
+        while left > Duration::ZERO {
+            let step = left.min(crate::TICK);
+            *self.0.lock().unwrap() += step;
+            left -= step;
+            iface.poll(Duration::ZERO)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
@@ -0,0 +1,225 @@
+//! Messages over a [`crate::TcpStream`], or anything else that reads and
+//! writes a byte stream: length-prefixed frames, see [`LengthDelimited`],
+//! and text lines, see [`Lines`].
+//!
+//! ```
+//! # use tcp_rust::codec::{LengthDelimited, Lines};
+//! # use std::io::{self, Cursor};
+//! # fn main() -> io::Result<()> {
+//! let mut frames = LengthDelimited::new(Vec::new());
+//! frames.write_frame(b"hello")?;
+//! frames.write_frame(b"")?;
+//!
+//! let mut frames = LengthDelimited::new(Cursor::new(frames.into_inner()));
+//! assert_eq!(frames.read_frame()?.as_deref(), Some(&b"hello"[..]));
+//! assert_eq!(frames.read_frame()?.as_deref(), Some(&b""[..]));
+//! assert_eq!(frames.read_frame()?, None);
+//!
+//! let mut lines = Lines::new(Cursor::new(b"GET /\r\nHost: a\nend".to_vec()));
+//! assert_eq!(lines.read_line()?.as_deref(), Some("GET /"));
+//! assert_eq!(lines.read_line()?.as_deref(), Some("Host: a"));
+//! // The last line may go without its newline
+//! assert_eq!(lines.read_line()?.as_deref(), Some("end"));
+//! assert_eq!(lines.read_line()?, None);
+//! # Ok(())
+//! # }
+//! ```
+use std::io::{self, Read, Write};
+
+/// Largest frame or line accepted by default, 8 MiB
+pub const DEFAULT_MAX_LEN: usize = 8 << 20;
+
+/// Frames preceded by their length, as a 32 bit big-endian integer
+pub struct LengthDelimited<S> {
+    inner: S,
+    max_len: usize,
+}
+
+impl<S> LengthDelimited<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            max_len: DEFAULT_MAX_LEN,
+        }
+    }
+
+    /// Frames longer than `max_len` are refused both ways, which keeps a
+    /// peer from making us allocate whatever its prefix says.
+    pub fn with_max_len(inner: S, max_len: usize) -> Self {
+        Self { inner, max_len }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> LengthDelimited<S> {
+    /// The next frame, `None` once the stream ends between frames. Ending
+    /// within one is [`io::ErrorKind::UnexpectedEof`].
+    pub fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut prefix = [0u8; 4];
+        let mut filled = 0;
+        while filled < prefix.len() {
+            match self.inner.read(&mut prefix[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Stream ended within a frame",
+                    ))
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > self.max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Frame is too long",
+            ));
+        }
+        let mut frame = vec![0; len];
+        self.inner.read_exact(&mut frame)?;
+        Ok(Some(frame))
+    }
+}
+
+impl<S: Write> LengthDelimited<S> {
+    /// Writes `frame` with its prefix, as one write when the stream takes
+    /// it all.
+    pub fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > self.max_len || frame.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Frame is too long",
+            ));
+        }
+        let mut buf = Vec::with_capacity(4 + frame.len());
+        buf.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        buf.extend_from_slice(frame);
+        self.inner.write_all(&buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// UTF-8 lines ending in `\n`, or `\r\n` when reading
+pub struct Lines<S> {
+    inner: S,
+    /// Read ahead, not returned yet
+    pending: Vec<u8>,
+    max_len: usize,
+}
+
+impl<S> Lines<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_max_len(inner, DEFAULT_MAX_LEN)
+    }
+
+    /// Lines longer than `max_len` bytes are refused both ways, so a peer
+    /// that never sends a newline can't make the buffer grow forever.
+    pub fn with_max_len(inner: S, max_len: usize) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            max_len,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Reading from the stream directly skips whatever is buffered here
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Drops the data read ahead but not returned yet
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Lines<S> {
+    /// The next line without its end, `None` once the stream is over
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut scanned = 0;
+        let line = loop {
+            if let Some(i) = self.pending[scanned..].iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.pending.drain(..=scanned + i).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                break line;
+            }
+            scanned = self.pending.len();
+            if scanned > self.max_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Line is too long",
+                ));
+            }
+
+            let mut chunk = [0u8; 4096];
+            match self.inner.read(&mut chunk) {
+                Ok(0) if self.pending.is_empty() => return Ok(None),
+                Ok(0) => break std::mem::take(&mut self.pending),
+                Ok(n) => self.pending.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+
+        if line.len() > self.max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Line is too long",
+            ));
+        }
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Line is not UTF-8"))
+    }
+}
+
+impl<S: Write> Lines<S> {
+    /// Writes `line` followed by `\n`. It can't have a newline of its own.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if line.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Line has a newline",
+            ));
+        }
+        if line.len() > self.max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Line is too long",
+            ));
+        }
+        let mut buf = Vec::with_capacity(line.len() + 1);
+        buf.extend_from_slice(line.as_bytes());
+        buf.push(b'\n');
+        self.inner.write_all(&buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,482 @@
+use std::{io, net::Ipv4Addr, sync::Arc, time::Duration};
+
+use crate::{
+    cc::CongestionAlgorithm,
+    clock::{Clock, SystemClock},
+};
+
+/// Runtime-tunable protocol parameters.
+///
+/// Every field can be changed while the interface is running through
+/// [`crate::Interface::update_config`]; the new values are picked up by all
+/// connections (including the ones already established) on the next tick.
+#[derive(Clone, Debug)]
+pub struct InterfaceConfig {
+    /// Maximum Segment Lifetime. Connections stay in TIME-WAIT for 2*MSL.
+    pub msl: Duration,
+    /// Answer a FIN retransmitted in TIME-WAIT with the final ACK again,
+    /// restarting the 2*MSL wait (RFC 9293 S3.10.7.4). Otherwise TIME-WAIT
+    /// ignores everything and the peer retransmits until it gives up.
+    pub time_wait_ack: bool,
+    /// Lower bound for the retransmission timeout
+    pub rto_min: Duration,
+    /// Upper bound for the retransmission timeout
+    pub rto_max: Duration,
+    /// How many times the SYN-ACK is retransmitted before giving up on a
+    /// half-open connection
+    pub syn_ack_retries: u32,
+    /// How many times the SYN of [`crate::Interface::connect`] is
+    /// retransmitted before giving up on the peer
+    pub syn_retries: u32,
+    /// How long to wait in FIN-WAIT-2 for the peer's FIN
+    pub fin_wait2_timeout: Duration,
+    /// How long the link can be down before blocking calls give up on it,
+    /// see [`crate::Interface::set_link_state`]
+    pub link_down_grace: Duration,
+    /// What to do with a SYN arriving on a synchronized connection
+    pub in_window_syn: SynPolicy,
+    /// What to do with packets whose TCP header has something odd about it
+    pub parsing: ParsePolicy,
+    /// Behaviors where the stack departs from the RFCs unless asked not to
+    pub compliance: Compliance,
+    /// Cap on the connections a single remote address can hold at once
+    pub ip_quota: Option<IpQuota>,
+    /// Hash function of the connection map, looked up on every packet
+    pub connection_hasher: ConnectionHasher,
+    /// Connections to reserve room for up front, to avoid rehashing the
+    /// connection map while it fills up
+    pub expected_connections: usize,
+    /// Worker threads running the connection state machines. With `0` the
+    /// packet loop does everything itself, otherwise it only parses and
+    /// demultiplexes, handing each segment to the worker owning its quad.
+    /// The connections are split in a shard per worker, so that a worker
+    /// runs the segments of established connections with only its shard
+    /// locked. New connections, and every segment while an event sink is
+    /// installed, still take the connection manager lock. A worker that
+    /// fails stops the packet loop with its error. Only read when the
+    /// interface is created.
+    pub workers: usize,
+    /// Local addresses the interface answers on, any of them if empty.
+    /// Left empty, a TUN interface takes the address of its device if it
+    /// has one already. Only read when the interface is created, see
+    /// [`crate::Interface::add_addr`] to change them.
+    pub addresses: Vec<Ipv4Addr>,
+    /// Check the addresses of every packet before it reaches a connection:
+    /// drop the ones that aren't for one of the `addresses` and the ones
+    /// claiming to come from one of them, counting both in
+    /// [`crate::stats::InterfaceStats::reverse_path`]. Without it, packets
+    /// for other addresses are dropped all the same but not counted.
+    pub reverse_path: bool,
+    /// How long a connection on a port with routes waits for the bytes
+    /// they look at before it goes to the listener that bound the port,
+    /// see [`crate::TcpListener::route`]
+    pub route_timeout: Duration,
+    /// Who runs the packet loop. Only read when the interface is created.
+    pub event_loop: EventLoop,
+    /// Threads running the handlers of [`crate::Interface::serve`], each
+    /// serving one connection at a time. Read when a server is started.
+    pub serve_threads: usize,
+    /// Non-blocking polls of the device to spin through before falling back
+    /// to a blocking wait, trading CPU for wakeup latency. `0` disables it.
+    pub busy_poll: u32,
+    /// Packets to read from the device in one go when more are ready.
+    /// In-order data segments of a flow within a batch are merged, so its
+    /// connection runs once per burst. `1` disables it.
+    pub rx_batch: usize,
+    /// Ask the TUN device for segmentation offload, so that bulk data can
+    /// be written in packets larger than a segment. Falls back to plain
+    /// packets when the kernel doesn't support it.
+    /// Only read when the interface is created.
+    pub offload: bool,
+    /// Leave the TCP checksums to the device when it can compute and
+    /// validate them. Segmentation offload relies on it, so turning it off
+    /// disables `offload` too. Only read when the interface is created.
+    pub checksum_offload: bool,
+    /// Offer timestamps in our SYNs and take them up when the peer offers
+    /// them, timing round trips off the echoed ones (RFC 7323 S3 and S4).
+    /// Read when a connection opens.
+    pub timestamps: bool,
+    /// Offer selective acknowledgments in our SYNs and take them up when
+    /// the peer offers them (RFC 2018): data arriving past a hole is held
+    /// on to and reported, and only the holes the peer reports get
+    /// retransmitted (RFC 6675). Read when a connection opens.
+    pub sack: bool,
+    /// Congestion controller of the new connections
+    pub congestion_control: CongestionAlgorithm,
+    /// Leave Reno's slow start as soon as the RTT goes up (HyStart++,
+    /// RFC 9406) rather than once the first loss happens
+    pub hystart: bool,
+    /// Take a [`crate::stats::CcSample`] of every connection this often,
+    /// see [`crate::TcpStream::take_cc_samples`]. `None` disables it.
+    pub cc_sample_interval: Option<Duration>,
+    /// Samples a connection holds, the oldest ones are dropped past it
+    pub cc_sample_capacity: usize,
+    /// What the connection timers go by, e.g. a
+    /// [`crate::clock::VirtualClock`] in tests. Connections keep the clock
+    /// they were created with.
+    pub clock: Arc<dyn Clock>,
+    /// Initial send sequence number of the new connections, e.g. right
+    /// below `u32::MAX` to exercise the wraparound. Takes precedence over
+    /// the entropy source.
+    #[cfg(feature = "testing")]
+    pub initial_sequence: Option<u32>,
+}
+
+/// Per-stream settings. A listener hands its defaults to every stream it
+/// accepts (see [`crate::TcpListener::set_stream_options`]), and each stream
+/// can change its own afterwards with [`crate::TcpStream::set_options`].
+///
+/// Small segments are never held back by this stack (there is no Nagle's
+/// algorithm), so there's nothing like `TCP_NODELAY` to turn off.
+/// # Examples
+/// ```no_run
+/// # use tcp_rust::StreamOptions;
+/// # use std::io;
+/// # fn main() -> io::Result<()> {
+/// let mut iface = tcp_rust::Interface::new()?;
+/// let mut listener = iface.bind(80)?;
+/// let stream = listener.accept()?;
+/// // Writers wake up once the queue is down to 2 of its 8 bytes
+/// stream.set_options(StreamOptions {
+///     send_buffer: 8,
+///     send_low_watermark: Some(2),
+///     ..Default::default()
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamOptions {
+    /// Bytes that can be queued for sending before writes block, the high
+    /// watermark of the send queue
+    pub send_buffer: usize,
+    /// Once the send queue is full, writes block until it drains down to
+    /// this many bytes, so a writer under pressure wakes up to a batch of
+    /// room rather than to every byte acknowledged. `None` takes writes as
+    /// soon as there's room.
+    pub send_low_watermark: Option<usize>,
+    /// Bytes received but not read yet that can be held, which is also the
+    /// largest window advertised. Windows aren't scaled, so this can't go
+    /// beyond 65535.
+    pub recv_buffer: usize,
+    /// Once this many bytes wait to be read, a
+    /// [`crate::events::EventKind::RecvPressure`] event tells that the
+    /// reader is falling behind, so that the application can throttle what
+    /// feeds it rather than let the window close. `None` emits no such
+    /// events.
+    pub recv_high_watermark: Option<usize>,
+    /// Under pressure, the event telling it's over waits for reads to bring
+    /// the bytes waiting down to this many. `None` ends it as soon as
+    /// they're below the high watermark.
+    pub recv_low_watermark: Option<usize>,
+    /// Probe the peer once the connection has been idle for this long, and
+    /// again at the same interval, resetting the connection once
+    /// [`KEEPALIVE_PROBES`] went unanswered (RFC 1122 S4.2.3.6)
+    pub keepalive: Option<Duration>,
+    /// How long reads wait for data
+    pub read_mode: ReadMode,
+    /// What becomes of the data arriving once the read half is shut down
+    pub read_shutdown: ReadShutdown,
+}
+
+/// How long a read waits for data, see [`crate::TcpStream::set_read_mode`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Returns as soon as there is anything to read
+    Any,
+    /// Waits for the whole buffer to fill up, like `MSG_WAITALL`, returning
+    /// less only at the end of the stream or when the read times out
+    Fill,
+}
+
+/// What [`crate::TcpStream::shutdown`] with [`std::net::Shutdown::Read`]
+/// does to the data the peer still sends
+/// # Examples
+/// ```
+/// # use tcp_rust::{ReadShutdown, StreamOptions};
+/// let options = StreamOptions {
+///     read_shutdown: ReadShutdown::Reset,
+///     ..Default::default()
+/// };
+/// # assert_eq!(options.read_shutdown, ReadShutdown::Reset);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadShutdown {
+    /// Acknowledged and dropped, the window staying open, like Linux
+    Discard,
+    /// Refused with a zero window, so that the peer stops sending. Its FIN
+    /// can't get in either, the connection only goes once we close too.
+    CloseWindow,
+    /// The connection is reset, telling the peer its data was lost
+    /// (RFC 1122 S4.2.2.13)
+    Reset,
+}
+
+/// Unanswered keep-alive probes after which a connection is reset, same as
+/// Linux's `tcp_keepalive_probes`
+pub const KEEPALIVE_PROBES: u32 = 9;
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            send_buffer: 1024,
+            recv_buffer: 1024,
+            send_low_watermark: None,
+            recv_high_watermark: None,
+            recv_low_watermark: None,
+            keepalive: None,
+            read_mode: ReadMode::Any,
+            read_shutdown: ReadShutdown::Discard,
+        }
+    }
+}
+
+impl StreamOptions {
+    /// Checks that the buffers can be used.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.send_buffer == 0 || self.recv_buffer == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Buffers can't be empty",
+            ));
+        }
+        if matches!(self.send_low_watermark, Some(low) if low >= self.send_buffer) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "send_low_watermark must be below send_buffer",
+            ));
+        }
+        if self.recv_buffer > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "recv_buffer must fit in an unscaled window",
+            ));
+        }
+        match (self.recv_high_watermark, self.recv_low_watermark) {
+            (Some(high), _) if high == 0 || high > self.recv_buffer => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "recv_high_watermark must be within recv_buffer",
+                ));
+            }
+            (Some(high), Some(low)) if low >= high => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "recv_low_watermark must be below recv_high_watermark",
+                ));
+            }
+            (None, Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "recv_low_watermark needs a recv_high_watermark",
+                ));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Cap on the concurrent connections from one remote address, enforced when
+/// their SYN arrives. Connections still in the handshake or in TIME-WAIT
+/// count too.
+/// # Examples
+/// ```
+/// # use tcp_rust::{InterfaceConfig, IpQuota};
+/// let config = InterfaceConfig {
+///     // A client gets one connection, its next SYNs a RST
+///     ip_quota: Some(IpQuota { max_connections: 1, reset: true }),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpQuota {
+    pub max_connections: usize,
+    /// Refuse the SYNs over the cap with a RST rather than silently
+    /// dropping them
+    pub reset: bool,
+}
+
+/// What [`crate::Interface::with_setup`] does to the TUN device while the
+/// process can, and who the process is afterwards.
+///
+/// Creating the device and giving it an address or routes needs
+/// `CAP_NET_ADMIN`, becoming `user` needs `CAP_SETUID` and `CAP_SETGID`.
+/// None of them is kept afterwards, the stack itself needs no privilege.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TunSetup {
+    pub name: String,
+    /// Address of the host's end of the device and the length of the
+    /// network prefix routed through it, the device is brought up with it
+    pub address: Option<(Ipv4Addr, u8)>,
+    /// More networks to route through the device, as address and prefix
+    /// length
+    pub routes: Vec<(Ipv4Addr, u8)>,
+    /// User and group ids to switch to, root is kept if `None`
+    pub user: Option<(u32, u32)>,
+}
+
+impl Default for TunSetup {
+    fn default() -> Self {
+        Self {
+            name: String::from("tun0"),
+            address: None,
+            routes: Vec::new(),
+            user: None,
+        }
+    }
+}
+
+/// Hash function used for the connection map
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionHasher {
+    /// std's randomly keyed SipHash, resistant to collision attacks
+    SipHash,
+    /// FNV-1a, much cheaper on the small quad keys but predictable
+    Fnv,
+}
+
+/// How the packet loop of an interface is run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventLoop {
+    /// On a thread of its own, spawned along with the interface
+    Thread,
+    /// By the user calling [`crate::Interface::poll`], from their own
+    /// thread or event loop. No thread is spawned, not even the workers.
+    Manual,
+}
+
+/// Handling of TCP segments with reserved bits set or an illegal mix of
+/// flags, e.g. SYN+FIN or no flags at all. Either way they're counted in
+/// [`crate::stats::MalformedStats`], and headers that can't be parsed at all
+/// are dropped.
+///
+/// ```
+/// # use tcp_rust::{InterfaceConfig, ParsePolicy};
+/// let config = InterfaceConfig {
+///     parsing: ParsePolicy::Strict,
+///     ..Default::default()
+/// };
+/// # assert_eq!(config.parsing, ParsePolicy::Strict);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParsePolicy {
+    /// Drop them
+    Strict,
+    /// Make the most of them: reserved bits are ignored (RFC 9293 S3.1)
+    /// and the flags go through the state machine like any others
+    Lenient,
+}
+
+/// Toggles for the places where the stack takes a shortcut from RFC 9293.
+/// The default keeps the shortcuts, [`Compliance::rfc9293`] turns them all
+/// off.
+/// # Examples
+/// ```
+/// # use tcp_rust::{Compliance, InterfaceConfig};
+/// let config = InterfaceConfig {
+///     compliance: Compliance {
+///         syn_data: true,
+///         ..Default::default()
+///     },
+///     ..Default::default()
+/// };
+/// assert_ne!(config.compliance, Compliance::rfc9293());
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Compliance {
+    /// Queue the data carried by a SYN for the application, rather than
+    /// leaving it for the peer to send again (RFC 9293 S3.10.7.2)
+    pub syn_data: bool,
+    /// Answer an unacceptable ACK in SYN-RECEIVED with
+    /// `<SEQ=SEG.ACK><CTL=RST>` rather than ignoring it (RFC 9293 S3.10.7.4)
+    pub reset_unacceptable_ack: bool,
+    /// Abort the connection on a RST at RCV.NXT, and challenge the other
+    /// ones within the window with an ACK (RFC 9293 S3.10.7.4, RFC 5961
+    /// S3.2). Otherwise RSTs are ignored, and the connection only goes away
+    /// once its timers run out.
+    pub honor_rst: bool,
+}
+
+impl Compliance {
+    /// Everything as RFC 9293 says
+    pub fn rfc9293() -> Self {
+        Self {
+            syn_data: true,
+            reset_unacceptable_ack: true,
+            honor_rst: true,
+        }
+    }
+}
+
+/// Reaction to a SYN arriving on an already synchronized connection,
+/// e.g. a retransmitted or spoofed one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SynPolicy {
+    /// Answer with a challenge ACK and drop the segment (RFC 5961 S4.2)
+    ChallengeAck,
+    /// Reset the connection (RFC 793 S3.9)
+    Reset,
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        Self {
+            // RFC 793 S3.3
+            msl: Duration::from_secs(2 * 60),
+            time_wait_ack: true,
+            // RFC 6298 S2.4 and S2.5
+            rto_min: Duration::from_secs(1),
+            rto_max: Duration::from_secs(60),
+            // Same defaults as Linux's `tcp_synack_retries`, `tcp_syn_retries`
+            // and `tcp_fin_timeout`
+            syn_ack_retries: 5,
+            syn_retries: 6,
+            fin_wait2_timeout: Duration::from_secs(60),
+            link_down_grace: Duration::from_secs(10),
+            in_window_syn: SynPolicy::ChallengeAck,
+            parsing: ParsePolicy::Lenient,
+            compliance: Compliance::default(),
+            ip_quota: None,
+            connection_hasher: ConnectionHasher::SipHash,
+            expected_connections: 0,
+            workers: 0,
+            addresses: Vec::new(),
+            reverse_path: false,
+            route_timeout: Duration::from_secs(1),
+            event_loop: EventLoop::Thread,
+            serve_threads: 4,
+            busy_poll: 0,
+            rx_batch: 32,
+            offload: true,
+            checksum_offload: true,
+            timestamps: true,
+            sack: true,
+            congestion_control: CongestionAlgorithm::Reno,
+            hystart: true,
+            cc_sample_interval: None,
+            cc_sample_capacity: 1024,
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "testing")]
+            initial_sequence: None,
+        }
+    }
+}
+
+impl InterfaceConfig {
+    /// Checks that the parameters are consistent with each other.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.rto_min > self.rto_max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rto_min must not be greater than rto_max",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Clamps a retransmission timeout to the configured bounds.
+    pub(crate) fn clamp_rto(&self, rto: Duration) -> Duration {
+        rto.max(self.rto_min).min(self.rto_max)
+    }
+}
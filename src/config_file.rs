@@ -0,0 +1,603 @@
+//! Settings of a whole run read from a file, so that an experiment can be
+//! set up the same way again, see [`ConfigFile`]. Only the parts of TOML
+//! and JSON the settings need are understood: tables, strings, integers,
+//! booleans and arrays of them.
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    fs, io,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    CongestionAlgorithm, ConnectionHasher, EventLoop, InterfaceConfig, ParsePolicy, ReadMode,
+    ReadShutdown, StreamOptions, SynPolicy, TunSetup,
+};
+
+/// Everything [`crate::Interface::with_setup`] and the streams are given,
+/// one table each. Keys are the names of the fields, durations are strings
+/// such as `"200ms"`, `"2s"` or `"5m"`, the variants of enums are in
+/// snake case and networks are written `"10.0.0.1/24"`. Keys left out keep
+/// their default value, unknown ones are an error.
+///
+/// ```
+/// # use tcp_rust::{CongestionAlgorithm, ConfigFile};
+/// # use std::{io, net::Ipv4Addr, time::Duration};
+/// # fn main() -> io::Result<()> {
+/// let toml = ConfigFile::from_toml(r#"
+///     [device]
+///     name = "tun1"
+///     address = "192.168.0.1/24"
+///     user = [65534, 65534]
+///
+///     [interface]
+///     addresses = ["192.168.0.2"]
+///     rto_min = "100ms"
+///     congestion_control = "bbr"
+///
+///     [stream]
+///     send_buffer = 65536  # bytes
+///     keepalive = "1m"
+///
+///     [log]
+///     events = "events.jsonl"
+/// "#)?;
+/// let json = ConfigFile::from_json(r#"{
+///     "device": {"name": "tun1", "address": "192.168.0.1/24", "user": [65534, 65534]},
+///     "interface": {"addresses": ["192.168.0.2"], "rto_min": "100ms", "congestion_control": "bbr"},
+///     "stream": {"send_buffer": 65536, "keepalive": "1m"},
+///     "log": {"events": "events.jsonl"}
+/// }"#)?;
+///
+/// for file in [&toml, &json] {
+///     assert_eq!(file.device.address, Some((Ipv4Addr::new(192, 168, 0, 1), 24)));
+///     assert_eq!(file.interface.rto_min, Duration::from_millis(100));
+///     assert_eq!(file.interface.congestion_control, CongestionAlgorithm::Bbr);
+///     assert_eq!(file.stream.keepalive, Some(Duration::from_secs(60)));
+/// }
+/// assert_eq!(toml.device, json.device);
+/// assert_eq!(toml.stream, json.stream);
+/// assert_eq!(toml.event_log, json.event_log);
+///
+/// let err = ConfigFile::from_toml("[interface]\nrto = \"1s\"").unwrap_err();
+/// assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConfigFile {
+    /// The `[device]` table
+    pub device: TunSetup,
+    /// The `[interface]` table
+    pub interface: InterfaceConfig,
+    /// The `[stream]` table, for every stream of the run
+    pub stream: StreamOptions,
+    /// `events` in the `[log]` table, where to write the events as JSON
+    /// lines, see [`crate::Interface::log_events`]
+    pub event_log: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    /// Reads `path` as TOML or JSON, going by its extension
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let parse = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml,
+            Some("json") => Self::from_json,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Config files must end in .toml or .json",
+                ))
+            }
+        };
+        parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml(text: &str) -> io::Result<Self> {
+        Self::from_entries(Parser::new(text).toml()?)
+    }
+
+    pub fn from_json(text: &str) -> io::Result<Self> {
+        Self::from_entries(Parser::new(text).json()?)
+    }
+
+    fn from_entries(entries: Vec<(String, Value)>) -> io::Result<Self> {
+        let mut file = Self::default();
+        let mut seen = HashSet::new();
+        for (key, value) in entries {
+            if !seen.insert(key.clone()) {
+                return Err(invalid(&key, "set more than once"));
+            }
+            file.set(&key, value)?;
+        }
+        file.interface.validate()?;
+        file.stream.validate()?;
+        Ok(file)
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> io::Result<()> {
+        let (device, config, stream) = (&mut self.device, &mut self.interface, &mut self.stream);
+        match key {
+            "device.name" => device.name = value.string(key)?,
+            "device.address" => device.address = Some(network(key, value)?),
+            "device.routes" => {
+                device.routes = value
+                    .array(key)?
+                    .into_iter()
+                    .map(|v| network(key, v))
+                    .collect::<io::Result<_>>()?
+            }
+            "device.user" => {
+                device.user = match value.array(key)?.as_slice() {
+                    [uid, gid] => Some((uid.clone().int(key)?, gid.clone().int(key)?)),
+                    _ => return Err(invalid(key, "expected [uid, gid]")),
+                }
+            }
+            "interface.msl" => config.msl = value.duration(key)?,
+            "interface.time_wait_ack" => config.time_wait_ack = value.bool(key)?,
+            "interface.rto_min" => config.rto_min = value.duration(key)?,
+            "interface.rto_max" => config.rto_max = value.duration(key)?,
+            "interface.syn_ack_retries" => config.syn_ack_retries = value.int(key)?,
+            "interface.syn_retries" => config.syn_retries = value.int(key)?,
+            "interface.fin_wait2_timeout" => config.fin_wait2_timeout = value.duration(key)?,
+            "interface.link_down_grace" => config.link_down_grace = value.duration(key)?,
+            "interface.in_window_syn" => {
+                config.in_window_syn = match value.string(key)?.as_str() {
+                    "challenge_ack" => SynPolicy::ChallengeAck,
+                    "reset" => SynPolicy::Reset,
+                    _ => return Err(invalid(key, "expected challenge_ack or reset")),
+                }
+            }
+            "interface.parsing" => {
+                config.parsing = match value.string(key)?.as_str() {
+                    "strict" => ParsePolicy::Strict,
+                    "lenient" => ParsePolicy::Lenient,
+                    _ => return Err(invalid(key, "expected strict or lenient")),
+                }
+            }
+            "interface.compliance.syn_data" => config.compliance.syn_data = value.bool(key)?,
+            "interface.compliance.reset_unacceptable_ack" => {
+                config.compliance.reset_unacceptable_ack = value.bool(key)?
+            }
+            "interface.compliance.honor_rst" => config.compliance.honor_rst = value.bool(key)?,
+            "interface.connection_hasher" => {
+                config.connection_hasher = match value.string(key)?.as_str() {
+                    "siphash" => ConnectionHasher::SipHash,
+                    "fnv" => ConnectionHasher::Fnv,
+                    _ => return Err(invalid(key, "expected siphash or fnv")),
+                }
+            }
+            "interface.expected_connections" => config.expected_connections = value.int(key)?,
+            "interface.workers" => config.workers = value.int(key)?,
+            "interface.addresses" => {
+                config.addresses = value
+                    .array(key)?
+                    .into_iter()
+                    .map(|v| address(key, &v.string(key)?))
+                    .collect::<io::Result<_>>()?
+            }
+            "interface.reverse_path" => config.reverse_path = value.bool(key)?,
+            "interface.route_timeout" => config.route_timeout = value.duration(key)?,
+            "interface.event_loop" => {
+                config.event_loop = match value.string(key)?.as_str() {
+                    "thread" => EventLoop::Thread,
+                    "manual" => EventLoop::Manual,
+                    _ => return Err(invalid(key, "expected thread or manual")),
+                }
+            }
+            "interface.serve_threads" => config.serve_threads = value.int(key)?,
+            "interface.busy_poll" => config.busy_poll = value.int(key)?,
+            "interface.rx_batch" => config.rx_batch = value.int(key)?,
+            "interface.offload" => config.offload = value.bool(key)?,
+            "interface.checksum_offload" => config.checksum_offload = value.bool(key)?,
+            "interface.timestamps" => config.timestamps = value.bool(key)?,
+            "interface.sack" => config.sack = value.bool(key)?,
+            "interface.congestion_control" => {
+                config.congestion_control = match value.string(key)?.as_str() {
+                    "reno" => CongestionAlgorithm::Reno,
+                    "bbr" => CongestionAlgorithm::Bbr,
+                    _ => return Err(invalid(key, "expected reno or bbr")),
+                }
+            }
+            "interface.hystart" => config.hystart = value.bool(key)?,
+            "interface.cc_sample_interval" => {
+                config.cc_sample_interval = value.optional().map(|v| v.duration(key)).transpose()?
+            }
+            "interface.cc_sample_capacity" => config.cc_sample_capacity = value.int(key)?,
+            "stream.send_buffer" => stream.send_buffer = value.int(key)?,
+            "stream.send_low_watermark" => {
+                stream.send_low_watermark = value.optional().map(|v| v.int(key)).transpose()?
+            }
+            "stream.recv_buffer" => stream.recv_buffer = value.int(key)?,
+            "stream.recv_high_watermark" => {
+                stream.recv_high_watermark = value.optional().map(|v| v.int(key)).transpose()?
+            }
+            "stream.recv_low_watermark" => {
+                stream.recv_low_watermark = value.optional().map(|v| v.int(key)).transpose()?
+            }
+            "stream.keepalive" => {
+                stream.keepalive = value.optional().map(|v| v.duration(key)).transpose()?
+            }
+            "stream.read_mode" => {
+                stream.read_mode = match value.string(key)?.as_str() {
+                    "any" => ReadMode::Any,
+                    "fill" => ReadMode::Fill,
+                    _ => return Err(invalid(key, "expected any or fill")),
+                }
+            }
+            "stream.read_shutdown" => {
+                stream.read_shutdown = match value.string(key)?.as_str() {
+                    "discard" => ReadShutdown::Discard,
+                    "close_window" => ReadShutdown::CloseWindow,
+                    "reset" => ReadShutdown::Reset,
+                    _ => return Err(invalid(key, "expected discard, close_window or reset")),
+                }
+            }
+            "log.events" => {
+                self.event_log = value
+                    .optional()
+                    .map(|v| v.string(key))
+                    .transpose()?
+                    .map(PathBuf::from)
+            }
+            _ => return Err(invalid(key, "unknown setting")),
+        }
+        Ok(())
+    }
+}
+
+impl InterfaceConfig {
+    /// The `[interface]` table of a config file, see [`ConfigFile`]
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        ConfigFile::from_path(path).map(|file| file.interface)
+    }
+}
+
+fn invalid(key: &str, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", key, what))
+}
+
+fn address(key: &str, s: &str) -> io::Result<Ipv4Addr> {
+    s.parse()
+        .map_err(|_| invalid(key, "expected an IPv4 address"))
+}
+
+/// `"a.b.c.d/len"`, or a single host without the length
+fn network(key: &str, value: Value) -> io::Result<(Ipv4Addr, u8)> {
+    let s = value.string(key)?;
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, len)) => (addr, len.parse().ok().filter(|&len| len <= 32)),
+        None => (s.as_str(), Some(32)),
+    };
+    match prefix {
+        Some(prefix) => Ok((address(key, addr)?, prefix)),
+        None => Err(invalid(key, "expected a prefix length of at most 32")),
+    }
+}
+
+fn duration(key: &str, s: &str) -> io::Result<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n: u64 = s[..split]
+        .parse()
+        .map_err(|_| invalid(key, "expected a duration such as \"200ms\""))?;
+    match &s[split..] {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(invalid(key, "expected a unit of ms, s, m or h")),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+    /// JSON's `null`, which unsets the optional settings
+    Null,
+}
+
+impl Value {
+    fn string(self, key: &str) -> io::Result<String> {
+        match self {
+            Value::Str(s) => Ok(s),
+            _ => Err(invalid(key, "expected a string")),
+        }
+    }
+
+    fn int<T: TryFrom<i64>>(self, key: &str) -> io::Result<T> {
+        match self {
+            Value::Int(n) => T::try_from(n).map_err(|_| invalid(key, "integer out of range")),
+            _ => Err(invalid(key, "expected an integer")),
+        }
+    }
+
+    fn bool(self, key: &str) -> io::Result<bool> {
+        match self {
+            Value::Bool(b) => Ok(b),
+            _ => Err(invalid(key, "expected true or false")),
+        }
+    }
+
+    fn array(self, key: &str) -> io::Result<Vec<Value>> {
+        match self {
+            Value::Array(values) => Ok(values),
+            _ => Err(invalid(key, "expected an array")),
+        }
+    }
+
+    fn duration(self, key: &str) -> io::Result<Duration> {
+        duration(key, &self.string(key)?)
+    }
+
+    fn optional(self) -> Option<Value> {
+        match self {
+            Value::Null => None,
+            value => Some(value),
+        }
+    }
+}
+
+/// Reads either format into `(dotted.key, value)` pairs, in file order
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self { text, pos: 0 }
+    }
+
+    fn error(&self, what: &str) -> io::Error {
+        let line = self.text[..self.pos].matches('\n').count() + 1;
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Line {}: {}", line, what),
+        )
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let matched = self.peek() == Some(c);
+        if matched {
+            self.pos += c.len_utf8();
+        }
+        matched
+    }
+
+    fn expect(&mut self, c: char) -> io::Result<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {:?}", c)))
+        }
+    }
+
+    fn eat_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+        &self.text[start..self.pos]
+    }
+
+    /// Spaces within a line
+    fn skip_spaces(&mut self) {
+        self.eat_while(|c| c == ' ' || c == '\t');
+    }
+
+    /// Whitespace and, in TOML, comments
+    fn skip_blank(&mut self, comments: bool) {
+        loop {
+            self.eat_while(char::is_whitespace);
+            if !(comments && self.peek() == Some('#')) {
+                break;
+            }
+            self.eat_while(|c| c != '\n');
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(match self.bump() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let hex = self.text.get(self.pos..self.pos + 4).unwrap_or("");
+                        let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+                        self.pos += hex.len();
+                        c.ok_or_else(|| self.error("invalid \\u escape"))?
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                }),
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// Integers, and the `true`, `false` and `null` that start with a letter
+    fn scalar(&mut self, allow_null: bool) -> io::Result<Value> {
+        let word = self.eat_while(|c| c.is_ascii_alphanumeric() || "+-_".contains(c));
+        match word {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            "null" if allow_null => Ok(Value::Null),
+            _ => word
+                .replace('_', "")
+                .parse()
+                .map(Value::Int)
+                .map_err(|_| self.error("expected a value")),
+        }
+    }
+
+    fn toml(mut self) -> io::Result<Vec<(String, Value)>> {
+        let mut entries = Vec::new();
+        let mut table = String::new();
+        loop {
+            self.skip_blank(true);
+            match self.peek() {
+                None => return Ok(entries),
+                Some('[') => {
+                    self.bump();
+                    self.skip_spaces();
+                    table = self.toml_key()?;
+                    self.skip_spaces();
+                    self.expect(']')?;
+                }
+                Some(_) => {
+                    let key = self.toml_key()?;
+                    self.skip_spaces();
+                    self.expect('=')?;
+                    self.skip_spaces();
+                    let value = self.toml_value()?;
+                    match table.as_str() {
+                        "" => entries.push((key, value)),
+                        table => entries.push((format!("{}.{}", table, key), value)),
+                    }
+                }
+            }
+            self.skip_spaces();
+            if self.eat('#') {
+                self.eat_while(|c| c != '\n');
+            }
+            self.eat('\r');
+            if !self.eat('\n') && self.peek().is_some() {
+                return Err(self.error("expected the end of the line"));
+            }
+        }
+    }
+
+    /// Bare or quoted keys, joined by dots
+    fn toml_key(&mut self) -> io::Result<String> {
+        let mut key = String::new();
+        loop {
+            if self.peek() == Some('"') {
+                key.push_str(&self.string()?);
+            } else {
+                let part = self.eat_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if part.is_empty() {
+                    return Err(self.error("expected a key"));
+                }
+                key.push_str(part);
+            }
+            self.skip_spaces();
+            if !self.eat('.') {
+                return Ok(key);
+            }
+            self.skip_spaces();
+            key.push('.');
+        }
+    }
+
+    fn toml_value(&mut self) -> io::Result<Value> {
+        match self.peek() {
+            Some('"') => self.string().map(Value::Str),
+            Some('[') => {
+                self.bump();
+                let mut values = Vec::new();
+                loop {
+                    self.skip_blank(true);
+                    if self.eat(']') {
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.toml_value()?);
+                    self.skip_blank(true);
+                    if !self.eat(',') {
+                        self.expect(']')?;
+                        return Ok(Value::Array(values));
+                    }
+                }
+            }
+            _ => self.scalar(false),
+        }
+    }
+
+    fn json(mut self) -> io::Result<Vec<(String, Value)>> {
+        let mut entries = Vec::new();
+        self.skip_blank(false);
+        self.json_object("", &mut entries)?;
+        self.skip_blank(false);
+        match self.peek() {
+            None => Ok(entries),
+            Some(_) => Err(self.error("expected the end of the file")),
+        }
+    }
+
+    /// Flattens nested objects into dotted keys
+    fn json_object(&mut self, prefix: &str, entries: &mut Vec<(String, Value)>) -> io::Result<()> {
+        self.expect('{')?;
+        self.skip_blank(false);
+        if self.eat('}') {
+            return Ok(());
+        }
+        loop {
+            self.skip_blank(false);
+            let key = match prefix {
+                "" => self.string()?,
+                prefix => format!("{}.{}", prefix, self.string()?),
+            };
+            self.skip_blank(false);
+            self.expect(':')?;
+            self.skip_blank(false);
+            if self.peek() == Some('{') {
+                self.json_object(&key, entries)?;
+            } else {
+                let value = self.json_value()?;
+                entries.push((key, value));
+            }
+            self.skip_blank(false);
+            if !self.eat(',') {
+                return self.expect('}');
+            }
+        }
+    }
+
+    fn json_value(&mut self) -> io::Result<Value> {
+        match self.peek() {
+            Some('"') => self.string().map(Value::Str),
+            Some('[') => {
+                self.bump();
+                let mut values = Vec::new();
+                self.skip_blank(false);
+                if self.eat(']') {
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    self.skip_blank(false);
+                    values.push(self.json_value()?);
+                    self.skip_blank(false);
+                    if !self.eat(',') {
+                        self.expect(']')?;
+                        return Ok(Value::Array(values));
+                    }
+                }
+            }
+            Some('{') => Err(self.error("objects within arrays aren't supported")),
+            _ => self.scalar(true),
+        }
+    }
+}
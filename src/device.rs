@@ -0,0 +1,727 @@
+use bitflags::bitflags;
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io,
+    net::Ipv4Addr,
+    os::unix::{
+        net::UnixDatagram,
+        prelude::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd},
+    },
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::bpf::{Program, SockFprog};
+
+bitflags! {
+    /// Work a device can take off the stack's hands
+    pub struct Capabilities: u8 {
+        /// Completes the TCP checksum of the packets sent through
+        /// [`Device::send_offloaded`]
+        const TX_CSUM = 0b00000001;
+        /// Validates the checksums of received packets. Their checksum
+        /// field may then be left incomplete
+        const RX_CSUM = 0b00000010;
+    }
+}
+
+/// Packet-level network device: every `send`/`recv` moves a single IPv4 packet.
+/// The file descriptor is polled for readability before calling `recv`.
+pub trait Device: AsRawFd + Send + Sync {
+    /// Sends a single packet
+    fn send(&self, packet: &[u8]) -> io::Result<usize>;
+    /// Receives a single packet, blocking until there is one
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Largest TCP packet the device segments by itself, if it can.
+    /// Packets received from such a device can be as large too.
+    fn gso_max_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Largest packet the link carries, IP header included
+    fn mtu(&self) -> usize {
+        crate::tcp::MAX_PACKET_LEN
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+
+    /// Sends a TCP packet whose checksum field only holds the pseudo-header
+    /// sum, for the device to complete. With an `mss`, the packet can be
+    /// larger than a segment and the device cuts it into `mss` sized ones.
+    /// Only called when [`Capabilities::TX_CSUM`] or
+    /// [`Device::gso_max_len`] say it's supported.
+    fn send_offloaded(&self, _packet: &[u8], _mss: Option<u16>) -> io::Result<usize> {
+        Err(offload_unsupported())
+    }
+
+    /// Has the kernel run `program` on the packets before they are received,
+    /// in place of the previous one. `None` removes it.
+    fn attach_filter(&self, _program: Option<&Program>) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Filters not supported by the device",
+        ))
+    }
+}
+
+fn offload_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Offload not supported by the device",
+    )
+}
+
+/// Hides the offloads of a device, so that the stack does everything itself
+pub struct NoOffload<D>(pub D);
+
+impl<D: AsRawFd> AsRawFd for NoOffload<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl<D: AsFd> AsFd for NoOffload<D> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl<D: Device> Device for NoOffload<D> {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.0.send(packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn mtu(&self) -> usize {
+        self.0.mtu()
+    }
+
+    fn attach_filter(&self, program: Option<&Program>) -> io::Result<()> {
+        self.0.attach_filter(program)
+    }
+}
+
+/// Packet written through a [`Deferred`] device, and how
+enum Queued {
+    Plain(Vec<u8>),
+    Offloaded(Vec<u8>, Option<u16>),
+}
+
+/// Holds on to the packets sent through it until [`Deferred::flush`], so
+/// that they can go out once the connection manager is unlocked.
+pub(crate) struct Deferred<'a> {
+    inner: &'a dyn Device,
+    // Only ever locked by the thread owning it, the trait wants it Sync
+    queue: Mutex<Vec<Queued>>,
+}
+
+impl<'a> Deferred<'a> {
+    pub(crate) fn new(inner: &'a dyn Device) -> Self {
+        Self {
+            inner,
+            queue: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sends everything queued so far, in order, stopping at the first
+    /// error
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let queue = std::mem::take(&mut *self.queue.lock().unwrap());
+        for packet in queue {
+            match packet {
+                Queued::Plain(p) => self.inner.send(&p)?,
+                Queued::Offloaded(p, mss) => self.inner.send_offloaded(&p, mss)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Deferred<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl Device for Deferred<'_> {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push(Queued::Plain(packet.to_vec()));
+        Ok(packet.len())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(buf)
+    }
+
+    fn gso_max_len(&self) -> Option<usize> {
+        self.inner.gso_max_len()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn attach_filter(&self, program: Option<&Program>) -> io::Result<()> {
+        self.inner.attach_filter(program)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn send_offloaded(&self, packet: &[u8], mss: Option<u16>) -> io::Result<usize> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push(Queued::Offloaded(packet.to_vec(), mss));
+        Ok(packet.len())
+    }
+}
+
+impl Device for tun_tap::Iface {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        tun_tap::Iface::recv(self, buf)
+    }
+}
+
+/// Pair of connected in-memory devices: what is sent on one end is received
+/// on the other. Handy to run the stack against a hand-crafted peer.
+pub struct Loopback {
+    sock: UnixDatagram,
+}
+
+impl Loopback {
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = UnixDatagram::pair()?;
+        Ok((Self { sock: a }, Self { sock: b }))
+    }
+
+    /// Makes `recv` give up with [`io::ErrorKind::WouldBlock`] after `timeout`
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+impl AsRawFd for Loopback {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl AsFd for Loopback {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.sock.as_fd()
+    }
+}
+
+impl Device for Loopback {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.sock.send(packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.recv(buf)
+    }
+
+    fn attach_filter(&self, program: Option<&Program>) -> io::Result<()> {
+        // SAFETY: the program outlives the calls, the kernel copies it
+        let res = unsafe {
+            match program {
+                Some(program) => {
+                    let fprog = program.as_fprog();
+                    libc::setsockopt(
+                        self.sock.as_raw_fd(),
+                        libc::SOL_SOCKET,
+                        libc::SO_ATTACH_FILTER,
+                        &fprog as *const SockFprog as *const libc::c_void,
+                        std::mem::size_of::<SockFprog>() as libc::socklen_t,
+                    )
+                }
+                None => libc::setsockopt(
+                    self.sock.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_DETACH_FILTER,
+                    std::ptr::null(),
+                    0,
+                ),
+            }
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            // Nothing to detach
+            if program.is_none() && err.raw_os_error() == Some(libc::ENOENT) {
+                return Ok(());
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+// linux/if_tun.h
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNSETOFFLOAD: libc::c_ulong = 0x4004_54d0;
+const TUNGETIFF: libc::c_ulong = 0x8004_54d2;
+const TUNSETFILTEREBPF: libc::c_ulong = 0x8004_54e1;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+const TUN_F_CSUM: libc::c_ulong = 0x01;
+const TUN_F_TSO4: libc::c_ulong = 0x02;
+
+// linux/virtio_net.h
+const VIRTIO_NET_HDR_LEN: usize = 10;
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+
+/// Largest IPv4 packet
+const IPV4_MAX_LEN: usize = 65535;
+/// Smallest MTU an IPv4 link can have (RFC 791)
+const MIN_MTU: usize = 68;
+
+/// `struct ifreq`, only the flags of the union are used
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// `struct ifreq` holding an address
+#[repr(C)]
+struct IfAddrReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    addr: libc::sockaddr_in,
+    _pad: [u8; 8],
+}
+
+/// Socket to configure network devices with, the TUN descriptor doesn't
+/// answer the address and route requests
+fn control_socket() -> io::Result<File> {
+    // SAFETY: plain socket creation, the descriptor is owned right away
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and nothing else owns it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn netmask(prefix: u8) -> io::Result<Ipv4Addr> {
+    if prefix > 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Prefix longer than 32 bits",
+        ));
+    }
+    Ok(Ipv4Addr::from(
+        u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0),
+    ))
+}
+
+fn sockaddr_in(addr: Ipv4Addr) -> libc::sockaddr_in {
+    // SAFETY: all zeroes is a valid `struct sockaddr_in`
+    let mut sin: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_addr.s_addr = u32::from(addr).to_be();
+    sin
+}
+
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = sockaddr_in(addr);
+    // SAFETY: both are 16 bytes, `struct sockaddr_in` being a `struct sockaddr`
+    unsafe { std::mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sin) }
+}
+
+/// TUN device that hands TCP segmentation over to the kernel when it can.
+///
+/// Every packet is prefixed with a virtio-net header, which lets large
+/// packets be written in one go and cut into MSS sized segments by the
+/// kernel (or the NIC) instead of by the stack. When the kernel doesn't
+/// support it, the device falls back to plain packets.
+pub struct Tun {
+    file: File,
+    name: String,
+    /// Whether the virtio-net header and segmentation offload are enabled
+    offload: bool,
+    mtu: usize,
+}
+
+impl Tun {
+    /// Creates the TUN device `name`, asking for segmentation offload if
+    /// `offload` is set.
+    pub fn open(name: &str, offload: bool) -> io::Result<Self> {
+        if offload {
+            if let Ok(tun) = Self::create(name, true) {
+                return Ok(tun);
+            }
+        }
+        Self::create(name, false)
+    }
+
+    fn create(name: &str, offload: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+
+        let cname = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid device name"))?;
+        let cname = cname.as_bytes_with_nul();
+        if cname.len() > libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Device name too long",
+            ));
+        }
+
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: IFF_TUN | IFF_NO_PI,
+            _pad: [0; 22],
+        };
+        for (dst, &src) in req.name.iter_mut().zip(cname) {
+            *dst = src as libc::c_char;
+        }
+        if offload {
+            req.flags |= IFF_VNET_HDR;
+        }
+
+        // SAFETY: `req` outlives the call and is laid out as a `struct ifreq`
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if offload {
+            let flags = TUN_F_CSUM | TUN_F_TSO4;
+            // SAFETY: TUNSETOFFLOAD takes its argument by value
+            if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETOFFLOAD, flags) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let len = req.name.iter().position(|&c| c == 0).unwrap_or(0);
+        let name = req.name[..len].iter().map(|&c| c as u8 as char).collect();
+
+        Ok(Self {
+            file,
+            name,
+            offload,
+            mtu: crate::tcp::MAX_PACKET_LEN,
+        })
+    }
+
+    /// Takes over a TUN device opened and set up by someone else, e.g. a
+    /// more privileged supervisor, whose link carries up to `mtu` bytes.
+    /// Segmentation offload is used if the device was created with a
+    /// virtio-net header.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open TUN descriptor, owned by nothing else from now on.
+    pub unsafe fn from_raw_fd(fd: RawFd, mtu: usize) -> io::Result<Self> {
+        // SAFETY: the caller hands over `fd`
+        let file = unsafe { File::from_raw_fd(fd) };
+        if !(MIN_MTU..=IPV4_MAX_LEN).contains(&mtu) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid MTU"));
+        }
+
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: 0,
+            _pad: [0; 22],
+        };
+        // SAFETY: `req` outlives the call and is laid out as a `struct ifreq`
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNGETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if req.flags & (IFF_TUN | IFF_NO_PI) != IFF_TUN | IFF_NO_PI {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a TUN device without packet information",
+            ));
+        }
+
+        let offload = req.flags & IFF_VNET_HDR != 0;
+        if offload {
+            let flags = TUN_F_CSUM | TUN_F_TSO4;
+            // SAFETY: TUNSETOFFLOAD takes its argument by value
+            if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETOFFLOAD, flags) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let len = req.name.iter().position(|&c| c == 0).unwrap_or(0);
+        let name = req.name[..len].iter().map(|&c| c as u8 as char).collect();
+
+        Ok(Self {
+            file,
+            name,
+            offload,
+            mtu,
+        })
+    }
+
+    /// Name of the device, as given by the kernel
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether segmentation offload could be enabled
+    pub fn offload(&self) -> bool {
+        self.offload
+    }
+
+    /// IPv4 address given to the device, e.g. by `ip addr add`, if any
+    pub fn address(&self) -> io::Result<Option<Ipv4Addr>> {
+        let sock = control_socket()?;
+        let mut req = self.addr_req(Ipv4Addr::UNSPECIFIED);
+        // SAFETY: `req` outlives the call and is laid out as a `struct ifreq`
+        if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFADDR, &mut req) } < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EADDRNOTAVAIL) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        Ok(Some(Ipv4Addr::from(u32::from_be(req.addr.sin_addr.s_addr))))
+    }
+
+    /// Gives the device the address `addr` on a network of `prefix` bits,
+    /// which is routed through it, and brings it up, like `ip addr add`
+    /// and `ip link set up` do. Needs `CAP_NET_ADMIN`.
+    pub fn set_address(&self, addr: Ipv4Addr, prefix: u8) -> io::Result<()> {
+        let sock = control_socket()?;
+        let mask = netmask(prefix)?;
+        for (request, addr) in [(libc::SIOCSIFADDR, addr), (libc::SIOCSIFNETMASK, mask)] {
+            let mut req = self.addr_req(addr);
+            // SAFETY: `req` outlives the call and is laid out as a `struct ifreq`
+            if unsafe { libc::ioctl(sock.as_raw_fd(), request, &mut req) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let mut req = IfReq {
+            name: self.addr_req(addr).name,
+            flags: 0,
+            _pad: [0; 22],
+        };
+        // SAFETY: `req` outlives the calls and is laid out as a `struct ifreq`
+        unsafe {
+            if libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFFLAGS, &mut req) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            req.flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            if libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFFLAGS, &mut req) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Routes the network `dst` of `prefix` bits through the device, like
+    /// `ip route add` does. The device must be up. Needs `CAP_NET_ADMIN`.
+    pub fn add_route(&self, dst: Ipv4Addr, prefix: u8) -> io::Result<()> {
+        let sock = control_socket()?;
+        let mask = netmask(prefix)?;
+        let mut dev = self.addr_req(dst).name;
+
+        // SAFETY: all zeroes is a valid `struct rtentry`
+        let mut route: libc::rtentry = unsafe { std::mem::zeroed() };
+        route.rt_dst = sockaddr(Ipv4Addr::from(u32::from(dst) & u32::from(mask)));
+        route.rt_genmask = sockaddr(mask);
+        route.rt_flags = libc::RTF_UP;
+        route.rt_dev = dev.as_mut_ptr();
+        // SAFETY: `route` and the device name it points to outlive the call
+        if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCADDRT, &mut route) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// `struct ifreq` of the device holding `addr`
+    fn addr_req(&self, addr: Ipv4Addr) -> IfAddrReq {
+        // SAFETY: all zeroes is a valid `struct ifreq`
+        let mut req: IfAddrReq = unsafe { std::mem::zeroed() };
+        for (dst, src) in req.name.iter_mut().zip(self.name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        req.addr = sockaddr_in(addr);
+        req
+    }
+
+    fn send_with(&self, packet: &[u8], hdr: [u8; VIRTIO_NET_HDR_LEN]) -> io::Result<usize> {
+        if !self.offload {
+            // SAFETY: `packet` is valid for reads of its length
+            let n = unsafe {
+                libc::write(
+                    self.file.as_raw_fd(),
+                    packet.as_ptr() as *const libc::c_void,
+                    packet.len(),
+                )
+            };
+            return if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as usize)
+            };
+        }
+
+        let iov = [
+            libc::iovec {
+                iov_base: hdr.as_ptr() as *mut libc::c_void,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            },
+        ];
+        // SAFETY: both buffers are valid for reads of their length, and the
+        // kernel doesn't write through `iov_base` on writev
+        let n = unsafe { libc::writev(self.file.as_raw_fd(), iov.as_ptr(), iov.len() as _) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((n as usize).saturating_sub(VIRTIO_NET_HDR_LEN))
+    }
+}
+
+impl AsRawFd for Tun {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for Tun {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl Device for Tun {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        let mut hdr = [0u8; VIRTIO_NET_HDR_LEN];
+        hdr[1] = VIRTIO_NET_HDR_GSO_NONE;
+        self.send_with(packet, hdr)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut hdr = [0u8; VIRTIO_NET_HDR_LEN];
+        let mut iov = [
+            libc::iovec {
+                iov_base: hdr.as_mut_ptr() as *mut libc::c_void,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            },
+        ];
+        let iov = if self.offload {
+            &mut iov[..]
+        } else {
+            &mut iov[1..]
+        };
+
+        // SAFETY: both buffers are valid for writes of their length
+        let n = unsafe { libc::readv(self.file.as_raw_fd(), iov.as_ptr(), iov.len() as _) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if self.offload {
+            // Whatever the kernel left to do (checksum, segmentation) is
+            // described by the header, the stack deals with the packet as a whole
+            Ok((n as usize).saturating_sub(VIRTIO_NET_HDR_LEN))
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    fn gso_max_len(&self) -> Option<usize> {
+        if self.offload {
+            Some(IPV4_MAX_LEN)
+        } else {
+            None
+        }
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn attach_filter(&self, program: Option<&Program>) -> io::Result<()> {
+        // The device holds on to the loaded program, our descriptor can go
+        let prog = program.map(Program::load).transpose()?;
+        let fd = prog.as_ref().map_or(-1, |p| p.as_raw_fd());
+        // SAFETY: `fd` outlives the call, -1 detaching the program
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), TUNSETFILTEREBPF, &fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        if self.offload {
+            // The kernel only hands over partial checksums for packets that
+            // never left the host
+            Capabilities::TX_CSUM | Capabilities::RX_CSUM
+        } else {
+            Capabilities::empty()
+        }
+    }
+
+    fn send_offloaded(&self, packet: &[u8], mss: Option<u16>) -> io::Result<usize> {
+        if !self.offload {
+            return Err(offload_unsupported());
+        }
+
+        let iph = etherparse::Ipv4HeaderSlice::from_slice(packet)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Not an IPv4 packet"))?;
+        let ip_len = iph.slice().len();
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&packet[ip_len..])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Not a TCP packet"))?;
+        let hdr_len = ip_len + tcph.slice().len();
+
+        // The TCP checksum field must hold the pseudo-header sum, the kernel
+        // completes it (for every segment)
+        let mut hdr = [0u8; VIRTIO_NET_HDR_LEN];
+        hdr[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        if let Some(mss) = mss {
+            hdr[1] = VIRTIO_NET_HDR_GSO_TCPV4;
+            hdr[2..4].copy_from_slice(&(hdr_len as u16).to_ne_bytes());
+            hdr[4..6].copy_from_slice(&mss.to_ne_bytes());
+        }
+        hdr[6..8].copy_from_slice(&(ip_len as u16).to_ne_bytes());
+        // Offset of the checksum in the TCP header
+        hdr[8..10].copy_from_slice(&16u16.to_ne_bytes());
+        self.send_with(packet, hdr)
+    }
+}
+
+/// Sum of the TCP pseudo-header (RFC 793 S3.1), folded but not complemented,
+/// as expected in the checksum field by devices doing checksum offload.
+pub(crate) fn pseudo_header_sum(src: [u8; 4], dst: [u8; 4], tcp_len: usize) -> u16 {
+    let mut sum: u32 = src
+        .chunks(2)
+        .chain(dst.chunks(2))
+        .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+        .sum();
+    sum += crate::TCP_PROTO_NO as u32 + tcp_len as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
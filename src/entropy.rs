@@ -0,0 +1,85 @@
+//! Where the stack's random numbers come from, see
+//! [`crate::Interface::set_entropy_source`]. The initial sequence numbers
+//! of the connections are drawn from it.
+use std::io;
+
+pub trait EntropySource: Send {
+    /// Fills `buf` with random bytes
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()>;
+
+    fn next_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.fill(&mut buf)?;
+        Ok(u32::from_ne_bytes(buf))
+    }
+}
+
+/// The kernel's random number generator, through getrandom(2). The
+/// default, as the sequence numbers must be hard to guess (RFC 9293 S3.4.1).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsEntropy;
+
+impl EntropySource for OsEntropy {
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let rest = &mut buf[filled..];
+            // SAFETY: `rest` is valid for writes of its length
+            // SAFETY: `rest` is valid for writes of its length
+            let n = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            filled += n as usize;
+        }
+        Ok(())
+    }
+}
+
+/// The same number every time, e.g. an initial sequence number of 0 so
+/// that the segments of a test peer can be written by hand.
+#[derive(Clone, Copy, Debug)]
+pub struct Fixed(pub u32);
+
+impl EntropySource for Fixed {
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let bytes = self.0.to_ne_bytes();
+        for (b, v) in buf.iter_mut().zip(bytes.iter().cycle()) {
+            *b = *v;
+        }
+        Ok(())
+    }
+
+    fn next_u32(&mut self) -> io::Result<u32> {
+        Ok(self.0)
+    }
+}
+
+/// Numbers that look random but replay the same from the same seed
+/// (xorshift64*), for test runs that can be reproduced
+#[derive(Clone, Copy, Debug)]
+pub struct Seeded(u64);
+
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Self(seed.max(1))
+    }
+}
+
+impl EntropySource for Seeded {
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        for chunk in buf.chunks_mut(8) {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            let v = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+            chunk.copy_from_slice(&v.to_ne_bytes()[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
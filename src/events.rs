@@ -0,0 +1,262 @@
+//! Machine-readable log of what happens on an interface, see
+//! [`crate::Interface::on_event`] and [`crate::Interface::log_events`].
+use std::{
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    json::{self, Object},
+    tcp::{Connection, State},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// Time since the sink was installed
+    pub at: Duration,
+    pub kind: EventKind,
+}
+
+/// What happened to the interface or to one of its connections
+/// # Examples
+/// The pressure on a receive buffer, for an application to throttle what
+/// feeds a reader that falls behind:
+/// ```
+/// # use tcp_rust::{device::Loopback, events::EventKind, Interface, InterfaceConfig};
+/// # fn main() -> std::io::Result<()> {
+/// # let (nic, _peer) = Loopback::pair()?;
+/// let iface = Interface::with_device(nic, InterfaceConfig::default())?;
+/// iface.on_event(|event| {
+///     if let EventKind::RecvPressure { pressured, waiting, .. } = event.kind {
+///         println!("{} bytes waiting, throttling: {}", waiting, pressured);
+///     }
+/// });
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    /// A connection moved to another state. `from` is `None` for the one
+    /// just created by a SYN.
+    State {
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        from: Option<State>,
+        to: State,
+    },
+    /// The congestion window of a connection changed
+    Cwnd {
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        cwnd: usize,
+    },
+    /// The data waiting to be read went up to
+    /// [`crate::StreamOptions::recv_high_watermark`] (`pressured`), or back
+    /// down to the low one
+    RecvPressure {
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        pressured: bool,
+        /// Bytes waiting to be read
+        waiting: usize,
+    },
+    /// The peer's window closed with data waiting to be sent (`closed`), or
+    /// opened again. Until it does, writes only fill up the send queue.
+    SendWindow {
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        closed: bool,
+        /// Bytes queued that weren't sent yet
+        unsent: usize,
+    },
+    /// A connection was removed from the interface
+    Removed {
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+    },
+    /// New protocol parameters were put in place
+    ConfigUpdated,
+}
+
+impl Event {
+    /// One line JSON object, e.g.
+    /// `{"at_us":1500,"event":"cwnd","local":"10.0.0.1:80","remote":"10.0.0.2:4000","cwnd":2920}`
+    pub fn to_json(&self) -> String {
+        let object = Object::new().raw("at_us", self.at.as_micros());
+        let object = match &self.kind {
+            EventKind::State {
+                local,
+                remote,
+                from,
+                to,
+            } => object
+                .str("event", "state")
+                .str("local", local)
+                .str("remote", remote)
+                .raw(
+                    "from",
+                    from.map_or(String::from("null"), |s| json::string(&format!("{:?}", s))),
+                )
+                .str("to", format!("{:?}", to)),
+            EventKind::Cwnd {
+                local,
+                remote,
+                cwnd,
+            } => object
+                .str("event", "cwnd")
+                .str("local", local)
+                .str("remote", remote)
+                .raw("cwnd", cwnd),
+            EventKind::RecvPressure {
+                local,
+                remote,
+                pressured,
+                waiting,
+            } => object
+                .str("event", "recv_pressure")
+                .str("local", local)
+                .str("remote", remote)
+                .raw("pressured", pressured)
+                .raw("waiting", waiting),
+            EventKind::SendWindow {
+                local,
+                remote,
+                closed,
+                unsent,
+            } => object
+                .str("event", "send_window")
+                .str("local", local)
+                .str("remote", remote)
+                .raw("closed", closed)
+                .raw("unsent", unsent),
+            EventKind::Removed { local, remote } => object
+                .str("event", "removed")
+                .str("local", local)
+                .str("remote", remote),
+            EventKind::ConfigUpdated => object.str("event", "config_updated"),
+        };
+        object.finish()
+    }
+}
+
+/// How much of the events reach the sink, see
+/// [`crate::Interface::set_event_level`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    /// Changes of state, pressure on the buffers, removed connections and
+    /// config updates
+    Info,
+    /// Also every change of a congestion window
+    #[default]
+    Debug,
+}
+
+impl EventKind {
+    /// The lowest [`Level`] the event is emitted at
+    pub fn level(&self) -> Level {
+        match self {
+            EventKind::Cwnd { .. } => Level::Debug,
+            _ => Level::Info,
+        }
+    }
+}
+
+/// Where the events go. It's called with the connection manager locked, so
+/// it must not call back into the interface.
+pub(crate) type EventSink = Box<dyn FnMut(&Event) + Send>;
+
+pub(crate) struct EventLog {
+    started: Instant,
+    sink: EventSink,
+    pub(crate) level: Level,
+}
+
+/// What the events of a connection are told apart from
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Snapshot {
+    state: State,
+    cwnd: usize,
+    recv_pressured: bool,
+    waiting: usize,
+    send_window_closed: bool,
+    unsent: usize,
+}
+
+impl Snapshot {
+    pub(crate) fn of(c: &Connection) -> Self {
+        let info = c.info();
+        Self {
+            state: info.state,
+            cwnd: info.cwnd,
+            recv_pressured: c.recv_pressured(),
+            waiting: c.incoming.len(),
+            send_window_closed: c.send_window_closed(),
+            unsent: c.unsent(),
+        }
+    }
+}
+
+impl EventLog {
+    pub(crate) fn new(sink: EventSink, level: Level) -> Self {
+        Self {
+            started: Instant::now(),
+            sink,
+            level,
+        }
+    }
+
+    pub(crate) fn emit(&mut self, kind: EventKind) {
+        if kind.level() > self.level {
+            return;
+        }
+        let event = Event {
+            at: self.started.elapsed(),
+            kind,
+        };
+        (self.sink)(&event);
+    }
+
+    /// Emits whatever changed on a connection, `before` being `None` if it
+    /// didn't exist yet
+    pub(crate) fn changes(
+        &mut self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        before: Option<Snapshot>,
+        after: Snapshot,
+    ) {
+        if before.map(|b| b.state) != Some(after.state) {
+            self.emit(EventKind::State {
+                local,
+                remote,
+                from: before.map(|b| b.state),
+                to: after.state,
+            });
+        }
+        if before.map(|b| b.cwnd) != Some(after.cwnd) {
+            self.emit(EventKind::Cwnd {
+                local,
+                remote,
+                cwnd: after.cwnd,
+            });
+        }
+        // A new connection starts out with neither
+        if before.is_some_and(|b| b.recv_pressured) != after.recv_pressured {
+            self.emit(EventKind::RecvPressure {
+                local,
+                remote,
+                pressured: after.recv_pressured,
+                waiting: after.waiting,
+            });
+        }
+        if before.is_some_and(|b| b.send_window_closed) != after.send_window_closed {
+            self.emit(EventKind::SendWindow {
+                local,
+                remote,
+                closed: after.send_window_closed,
+                unsent: after.unsent,
+            });
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+/// Port-knocking gate: only lets in the SYNs of the sources that
+/// knocked on a given sequence of ports, in order, within a time window.
+#[derive(Clone, Debug)]
+pub(crate) struct KnockGate {
+    /// Ports to knock on, in order
+    sequence: Vec<u16>,
+    /// Time to go through the whole sequence, and how long the gate stays
+    /// open for a source once it did
+    window: Duration,
+    /// Sources in the middle of the sequence: next knock expected and when
+    /// the first knock happened
+    progress: HashMap<Ipv4Addr, (usize, Instant)>,
+    /// Sources that completed the sequence, and when they did
+    open: HashMap<Ipv4Addr, Instant>,
+}
+
+impl KnockGate {
+    pub(crate) fn new(sequence: Vec<u16>, window: Duration) -> Self {
+        Self {
+            sequence,
+            window,
+            progress: HashMap::new(),
+            open: HashMap::new(),
+        }
+    }
+
+    /// Tracks a connection attempt (a SYN) from `src` to `port`.
+    pub(crate) fn on_syn(&mut self, src: Ipv4Addr, port: u16, now: Instant) {
+        let window = self.window;
+        self.progress
+            .retain(|_, (_, since)| now.duration_since(*since) <= window);
+        self.open
+            .retain(|_, since| now.duration_since(*since) <= window);
+
+        let (next, since) = self.progress.get(&src).copied().unwrap_or((0, now));
+        if self.sequence.get(next) == Some(&port) {
+            if next + 1 == self.sequence.len() {
+                self.progress.remove(&src);
+                self.open.insert(src, now);
+            } else {
+                self.progress.insert(src, (next + 1, since));
+            }
+        } else if self.sequence.first() == Some(&port) {
+            // Starting over
+            self.progress.insert(src, (1, now));
+        } else {
+            self.progress.remove(&src);
+        }
+    }
+
+    /// Whether `src` went through the knock sequence recently enough.
+    pub(crate) fn admits(&self, src: Ipv4Addr, now: Instant) -> bool {
+        self.open
+            .get(&src)
+            .is_some_and(|since| now.duration_since(*since) <= self.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    fn gate() -> KnockGate {
+        KnockGate::new(vec![7000, 8000, 9000], WINDOW)
+    }
+
+    fn knock(gate: &mut KnockGate, ports: &[u16], now: Instant) {
+        for &port in ports {
+            gate.on_syn(SRC, port, now);
+        }
+    }
+
+    #[test]
+    fn sequence_opens_the_gate_for_the_window() {
+        let now = Instant::now();
+        let mut gate = gate();
+        knock(&mut gate, &[7000, 8000], now);
+        assert!(!gate.admits(SRC, now));
+        knock(&mut gate, &[9000], now);
+        assert!(gate.admits(SRC, now));
+        // For that source only
+        assert!(!gate.admits(Ipv4Addr::new(10, 0, 0, 3), now));
+
+        assert!(gate.admits(SRC, now + WINDOW));
+        assert!(!gate.admits(SRC, now + WINDOW + Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn knocks_out_of_order_start_over() {
+        let now = Instant::now();
+        let mut gate = gate();
+        knock(&mut gate, &[8000, 7000, 9000], now);
+        assert!(!gate.admits(SRC, now));
+        // A wrong port forgets the progress
+        knock(&mut gate, &[7000, 8000, 80, 9000], now);
+        assert!(!gate.admits(SRC, now));
+        // The first port again starts a new attempt
+        knock(&mut gate, &[7000, 8000, 7000, 8000, 9000], now);
+        assert!(gate.admits(SRC, now));
+    }
+
+    #[test]
+    fn slow_knocks_expire() {
+        let now = Instant::now();
+        let mut gate = gate();
+        knock(&mut gate, &[7000, 8000], now);
+        // Past the window since the first knock
+        let late = now + WINDOW + Duration::from_millis(1);
+        knock(&mut gate, &[9000], late);
+        assert!(!gate.admits(SRC, late));
+        knock(&mut gate, &[7000, 8000, 9000], late);
+        assert!(gate.admits(SRC, late));
+    }
+}
@@ -0,0 +1,185 @@
+//! Minimal userspace router between two devices.
+//!
+//! Every IPv4 packet read from one side is written to the other with its
+//! TTL decremented, so that test topologies can be put together entirely
+//! in-process. With the `nat` feature, the flows started from side
+//! [`Side::A`] can also be masqueraded on their way to [`Side::B`].
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use etherparse::Ipv4HeaderSlice;
+
+use crate::device::Device;
+#[cfg(feature = "nat")]
+use crate::nat::{Nat, NatConfig};
+
+/// One of the two devices attached to a [`Router`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    /// The inside network when translating
+    A,
+    B,
+}
+
+impl Side {
+    /// Where the packets from this side go
+    pub fn other(self) -> Self {
+        match self {
+            Side::A => Side::B,
+            Side::B => Side::A,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ForwardStats {
+    /// Packets handed to the other side
+    pub forwarded: u64,
+    /// Packets dropped as their TTL ran out. No ICMP Time Exceeded is sent.
+    pub ttl_expired: u64,
+    /// Packets dropped as they weren't valid IPv4
+    pub malformed: u64,
+    /// Packets dropped by the NAT, as they didn't belong to a translated flow
+    pub untranslated: u64,
+}
+
+#[derive(Default)]
+pub struct Router {
+    #[cfg(feature = "nat")]
+    nat: Option<Nat>,
+    stats: ForwardStats,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Masquerades the flows started from [`Side::A`] behind the NAT's
+    /// public address. Only TCP gets through then.
+    #[cfg(feature = "nat")]
+    pub fn with_nat(config: NatConfig) -> Self {
+        Self {
+            nat: Some(Nat::new(config)),
+            ..Self::default()
+        }
+    }
+
+    pub fn stats(&self) -> &ForwardStats {
+        &self.stats
+    }
+
+    /// Prepares a packet read from `from` to be written to the other side.
+    /// Returns `false` when it must be dropped instead.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::forward::{Router, Side};
+    /// let mut router = Router::new();
+    /// let mut packet = Vec::new();
+    /// etherparse::PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 1, 2], 2)
+    ///     .tcp(4000, 80, 0, 1024)
+    ///     .write(&mut packet, &[])
+    ///     .unwrap();
+    ///
+    /// assert!(router.route(&mut packet, Side::A));
+    /// let ip = etherparse::Ipv4HeaderSlice::from_slice(&packet).unwrap();
+    /// assert_eq!(ip.ttl(), 1);
+    /// assert_eq!(ip.header_checksum(), ip.to_header().calc_header_checksum().unwrap());
+    ///
+    /// // No more hops left
+    /// assert!(!router.route(&mut packet, Side::B));
+    /// assert_eq!(router.stats().ttl_expired, 1);
+    /// ```
+    pub fn route(&mut self, packet: &mut [u8], from: Side) -> bool {
+        let ttl = match Ipv4HeaderSlice::from_slice(packet) {
+            Ok(ip) => ip.ttl(),
+            Err(_) => {
+                self.stats.malformed += 1;
+                return false;
+            }
+        };
+        if ttl <= 1 {
+            self.stats.ttl_expired += 1;
+            return false;
+        }
+        decrement_ttl(packet);
+
+        #[cfg(feature = "nat")]
+        if let Some(nat) = &mut self.nat {
+            let now = Instant::now();
+            let translated = match from {
+                Side::A => nat.outbound(packet, now),
+                Side::B => nat.inbound(packet, now),
+            };
+            if !translated {
+                self.stats.untranslated += 1;
+                return false;
+            }
+        }
+        #[cfg(not(feature = "nat"))]
+        let _ = from;
+
+        self.stats.forwarded += 1;
+        true
+    }
+
+    /// Forwards packets between `a` and `b` until one of them fails.
+    pub fn run(&mut self, a: &dyn Device, b: &dyn Device) -> io::Result<()> {
+        let mut buf = [0u8; 1504];
+        let mut last_expiry = Instant::now();
+
+        loop {
+            let mut pfd = [
+                nix::poll::PollFd::new(a.as_raw_fd(), nix::poll::PollFlags::POLLIN),
+                nix::poll::PollFd::new(b.as_raw_fd(), nix::poll::PollFlags::POLLIN),
+            ];
+            nix::poll::poll(&mut pfd[..], 1000).map_err(|e| e.as_errno().unwrap())?;
+
+            let now = Instant::now();
+            if now.duration_since(last_expiry) >= Duration::from_secs(1) {
+                #[cfg(feature = "nat")]
+                if let Some(nat) = &mut self.nat {
+                    nat.expire(now);
+                }
+                last_expiry = now;
+            }
+
+            let ready = |pfd: &nix::poll::PollFd| {
+                pfd.revents()
+                    .is_some_and(|r| r.contains(nix::poll::PollFlags::POLLIN))
+            };
+
+            for (from, pfd) in [(Side::A, &pfd[0]), (Side::B, &pfd[1])] {
+                if !ready(pfd) {
+                    continue;
+                }
+                let (src, dst) = match from {
+                    Side::A => (a, b),
+                    Side::B => (b, a),
+                };
+                let n = src.recv(&mut buf[..])?;
+                if self.route(&mut buf[..n], from) {
+                    dst.send(&buf[..n])?;
+                }
+            }
+        }
+    }
+}
+
+/// Takes one off the TTL, patching the header checksum (RFC 1624 S3)
+fn decrement_ttl(packet: &mut [u8]) {
+    // The TTL is the high byte of the 5th header word
+    let old = u16::from_be_bytes([packet[8], packet[9]]);
+    packet[8] -= 1;
+    let new = u16::from_be_bytes([packet[8], packet[9]]);
+
+    let checksum = u16::from_be_bytes([packet[10], packet[11]]);
+    // HC' = ~(~HC + ~m + m')
+    let mut sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
@@ -0,0 +1,150 @@
+//! Receive coalescing: in-order data segments of a flow read in the same
+//! batch are merged into a single packet, so that the connection runs once
+//! for the whole burst.
+use std::collections::HashMap;
+
+use crate::{segment::InboundSegment, stats::CoalesceStats, Quad};
+
+/// PSH bit of the TCP flags byte
+const PSH: u8 = 0x08;
+
+/// What needs to match for a segment to be appended to the previous one
+struct Segment {
+    /// Offset of the TCP header
+    tcp_at: usize,
+    /// Offset of the payload
+    data_at: usize,
+    /// Sequence number right after the payload
+    next_seq: u32,
+    /// Whether nothing more can be appended
+    sealed: bool,
+}
+
+impl Segment {
+    fn parse(packet: &[u8]) -> Option<(Quad, u32, Self)> {
+        let seg = InboundSegment::parse(packet)?;
+        let (iph, tcph) = (&seg.iph, &seg.tcph);
+        if iph.more_fragments()
+            || iph.fragments_offset() != 0
+            || iph.total_len() as usize != packet.len()
+        {
+            return None;
+        }
+
+        // Only plain data segments, anything else goes through on its own
+        if tcph.syn()
+            || tcph.fin()
+            || tcph.rst()
+            || tcph.urg()
+            || !tcph.ack()
+            || seg.data.is_empty()
+        {
+            return None;
+        }
+
+        let seq = tcph.sequence_number();
+        Some((
+            seg.quad(),
+            seq,
+            Self {
+                tcp_at: iph.slice().len(),
+                data_at: seg.headers_len(),
+                next_seq: seq.wrapping_add(seg.data.len() as u32),
+                sealed: tcph.psh(),
+            },
+        ))
+    }
+}
+
+/// Merges the segments of `batch` that directly follow each other within
+/// a flow, in place. The ACK number, window and options have to be the same
+/// too, and a PSH ends the burst. Packets of different flows keep their
+/// relative order. The buffers of the packets merged into others go to
+/// `spare`, for the next batch to read into.
+///
+/// The TCP checksum of a merged packet is left as it was.
+pub(crate) fn coalesce(
+    batch: &mut Vec<Vec<u8>>,
+    spare: &mut Vec<Vec<u8>>,
+    stats: &mut CoalesceStats,
+) {
+    // Last packet of each flow kept so far, if more can be appended to it
+    let mut tails: HashMap<Quad, (usize, Segment)> = HashMap::new();
+    // Packets kept that are the result of a merge
+    let mut merged = vec![false; batch.len()];
+    let mut kept = 0;
+
+    for next in 0..batch.len() {
+        let packet = std::mem::take(&mut batch[next]);
+        let (quad, seq, seg) = match Segment::parse(&packet) {
+            Some(parsed) => parsed,
+            None => {
+                // Whatever comes after it can't be merged with what came before
+                if let Some(quad) = crate::peek_quad(&packet) {
+                    tails.remove(&quad);
+                }
+                batch[kept] = packet;
+                kept += 1;
+                continue;
+            }
+        };
+
+        if let Some((i, tail)) = tails.get_mut(&quad) {
+            let prev = &batch[*i];
+            let data = &packet[seg.data_at..];
+            let (prev_tcp, tcp) = (
+                &prev[tail.tcp_at..tail.data_at],
+                &packet[seg.tcp_at..seg.data_at],
+            );
+            let appendable = !tail.sealed
+                && tail.next_seq == seq
+                // Same ACK number, header length, flags (but PSH), window and options
+                && prev_tcp[8..13] == tcp[8..13]
+                && prev_tcp[13] | PSH == tcp[13] | PSH
+                && prev_tcp[14..16] == tcp[14..16]
+                && prev_tcp[20..] == tcp[20..]
+                && prev.len() + data.len() <= u16::MAX as usize;
+
+            if appendable {
+                let (i, psh) = (*i, tcp[13] & PSH);
+                tail.next_seq = seg.next_seq;
+                tail.sealed = seg.sealed;
+                batch[i][tail.tcp_at + 13] |= psh;
+                batch[i].extend_from_slice(data);
+                set_total_len(&mut batch[i]);
+                merged[i] = true;
+                stats.segments += 1;
+                spare.push(packet);
+                continue;
+            }
+        }
+
+        tails.insert(quad, (kept, seg));
+        batch[kept] = packet;
+        kept += 1;
+    }
+    batch.truncate(kept);
+
+    for (packet, _) in batch.iter().zip(&merged).filter(|(_, &m)| m) {
+        if let Some((_, _, seg)) = Segment::parse(packet) {
+            stats.bytes += (packet.len() - seg.data_at) as u64;
+        }
+    }
+}
+
+/// Fixes the IPv4 total length and header checksum after growing the packet
+fn set_total_len(packet: &mut [u8]) {
+    let total = packet.len() as u16;
+    packet[2..4].copy_from_slice(&total.to_be_bytes());
+    packet[10..12].copy_from_slice(&[0, 0]);
+
+    let ihl = (packet[0] & 0x0f) as usize * 4;
+    let mut sum: u32 = packet[..ihl]
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
@@ -0,0 +1,99 @@
+//! JSON written by hand for the event log, the spans and `stackd`, which
+//! need no more than objects, arrays, strings and numbers.
+use std::fmt::{self, Write as _};
+
+/// Object written a member at a time
+pub(crate) struct Object(String);
+
+impl Object {
+    pub(crate) fn new() -> Self {
+        Self(String::from("{"))
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        escape(&mut self.0, key);
+        self.0.push(':');
+    }
+
+    /// Member whose value is `value` as a string
+    pub(crate) fn str(mut self, key: &str, value: impl fmt::Display) -> Self {
+        self.key(key);
+        escape(&mut self.0, &value.to_string());
+        self
+    }
+
+    /// Member whose value is JSON already: a number, a bool, `null`, or an
+    /// object or array written with this module
+    pub(crate) fn raw(mut self, key: &str, value: impl fmt::Display) -> Self {
+        self.key(key);
+        let _ = write!(self.0, "{}", value);
+        self
+    }
+
+    pub(crate) fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+/// Array of values that are JSON already
+#[cfg(any(feature = "otel", feature = "stackd"))]
+pub(crate) fn array(values: impl IntoIterator<Item = String>) -> String {
+    let values: Vec<String> = values.into_iter().collect();
+    format!("[{}]", values.join(","))
+}
+
+/// `s` as a string
+pub(crate) fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    escape(&mut out, s);
+    out
+}
+
+/// Appends `s` quoted, escaping what has to be, RFC 8259 section 7
+fn escape(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(string("a\"b\\c\nd\u{1}é"), r#""a\"b\\c\nd\u0001é""#);
+    }
+
+    #[test]
+    #[cfg(any(feature = "otel", feature = "stackd"))]
+    fn objects_nest_in_arrays() {
+        let inner = Object::new().str("rule", "drop 1%").raw("hits", 3).finish();
+        let json = Object::new()
+            .raw("rules", array([inner]))
+            .raw("none", "null")
+            .finish();
+        assert_eq!(
+            json,
+            r#"{"rules":[{"rule":"drop 1%","hits":3}],"none":null}"#
+        );
+        assert_eq!(array(Vec::new()), "[]");
+        assert_eq!(Object::new().finish(), "{}");
+    }
+}
@@ -1,18 +1,88 @@
 use std::{
     cmp,
-    collections::{hash_map::Entry, HashMap, VecDeque},
+    collections::{
+        hash_map::{DefaultHasher, Entry, RandomState},
+        HashMap, VecDeque,
+    },
+    hash::{BuildHasher, Hash, Hasher},
     io::{self, Read, Write},
-    net::Ipv4Addr,
-    os::unix::prelude::AsRawFd,
-    sync::{Arc, Condvar, Mutex},
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    os::unix::prelude::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex, Weak,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-mod tcp;
+pub mod bpf;
+pub mod cc;
+pub mod clock;
+#[cfg(feature = "codec")]
+pub mod codec;
+mod config;
+mod config_file;
+pub mod device;
+pub mod entropy;
+pub mod events;
+mod filter;
+pub mod forward;
+mod gro;
+mod json;
+mod link;
+pub mod mirror;
+#[cfg(feature = "nat")]
+pub mod nat;
+pub mod options;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "testing")]
+pub mod peer_window;
+pub mod prelude;
+mod privilege;
+mod rate;
+pub mod ring;
+mod sack;
+pub mod segment;
+mod serve;
+#[cfg(feature = "testing")]
+pub mod shaping;
+mod shard;
+#[cfg(feature = "stackd")]
+pub mod stackd;
+pub mod stats;
+/// The connection state machine. Its types worth depending on are
+/// re-exported here and in the [`prelude`], the rest changes freely.
+pub mod tcp;
+pub mod trace;
+mod wake;
+
+pub use cc::CongestionAlgorithm;
+pub use config::{
+    Compliance, ConnectionHasher, EventLoop, InterfaceConfig, IpQuota, ParsePolicy, ReadMode,
+    ReadShutdown, StreamOptions, SynPolicy, TunSetup, KEEPALIVE_PROBES,
+};
+pub use config_file::ConfigFile;
+use device::Device;
+use segment::InboundSegment;
+pub use serve::Server;
+use shard::Shards;
+use stats::Stopwatch;
+pub use stats::{GroupStats, InterfaceStats};
+pub use tcp::{ConnectionInfo, Feature, FeatureReport, PeerOptions, State, TimerInfo};
 
-const SENDQUEUE_SIZE: usize = 1024;
 const TCP_PROTO_NO: u8 = 0x06;
 
+/// Shortest time between two runs of the connection timers
+const TICK: Duration = Duration::from_millis(10);
+/// Longest the packet loop sleeps when no timer is running
+const IDLE_TICK: Duration = Duration::from_secs(1);
+
+/// Local ports of the connections opened with [`Interface::connect`], the
+/// dynamic range of RFC 6335 S6
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
 /// Connection quad
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
 struct Quad {
@@ -22,332 +92,3113 @@ struct Quad {
     dst: (Ipv4Addr, u16),
 }
 
-#[derive(Default)]
+impl Quad {
+    /// Our end of the connection
+    fn local(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.dst.0, self.dst.1)
+    }
+
+    fn remote(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.src.0, self.src.1)
+    }
+}
+
 struct Handler {
+    /// Network device shared by the packet loop and the streams
+    nic: mirror::Tap,
     manager: Mutex<ConnectionManager>,
     pending_var: Condvar,
     recv_var: Condvar,
     flush_var: Condvar,
+    /// Notified when room frees up in a send queue
+    send_var: Condvar,
+    /// Wakes the packet loop up to send the data just queued, and to see
+    /// whether the interface was dropped
+    wake: Arc<wake::Wake>,
+    /// Whether an event sink is installed. Workers then run every segment
+    /// with the manager locked, for the events to come out in order.
+    events_on: AtomicBool,
 }
 
 type InterfaceHandle = Arc<Handler>;
 
+/// Decides what happens to an incoming SYN, see [`TcpListener::set_admission_policy`]
+type AdmissionPolicy = Arc<dyn Fn(&SynInfo) -> Admission + Send + Sync>;
+type RoutePredicate = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Accept queue of the connections whose first bytes match, see
+/// [`TcpListener::route`]
+struct Route {
+    id: u64,
+    ports: Vec<u16>,
+    /// Bytes the predicate looks at
+    peek: usize,
+    predicate: RoutePredicate,
+    queue: VecDeque<Quad>,
+}
+
+/// What a listener's admission policy decided for an incoming SYN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// Go on with the handshake
+    Accept,
+    /// Silently discard the SYN, the peer will retransmit it later
+    Drop,
+    /// Refuse the connection with a RST
+    Reset,
+}
+
+/// Connection attempt handed to a listener's admission policy
+#[derive(Debug, Clone, Copy)]
+pub struct SynInfo {
+    /// Remote end of the connection attempt
+    pub src: SocketAddrV4,
+    /// Local end of the connection attempt
+    pub dst: SocketAddrV4,
+    /// Established connections waiting to be accepted on this port
+    pub pending: usize,
+    /// Connections of this port still in the middle of the handshake
+    pub half_open: usize,
+    /// Connections currently held by the interface
+    pub connections: usize,
+}
+
 pub struct Interface {
     /// Interface handle
     ih: Option<InterfaceHandle>,
-    /// Join handle
+    /// Join handle, unless the user drives the packet loop
     jh: Option<thread::JoinHandle<io::Result<()>>>,
+    /// Packet loop driven through [`Interface::poll`] or an [`InterfaceSet`]
+    driver: Option<Arc<Mutex<Driver>>>,
 }
 
 impl Drop for Interface {
     fn drop(&mut self) {
-        // TODO: self.ih.as_mut().unwrap().lock().unwrap().terminate = true;
+        // The packet loop exits once the listeners and streams are gone too,
+        // it may be asleep until its next timer
+        let wake = self.ih.as_ref().map(|ih| ih.wake.clone());
+        drop(self.ih.take());
+        drop(self.driver.take());
+        if let Some(wake) = wake {
+            let _ = wake.notify();
+        }
+        if let Some(jh) = self.jh.take() {
+            jh.join().unwrap().unwrap();
+        }
+    }
+}
 
-        drop(&self.ih.take());
-        self.jh
-            .take()
-            .expect("Interface dropped more than once")
-            .join()
-            .unwrap()
-            .unwrap();
+/// The descriptor of the device, readable when packets arrived
+impl AsRawFd for Interface {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ih.as_ref().unwrap().nic.as_raw_fd()
+    }
+}
+
+impl AsFd for Interface {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // SAFETY: the device is closed with the handler, which outlives the
+        // borrow of `self`
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
     }
 }
 
 impl Interface {
     pub fn new() -> io::Result<Self> {
-        let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
+        Self::with_config(InterfaceConfig::default())
+    }
 
-        let ih: InterfaceHandle = Arc::default();
+    /// Creates the interface with the given protocol parameters.
+    pub fn with_config(mut config: InterfaceConfig) -> io::Result<Self> {
+        config.validate()?;
+        let offload = config.offload && config.checksum_offload;
+        let nic = device::Tun::open("tun0", offload)?;
+        if offload && !nic.offload() {
+            eprintln!("\x1b[1;33m[WARN]\x1b[;m TUN/TAP: Segmentation offload not available.");
+        }
 
-        let jh = {
-            let ih = ih.clone();
-            thread::spawn(move || packet_loop(nic, ih))
-        };
+        if config.addresses.is_empty() {
+            config.addresses.extend(nic.address()?);
+        }
 
+        let iface = Self::with_tun(nic, config)?;
         eprintln!("\x1b[1;32m[INFO]\x1b[;m TUN/TAP: New virtual network device created.");
+        Ok(iface)
+    }
 
-        Ok(Interface {
-            ih: Some(ih),
-            jh: Some(jh),
-        })
+    /// Runs the stack on a TUN device opened and set up by someone else,
+    /// e.g. a supervisor with the privileges this process lacks. See
+    /// [`device::Tun::from_raw_fd`].
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open TUN descriptor, owned by nothing else from now on.
+    pub unsafe fn from_raw_fd(fd: RawFd, mtu: usize) -> io::Result<Self> {
+        // SAFETY: passed on from the caller
+        let nic = unsafe { device::Tun::from_raw_fd(fd, mtu)? };
+        let config = InterfaceConfig {
+            addresses: nic.address()?.into_iter().collect(),
+            ..Default::default()
+        };
+        Self::with_tun(nic, config)
     }
 
-    pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
-        // Take the lock
-        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
-        match cm.pending.entry(port) {
-            Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
-                eprintln!("\x1b[1;32m[INFO]\x1b[;m Listening at port {}", port);
+    /// Creates the TUN device and sets it up as `setup` says, then drops
+    /// to its user before anything comes in, so that the stack never
+    /// handles a packet as root. The address of the device is the host's,
+    /// the stack answers on the rest of the network unless
+    /// [`InterfaceConfig::addresses`] says otherwise.
+    pub fn with_setup(setup: &TunSetup, config: InterfaceConfig) -> io::Result<Self> {
+        config.validate()?;
+        let offload = config.offload && config.checksum_offload;
+        let nic = device::Tun::open(&setup.name, offload)?;
+        if let Some((addr, prefix)) = setup.address {
+            nic.set_address(addr, prefix)?;
+        }
+        for &(dst, prefix) in &setup.routes {
+            nic.add_route(dst, prefix)?;
+        }
+        if let Some((uid, gid)) = setup.user {
+            privilege::drop_to(uid, gid)?;
+        }
+        Self::with_tun(nic, config)
+    }
+
+    fn with_tun(nic: device::Tun, config: InterfaceConfig) -> io::Result<Self> {
+        let monitor = link::Monitor::open(nic.name(), Duration::from_secs(1));
+        let iface = Self::with_device(nic, config)?;
+        match monitor {
+            Ok(monitor) => {
+                let ih = Arc::downgrade(iface.ih.as_ref().unwrap());
+                thread::spawn(move || watch_link(monitor, ih));
             }
-            Entry::Occupied(_) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::AddrInUse,
-                    "Port already bound",
-                ));
+            Err(e) => eprintln!(
+                "\x1b[1;33m[WARN]\x1b[;m TUN/TAP: Link state not monitored: {}.",
+                e
+            ),
+        }
+        Ok(iface)
+    }
+
+    /// Runs the stack on top of any packet device instead of a TUN device.
+    pub fn with_device<D: Device + 'static>(nic: D, config: InterfaceConfig) -> io::Result<Self> {
+        config.validate()?;
+        let event_loop = config.event_loop;
+        // A shard per worker, the workers are left to the user when they
+        // drive the loop
+        let shards = match event_loop {
+            EventLoop::Thread => config.workers,
+            EventLoop::Manual => 0,
+        };
+        let nic: Box<dyn Device> = if config.checksum_offload {
+            Box::new(nic)
+        } else {
+            Box::new(device::NoOffload(nic))
+        };
+
+        let ih: InterfaceHandle = Arc::new(Handler {
+            nic: mirror::Tap::new(nic),
+            manager: Mutex::new(ConnectionManager {
+                shards: Arc::new(Shards::new(shards, &config)),
+                addresses: (!config.addresses.is_empty()).then(|| config.addresses.clone()),
+                config,
+                ..Default::default()
+            }),
+            pending_var: Condvar::new(),
+            recv_var: Condvar::new(),
+            flush_var: Condvar::new(),
+            send_var: Condvar::new(),
+            wake: Arc::new(wake::Wake::new()?),
+            events_on: AtomicBool::new(false),
+        });
+
+        let (jh, driver) = match event_loop {
+            EventLoop::Thread => {
+                let ih = ih.clone();
+                (Some(thread::spawn(move || packet_loop(ih))), None)
             }
+            EventLoop::Manual => (None, Some(Arc::new(Mutex::new(Driver::new(ih.clone()))))),
         };
-        drop(cm);
-        Ok(TcpListener {
-            port,
-            ih: self.ih.as_mut().unwrap().clone(),
+
+        Ok(Interface {
+            ih: Some(ih),
+            jh,
+            driver,
         })
     }
-}
 
-#[derive(Default)]
-pub struct ConnectionManager {
-    // TODO: terminate: bool,
-    /// Connections map
-    connections: HashMap<Quad, tcp::Connection>,
-    /// List of pending connections to a port
-    pending: HashMap<u16, VecDeque<Quad>>,
-}
+    /// Runs one iteration of the packet loop when it is driven by the user
+    /// ([`EventLoop::Manual`]): waits up to `timeout` for packets and
+    /// processes them, or runs the connection timers if none arrived.
+    /// Nothing makes progress in between, so blocking calls such as
+    /// [`TcpListener::accept`] need `poll` to be called from another thread.
+    /// # Examples
+    /// ```no_run
+    /// # use tcp_rust::{EventLoop, Interface, InterfaceConfig};
+    /// # use std::{io, time::Duration};
+    /// # fn main() -> io::Result<()> {
+    /// let config = InterfaceConfig {
+    ///     event_loop: EventLoop::Manual,
+    ///     ..Default::default()
+    /// };
+    /// let mut iface = Interface::with_config(config)?;
+    /// let mut listener = iface.bind(80)?;
+    /// loop {
+    ///     iface.poll(Duration::from_millis(10))?;
+    ///     while let Some(stream) = listener.try_accept()? {
+    ///         drop(stream);
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn poll(&self, timeout: Duration) -> io::Result<()> {
+        let driver = self.driver.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The packet loop runs on its own thread",
+            )
+        })?;
+        let timeout = cmp::min(timeout.as_millis(), i32::MAX as u128) as i32;
+        driver.lock().unwrap().step(timeout).map(|_| ())
+    }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
+    /// Descriptor readable when streams queued data for the packet loop to
+    /// send (eventfd(2)). With [`EventLoop::Manual`], an outside event loop
+    /// polls it along with the device, the descriptor of the interface, and
+    /// calls [`Interface::poll`] when either is readable, or every few
+    /// milliseconds for the timers.
+    /// # Examples
+    /// ```no_run
+    /// # use tcp_rust::{EventLoop, Interface, InterfaceConfig};
+    /// # use std::{io, os::unix::io::{AsFd, AsRawFd}, time::Duration};
+    /// use nix::poll::{poll, PollFd, PollFlags};
+    /// # fn main() -> io::Result<()> {
+    /// let iface = Interface::with_config(InterfaceConfig {
+    ///     event_loop: EventLoop::Manual,
+    ///     ..Default::default()
+    /// })?;
+    /// let mut fds = [
+    ///     PollFd::new(iface.as_fd().as_raw_fd(), PollFlags::POLLIN),
+    ///     PollFd::new(iface.wake_fd().as_raw_fd(), PollFlags::POLLIN),
+    /// ];
+    /// loop {
+    ///     poll(&mut fds, 10).expect("poll");
+    ///     iface.poll(Duration::ZERO)?;
+    /// }
+    /// # }
+    /// ```
+    pub fn wake_fd(&self) -> BorrowedFd<'_> {
+        self.ih.as_ref().unwrap().wake.as_fd()
+    }
 
-    loop {
-        let mut pfd = [nix::poll::PollFd::new(
-            nic.as_raw_fd(),
-            nix::poll::PollFlags::POLLIN,
-        )];
-        let n = nix::poll::poll(&mut pfd[..], 10).map_err(|e| e.as_errno().unwrap())?;
-        assert_ne!(n, -1);
-        if n == 0 {
-            let mut cmg = ih.manager.lock().unwrap();
-            for conn in cmg.connections.values_mut() {
-                conn.on_tick(&mut nic)?;
-            }
-            continue;
+    /// Sends a copy of every packet read from or written to the device
+    /// down `tx`, in place of the previous mirror if there was one. The
+    /// mirror is removed once the receiving end is dropped.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, Interface, InterfaceConfig};
+    /// # use std::{io, sync::mpsc, thread};
+    /// # fn main() -> io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let iface = Interface::with_device(nic, InterfaceConfig::default())?;
+    /// let (tx, rx) = mpsc::channel();
+    /// iface.mirror(tx);
+    /// thread::spawn(move || {
+    ///     for packet in rx {
+    ///         println!("{:?}: {} bytes", packet.direction, packet.data.len());
+    ///     }
+    /// });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn mirror(&self, tx: mpsc::Sender<mirror::MirroredPacket>) {
+        self.ih.as_ref().unwrap().nic.set(Some(tx));
+    }
+
+    /// Has the kernel drop the packets `program` rejects before the stack
+    /// reads them, see [`bpf`]. Fails with [`io::ErrorKind::Unsupported`]
+    /// if the device can't run filters.
+    pub fn attach_filter(&self, program: &bpf::Program) -> io::Result<()> {
+        self.ih.as_ref().unwrap().nic.attach_filter(Some(program))
+    }
+
+    /// Removes the filter of [`Interface::attach_filter`].
+    pub fn detach_filter(&self) -> io::Result<()> {
+        self.ih.as_ref().unwrap().nic.attach_filter(None)
+    }
+
+    /// Stops mirroring packets.
+    pub fn clear_mirror(&self) {
+        self.ih.as_ref().unwrap().nic.set(None);
+    }
+
+    /// Stops handing packets to the mirror for a while, without dropping
+    /// it. Returns whether there is a mirror at all.
+    pub fn pause_mirror(&self, paused: bool) -> bool {
+        self.ih.as_ref().unwrap().nic.pause(paused)
+    }
+
+    /// Returns the protocol parameters currently in use.
+    pub fn config(&self) -> InterfaceConfig {
+        self.ih
+            .as_ref()
+            .unwrap()
+            .manager
+            .lock()
+            .unwrap()
+            .config
+            .clone()
+    }
+
+    /// Local addresses the interface answers on, empty if it answers on
+    /// any, see [`InterfaceConfig::addresses`].
+    pub fn local_addrs(&self) -> Vec<Ipv4Addr> {
+        let cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.addresses.clone().unwrap_or_default()
+    }
+
+    /// Starts answering on `addr` too. The first address added to an
+    /// interface answering on any makes it answer on that one only.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, Interface, InterfaceConfig};
+    /// # fn main() -> std::io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let config = InterfaceConfig {
+    ///     addresses: vec![[10, 0, 0, 1].into()],
+    ///     ..Default::default()
+    /// };
+    /// let iface = Interface::with_device(nic, config)?;
+    /// iface.add_addr([10, 0, 0, 9].into())?;
+    /// assert_eq!(iface.local_addrs().len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_addr(&self, addr: Ipv4Addr) -> io::Result<()> {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        let addresses = cm.addresses.get_or_insert_with(Vec::new);
+        if addresses.contains(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Address already assigned",
+            ));
         }
-        // NIC file descriptor is now available for reading
+        addresses.push(addr);
+        cm.shards.set_addresses(&cm.addresses);
+        Ok(())
+    }
 
-        let nbytes = nic.recv(&mut buf[..])?;
+    /// Stops answering on `addr`. Its connections are reset, their streams
+    /// failing with [`io::ErrorKind::AddrNotAvailable`] from then on.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, Interface, InterfaceConfig};
+    /// # fn main() -> std::io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let config = InterfaceConfig {
+    ///     addresses: vec![[10, 0, 0, 1].into(), [10, 0, 0, 9].into()],
+    ///     ..Default::default()
+    /// };
+    /// let iface = Interface::with_device(nic, config)?;
+    /// iface.remove_addr([10, 0, 0, 9].into())?;
+    /// assert_eq!(iface.local_addrs().len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remove_addr(&self, addr: Ipv4Addr) -> io::Result<()> {
+        let ih = self.ih.as_ref().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        let addresses = cm.addresses.as_mut().filter(|a| a.contains(&addr));
+        let addresses = addresses.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "Address not assigned")
+        })?;
+        addresses.retain(|&a| a != addr);
+        cm.shards.set_addresses(&cm.addresses);
 
-        // Parse IPV4 packet
-        if let Ok(iph) = etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            // Filter non-TCP packets
-            if iph.protocol() != TCP_PROTO_NO {
-                continue;
+        let mut affected = Vec::new();
+        cm.shards.for_each(|quad, c| {
+            if quad.dst.0 == addr {
+                // Only streams handed out can ask what happened
+                affected.push((*quad, c.accept_wait().is_some()));
             }
+        });
+        let affected: Vec<Quad> = affected
+            .into_iter()
+            .map(|(quad, accepted)| {
+                if accepted {
+                    cm.lost.insert(quad, io::ErrorKind::AddrNotAvailable);
+                }
+                cm.dequeue(&quad);
+                quad
+            })
+            .collect();
+        drop(cm);
 
-            let p_src = iph.source_addr();
-            let p_dest = iph.destination_addr();
+        for quad in affected {
+            // The peer is told when it can be, the connection is gone anyway
+            let _ = reset(ih, quad);
+        }
+        Ok(())
+    }
 
-            if let Ok(tcph) = etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..]) {
-                // Here we know we have a TCP packet
-                // Try to lock the thread
-                let mut cmg = ih.manager.lock().unwrap();
-                // Dereference to get a mutable reference to the CM, instead of the Mutex
-                let cm = &mut *cmg;
+    /// Tells the interface whether its link is up, as the monitor of a TUN
+    /// device does when the kernel reports a change. While the link is down
+    /// the connection timers stand still, and blocking calls fail with
+    /// [`io::ErrorKind::NetworkDown`] once it has been down for longer than
+    /// [`InterfaceConfig::link_down_grace`].
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, Interface, InterfaceConfig};
+    /// # use std::time::Duration;
+    /// # fn main() -> std::io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let config = InterfaceConfig {
+    ///     link_down_grace: Duration::from_secs(5),
+    ///     ..Default::default()
+    /// };
+    /// let iface = Interface::with_device(nic, config)?;
+    /// // Blocking calls fail if it isn't back up within 5 seconds
+    /// iface.set_link_state(false);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_link_state(&self, up: bool) {
+        set_link_state(self.ih.as_ref().unwrap(), up);
+    }
 
-                let data = iph.slice().len() + tcph.slice().len();
-                let quad = Quad {
-                    src: (p_src, tcph.source_port()),
-                    dst: (p_dest, tcph.destination_port()),
-                };
+    /// Replaces the protocol parameters at runtime.
+    /// Existing connections pick the new values up on their next tick.
+    pub fn update_config(&self, config: InterfaceConfig) -> io::Result<()> {
+        config.validate()?;
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.shards.set_config(&config);
+        cm.config = config;
+        if let Some(log) = cm.events.as_mut() {
+            log.emit(events::EventKind::ConfigUpdated);
+        }
+        Ok(())
+    }
 
-                // Is the incoming connection known already?
-                match cm.connections.entry(quad) {
-                    Entry::Occupied(mut c) => {
-                        let available =
-                            c.get_mut()
-                                .on_packet(&mut nic, iph, tcph, &buf[data..nbytes])?;
+    /// Returns a snapshot of the interface statistics.
+    pub fn stats(&self) -> InterfaceStats {
+        self.ih
+            .as_ref()
+            .unwrap()
+            .manager
+            .lock()
+            .unwrap()
+            .stats
+            .clone()
+    }
 
-                        // TODO: compare before/after
-                        drop(cmg);
+    /// Lists the connections the interface holds, by local and remote end.
+    pub fn connections(&self) -> Vec<(SocketAddrV4, SocketAddrV4, ConnectionInfo)> {
+        connections(self.ih.as_ref().unwrap())
+    }
 
-                        if available.contains(tcp::Available::READ) {
-                            ih.recv_var.notify_all();
-                        }
+    /// Calls `sink` on every event of the interface and its connections,
+    /// see [`events`]. It's called with the interface locked, from whoever
+    /// runs the packet loop, so it must be quick and must not call back
+    /// into the interface. Replaces the previous sink, if any.
+    pub fn on_event<F: FnMut(&events::Event) + Send + 'static>(&self, sink: F) {
+        let ih = self.ih.as_ref().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        cm.events = Some(events::EventLog::new(Box::new(sink), cm.event_level));
+        ih.events_on.store(true, Ordering::Relaxed);
+        // Waits out the workers that didn't see it yet
+        drop(cm.shards.lock_all());
+    }
 
-                        if available.contains(tcp::Available::FLUSH) {
-                            ih.flush_var.notify_all();
-                        }
-                    }
-                    Entry::Vacant(e) => {
-                        // Do we have a listener for this port?
-                        if let Some(pending) = cm.pending.get_mut(&tcph.destination_port()) {
-                            if let Some(c) =
-                                tcp::Connection::accept(&mut nic, iph, tcph, &buf[data..nbytes])?
-                            {
-                                e.insert(c);
-                                pending.push_back(quad);
-                                drop(cmg);
-                                ih.pending_var.notify_all();
-                            }
-                        }
-                    }
-                }
+    /// Only hands out the events of `level` and below from now on, this
+    /// sink and the next ones. Everything goes out by default.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, events::Level, Interface, InterfaceConfig};
+    /// # fn main() -> std::io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let iface = Interface::with_device(nic, InterfaceConfig::default())?;
+    /// iface.on_event(|event| println!("{:?}", event.kind));
+    /// // State changes, but no congestion window updates
+    /// iface.set_event_level(Level::Info);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_event_level(&self, level: events::Level) {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.set_event_level(level);
+    }
+
+    /// Draws the random numbers from `source` from now on instead of the
+    /// OS, e.g. to fix the initial sequence numbers of a test run.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, entropy::Fixed, Interface, InterfaceConfig};
+    /// # fn main() -> std::io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let iface = Interface::with_device(nic, InterfaceConfig::default())?;
+    /// // Every connection starts at sequence number 1000
+    /// iface.set_entropy_source(Fixed(1000));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_entropy_source<E: entropy::EntropySource + 'static>(&self, source: E) {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.entropy = Some(Box::new(source));
+    }
+
+    /// Writes every event to `out` as a line of JSON, see
+    /// [`events::Event::to_json`]. Writing stops at the first error.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, Interface, InterfaceConfig};
+    /// # fn main() -> std::io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let iface = Interface::with_device(nic, InterfaceConfig::default())?;
+    /// iface.log_events(std::io::stderr());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn log_events<W: Write + Send + 'static>(&self, mut out: W) {
+        let mut failed = false;
+        self.on_event(move |event| {
+            if !failed {
+                failed = writeln!(out, "{}", event.to_json()).is_err();
             }
-        }
+        });
     }
-}
 
-pub struct TcpListener {
-    port: u16,
-    ih: InterfaceHandle,
-}
+    /// Stops handing out events.
+    pub fn clear_events(&self) {
+        let ih = self.ih.as_ref().unwrap();
+        ih.manager.lock().unwrap().events = None;
+        ih.events_on.store(false, Ordering::Relaxed);
+    }
 
-impl TcpListener {
-    pub fn accept(&mut self) -> io::Result<TcpStream> {
-        let mut cm = self.ih.manager.lock().unwrap();
-        loop {
-            if let Some(quad) = cm
-                .pending
-                .get_mut(&self.port)
-                .expect("Port closed while listener still active")
-                .pop_front()
-            {
-                return Ok(TcpStream {
-                    ih: self.ih.clone(),
-                    quad,
-                });
+    /// Writes OpenTelemetry spans of every connection to `out` once it's
+    /// gone, see [`otel`]. Replaces the previous writer, if any.
+    /// # Examples
+    /// ```no_run
+    /// # use tcp_rust::Interface;
+    /// # fn main() -> std::io::Result<()> {
+    /// let iface = Interface::new()?;
+    /// iface.export_spans(std::fs::File::create("spans.jsonl")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "otel")]
+    pub fn export_spans<W: Write + Send + 'static>(&self, out: W) {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.span_exporter = Some(Box::new(out));
+    }
+
+    /// Totals over the connections tagged `tag`, see [`TcpStream::set_tag`].
+    pub fn group_stats(&self, tag: &str) -> GroupStats {
+        let cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        let mut g = GroupStats::default();
+        cm.shards.for_each(|_, c| {
+            if c.tag.as_deref() == Some(tag) {
+                g.connections += 1;
+                g.delivered += c.info().delivered;
+                g.unacked += c.unacked.len();
+                g.unread += c.incoming.len();
+            }
+        });
+        g
+    }
+
+    /// Resets every connection tagged `tag`, as with [`TcpStream::reset`].
+    /// Returns how many there were.
+    /// # Examples
+    /// ```no_run
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let stream = listener.accept()?;
+    /// stream.set_tag(Some("tenant-a"))?;
+    /// // The tenant is gone, and its connections with it
+    /// assert_eq!(iface.close_group("tenant-a"), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn close_group(&self, tag: &str) -> usize {
+        let ih = self.ih.as_ref().unwrap();
+        let quads: Vec<Quad> = {
+            let cm = ih.manager.lock().unwrap();
+            cm.shards.quads(|c| c.tag.as_deref() == Some(tag))
+        };
+        // Connections going away in between aren't counted
+        quads.into_iter().filter(|&q| reset(ih, q).is_ok()).count()
+    }
+
+    /// Closes every connection, as [`TcpStream::shutdown`] with
+    /// [`Shutdown::Write`] would, and refuses new ones from then on: SYNs are
+    /// answered with a RST and [`Interface::connect`] fails. The handle
+    /// tells when the FINs were exchanged.
+    /// # Examples
+    /// ```no_run
+    /// # use std::{io, time::Duration};
+    /// # fn main() -> io::Result<()> {
+    /// let iface = tcp_rust::Interface::new()?;
+    /// // Give the peers 5 seconds to say goodbye
+    /// iface.shutdown().wait(Duration::from_secs(5))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shutdown(&self) -> ShutdownHandle {
+        let ih = self.ih.as_ref().unwrap();
+        let mut cmg = ih.manager.lock().unwrap();
+        let cm = &mut *cmg;
+        cm.shutting_down = true;
+
+        for quad in cm.shards.quads(|_| true) {
+            let mut c = match cm.shards.get(&quad) {
+                Some(c) => c,
+                None => continue,
+            };
+            let before = cm.events.as_ref().map(|_| events::Snapshot::of(&c));
+            // Those in TIME-WAIT are done already
+            if c.close().is_err() {
+                continue;
             }
-            cm = self.ih.pending_var.wait(cm).unwrap();
+            if let Some(log) = cm.events.as_mut() {
+                let after = events::Snapshot::of(&c);
+                log.changes(quad.local(), quad.remote(), before, after);
+            }
+            drop(c);
+            // The wake fd only fails if the packet loop is gone, the timers
+            // of the connection would send the FIN anyway
+            let _ = cm.schedule(&ih.wake, quad);
+        }
+        drop(cmg);
+        // connect() calls still in SYN-SENT were just closed
+        ih.pending_var.notify_all();
+        ShutdownHandle {
+            ih: Arc::downgrade(ih),
         }
     }
-}
 
-impl Drop for TcpListener {
-    fn drop(&mut self) {
-        let mut cm = self.ih.manager.lock().unwrap();
-        let pending = cm
-            .pending
-            .remove(&self.port)
-            .expect("Port closed while listener still active");
+    /// Listens on `port`. A SYN to a port nobody listens on, or any other
+    /// segment of a connection the interface doesn't know, is answered with
+    /// a RST (RFC 9293 S3.10.7.1).
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, Interface, InterfaceConfig};
+    /// # fn main() -> std::io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let mut iface = Interface::with_device(nic, InterfaceConfig::default())?;
+    /// let _listener = iface.bind(80)?;
+    /// // Taken already
+    /// assert!(iface.bind(80).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
+        self.bind_ports(std::iter::once(port))
+    }
+
+    /// Listens on several ports at once, the returned listener accepts
+    /// connections coming in on any of them. Either all the ports get bound
+    /// or none do.
+    pub fn bind_ports(&mut self, ports: impl IntoIterator<Item = u16>) -> io::Result<TcpListener> {
+        let mut ports: Vec<u16> = ports.into_iter().collect();
+        ports.sort_unstable();
+        ports.dedup();
+
+        if ports.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No port to bind",
+            ));
+        }
+
+        // Take the lock
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        if ports.iter().any(|port| cm.pending.contains_key(port)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "Port already bound",
+            ));
+        }
 
-        // Terminate the connections that are being dropped here
-        for _quad in pending {
-            // TODO: terminate cm.connections[quad]
-            unimplemented!()
+        for &port in &ports {
+            cm.pending.insert(port, VecDeque::new());
+            eprintln!("\x1b[1;32m[INFO]\x1b[;m Listening at port {}", port);
         }
+        drop(cm);
+        Ok(TcpListener {
+            ports,
+            next: 0,
+            route: None,
+            ih: self.ih.as_mut().unwrap().clone(),
+        })
     }
-}
 
-pub struct TcpStream {
-    quad: Quad,
-    ih: InterfaceHandle,
-}
+    /// Opens a connection to `addr`, blocking until the handshake is over.
+    /// It goes out from the first of the [`InterfaceConfig::addresses`],
+    /// on an ephemeral port. Fails with [`io::ErrorKind::ConnectionRefused`]
+    /// if the peer answers with a RST, or [`io::ErrorKind::TimedOut`] once
+    /// [`InterfaceConfig::syn_retries`] retransmissions of the SYN went
+    /// unanswered.
+    /// # Examples
+    /// ```no_run
+    /// # use std::io::{self, Write};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut stream = iface.connect("10.0.0.2:80".parse().unwrap())?;
+    /// stream.write_all(b"GET / HTTP/1.0\r\n\r\n")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn connect(&mut self, addr: SocketAddrV4) -> io::Result<TcpStream> {
+        let ih = self.ih.as_ref().unwrap();
+        let mut cmg = ih.manager.lock().unwrap();
+        let cm = &mut *cmg;
+        cm.link_ok()?;
+        if cm.shutting_down {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Interface is shutting down",
+            ));
+        }
 
-impl TcpStream {
-    pub fn shutdown(&self, _how: std::net::Shutdown) -> io::Result<()> {
-        let mut cm = self.ih.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+        let local = cm.addresses.as_ref().and_then(|a| a.first()).copied();
+        let local = local.ok_or_else(|| {
             io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Stream was terminated succesfully",
+                io::ErrorKind::AddrNotAvailable,
+                "No local address to connect from",
             )
         })?;
 
-        c.close()
+        let mut os = entropy::OsEntropy;
+        let entropy: &mut dyn entropy::EntropySource = match &mut cm.entropy {
+            Some(source) => source.as_mut(),
+            None => &mut os,
+        };
+        // Start looking for a free port at random, so that the next
+        // connection's port can't be guessed (RFC 6056 S3.3.1)
+        let first = *EPHEMERAL_PORTS.start() as u32;
+        let count = *EPHEMERAL_PORTS.end() as u32 - first + 1;
+        let offset = entropy.next_u32()?;
+        let (shards, listening) = (&cm.shards, &cm.pending);
+        let quad = (0..count)
+            .map(|i| Quad {
+                src: (*addr.ip(), addr.port()),
+                dst: (local, (first + offset.wrapping_add(i) % count) as u16),
+            })
+            .find(|q| !shards.contains(q) && !listening.contains_key(&q.dst.1))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "No ephemeral port left"))?;
+
+        let c = tcp::Connection::connect(
+            &ih.nic,
+            entropy,
+            &cm.config,
+            StreamOptions::default(),
+            quad.local(),
+            quad.remote(),
+        )?;
+        if let Some(log) = cm.events.as_mut() {
+            let after = events::Snapshot::of(&c);
+            log.changes(quad.local(), quad.remote(), None, after);
+        }
+        cm.shards.insert(quad, c);
+        *cm.per_source.entry(quad.src.0).or_default() += 1;
+        stats::flush_sends(&mut cm.stats);
+
+        loop {
+            let cm = &mut *cmg;
+            // Refused or timed out, the connection is gone or about to be
+            if cm.lost.contains_key(&quad) {
+                let err = gone(&cm.lost, &quad);
+                cm.lost.remove(&quad);
+                return Err(err);
+            }
+            let mut c = cm.connection(&quad)?;
+            if !c.is_handshaking() {
+                if c.is_closed() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Handshake failed",
+                    ));
+                }
+                c.on_accept();
+                return Ok(TcpStream {
+                    ih: ih.clone(),
+                    quad,
+                });
+            }
+            drop(c);
+
+            if let Err(e) = cm.link_ok() {
+                drop(cmg);
+                let _ = reset(ih, quad);
+                return Err(e);
+            }
+            cmg = ih.pending_var.wait(cmg).unwrap();
+        }
+    }
+
+    /// Listens on `port` and runs `handler` on every connection accepted,
+    /// from a pool of [`InterfaceConfig::serve_threads`] threads rather
+    /// than a thread per connection, see [`Server`] for the connections
+    /// that wait for a thread. The stream is closed once the handler
+    /// returns, or reset if it failed or panicked.
+    /// # Examples
+    /// ```no_run
+    /// # use std::io::{self, Read, Write};
+    /// # use tcp_rust::Interface;
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = Interface::new()?;
+    /// let server = iface.serve(7, |stream| {
+    ///     let mut buf = [0u8; 512];
+    ///     loop {
+    ///         let n = stream.read(&mut buf)?;
+    ///         if n == 0 {
+    ///             return Ok(());
+    ///         }
+    ///         stream.write_all(&buf[..n])?;
+    ///     }
+    /// })?;
+    /// server.join()
+    /// # }
+    /// ```
+    pub fn serve<F>(&mut self, port: u16, handler: F) -> io::Result<Server>
+    where
+        F: Fn(&mut TcpStream) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.bind(port)?.serve(handler)
     }
 }
 
-impl Read for TcpStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        // Try to take the lock
-        let mut cm = self.ih.manager.lock().unwrap();
+/// Waits for the connections closed by [`Interface::shutdown`]. It doesn't
+/// keep the interface alive.
+pub struct ShutdownHandle {
+    ih: Weak<Handler>,
+}
 
+impl ShutdownHandle {
+    /// Blocks until every connection is closed or in TIME-WAIT, i.e. both
+    /// FINs were sent and acknowledged, or `timeout` elapses. Returns right
+    /// away if the interface was dropped.
+    pub fn wait(&self, timeout: Duration) -> io::Result<()> {
+        let ih = match self.ih.upgrade() {
+            Some(ih) => ih,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + timeout;
+        let mut cm = ih.manager.lock().unwrap();
         loop {
-            // Lookup the connection for the TCP Stream we're trying to read from
-            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Stream was terminated unexpectedly",
-                )
-            })?;
-
-            if c.is_recv_closed() && c.incoming.is_empty() {
-                // No more data to read and no need to block
-                // because there won't be anymore
-                return Ok(0);
+            let open = cm.shards.quads(|c| !c.is_done()).len();
+            if open == 0 {
+                return Ok(());
             }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} connections still open", open),
+                ));
+            }
+            // Nothing is notified when a connection enters TIME-WAIT, look
+            // again every tick
+            cm = ih.pending_var.wait_timeout(cm, left.min(TICK)).unwrap().0;
+        }
+    }
+}
 
-            if !c.incoming.is_empty() {
-                // Try to read as much data as we can
-                let mut n_read = 0;
-
-                // c.incoming is a VecDeque, so read from head and from tail
-                let (head, tail) = c.incoming.as_slices();
+fn connections(ih: &Handler) -> Vec<(SocketAddrV4, SocketAddrV4, ConnectionInfo)> {
+    let cm = ih.manager.lock().unwrap();
+    let mut connections = Vec::new();
+    cm.shards.for_each(|quad, c| {
+        connections.push((
+            SocketAddrV4::new(quad.dst.0, quad.dst.1),
+            SocketAddrV4::new(quad.src.0, quad.src.1),
+            c.info(),
+        ))
+    });
+    connections
+}
 
-                // Read from head without overflowing the buf len
-                let h_read = cmp::min(buf.len(), head.len());
-                buf[..h_read].copy_from_slice(&head[..h_read]);
-                n_read += h_read;
+#[derive(Default)]
+pub struct ConnectionManager {
+    /// Whether [`Interface::shutdown`] was called, new connections are
+    /// refused then
+    shutting_down: bool,
+    /// Protocol parameters shared by all connections
+    config: InterfaceConfig,
+    /// Connections, split over the workers
+    shards: Arc<Shards>,
+    /// Established connections of a port, waiting to be accepted
+    pending: HashMap<u16, VecDeque<Quad>>,
+    /// Connections of a port still in the handshake
+    syn_queue: HashMap<u16, VecDeque<Quad>>,
+    /// Admission policies of the listening ports
+    admission: HashMap<u16, AdmissionPolicy>,
+    /// Port-knocking gates of the listening ports
+    knock_gates: HashMap<u16, filter::KnockGate>,
+    /// Options the listening ports give their new streams
+    stream_options: HashMap<u16, StreamOptions>,
+    /// Accept queues by first bytes, tried in order
+    routes: Vec<Route>,
+    /// Id of the next route
+    next_route: u64,
+    /// Established connections waiting for the bytes the routes of their
+    /// port look at, and since when
+    unrouted: Vec<(Quad, Instant)>,
+    /// Local addresses, any goes if `None`, see [`InterfaceConfig::addresses`]
+    addresses: Option<Vec<Ipv4Addr>>,
+    /// Why the streams of connections removed from under them are gone
+    lost: HashMap<Quad, io::ErrorKind>,
+    /// Streams that queued data since the packet loop last sent
+    unsent: Vec<Quad>,
+    /// Since when the link has been down, if it is
+    link_down: Option<Instant>,
+    /// Whether the blocking calls were told the link is gone for good
+    link_down_notified: bool,
+    /// Connections held per remote address, see [`InterfaceConfig::ip_quota`]
+    per_source: HashMap<Ipv4Addr, usize>,
+    /// Where the random numbers come from, the OS if `None`
+    entropy: Option<Box<dyn entropy::EntropySource>>,
+    /// Where the events go, see [`Interface::on_event`]
+    events: Option<events::EventLog>,
+    /// How much of them, see [`Interface::set_event_level`]
+    event_level: events::Level,
+    /// Where the spans of the connections go, see [`Interface::export_spans`]
+    #[cfg(feature = "otel")]
+    span_exporter: Option<Box<dyn Write + Send>>,
+    /// Aggregated statistics
+    stats: InterfaceStats,
+}
 
-                // Read from tail without overflowing the buf len
-                let t_read = cmp::min(buf.len() - n_read, tail.len());
-                buf[h_read..(h_read + t_read)].copy_from_slice(&tail[..t_read]);
-                n_read += t_read;
+/// Error for a stream whose connection isn't there anymore
+fn gone(lost: &HashMap<Quad, io::ErrorKind>, quad: &Quad) -> io::Error {
+    match lost.get(quad) {
+        Some(&kind) => io::Error::new(
+            kind,
+            match kind {
+                io::ErrorKind::ConnectionRefused => "Connection refused",
+                io::ErrorKind::TimedOut => "Connection timed out",
+                _ => "Local address was removed",
+            },
+        ),
+        None => io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Stream was terminated unexpectedly",
+        ),
+    }
+}
 
-                // Drop the read bytes
-                drop(c.incoming.drain(..n_read));
+/// What a read or a write that got through `n` bytes before `err` returns,
+/// the error waits for the next call if some bytes made it
+fn partial(n: usize, err: io::Error) -> io::Result<usize> {
+    if n > 0 {
+        Ok(n)
+    } else {
+        Err(err)
+    }
+}
 
-                // Return amount of bytes read
-                return Ok(n_read);
-            }
+/// Builds the hasher picked by [`InterfaceConfig::connection_hasher`]
+#[derive(Clone)]
+enum QuadHashBuilder {
+    Sip(RandomState),
+    Fnv,
+}
 
-            cm = self.ih.recv_var.wait(cm).unwrap();
+impl QuadHashBuilder {
+    fn new(kind: ConnectionHasher) -> Self {
+        match kind {
+            ConnectionHasher::SipHash => Self::Sip(RandomState::new()),
+            ConnectionHasher::Fnv => Self::Fnv,
         }
     }
 }
 
-impl Write for TcpStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // Try to take the lock
-        let mut cm = self.ih.manager.lock().unwrap();
+impl Default for QuadHashBuilder {
+    fn default() -> Self {
+        Self::new(InterfaceConfig::default().connection_hasher)
+    }
+}
 
-        // Lookup the connection for the TCP Stream we're trying to read from
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Stream was terminated unexpectedly",
-            )
-        })?;
+impl BuildHasher for QuadHashBuilder {
+    type Hasher = QuadHasher;
 
-        if c.unacked.len() >= SENDQUEUE_SIZE {
-            // TODO: block
-            return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "Too many bytes buffered",
-            ));
-        };
+    fn build_hasher(&self) -> QuadHasher {
+        match self {
+            Self::Sip(state) => QuadHasher::Sip(state.build_hasher()),
+            Self::Fnv => QuadHasher::Fnv(fnv::FnvHasher::default()),
+        }
+    }
+}
 
-        let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
-        c.unacked.extend(&buf[..nwrite]);
+enum QuadHasher {
+    Sip(DefaultHasher),
+    Fnv(fnv::FnvHasher),
+}
 
-        // TODO: Wake up writer
-        Ok(nwrite)
+impl Hasher for QuadHasher {
+    fn finish(&self) -> u64 {
+        match self {
+            Self::Sip(h) => h.finish(),
+            Self::Fnv(h) => h.finish(),
+        }
     }
 
-    // Block until there are no bytes in the local buffer
-    fn flush(&mut self) -> io::Result<()> {
-        let mut cm = self.ih.manager.lock().unwrap();
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Sip(h) => h.write(bytes),
+            Self::Fnv(h) => h.write(bytes),
+        }
+    }
+}
 
-        loop {
-            // Lookup the connection for the TCP Stream we're trying to read from
-            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Stream was terminated unexpectedly",
-                )
-            })?;
-
-            if c.unacked.is_empty() {
-                return Ok(());
+impl ConnectionManager {
+    /// Has the packet loop send what `quad` queued without waiting for
+    /// the next tick
+    fn schedule(&mut self, wake: &wake::Wake, quad: Quad) -> io::Result<()> {
+        if !self.unsent.contains(&quad) {
+            // One wakeup for however many writes before the loop gets to it
+            if self.unsent.is_empty() {
+                wake.notify()?;
             }
+            self.unsent.push(quad);
+        }
+        Ok(())
+    }
 
-            cm = self.ih.flush_var.wait(cm).unwrap();
+    fn set_event_level(&mut self, level: events::Level) {
+        self.event_level = level;
+        if let Some(log) = self.events.as_mut() {
+            log.level = level;
         }
     }
-}
 
-impl Drop for TcpStream {
-    fn drop(&mut self) {
-        let mut _cm = self.ih.manager.lock().unwrap();
-        // TODO: send FIN cm.connections[quad]
-        // Terminate the connections that are being dropped here
+    /// Removes the connections whose lifetime is over, along with any
+    /// pending-queue entry still waiting to be accepted.
+    /// Returns whether any connection was removed.
+    fn reap(&mut self) -> bool {
+        let closed = self.shards.quads(|c| c.is_closed());
+
+        for quad in &closed {
+            let _c = self.shards.remove(quad);
+            #[cfg(feature = "otel")]
+            if let Some(c) = &_c {
+                self.export_spans(quad, c);
+            }
+            self.release_source(quad);
+            if let Some(log) = self.events.as_mut() {
+                log.emit(events::EventKind::Removed {
+                    local: quad.local(),
+                    remote: quad.remote(),
+                });
+            }
+            self.dequeue(quad);
+        }
+
+        !closed.is_empty()
+    }
+
+    /// Takes `quad` out of the SYN and accept queues of its port. A
+    /// connection is in one of them until it is handed out by `accept`,
+    /// removed, or reset along with its listener.
+    fn dequeue(&mut self, quad: &Quad) {
+        if let Some(pending) = self.pending.get_mut(&quad.dst.1) {
+            pending.retain(|q| q != quad);
+        }
+        for route in &mut self.routes {
+            route.queue.retain(|q| q != quad);
+        }
+        self.unrouted.retain(|(q, _)| q != quad);
+        if let Some(syn_queue) = self.syn_queue.get_mut(&quad.dst.1) {
+            syn_queue.retain(|q| q != quad);
+        }
+    }
+
+    /// Moves `quad` from the SYN queue of its port to the accept queue once
+    /// its handshake completed. Returns whether it can be accepted now,
+    /// which it can't if it wasn't waiting in the SYN queue.
+    fn on_established(&mut self, quad: Quad) -> bool {
+        let queued = self.syn_queue.get_mut(&quad.dst.1).is_some_and(|q| {
+            let len = q.len();
+            q.retain(|q| *q != quad);
+            q.len() < len
+        });
+        if !queued || !self.pending.contains_key(&quad.dst.1) {
+            return false;
+        }
+        if self.routes.iter().any(|r| r.ports.contains(&quad.dst.1)) {
+            self.unrouted.push((quad, self.config.clock.now()));
+            return self.route(quad);
+        }
+        self.pending.get_mut(&quad.dst.1).unwrap().push_back(quad);
+        true
+    }
+
+    /// Puts an unrouted connection in the accept queue of the first route
+    /// matching its first bytes, or of its listener if none does. Returns
+    /// false if it has to wait for more, see [`TcpListener::route`].
+    fn route(&mut self, quad: Quad) -> bool {
+        let at = match self.unrouted.iter().position(|(q, _)| *q == quad) {
+            Some(at) => at,
+            None => return false,
+        };
+        let c = match self.shards.get(&quad) {
+            Some(c) => c,
+            None => {
+                self.unrouted.remove(at);
+                return false;
+            }
+        };
+        // Whatever arrived is all there is going to be
+        let last = c.is_recv_closed()
+            || self.config.clock.since(self.unrouted[at].1) >= self.config.route_timeout;
+
+        let mut routes = self
+            .routes
+            .iter_mut()
+            .filter(|r| r.ports.contains(&quad.dst.1));
+        let mut queue = None;
+        for route in &mut routes {
+            if c.incoming.len() < route.peek && !last {
+                return false;
+            }
+            let n = cmp::min(c.incoming.len(), route.peek);
+            let (a, b) = c.incoming.slices(0..n);
+            if (route.predicate)(&[a, b].concat()) {
+                queue = Some(&mut route.queue);
+                break;
+            }
+        }
+        let queue = match queue {
+            Some(queue) => queue,
+            None => match self.pending.get_mut(&quad.dst.1) {
+                Some(pending) => pending,
+                None => return false,
+            },
+        };
+        queue.push_back(quad);
+        self.unrouted.remove(at);
+        true
+    }
+
+    /// Routes the connections that waited long enough, see
+    /// [`InterfaceConfig::route_timeout`]. Returns whether any was.
+    fn route_expired(&mut self) -> bool {
+        let timeout = self.config.route_timeout;
+        let expired: Vec<Quad> = self
+            .unrouted
+            .iter()
+            .filter(|(_, since)| self.config.clock.since(*since) >= timeout)
+            .map(|(quad, _)| *quad)
+            .collect();
+        expired
+            .into_iter()
+            .fold(false, |any, quad| self.route(quad) | any)
+    }
+
+    /// Writes the spans of a connection that is going away. A writer that
+    /// fails is dropped.
+    #[cfg(feature = "otel")]
+    fn export_spans(&mut self, quad: &Quad, c: &tcp::Connection) {
+        if let Some(out) = self.span_exporter.as_mut() {
+            let ends = otel::Ends {
+                local: SocketAddrV4::new(quad.dst.0, quad.dst.1),
+                remote: SocketAddrV4::new(quad.src.0, quad.src.1),
+                delivered: c.info().delivered,
+            };
+            if otel::export(out.as_mut(), &c.lifecycle, &ends).is_err() {
+                self.span_exporter = None;
+            }
+        }
+    }
+
+    /// How long the packet loop can sleep before the timers have something
+    /// to do, from one [`TICK`] up to [`IDLE_TICK`]
+    fn next_tick(&self) -> Duration {
+        // The grace of a link that is down and the route timeout are only
+        // looked at on the ticks
+        if self.link_down.is_some() || !self.unrouted.is_empty() {
+            return TICK;
+        }
+        let mut next = IDLE_TICK;
+        self.shards.for_each(|_, c| {
+            if let Some(timer) = c.next_timer(&self.config) {
+                next = next.min(timer);
+            }
+        });
+        next.clamp(TICK, IDLE_TICK)
+    }
+
+    /// Fails once the link has been down for longer than
+    /// [`InterfaceConfig::link_down_grace`]
+    fn link_ok(&self) -> io::Result<()> {
+        match self.link_down {
+            Some(since) if self.config.clock.since(since) >= self.config.link_down_grace => {
+                Err(io::Error::new(io::ErrorKind::NetworkDown, "Link is down"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the send path of `quad` now, see [`TcpStream::kick`]. Nothing
+    /// is sent while the link is down.
+    fn transmit(&mut self, nic: &dyn Device, quad: &Quad) -> io::Result<()> {
+        if self.link_down.is_some() {
+            return Ok(());
+        }
+        let mut c = match self.shards.get(quad) {
+            Some(c) => c,
+            None => return Err(gone(&self.lost, quad)),
+        };
+        let before = self.events.as_ref().map(|_| events::Snapshot::of(&c));
+        c.transmit(nic)?;
+        if let Some(log) = self.events.as_mut() {
+            let after = events::Snapshot::of(&c);
+            log.changes(quad.local(), quad.remote(), before, after);
+        }
+        Ok(())
+    }
+
+    /// The connection of `quad`, with its shard locked until it's dropped
+    fn connection(&self, quad: &Quad) -> io::Result<shard::Locked<'_>> {
+        self.shards.get(quad).ok_or_else(|| gone(&self.lost, quad))
+    }
+
+    /// Accounts for a connection from `quad`'s remote address going away
+    fn release_source(&mut self, quad: &Quad) {
+        if let Entry::Occupied(mut e) = self.per_source.entry(quad.src.0) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
+    }
+}
+
+/// Sleeps until the nearest timer of the connections is due, or until
+/// packets or data to send come in. The timers run no later than a [`TICK`]
+/// after those, as they can arm new ones.
+fn packet_loop(ih: InterfaceHandle) -> io::Result<()> {
+    let mut driver = Driver::new(ih);
+    let mut deadline = Instant::now();
+    loop {
+        if Instant::now() >= deadline {
+            if !driver.tick()? {
+                return Ok(());
+            }
+            deadline = Instant::now() + driver.ih.manager.lock().unwrap().next_tick();
+        }
+        // Rounded up, so that the deadline has passed once poll times out
+        let left = deadline.saturating_duration_since(Instant::now());
+        let timeout = left.as_micros().div_ceil(1000) as i32;
+        if driver.wait(timeout)? {
+            deadline = deadline.min(Instant::now() + TICK);
+        }
+    }
+}
+
+/// Drives several interfaces from a single thread: the devices are all
+/// waited on with one `poll`, and the connection timers of every interface
+/// run from the same clock. Handy for multi-homed setups, e.g. a TUN device
+/// next to a [`device::Loopback`] test device, without a thread per interface.
+///
+/// The interfaces stop making progress once the set is dropped, unless
+/// they are driven by hand with [`Interface::poll`].
+/// # Examples
+/// ```no_run
+/// # use tcp_rust::{device::Tun, InterfaceConfig, InterfaceSet};
+/// # use std::io;
+/// # fn main() -> io::Result<()> {
+/// let set = InterfaceSet::new();
+/// let mut a = set.add(Tun::open("tun0", false)?, InterfaceConfig::default())?;
+/// let mut b = set.add(Tun::open("tun1", false)?, InterfaceConfig::default())?;
+/// // Both served from the same thread
+/// let (_http, _https) = (a.bind(80)?, b.bind(443)?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct InterfaceSet {
+    members: Arc<SetMembers>,
+    stop: Arc<AtomicBool>,
+    jh: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl InterfaceSet {
+    pub fn new() -> Self {
+        let members: Arc<SetMembers> = Default::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let jh = {
+            let (members, stop) = (members.clone(), stop.clone());
+            thread::spawn(move || set_loop(&members, &stop))
+        };
+
+        Self {
+            members,
+            stop,
+            jh: Some(jh),
+        }
+    }
+
+    /// Creates an interface on top of `nic` driven by the set. Its
+    /// [`InterfaceConfig::event_loop`] is ignored, and no worker threads
+    /// are spawned for it.
+    pub fn add<D: Device + 'static>(
+        &self,
+        nic: D,
+        config: InterfaceConfig,
+    ) -> io::Result<Interface> {
+        let fd = nic.as_raw_fd();
+        let iface = Interface::with_device(
+            nic,
+            InterfaceConfig {
+                event_loop: EventLoop::Manual,
+                ..config
+            },
+        )?;
+
+        let driver = iface.driver.clone().unwrap();
+        let wake = iface.ih.as_ref().unwrap().wake.as_raw_fd();
+        self.members.lock().unwrap().push(([fd, wake], driver));
+        Ok(iface)
+    }
+
+    /// Number of interfaces still driven by the set
+    pub fn len(&self) -> usize {
+        self.members.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.lock().unwrap().is_empty()
+    }
+}
+
+impl Default for InterfaceSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InterfaceSet {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(jh) = self.jh.take() {
+            jh.join().unwrap().unwrap();
+        }
+    }
+}
+
+/// Packet loops of an [`InterfaceSet`], along with the descriptors of their
+/// device and of their wakeups
+type SetMembers = Mutex<Vec<([RawFd; 2], Arc<Mutex<Driver>>)>>;
+
+/// Packet loop of an [`InterfaceSet`]
+fn set_loop(members: &SetMembers, stop: &AtomicBool) -> io::Result<()> {
+    let mut ticked_at = Instant::now();
+
+    while !stop.load(Ordering::Relaxed) {
+        let drivers = members.lock().unwrap().clone();
+        let mut pfd: Vec<_> = drivers
+            .iter()
+            .flat_map(|(fds, _)| fds)
+            .map(|fd| nix::poll::PollFd::new(*fd, nix::poll::PollFlags::POLLIN))
+            .collect();
+        if pfd.is_empty() {
+            thread::sleep(TICK);
+            continue;
+        }
+        nix::poll::poll(&mut pfd[..], TICK.as_millis() as i32)
+            .map_err(|e| e.as_errno().unwrap())?;
+
+        // The timers of the idle interfaces run every tick
+        let tick = ticked_at.elapsed() >= TICK;
+        if tick {
+            ticked_at = Instant::now();
+        }
+
+        let mut gone = Vec::new();
+        for (pfd, (fds, driver)) in pfd.chunks(2).zip(&drivers) {
+            let ready = pfd.iter().any(|pfd| {
+                pfd.revents()
+                    .is_some_and(|r| r.contains(nix::poll::PollFlags::POLLIN))
+            });
+            if (ready || tick) && !driver.lock().unwrap().step(0)? {
+                gone.push(fds[0]);
+            }
+        }
+
+        if !gone.is_empty() {
+            // Their interface was dropped
+            members
+                .lock()
+                .unwrap()
+                .retain(|(fds, _)| !gone.contains(&fds[0]));
+        }
+    }
+    Ok(())
+}
+
+/// Sends the data the streams queued since the last time
+fn send_unsent(ih: &InterfaceHandle) -> io::Result<()> {
+    ih.wake.clear()?;
+    let mut cm = ih.manager.lock().unwrap();
+    for quad in std::mem::take(&mut cm.unsent) {
+        match cm.transmit(&ih.nic, &quad) {
+            // Reset or reaped since
+            Err(_) if !cm.shards.contains(&quad) => {}
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+/// State of the packet loop between two iterations, whether it runs on its
+/// own thread or gets driven through [`Interface::poll`]
+struct Driver {
+    ih: InterfaceHandle,
+    buf: Vec<u8>,
+    /// Packets read in one go, see [`InterfaceConfig::rx_batch`]
+    batch: Vec<Vec<u8>>,
+    /// Buffers of the batches gone through, to read the next ones into
+    spare: Vec<Vec<u8>>,
+    workers: Vec<Worker>,
+    /// Connections of the interface, a shard per worker
+    shards: Arc<Shards>,
+    busy_poll: u32,
+    rx_batch: usize,
+}
+
+/// Thread running the segments of the connections of a shard, see
+/// [`InterfaceConfig::workers`]
+struct Worker {
+    tx: mpsc::Sender<Vec<u8>>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Worker {
+    fn spawn(ih: InterfaceHandle, shards: Arc<Shards>) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let thread = thread::spawn(move || -> io::Result<()> {
+            for packet in rx {
+                if let Some((seg, parsed)) = parse(&packet) {
+                    on_worker_packet(&ih, &shards, &seg, parsed)?;
+                }
+            }
+            Ok(())
+        });
+        Self {
+            tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// Why the worker stopped, once it did
+    fn join(&mut self) -> io::Error {
+        match self.thread.take().map(|t| t.join()) {
+            Some(Ok(Err(e))) => e,
+            Some(Err(_)) => io::Error::other("Packet worker panicked"),
+            _ => io::Error::new(io::ErrorKind::BrokenPipe, "Packet worker exited"),
+        }
+    }
+}
+
+impl Driver {
+    fn new(ih: InterfaceHandle) -> Self {
+        // Offloading devices can hand over packets much larger than the MTU
+        let buf = vec![0u8; ih.nic.gso_max_len().unwrap_or_else(|| ih.nic.mtu())];
+
+        let (n_workers, busy_poll, rx_batch, event_loop, shards) = {
+            let cm = ih.manager.lock().unwrap();
+            let c = &cm.config;
+            let shards = Arc::clone(&cm.shards);
+            (c.workers, c.busy_poll, c.rx_batch, c.event_loop, shards)
+        };
+        // Threads are left to the user when they drive the loop
+        let n_workers = match event_loop {
+            EventLoop::Thread => n_workers,
+            EventLoop::Manual => 0,
+        };
+        let workers = (0..n_workers)
+            .map(|_| Worker::spawn(ih.clone(), shards.clone()))
+            .collect();
+
+        Self {
+            ih,
+            buf,
+            batch: Vec::with_capacity(rx_batch),
+            spare: Vec::with_capacity(rx_batch),
+            workers,
+            shards,
+            busy_poll,
+            rx_batch,
+        }
+    }
+
+    /// Waits up to `timeout` milliseconds for packets and processes them,
+    /// or runs the timers if none came. Returns `false` once nothing but
+    /// the packet loop references the interface anymore.
+    fn step(&mut self, timeout: i32) -> io::Result<bool> {
+        if self.wait(timeout)? {
+            return Ok(true);
+        }
+        self.tick()
+    }
+
+    /// Runs the timers of the connections, and reaps those that are done.
+    /// Returns `false` once nothing but the packet loop references the
+    /// interface anymore.
+    fn tick(&mut self) -> io::Result<bool> {
+        let ih = &self.ih;
+        let nic: &dyn Device = &ih.nic;
+        // Everything but the workers let go of the interface
+        if Arc::strong_count(ih) <= 1 + self.workers.len() {
+            return Ok(false);
+        }
+        // A worker that failed stops the loop, as the packet loop would
+        if let Some(worker) = self
+            .workers
+            .iter_mut()
+            .find(|w| w.thread.as_ref().is_some_and(|t| t.is_finished()))
+        {
+            return Err(worker.join());
+        }
+
+        let mut cmg = ih.manager.lock().unwrap();
+        let cm = &mut *cmg;
+        self.busy_poll = cm.config.busy_poll;
+        self.rx_batch = cm.config.rx_batch;
+        stats::flush_sends(&mut cm.stats);
+        // Nothing gets through a link that is down, the timers wait for it
+        let paused = cm.link_down.is_some();
+        let mut handshakes_over = false;
+        for mut shard in cm.shards.lock_all().into_iter().filter(|_| !paused) {
+            for (quad, conn) in shard.connections.iter_mut() {
+                let before = cm.events.as_ref().map(|_| events::Snapshot::of(conn));
+                let connecting = conn.is_connecting();
+                conn.on_tick(nic, &cm.config)?;
+                if connecting && conn.is_closed() {
+                    // Out of SYN retransmissions
+                    cm.lost.insert(*quad, io::ErrorKind::TimedOut);
+                    handshakes_over = true;
+                }
+                if let Some(log) = cm.events.as_mut() {
+                    let after = events::Snapshot::of(conn);
+                    log.changes(quad.local(), quad.remote(), before, after);
+                }
+            }
+        }
+
+        let given_up = paused && !cm.link_down_notified && cm.link_ok().is_err();
+        cm.link_down_notified |= given_up;
+        if cm.route_expired() | cm.reap() | given_up | handshakes_over {
+            drop(cmg);
+            ih.pending_var.notify_all();
+            ih.recv_var.notify_all();
+            ih.flush_var.notify_all();
+            ih.send_var.notify_all();
+        }
+        Ok(true)
+    }
+
+    /// Waits up to `timeout` milliseconds for packets, or for data the
+    /// streams queued, and handles them. Returns whether anything came.
+    fn wait(&mut self, timeout: i32) -> io::Result<bool> {
+        let ih = &self.ih;
+        let nic: &dyn Device = &ih.nic;
+        let mut pfd = [
+            nix::poll::PollFd::new(nic.as_raw_fd(), nix::poll::PollFlags::POLLIN),
+            nix::poll::PollFd::new(ih.wake.as_raw_fd(), nix::poll::PollFlags::POLLIN),
+        ];
+
+        // Spin for a while before going to sleep
+        let mut n = 0;
+        for _ in 0..self.busy_poll {
+            n = nix::poll::poll(&mut pfd[..], 0).map_err(|e| e.as_errno().unwrap())?;
+            if n != 0 {
+                break;
+            }
+            std::hint::spin_loop();
+        }
+
+        if n == 0 {
+            n = nix::poll::poll(&mut pfd[..], timeout).map_err(|e| e.as_errno().unwrap())?;
+        }
+        assert_ne!(n, -1);
+        let readable = |pfd: &nix::poll::PollFd| {
+            pfd.revents()
+                .is_some_and(|r| r.contains(nix::poll::PollFlags::POLLIN))
+        };
+        if n == 0 {
+            return Ok(false);
+        }
+        if readable(&pfd[1]) {
+            send_unsent(ih)?;
+            if !readable(&pfd[0]) {
+                return Ok(true);
+            }
+        }
+        // NIC file descriptor is now available for reading
+
+        let buf = &mut self.buf;
+        let nbytes = nic.recv(&mut buf[..])?;
+        let verify = !nic.capabilities().contains(device::Capabilities::RX_CSUM);
+        let packet = &buf[..nbytes];
+        if !well_formed(ih, packet) {
+            return Ok(true);
+        }
+        // Nothing but TCP over IPv4 goes any further
+        let (seg, parsed) = match parse(packet) {
+            Some(parsed) => parsed,
+            None => return Ok(true),
+        };
+        if verify && !checksum_ok(ih, &seg) {
+            return Ok(true);
+        }
+        let mut coalesced = stats::CoalesceStats::default();
+        note_super_packet(nic, &seg, &mut coalesced);
+
+        if self.rx_batch <= 1 {
+            record_coalesced(ih, &coalesced);
+            if self.workers.is_empty() {
+                on_packet(ih, &seg, parsed)?;
+            } else {
+                dispatch(&mut self.workers, &self.shards, seg.quad(), packet.to_vec())?;
+            }
+            return Ok(true);
+        }
+
+        // Grab whatever else is already waiting on the device, a pending
+        // wake would leave the read below blocking
+        let (batch, spare) = (&mut self.batch, &mut self.spare);
+        batch.push(reuse(spare, packet));
+        while batch.len() < self.rx_batch
+            && nix::poll::poll(&mut pfd[..1], 0).map_err(|e| e.as_errno().unwrap())? != 0
+            && readable(&pfd[0])
+        {
+            let nbytes = nic.recv(&mut buf[..])?;
+            let packet = &buf[..nbytes];
+            let seg = match well_formed(ih, packet).then(|| InboundSegment::parse(packet)) {
+                Some(Some(seg)) if !verify || checksum_ok(ih, &seg) => seg,
+                _ => continue,
+            };
+            note_super_packet(nic, &seg, &mut coalesced);
+            batch.push(reuse(spare, packet));
+        }
+
+        if batch.len() > 1 {
+            gro::coalesce(batch, spare, &mut coalesced);
+        }
+        record_coalesced(ih, &coalesced);
+
+        for packet in batch.drain(..) {
+            if self.workers.is_empty() {
+                if let Some((seg, parsed)) = parse(&packet) {
+                    on_packet(ih, &seg, parsed)?;
+                }
+                spare.push(packet);
+            } else if let Some(quad) = peek_quad(&packet) {
+                dispatch(&mut self.workers, &self.shards, quad, packet)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Copy of `packet` in one of the `spare` buffers, if any is left
+fn reuse(spare: &mut Vec<Vec<u8>>, packet: &[u8]) -> Vec<u8> {
+    let mut buf = spare.pop().unwrap_or_default();
+    buf.clear();
+    buf.extend_from_slice(packet);
+    buf
+}
+
+/// Counts a packet the device handed over larger than the MTU, i.e. that
+/// it coalesced itself. Those the stack merges are counted apart.
+fn note_super_packet(nic: &dyn Device, seg: &InboundSegment, stats: &mut stats::CoalesceStats) {
+    if seg.headers_len() + seg.data.len() > nic.mtu() {
+        stats.super_packets += 1;
+        stats.super_packet_bytes += seg.data.len() as u64;
+    }
+}
+
+fn record_coalesced(ih: &Handler, coalesced: &stats::CoalesceStats) {
+    if coalesced.segments != 0 || coalesced.super_packets != 0 {
+        let mut cm = ih.manager.lock().unwrap();
+        let stats = &mut cm.stats.coalesce;
+        stats.segments += coalesced.segments;
+        stats.bytes += coalesced.bytes;
+        stats.super_packets += coalesced.super_packets;
+        stats.super_packet_bytes += coalesced.super_packet_bytes;
+    }
+}
+
+/// Hands the packet to the worker owning the shard of its quad, so that the
+/// segments of a connection are still processed in order
+fn dispatch(
+    workers: &mut [Worker],
+    shards: &Shards,
+    quad: Quad,
+    packet: Vec<u8>,
+) -> io::Result<()> {
+    let worker = &mut workers[shards.index(&quad)];
+    match worker.tx.send(packet) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(worker.join()),
+    }
+}
+
+/// Extracts the quad out of a TCP/IPv4 packet, without looking any further.
+fn peek_quad(packet: &[u8]) -> Option<Quad> {
+    InboundSegment::parse(packet).map(|seg| seg.quad())
+}
+
+/// Parses a packet for [`on_packet`], timing it for the packet path stats
+fn parse(packet: &[u8]) -> Option<(InboundSegment<'_>, stats::Lap)> {
+    let parsing = Stopwatch::start();
+    let seg = InboundSegment::parse(packet)?;
+    Some((seg, parsing.stop()))
+}
+
+/// What's wrong with the headers of a TCP packet, if anything
+fn malformed(packet: &[u8]) -> Option<stats::Malformed> {
+    use stats::Malformed;
+
+    let iph = match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+        Ok(iph) => iph,
+        Err(_) => return Some(Malformed::IpHeader),
+    };
+    if iph.protocol() != TCP_PROTO_NO {
+        return None;
+    }
+    let tcp = &packet[iph.slice().len()..];
+    let tcph = match etherparse::TcpHeaderSlice::from_slice(tcp) {
+        Ok(tcph) => tcph,
+        Err(_) => return Some(Malformed::TcpHeader),
+    };
+
+    // The 3 bits between the data offset and NS
+    if tcp[12] & 0b0000_1110 != 0 {
+        return Some(Malformed::ReservedBits);
+    }
+    let (syn, ack, rst, fin) = (tcph.syn(), tcph.ack(), tcph.rst(), tcph.fin());
+    if (syn && (fin || rst)) || !(syn || ack || rst) {
+        return Some(Malformed::IllegalFlags);
+    }
+    None
+}
+
+/// Counts the packets with broken or odd headers, and tells whether they
+/// go on anyway, see [`InterfaceConfig::parsing`].
+fn well_formed(ih: &Handler, packet: &[u8]) -> bool {
+    let reason = match malformed(packet) {
+        Some(reason) => reason,
+        None => return true,
+    };
+
+    let mut cm = ih.manager.lock().unwrap();
+    cm.stats.malformed.record(reason, packet);
+    matches!(
+        (reason, cm.config.parsing),
+        (
+            stats::Malformed::ReservedBits | stats::Malformed::IllegalFlags,
+            ParsePolicy::Lenient
+        )
+    )
+}
+
+/// Checks the TCP checksum of a segment, counting it if it's wrong.
+/// Headers that don't add up are left for [`on_packet`] to deal with.
+fn checksum_ok(ih: &Handler, seg: &InboundSegment) -> bool {
+    let ok = (|| {
+        // Without whatever pads the packet past its total length
+        let len = (seg.iph.total_len() as usize).checked_sub(seg.headers_len())?;
+        let data = &seg.data[..cmp::min(len, seg.data.len())];
+        let checksum = seg.tcph.calc_checksum_ipv4(&seg.iph, data).ok()?;
+        Some(checksum == seg.tcph.checksum())
+    })()
+    .unwrap_or(true);
+
+    if !ok {
+        ih.manager.lock().unwrap().stats.checksum_errors += 1;
+    }
+    ok
+}
+
+/// Runs a single incoming packet through the connection it belongs to.
+/// What it sends in reply only goes out once the connection manager is
+/// unlocked, so that a slow device doesn't hold up the streams.
+fn on_packet(ih: &Handler, seg: &InboundSegment, parsed: stats::Lap) -> io::Result<()> {
+    let out = device::Deferred::new(&ih.nic);
+    let res = process(ih, &out, seg, parsed);
+    out.flush()?;
+    res
+}
+
+/// [`on_packet`] for the workers, which only lock the shard of the
+/// connection for the segments of those already established
+fn on_worker_packet(
+    ih: &Handler,
+    shards: &Shards,
+    seg: &InboundSegment,
+    parsed: stats::Lap,
+) -> io::Result<()> {
+    let out = device::Deferred::new(&ih.nic);
+    let res = match process_in_shard(ih, shards, &out, seg, parsed) {
+        Some(res) => res,
+        None => process(ih, &out, seg, parsed),
+    };
+    out.flush()?;
+    res
+}
+
+/// Runs a segment through its connection with only the shard of the
+/// connection locked. `None` if it takes the manager: the connection is new
+/// or in the handshake, the segment isn't for one of our addresses, or an
+/// event sink wants the changes in order.
+fn process_in_shard(
+    ih: &Handler,
+    shards: &Shards,
+    nic: &dyn Device,
+    seg: &InboundSegment,
+    parsed: stats::Lap,
+) -> Option<io::Result<()>> {
+    if ih.events_on.load(Ordering::Relaxed) || (seg.tcph.syn() && !seg.tcph.ack()) {
+        return None;
+    }
+    let quad = seg.quad();
+    let locking = Stopwatch::start();
+    let mut guard = shards.lock(shards.index(&quad));
+    let waited = locking.stop();
+    let shard = &mut *guard;
+    if let Some(addresses) = &shard.addresses {
+        let dst = seg.iph.destination_addr();
+        let spoofed = shard.config.reverse_path && addresses.contains(&seg.iph.source_addr());
+        if !addresses.contains(&dst) || spoofed {
+            return None;
+        }
+    }
+    let c = shard.connections.get_mut(&quad)?;
+    if c.is_handshaking() {
+        return None;
+    }
+    let processing = Stopwatch::start();
+    let available = match c.on_packet(nic, &shard.config, seg) {
+        Ok(available) => available,
+        Err(e) => return Some(Err(e)),
+    };
+    let processed = processing.stop();
+    drop(guard);
+
+    let mut cm = ih.manager.lock().unwrap();
+    waited.record(&mut cm.stats.packet_path.lock);
+    parsed.record(&mut cm.stats.packet_path.parse);
+    processed.record(&mut cm.stats.packet_path.on_packet);
+    stats::flush_sends(&mut cm.stats);
+    // The first bytes decide which queue it goes to
+    let routed = cm.unrouted.iter().any(|(q, _)| *q == quad) && cm.route(quad);
+    drop(cm);
+
+    if routed {
+        ih.pending_var.notify_all();
+    }
+    notify(ih, available);
+    Some(Ok(()))
+}
+
+/// Does the work of [`on_packet`], sending through `nic`
+fn process(
+    ih: &Handler,
+    nic: &dyn Device,
+    seg: &InboundSegment,
+    parsed: stats::Lap,
+) -> io::Result<()> {
+    let (iph, tcph) = (&seg.iph, &seg.tcph);
+
+    // Try to lock the thread
+    let locking = Stopwatch::start();
+    let mut cmg = ih.manager.lock().unwrap();
+    // Dereference to get a mutable reference to the CM, instead of the Mutex
+    let cm = &mut *cmg;
+
+    locking.record(&mut cm.stats.packet_path.lock);
+    parsed.record(&mut cm.stats.packet_path.parse);
+    stats::flush_sends(&mut cm.stats);
+
+    // Not one of our addresses, as if it never reached us
+    if let Some(addresses) = &cm.addresses {
+        let stats = &mut cm.stats.reverse_path;
+        if !addresses.contains(&iph.destination_addr()) {
+            if cm.config.reverse_path {
+                stats.wrong_destination += 1;
+            }
+            return Ok(());
+        }
+        // Nothing of ours comes in from the device
+        if cm.config.reverse_path && addresses.contains(&iph.source_addr()) {
+            stats.spoofed_source += 1;
+            return Ok(());
+        }
+    }
+
+    let quad = seg.quad();
+
+    if tcph.syn() && !tcph.ack() {
+        let now = cm.config.clock.now();
+        for gate in cm.knock_gates.values_mut() {
+            gate.on_syn(quad.src.0, quad.dst.1, now);
+        }
+    }
+
+    // Is the incoming connection known already?
+    let shards = cm.shards.clone();
+    match shards.get(&quad) {
+        Some(mut c) => {
+            let handshaking = c.is_handshaking();
+            let connecting = c.is_connecting();
+            let before = cm.events.as_ref().map(|_| events::Snapshot::of(&c));
+            let processing = Stopwatch::start();
+            let available = c.on_packet(nic, &cm.config, seg)?;
+            processing.record(&mut cm.stats.packet_path.on_packet);
+
+            let handshake_over = handshaking && !c.is_handshaking();
+            let failed = c.is_closed();
+            if handshake_over {
+                if let Some(latency) = c.handshake_latency() {
+                    cm.stats.handshake_latency.record(latency);
+                }
+            }
+
+            if let Some(log) = cm.events.as_mut() {
+                let after = events::Snapshot::of(&c);
+                log.changes(quad.local(), quad.remote(), before, after);
+            }
+            drop(c);
+
+            // Out of the SYN queue, and into the accept queue
+            // unless the handshake failed. Connections we opened
+            // were never in either.
+            if handshake_over && failed {
+                if connecting {
+                    cm.lost.insert(quad, io::ErrorKind::ConnectionRefused);
+                }
+                cm.dequeue(&quad);
+            } else if handshake_over {
+                cm.on_established(quad);
+            }
+            // The first bytes decide which queue it goes to
+            let routed =
+                !handshake_over && cm.unrouted.iter().any(|(q, _)| *q == quad) && cm.route(quad);
+
+            // TODO: compare before/after
+            drop(cmg);
+
+            if handshake_over || routed {
+                ih.pending_var.notify_all();
+            }
+
+            notify(ih, available);
+        }
+        None => {
+            if cm.shutting_down {
+                return tcp::send_rst_reply(nic, seg);
+            }
+            // Do we have a listener for this port?
+            let pending = match cm.pending.get_mut(&tcph.destination_port()) {
+                Some(pending) => pending,
+                // The port is closed (RFC 9293 S3.10.7.1)
+                None => return tcp::send_rst_reply(nic, seg),
+            };
+            if !tcph.syn() {
+                // Not a connection we know of, e.g. one that's long gone
+                return tcp::send_rst_reply(nic, seg);
+            }
+
+            // Sources that didn't knock don't even get to see the port
+            if let Some(gate) = cm.knock_gates.get(&tcph.destination_port()) {
+                if !gate.admits(quad.src.0, cm.config.clock.now()) {
+                    return Ok(());
+                }
+            }
+
+            // Let the listener shed load before any state is created
+            if let Some(admit) = cm.admission.get(&tcph.destination_port()) {
+                let info = SynInfo {
+                    src: SocketAddrV4::new(quad.src.0, quad.src.1),
+                    dst: SocketAddrV4::new(quad.dst.0, quad.dst.1),
+                    pending: pending.len(),
+                    half_open: cm.syn_queue.get(&quad.dst.1).map_or(0, |q| q.len()),
+                    connections: cm.shards.len(),
+                };
+
+                match admit(&info) {
+                    Admission::Accept => {}
+                    Admission::Drop => return Ok(()),
+                    Admission::Reset => {
+                        return tcp::send_rst_reply(nic, seg);
+                    }
+                }
+            }
+
+            // A single source can't take all the connections
+            if let Some(quota) = cm.config.ip_quota {
+                let held = cm.per_source.get(&quad.src.0).copied().unwrap_or(0);
+                if held >= quota.max_connections {
+                    cm.stats.quota_refused += 1;
+                    if quota.reset {
+                        return tcp::send_rst_reply(nic, seg);
+                    }
+                    return Ok(());
+                }
+            }
+
+            let options = cm
+                .stream_options
+                .get(&tcph.destination_port())
+                .copied()
+                .unwrap_or_default();
+            let processing = Stopwatch::start();
+            let mut os = entropy::OsEntropy;
+            let entropy: &mut dyn entropy::EntropySource = match &mut cm.entropy {
+                Some(source) => source.as_mut(),
+                None => &mut os,
+            };
+            let accepted = tcp::Connection::accept(nic, entropy, &cm.config, options, seg)?;
+            processing.record(&mut cm.stats.packet_path.on_packet);
+
+            if let Some(c) = accepted {
+                // Only handed out to accept() once established
+                if let Some(log) = cm.events.as_mut() {
+                    let after = events::Snapshot::of(&c);
+                    log.changes(quad.local(), quad.remote(), None, after);
+                }
+                cm.shards.insert(quad, c);
+                cm.syn_queue.entry(quad.dst.1).or_default().push_back(quad);
+                *cm.per_source.entry(quad.src.0).or_default() += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Wakes up whoever waits for what a segment made `available`
+fn notify(ih: &Handler, available: tcp::Available) {
+    if available.contains(tcp::Available::READ) {
+        ih.recv_var.notify_all();
+    }
+
+    if available.contains(tcp::Available::FLUSH) {
+        ih.flush_var.notify_all();
+    }
+
+    if available.contains(tcp::Available::WRITE) {
+        ih.send_var.notify_all();
+    }
+}
+
+/// Ports to accept connections on. Dropping it resets the connections
+/// established or in the handshake that weren't accepted yet, while the
+/// streams already accepted carry on.
+/// # Examples
+/// ```no_run
+/// # use std::io::{self, Read};
+/// # fn main() -> io::Result<()> {
+/// let mut iface = tcp_rust::Interface::new()?;
+/// let mut listener = iface.bind(80)?;
+/// loop {
+///     let mut request = String::new();
+///     listener.accept()?.read_to_string(&mut request)?;
+/// }
+/// # }
+/// ```
+pub struct TcpListener {
+    /// Bound ports, sorted
+    ports: Vec<u16>,
+    /// Port to look at first on the next accept, so that no port starves
+    next: usize,
+    /// Which of the routes of the ports it accepts from, see
+    /// [`TcpListener::route`]. The listener that bound them if `None`.
+    route: Option<u64>,
+    ih: InterfaceHandle,
+}
+
+impl TcpListener {
+    /// Ports this listener accepts connections on
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+
+    /// Installs a policy that gets to inspect every SYN arriving on this
+    /// listener's ports and decide whether the handshake goes on, so load
+    /// can be shed before a connection is even created.
+    pub fn set_admission_policy<F>(&self, policy: F)
+    where
+        F: Fn(&SynInfo) -> Admission + Send + Sync + 'static,
+    {
+        let policy: AdmissionPolicy = Arc::new(policy);
+        let mut cm = self.ih.manager.lock().unwrap();
+        for &port in &self.ports {
+            cm.admission.insert(port, policy.clone());
+        }
+    }
+
+    /// Removes the admission policy, accepting every SYN again.
+    pub fn clear_admission_policy(&self) {
+        let mut cm = self.ih.manager.lock().unwrap();
+        for port in &self.ports {
+            cm.admission.remove(port);
+        }
+    }
+
+    /// Hides the listener behind a port-knocking sequence: SYNs are only let
+    /// in from sources that first sent SYNs to `sequence`, in order, within
+    /// `window`. The gate then stays open for that source for `window`.
+    pub fn set_knock_sequence(&self, sequence: &[u16], window: Duration) -> io::Result<()> {
+        if sequence.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Empty knock sequence",
+            ));
+        }
+
+        let gate = filter::KnockGate::new(sequence.to_vec(), window);
+        let mut cm = self.ih.manager.lock().unwrap();
+        for &port in &self.ports {
+            cm.knock_gates.insert(port, gate.clone());
+        }
+        Ok(())
+    }
+
+    /// Sets the options every stream accepted from now on starts with,
+    /// instead of [`StreamOptions::default`]. The handshakes in progress
+    /// keep the previous ones.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::{device::Loopback, Interface, InterfaceConfig, StreamOptions};
+    /// # use std::{io, time::Duration};
+    /// # fn main() -> io::Result<()> {
+    /// # let (nic, _peer) = Loopback::pair()?;
+    /// let mut iface = Interface::with_device(nic, InterfaceConfig::default())?;
+    /// let listener = iface.bind(80)?;
+    /// listener.set_stream_options(StreamOptions {
+    ///     recv_buffer: 4096,
+    ///     keepalive: Some(Duration::from_secs(60)),
+    ///     ..Default::default()
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_stream_options(&self, options: StreamOptions) -> io::Result<()> {
+        options.validate()?;
+        let mut cm = self.ih.manager.lock().unwrap();
+        for &port in &self.ports {
+            cm.stream_options.insert(port, options);
+        }
+        Ok(())
+    }
+
+    /// [`Interface::serve`] on the ports of this listener, for one set up
+    /// before the first connection comes, e.g. with
+    /// [`TcpListener::set_stream_options`].
+    /// # Examples
+    /// ```no_run
+    /// # use std::io::{self, Write};
+    /// # use tcp_rust::{Interface, StreamOptions};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = Interface::new()?;
+    /// let listener = iface.bind(7)?;
+    /// listener.set_stream_options(StreamOptions {
+    ///     recv_buffer: 4096,
+    ///     ..Default::default()
+    /// })?;
+    /// let server = listener.serve(|stream| stream.write_all(b"hello\n"))?;
+    /// server.join()
+    /// # }
+    /// ```
+    pub fn serve<F>(self, handler: F) -> io::Result<Server>
+    where
+        F: Fn(&mut TcpStream) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let threads = self.ih.manager.lock().unwrap().config.serve_threads;
+        if threads == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "serve_threads must not be 0",
+            ));
+        }
+        Ok(Server::spawn(self, threads, handler))
+    }
+
+    /// Removes the port-knocking gate, admitting every source again.
+    pub fn clear_knock_sequence(&self) {
+        let mut cm = self.ih.manager.lock().unwrap();
+        for port in &self.ports {
+            cm.knock_gates.remove(port);
+        }
+    }
+
+    pub fn accept(&mut self) -> io::Result<TcpStream> {
+        let ih = self.ih.clone();
+        let mut cm = ih.manager.lock().unwrap();
+        loop {
+            if let Some(stream) = self.pop_pending(&mut cm)? {
+                return Ok(stream);
+            }
+            cm.link_ok()?;
+            cm = ih.pending_var.wait(cm).unwrap();
+        }
+    }
+
+    /// Like [`TcpListener::accept`], but gives up with
+    /// [`io::ErrorKind::TimedOut`] if no connection comes in within `timeout`.
+    pub fn accept_timeout(&mut self, timeout: Duration) -> io::Result<TcpStream> {
+        let deadline = Instant::now() + timeout;
+        let ih = self.ih.clone();
+        let mut cm = ih.manager.lock().unwrap();
+        loop {
+            if let Some(stream) = self.pop_pending(&mut cm)? {
+                return Ok(stream);
+            }
+
+            cm.link_ok()?;
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::ZERO {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "No connection to accept",
+                ));
+            }
+            cm = ih.pending_var.wait_timeout(cm, left).unwrap().0;
+        }
+    }
+
+    /// Accepts a pending connection if there is one, without blocking.
+    /// Connections are only handed out once their handshake is complete.
+    /// # Examples
+    /// ```no_run
+    /// # use std::{io, time::Duration};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// loop {
+    ///     match listener.try_accept()? {
+    ///         Some(stream) => drop(stream),
+    ///         // Free to do something else in the meantime
+    ///         None => std::thread::sleep(Duration::from_millis(10)),
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn try_accept(&mut self) -> io::Result<Option<TcpStream>> {
+        let ih = self.ih.clone();
+        let mut cm = ih.manager.lock().unwrap();
+        self.pop_pending(&mut cm)
+    }
+
+    /// Splits off an accept queue for the connections whose first `peek`
+    /// bytes `predicate` accepts, e.g. to serve several protocols on a port
+    /// by their magic bytes. Connections of the ports are held after their
+    /// handshake until the routes can tell where they go: routes are tried
+    /// in the order they were added, and those that none takes stay with
+    /// this listener. A route sees fewer bytes if the peer closes its side
+    /// first or [`InterfaceConfig::route_timeout`] passes, e.g. for protocols
+    /// where the server speaks first.
+    ///
+    /// The returned listener accepts from the new queue only. Policies set
+    /// through it apply to the whole port, and dropping it resets the
+    /// connections still in its queue.
+    /// # Examples
+    /// ```no_run
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// // HTTP on one side, whatever else comes in on the other
+    /// let mut http = listener.route(4, |first| first == b"GET ")?;
+    /// let web = http.accept()?;
+    /// let other = listener.accept()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn route<F>(&self, peek: usize, predicate: F) -> io::Result<TcpListener>
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        if self.route.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Routes are split off the listener that bound the ports",
+            ));
+        }
+        let mut cm = self.ih.manager.lock().unwrap();
+        let id = cm.next_route;
+        cm.next_route += 1;
+        cm.routes.push(Route {
+            id,
+            ports: self.ports.clone(),
+            peek,
+            predicate: Arc::new(predicate),
+            queue: VecDeque::new(),
+        });
+        Ok(TcpListener {
+            ports: self.ports.clone(),
+            next: 0,
+            route: Some(id),
+            ih: self.ih.clone(),
+        })
+    }
+
+    fn pop_pending(&mut self, cm: &mut ConnectionManager) -> io::Result<Option<TcpStream>> {
+        let quad = match self.route {
+            Some(id) => {
+                let route = cm.routes.iter_mut().find(|r| r.id == id);
+                let route = route.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotConnected, "Listener was dropped")
+                })?;
+                route.queue.pop_front()
+            }
+            None => {
+                let n = self.ports.len();
+                (0..n).find_map(|i| {
+                    let port = self.ports[(self.next + i) % n];
+                    let quad = cm.pending.get_mut(&port)?.pop_front()?;
+                    self.next = (self.next + i + 1) % n;
+                    Some(quad)
+                })
+            }
+        };
+        let quad = match quad {
+            Some(quad) => quad,
+            None => return Ok(None),
+        };
+
+        let wait = cm.shards.get(&quad).and_then(|mut c| c.on_accept());
+        if let Some(wait) = wait {
+            cm.stats.accept_wait.record(wait);
+        }
+
+        Ok(Some(TcpStream {
+            ih: self.ih.clone(),
+            quad,
+        }))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut cm = self.ih.manager.lock().unwrap();
+        // Nobody is left to accept these, nor the handshakes in progress
+        let mut orphans = Vec::new();
+        if let Some(id) = self.route {
+            if let Some(at) = cm.routes.iter().position(|r| r.id == id) {
+                orphans.extend(cm.routes.remove(at).queue);
+            }
+            drop(cm);
+            for quad in orphans {
+                let _ = reset(&self.ih, quad);
+            }
+            return;
+        }
+        let ports = &self.ports;
+        for route in cm.routes.iter_mut().filter(|r| r.ports == *ports) {
+            orphans.extend(route.queue.drain(..));
+        }
+        cm.routes.retain(|r| r.ports != *ports);
+        orphans.extend(
+            cm.unrouted
+                .iter()
+                .filter(|(q, _)| ports.contains(&q.dst.1))
+                .map(|(q, _)| *q),
+        );
+        cm.unrouted.retain(|(q, _)| !ports.contains(&q.dst.1));
+        for port in &self.ports {
+            orphans.extend(cm.pending.remove(port).unwrap_or_default());
+            orphans.extend(cm.syn_queue.remove(port).unwrap_or_default());
+            cm.admission.remove(port);
+            cm.knock_gates.remove(port);
+            cm.stream_options.remove(port);
+        }
+        drop(cm);
+
+        for quad in orphans {
+            let _ = reset(&self.ih, quad);
+        }
+    }
+}
+
+pub struct TcpStream {
+    quad: Quad,
+    ih: InterfaceHandle,
+}
+
+impl TcpStream {
+    /// Local end of the connection, one of the listener's ports
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.quad.dst.0, self.quad.dst.1)
+    }
+
+    /// Remote end of the connection
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.quad.src.0, self.quad.src.1)
+    }
+
+    /// Shuts down the read half, the write half or both:
+    /// - after [`Shutdown::Read`], `read` returns `Ok(0)` right away and
+    ///   whatever the peer still sends is handled as
+    ///   [`StreamOptions::read_shutdown`] says
+    /// - after [`Shutdown::Write`], a FIN is sent once the queued data is out
+    ///   and `write` fails with [`io::ErrorKind::BrokenPipe`]. Shutting it
+    ///   down again does nothing, but fails with
+    ///   [`io::ErrorKind::NotConnected`] once the connection is over.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use std::{io::{self, Read, Write}, net::Shutdown};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let mut stream = listener.accept()?;
+    /// stream.write_all(b"GET / HTTP/1.0\r\n\r\n")?;
+    /// // Done sending, the FIN goes out after the request
+    /// stream.shutdown(Shutdown::Write)?;
+    /// let mut response = Vec::new();
+    /// stream.read_to_end(&mut response)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut cmg = self.ih.manager.lock().unwrap();
+        let cm = &mut *cmg;
+        let lost = &cm.lost;
+        let mut c = cm
+            .shards
+            .get(&self.quad)
+            .ok_or_else(|| gone(lost, &self.quad))?;
+
+        if let Shutdown::Read | Shutdown::Both = how {
+            c.shutdown_read();
+        }
+
+        if let Shutdown::Write | Shutdown::Both = how {
+            let before = cm.events.as_ref().map(|_| events::Snapshot::of(&c));
+            c.close()?;
+            if let Some(log) = cm.events.as_mut() {
+                let after = events::Snapshot::of(&c);
+                log.changes(self.quad.local(), self.quad.remote(), before, after);
+            }
+            drop(c);
+            // The FIN goes out right away if nothing is queued before it
+            cm.schedule(&self.ih.wake, self.quad)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a snapshot of the connection's state and metrics.
+    /// # Examples
+    /// ```no_run
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let stream = listener.accept()?;
+    /// let info = stream.info()?;
+    /// println!("{:?} with a window of {}", info.state, info.peer_window);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// The rate the peer gets the data at, sampled by every ACK:
+    /// ```no_run
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// # let mut iface = tcp_rust::Interface::new()?;
+    /// # let mut listener = iface.bind(80)?;
+    /// # let stream = listener.accept()?;
+    /// if let Some(goodput) = stream.info()?.goodput {
+    ///     println!("{:.0} bytes/s", goodput);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn info(&self) -> io::Result<ConnectionInfo> {
+        let cm = self.ih.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        Ok(c.info())
+    }
+
+    /// Time left on the timers of the connection, e.g. to tell why nothing
+    /// is being sent right now
+    /// # Examples
+    /// ```no_run
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let stream = listener.accept()?;
+    /// if let Some(left) = stream.timers()?.retransmission {
+    ///     println!("retransmitting in {:?}", left);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn timers(&self) -> io::Result<TimerInfo> {
+        let cm = self.ih.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+        Ok(c.timers(&cm.config))
+    }
+
+    /// Options this stream runs with, see [`StreamOptions`]
+    pub fn options(&self) -> io::Result<StreamOptions> {
+        let cm = self.ih.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+        Ok(c.options)
+    }
+
+    /// Changes the options of this stream. A smaller receive buffer only
+    /// shrinks the window as the data already received gets read.
+    pub fn set_options(&self, options: StreamOptions) -> io::Result<()> {
+        options.validate()?;
+        let cm = self.ih.manager.lock().unwrap();
+        cm.connection(&self.quad)?.options = options;
+        drop(cm);
+        // Writers may have more room now
+        self.ih.send_var.notify_all();
+        Ok(())
+    }
+
+    /// Sends an empty ACK probe now, which the peer acknowledges, on the
+    /// application's own schedule rather than the keepalive timer's, e.g.
+    /// to keep a NAT mapping on the path from expiring.
+    /// # Examples
+    /// ```no_run
+    /// # use std::{io, thread, time::Duration};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let stream = listener.accept()?;
+    /// loop {
+    ///     thread::sleep(Duration::from_secs(20));
+    ///     stream.send_probe()?;
+    /// }
+    /// # }
+    /// ```
+    pub fn send_probe(&self) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        cm.connection(&self.quad)?.send_probe(&self.ih.nic)?;
+        stats::flush_sends(&mut cm.stats);
+        Ok(())
+    }
+
+    /// Has `hook` write and read its kind of option on every segment of
+    /// the connection from now on, the handshake being over already.
+    /// # Examples
+    /// ```no_run
+    /// # use tcp_rust::options::{OptionHook, OutgoingSegment};
+    /// # use std::io;
+    /// /// Tags every segment with experimental option 253
+    /// struct Tag;
+    ///
+    /// impl OptionHook for Tag {
+    ///     fn kind(&self) -> u8 {
+    ///         253
+    ///     }
+    ///     fn exid(&self) -> Option<u16> {
+    ///         Some(0xbeef)
+    ///     }
+    ///     fn on_send(&mut self, _: &OutgoingSegment) -> Option<Vec<u8>> {
+    ///         Some(b"tag".to_vec())
+    ///     }
+    ///     fn on_receive(&mut self, _: &[u8]) {}
+    /// }
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let stream = listener.accept()?;
+    /// stream.add_option_hook(Tag)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_option_hook<H: options::OptionHook + 'static>(&self, hook: H) -> io::Result<()> {
+        let cm = self.ih.manager.lock().unwrap();
+        let mut c = cm.connection(&self.quad)?;
+        c.option_hooks.push(Arc::new(Mutex::new(hook)));
+        Ok(())
+    }
+
+    /// Puts the stream in a group, e.g. one per tenant, for
+    /// [`Interface::group_stats`] and [`Interface::close_group`]. `None`
+    /// takes it out of its group.
+    pub fn set_tag(&self, tag: Option<&str>) -> io::Result<()> {
+        let cm = self.ih.manager.lock().unwrap();
+        let mut c = cm.connection(&self.quad)?;
+        c.tag = tag.map(String::from);
+        Ok(())
+    }
+
+    /// Makes the spans of this connection part of an application trace,
+    /// under the span `context` points to, see [`Interface::export_spans`].
+    #[cfg(feature = "otel")]
+    pub fn set_trace_context(&self, context: otel::TraceContext) -> io::Result<()> {
+        let cm = self.ih.manager.lock().unwrap();
+        let mut c = cm.connection(&self.quad)?;
+        c.lifecycle.set_context(context);
+        Ok(())
+    }
+
+    /// Hands out the congestion control samples taken so far, oldest first,
+    /// and forgets them. Nothing is sampled unless
+    /// [`InterfaceConfig::cc_sample_interval`] is set.
+    /// # Examples
+    /// ```no_run
+    /// # use tcp_rust::{Interface, InterfaceConfig};
+    /// # use std::{io, time::Duration};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = Interface::with_config(InterfaceConfig {
+    ///     cc_sample_interval: Some(Duration::from_millis(10)),
+    ///     ..Default::default()
+    /// })?;
+    /// let mut listener = iface.bind(80)?;
+    /// let stream = listener.accept()?;
+    /// for sample in stream.take_cc_samples()? {
+    ///     println!("{:?}: cwnd {}", sample.at, sample.cwnd);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn take_cc_samples(&self) -> io::Result<Vec<stats::CcSample>> {
+        let cm = self.ih.manager.lock().unwrap();
+        let mut c = cm.connection(&self.quad)?;
+        Ok(c.cc_samples.drain(..).collect())
+    }
+
+    /// Sends what the windows allow of the queued data right away, instead
+    /// of at the next tick. Nothing is sent while the link is down.
+    /// # Examples
+    /// ```no_run
+    /// # use std::io::{self, Write};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let mut stream = listener.accept()?;
+    /// stream.write_all(b"ping")?;
+    /// stream.kick()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn kick(&self) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        cm.link_ok()?;
+        cm.transmit(&self.ih.nic, &self.quad)
+    }
+
+    /// Stops the connection where it is, e.g. to look at it in a debugger:
+    /// nothing is sent and none of its timers run until [`Self::thaw`].
+    /// Segments of the peer aren't taken in meanwhile, RSTs included: the
+    /// ones taking sequence numbers get an ACK of what came before.
+    /// # Examples
+    /// ```no_run
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let stream = listener.accept()?;
+    /// // Hold the connection still while its state is looked at
+    /// stream.freeze()?;
+    /// println!("{:?}", stream.info()?);
+    /// stream.thaw()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn freeze(&self) -> io::Result<()> {
+        let cm = self.ih.manager.lock().unwrap();
+        let mut c = cm.connection(&self.quad)?;
+        c.freeze();
+        Ok(())
+    }
+
+    /// Resumes a connection stopped by [`Self::freeze`]. Its timers pick up
+    /// where they were, so the time it spent frozen counts towards neither a
+    /// retransmission nor a timeout.
+    pub fn thaw(&self) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+        cm.connection(&self.quad)?.thaw(&self.ih.nic);
+        cm.transmit(&self.ih.nic, &self.quad)
+    }
+
+    /// Waits up to `timeout` for the handshake of the connection to be
+    /// over, failing with [`io::ErrorKind::TimedOut`] otherwise. Both
+    /// [`TcpListener::accept`] and [`Interface::connect`] only hand out
+    /// established connections, so it returns at once for theirs, e.g. for
+    /// a server to hold it the same way whichever side opened it.
+    pub fn wait_established(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut cm = self.ih.manager.lock().unwrap();
+        loop {
+            let c = cm.connection(&self.quad)?;
+            if !c.is_handshaking() {
+                if c.is_closed() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Handshake failed",
+                    ));
+                }
+                return Ok(());
+            }
+            drop(c);
+            cm.link_ok()?;
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::ZERO {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Handshake still in progress",
+                ));
+            }
+            cm = self.ih.pending_var.wait_timeout(cm, left).unwrap().0;
+        }
+    }
+
+    /// Like [`Write::write_all`], but gives up waiting for room in the send
+    /// queue once `deadline` passes. Returns how many bytes of `buf` were
+    /// queued: all of them, or fewer if the deadline passed, which isn't an
+    /// error. An error after some bytes were queued waits for the next
+    /// call, so the count is never lost.
+    pub fn write_all_deadline(&mut self, buf: &[u8], deadline: Instant) -> io::Result<usize> {
+        self.enqueue(buf, Some(deadline), true)
+    }
+
+    /// Like [`Read::read`], but gives up with [`io::ErrorKind::TimedOut`]
+    /// if nothing could be read within `timeout`. In [`ReadMode::Fill`],
+    /// returns what arrived by then.
+    pub fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.recv(buf, Some(Instant::now() + timeout))
+    }
+
+    /// Changes how long reads wait for data, leaving the other options
+    /// alone.
+    /// # Examples
+    /// ```no_run
+    /// # use tcp_rust::ReadMode;
+    /// # use std::io::{self, Read};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let mut stream = listener.accept()?;
+    /// // Frames of 8 bytes, however the segments split them
+    /// stream.set_read_mode(ReadMode::Fill)?;
+    /// let mut frame = [0u8; 8];
+    /// assert_eq!(stream.read(&mut frame)?, 8);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_read_mode(&self, mode: ReadMode) -> io::Result<()> {
+        let cm = self.ih.manager.lock().unwrap();
+        cm.connection(&self.quad)?.options.read_mode = mode;
+        Ok(())
+    }
+
+    /// Like [`Write::flush`], but gives up with [`io::ErrorKind::TimedOut`]
+    /// if the peer didn't acknowledge everything within `timeout`.
+    pub fn flush_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.wait_flushed(Some(Instant::now() + timeout))
+    }
+
+    /// Bytes written to the stream that the peer didn't acknowledge yet,
+    /// whether they were sent already or not.
+    pub fn bytes_unacked(&self) -> io::Result<usize> {
+        let cm = self.ih.manager.lock().unwrap();
+        let c = cm.connection(&self.quad)?;
+
+        Ok(c.unacked.len())
+    }
+
+    /// Blocks until there are no bytes in the local buffer, up to `deadline`
+    /// if any.
+    fn wait_flushed(&self, deadline: Option<Instant>) -> io::Result<()> {
+        let mut cm = self.ih.manager.lock().unwrap();
+
+        loop {
+            // Lookup the connection for the TCP Stream we're trying to read from
+            if cm.connection(&self.quad)?.unacked.is_empty() {
+                return Ok(());
+            }
+
+            cm.link_ok()?;
+            cm = match deadline {
+                None => self.ih.flush_var.wait(cm).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left == Duration::ZERO {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Data still unacknowledged",
+                        ));
+                    }
+                    self.ih.flush_var.wait_timeout(cm, left).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Queues `buf` for sending, blocking while the send queue is full, up to
+    /// `deadline` if any. Returns once some bytes are queued, or with `all`
+    /// once they all are. An empty `buf` returns `Ok(0)` and sends nothing.
+    fn enqueue(&self, buf: &[u8], deadline: Option<Instant>, all: bool) -> io::Result<usize> {
+        // Try to take the lock
+        let mut cm = self.ih.manager.lock().unwrap();
+        let shards = cm.shards.clone();
+        let mut nwritten = 0;
+
+        loop {
+            // Lookup the connection for the TCP Stream we're trying to write to
+            let mut c = match shards.get(&self.quad) {
+                Some(c) => c,
+                None => return partial(nwritten, gone(&cm.lost, &self.quad)),
+            };
+
+            if c.reset {
+                // Same as once the connection is reaped
+                return partial(nwritten, gone(&cm.lost, &self.quad));
+            }
+            if c.closed {
+                let e = io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Stream was shut down for writing",
+                );
+                return partial(nwritten, e);
+            }
+
+            let room = c.send_room();
+            let nwrite = cmp::min(buf.len() - nwritten, room);
+            let before = cm.events.as_ref().map(|_| events::Snapshot::of(&c));
+            c.unacked.extend_from(&buf[nwritten..nwritten + nwrite]);
+            if let Some(log) = cm.events.as_mut() {
+                let after = events::Snapshot::of(&c);
+                log.changes(self.quad.local(), self.quad.remote(), before, after);
+            }
+            nwritten += nwrite;
+            if room > 0 {
+                c.send_full = c.unacked.len() >= c.options.send_buffer;
+            }
+            if nwrite > 0 {
+                cm.schedule(&self.ih.wake, self.quad)?;
+            }
+
+            if nwritten == buf.len() || (nwritten > 0 && !all) {
+                return Ok(nwritten);
+            }
+
+            drop(c);
+            if let Err(e) = cm.link_ok() {
+                return partial(nwritten, e);
+            }
+            cm = match deadline {
+                None => self.ih.send_var.wait(cm).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left == Duration::ZERO {
+                        return Ok(nwritten);
+                    }
+                    self.ih.send_var.wait_timeout(cm, left).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Reads into `buf`, blocking as long as the [`ReadMode`] says, up to
+    /// `deadline` if any. Bytes already read are returned rather than an
+    /// error, which the next read gets.
+    fn recv(&self, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<usize> {
+        // Try to take the lock
+        let mut cm = self.ih.manager.lock().unwrap();
+        let shards = cm.shards.clone();
+        let mut n_read = 0;
+
+        loop {
+            // Lookup the connection for the TCP Stream we're trying to read from
+            let mut c = match shards.get(&self.quad) {
+                Some(c) => c,
+                None => return partial(n_read, gone(&cm.lost, &self.quad)),
+            };
+
+            if c.reset {
+                // Same as once the connection is reaped
+                return partial(n_read, gone(&cm.lost, &self.quad));
+            }
+
+            if c.read_shutdown || (c.is_recv_closed() && c.incoming.is_empty()) {
+                // No more data to read and no need to block
+                // because there won't be anymore
+                return Ok(n_read);
+            }
+
+            if !c.incoming.is_empty() {
+                // Read as much data as we can, then drop it, which makes
+                // room for the rest of a buffer larger than the window
+                let before = cm.events.as_ref().map(|_| events::Snapshot::of(&c));
+                let n = c.incoming.copy_to(&mut buf[n_read..]);
+                c.incoming.consume(n);
+                n_read += n;
+                let update = c.on_read(&self.ih.nic);
+                if let Some(log) = cm.events.as_mut() {
+                    let after = events::Snapshot::of(&c);
+                    log.changes(self.quad.local(), self.quad.remote(), before, after);
+                }
+                let done = n_read == buf.len() || c.options.read_mode == ReadMode::Any;
+                if update {
+                    if let Err(e) = cm.schedule(&self.ih.wake, self.quad) {
+                        return partial(n_read, e);
+                    }
+                }
+                if done {
+                    return Ok(n_read);
+                }
+            }
+
+            drop(c);
+            if let Err(e) = cm.link_ok() {
+                return partial(n_read, e);
+            }
+            cm = match deadline {
+                None => self.ih.recv_var.wait(cm).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left == Duration::ZERO {
+                        return partial(
+                            n_read,
+                            io::Error::new(io::ErrorKind::TimedOut, "No data to read"),
+                        );
+                    }
+                    self.ih.recv_var.wait_timeout(cm, left).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Aborts the connection right away with a RST instead of going through
+    /// the four-way close, discarding any data still buffered in either
+    /// direction, as a close with `SO_LINGER` set to 0 would: the connection
+    /// is forgotten at once, e.g. to shed a misbehaving client. Every later
+    /// operation on the stream fails.
+    pub fn reset(&self) -> io::Result<()> {
+        reset(&self.ih, self.quad)
+    }
+}
+
+/// Passes the link changes the kernel reports on, until the interface is gone
+fn watch_link(mut monitor: link::Monitor, ih: Weak<Handler>) -> io::Result<()> {
+    loop {
+        let up = monitor.next()?;
+        let ih = match ih.upgrade() {
+            Some(ih) => ih,
+            None => return Ok(()),
+        };
+        if let Some(up) = up {
+            set_link_state(&ih, up);
+        }
+    }
+}
+
+fn set_link_state(ih: &Handler, up: bool) {
+    let mut cmg = ih.manager.lock().unwrap();
+    let cm = &mut *cmg;
+    match (cm.link_down, up) {
+        (None, false) => cm.link_down = Some(cm.config.clock.now()),
+        (Some(since), true) => {
+            let down = cm.config.clock.since(since);
+            cm.shards.for_each(|_, c| c.on_link_up(down));
+            cm.link_down = None;
+            cm.link_down_notified = false;
+        }
+        _ => {}
+    }
+}
+
+/// Sends a RST and forgets the connection, waking up whoever waits on it
+fn reset(ih: &Handler, quad: Quad) -> io::Result<()> {
+    let mut cm = ih.manager.lock().unwrap();
+    let mut c = match cm.shards.remove(&quad) {
+        Some(c) => c,
+        None => return Err(gone(&cm.lost, &quad)),
+    };
+    cm.release_source(&quad);
+    cm.dequeue(&quad);
+
+    let res = c.reset(&ih.nic);
+    #[cfg(feature = "otel")]
+    cm.export_spans(&quad, &c);
+    if let Some(log) = cm.events.as_mut() {
+        log.emit(events::EventKind::Removed {
+            local: quad.local(),
+            remote: quad.remote(),
+        });
+    }
+    stats::flush_sends(&mut cm.stats);
+    drop(cm);
+
+    ih.recv_var.notify_all();
+    ih.flush_var.notify_all();
+    ih.send_var.notify_all();
+    res
+}
+
+impl Read for TcpStream {
+    /// Blocks until some data arrived. The peer can only send as much as
+    /// the receive queue has room for, reading reopens the window. Data
+    /// that came with the FIN is read before the end of the stream.
+    /// # Examples
+    /// ```no_run
+    /// # use std::io::{self, Read};
+    /// # fn main() -> io::Result<()> {
+    /// let mut iface = tcp_rust::Interface::new()?;
+    /// let mut listener = iface.bind(80)?;
+    /// let mut stream = listener.accept()?;
+    /// let mut buf = [0u8; 1024];
+    /// let n = stream.read(&mut buf)?;
+    /// println!("{:?}", &buf[..n]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(buf, None)
+    }
+}
+
+impl Write for TcpStream {
+    /// Blocks until there is room in the send queue for at least part of `buf`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.enqueue(buf, None, false)
+    }
+
+    // Block until there are no bytes in the local buffer
+    fn flush(&mut self) -> io::Result<()> {
+        self.wait_flushed(None)
+    }
+}
+
+impl Drop for TcpStream {
+    /// Closes the connection: our FIN goes out after the queued data, and
+    /// the connection is reaped once the peer ACKed it (RFC 9293 S3.10.4)
+    fn drop(&mut self) {
+        let mut cmg = self.ih.manager.lock().unwrap();
+        let cm = &mut *cmg;
+        cm.lost.remove(&self.quad);
+        let shards = cm.shards.clone();
+        let mut c = match shards.get(&self.quad) {
+            Some(c) => c,
+            None => return,
+        };
+        let before = cm.events.as_ref().map(|_| events::Snapshot::of(&c));
+        // Nothing is left to close in TIME-WAIT
+        if c.close().is_err() {
+            return;
+        }
+        if let Some(log) = cm.events.as_mut() {
+            let after = events::Snapshot::of(&c);
+            log.changes(self.quad.local(), self.quad.remote(), before, after);
+        }
+        drop(c);
+        // The wake fd only fails if the packet loop is gone, the timers
+        // of the connection would send the FIN anyway
+        let _ = cm.schedule(&self.ih.wake, self.quad);
     }
 }
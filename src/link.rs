@@ -0,0 +1,127 @@
+//! Carrier state of the TUN device as the kernel reports it on a routing
+//! netlink socket (rtnetlink(7)), see [`crate::Interface::set_link_state`].
+use std::{
+    convert::TryInto,
+    ffi::CString,
+    fs::File,
+    io::{self, Read},
+    os::unix::prelude::FromRawFd,
+    time::Duration,
+};
+
+/// `struct nlmsghdr`
+const NLMSG_HDR_LEN: usize = 16;
+/// `struct ifinfomsg`
+const IFINFO_LEN: usize = 16;
+/// Multicast group of the link notifications, from `<linux/rtnetlink.h>`
+const RTMGRP_LINK: u32 = 1;
+
+/// The link notifications of one device
+pub(crate) struct Monitor {
+    sock: File,
+    index: u32,
+}
+
+impl Monitor {
+    /// Subscribes to the notifications of device `name`. Waiting for the
+    /// next one gives up after `poll`, for the caller to check if anyone
+    /// still cares.
+    pub(crate) fn open(name: &str, poll: Duration) -> io::Result<Self> {
+        let name = CString::new(name)?;
+        // SAFETY: `name` is a valid C string for the duration of the call
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: plain socket creation, the descriptor is owned right away
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        let sock = unsafe { File::from_raw_fd(fd) };
+
+        // SAFETY: all zeroes is a valid `struct sockaddr_nl`
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = RTMGRP_LINK;
+        // SAFETY: `addr` is a `struct sockaddr_nl` of the given size
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let timeout = libc::timeval {
+            tv_sec: poll.as_secs() as libc::time_t,
+            tv_usec: poll.subsec_micros() as libc::suseconds_t,
+        };
+        // SAFETY: `timeout` is a `struct timeval` of the given size
+        let res = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { sock, index })
+    }
+
+    /// Waits for news about the device: whether it is up and has a carrier
+    /// now, `None` if nothing changed in time.
+    pub(crate) fn next(&mut self) -> io::Result<Option<bool>> {
+        let mut buf = [0u8; 8192];
+        let n = match self.sock.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(parse(&buf[..n], self.index))
+    }
+}
+
+/// Latest state of device `index` in a batch of netlink messages
+fn parse(mut msgs: &[u8], index: u32) -> Option<bool> {
+    let mut up = None;
+    while msgs.len() >= NLMSG_HDR_LEN {
+        let len = u32::from_ne_bytes(msgs[..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(msgs[4..6].try_into().unwrap());
+        if len < NLMSG_HDR_LEN || len > msgs.len() {
+            break;
+        }
+
+        let info = &msgs[NLMSG_HDR_LEN..len];
+        if info.len() >= IFINFO_LEN
+            && i32::from_ne_bytes(info[4..8].try_into().unwrap()) as u32 == index
+        {
+            let flags = u32::from_ne_bytes(info[8..12].try_into().unwrap());
+            let running = (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+            match kind {
+                libc::RTM_NEWLINK => up = Some(flags & running == running),
+                libc::RTM_DELLINK => up = Some(false),
+                _ => {}
+            }
+        }
+        // Messages are 4-byte aligned
+        msgs = &msgs[(len.div_ceil(4) * 4).min(msgs.len())..];
+    }
+    up
+}
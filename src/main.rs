@@ -1,49 +1,100 @@
 use std::{
+    fs::File,
     io::{self, Read, Write},
-    thread,
+    time::Duration,
 };
 
-use tcp_rust::Interface;
+use tcp_rust::{
+    trace::{self, Trace},
+    ConfigFile, Interface,
+};
 
 fn main() -> io::Result<()> {
+    if std::env::args().nth(1).as_deref() == Some("trace-diff") {
+        return trace_diff(std::env::args().skip(2));
+    }
     eprintln!("\u{001b}c");
-    let port = std::env::args()
-        .collect::<Vec<String>>()
-        .get(1)
-        .or(Some(&String::from("9000")))
-        .unwrap()
-        .parse::<u16>()
-        .unwrap();
-
-    let mut interface = Interface::new()?;
-    let mut listener = interface.bind(port)?;
-
-    while let Ok(mut stream) = listener.accept() {
-        thread::spawn(move || {
-            stream.write(b"Connected to TCP server.\n").unwrap();
-            stream.shutdown(std::net::Shutdown::Write).unwrap();
-            loop {
-                let mut buf = [0; 512];
-                let n = stream.read(&mut buf[..]).unwrap();
-
-                eprintln!("\x1b[1;33m[READ]\x1b[;m Read {} bytes of data.", n);
-
-                if n.eq(&0) {
-                    eprintln!("\x1b[1;32m[INFO]\x1b[;m Buffer is empty. No data left to read.");
-                    break;
-                }
-
-                stream.write(&buf[..n]).unwrap();
-
-                eprintln!(
-                    "\x1b[1;33m[READ]\x1b[;m {} bytes | UTF-8: {:?} | Raw: {:?}",
-                    n,
-                    std::str::from_utf8(&buf[..n]).unwrap(),
-                    &buf[..n],
-                );
+    let mut args = std::env::args().skip(1);
+    let mut port = String::from("9000");
+    let mut config = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                config = Some(args.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "usage: --config <path>")
+                })?)
+            }
+            _ => port = arg,
+        }
+    }
+    let port = port.parse::<u16>().unwrap();
+
+    let (mut interface, options) = match config {
+        Some(path) => {
+            let file = ConfigFile::from_path(path)?;
+            let interface = Interface::with_setup(&file.device, file.interface)?;
+            if let Some(path) = &file.event_log {
+                interface.log_events(File::create(path)?);
             }
-        });
+            (interface, Some(file.stream))
+        }
+        None => (Interface::new()?, None),
+    };
+    let listener = interface.bind(port)?;
+    if let Some(options) = options {
+        listener.set_stream_options(options)?;
     }
+    let server = listener.serve(|stream| {
+        stream.write_all(b"Connected to TCP server.\n")?;
+        loop {
+            let mut buf = [0; 512];
+            let n = stream.read(&mut buf[..])?;
+
+            eprintln!("\x1b[1;33m[READ]\x1b[;m Read {} bytes of data.", n);
+
+            if n.eq(&0) {
+                eprintln!("\x1b[1;32m[INFO]\x1b[;m Buffer is empty. No data left to read.");
+                return stream.shutdown(std::net::Shutdown::Write);
+            }
 
+            stream.write_all(&buf[..n])?;
+
+            eprintln!(
+                "\x1b[1;33m[READ]\x1b[;m {} bytes | UTF-8: {:?} | Raw: {:?}",
+                n,
+                String::from_utf8_lossy(&buf[..n]),
+                &buf[..n],
+            );
+        }
+    })?;
+
+    server.join()
+}
+
+/// `trace-diff <a.pcap> <b.pcap> [--interval-ms <ms>]`: compares two
+/// captures of the same workload
+fn trace_diff(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: trace-diff <a.pcap> <b.pcap> [--interval-ms <ms>]",
+        )
+    };
+    let mut paths = Vec::new();
+    let mut interval = Duration::from_millis(100);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interval-ms" => {
+                let ms = args.next().and_then(|ms| ms.parse().ok());
+                interval = Duration::from_millis(ms.filter(|&ms| ms > 0).ok_or_else(usage)?);
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        return Err(usage());
+    }
+    let (a, b) = (Trace::open(&paths[0])?, Trace::open(&paths[1])?);
+    print!("{}", trace::compare(&a, &b, interval));
     Ok(())
 }
@@ -0,0 +1,125 @@
+//! Copies of the packets going through an interface, see
+//! [`crate::Interface::mirror`].
+use std::{
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Mutex,
+    },
+};
+
+use crate::{
+    bpf::Program,
+    device::{Capabilities, Device},
+};
+
+/// Which way a mirrored packet went
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the device
+    Inbound,
+    /// Written to the device
+    Outbound,
+}
+
+/// Packet as it crossed the device. Inbound ones are mirrored before their
+/// checksum is verified, and with offloads the outbound ones can be larger
+/// than a segment, their TCP checksum left for the device to complete.
+#[derive(Clone, Debug)]
+pub struct MirroredPacket {
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// Device handing a copy of every packet to the mirror, if there is one
+pub(crate) struct Tap {
+    inner: Box<dyn Device>,
+    /// Whether `tx` is set, to skip the lock when nobody is watching
+    active: AtomicBool,
+    /// Whether copies are held back for now, see
+    /// [`crate::Interface::pause_mirror`]
+    paused: AtomicBool,
+    tx: Mutex<Option<Sender<MirroredPacket>>>,
+}
+
+impl Tap {
+    pub(crate) fn new(inner: Box<dyn Device>) -> Self {
+        Self {
+            inner,
+            active: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            tx: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn set(&self, tx: Option<Sender<MirroredPacket>>) {
+        let mut current = self.tx.lock().unwrap();
+        self.active.store(tx.is_some(), Ordering::Relaxed);
+        *current = tx;
+    }
+
+    /// Returns whether there is a mirror
+    pub(crate) fn pause(&self, paused: bool) -> bool {
+        self.paused.store(paused, Ordering::Relaxed);
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn copy(&self, direction: Direction, data: &[u8]) {
+        if !self.active.load(Ordering::Relaxed) || self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut tx = self.tx.lock().unwrap();
+        let packet = MirroredPacket {
+            direction,
+            data: data.to_vec(),
+        };
+        if tx.as_ref().is_some_and(|tx| tx.send(packet).is_err()) {
+            // The receiving end is gone
+            *tx = None;
+            self.active.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl Device for Tap {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.copy(Direction::Outbound, packet);
+        self.inner.send(packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.recv(buf)?;
+        self.copy(Direction::Inbound, &buf[..n]);
+        Ok(n)
+    }
+
+    fn gso_max_len(&self) -> Option<usize> {
+        self.inner.gso_max_len()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn attach_filter(&self, program: Option<&Program>) -> io::Result<()> {
+        self.inner.attach_filter(program)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn send_offloaded(&self, packet: &[u8], mss: Option<u16>) -> io::Result<usize> {
+        self.copy(Direction::Outbound, packet);
+        self.inner.send_offloaded(packet, mss)
+    }
+}
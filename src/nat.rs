@@ -0,0 +1,309 @@
+//! Userspace masquerading NAT.
+//!
+//! TCP flows started from the inside network get their source rewritten to
+//! [`NatConfig::public_addr`] and a port out of [`NatConfig::ports`], replies
+//! coming back on that port are rewritten back to the inside endpoint. Flows
+//! are forgotten once idle for too long, or shortly after they get closed
+//! (FIN both ways) or reset.
+use std::{
+    collections::HashMap,
+    io,
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use etherparse::{Ipv4Header, TcpHeader};
+
+use crate::device::Device;
+
+type Endpoint = (Ipv4Addr, u16);
+
+#[derive(Clone, Debug)]
+pub struct NatConfig {
+    /// Address the inside flows are masqueraded behind
+    pub public_addr: Ipv4Addr,
+    /// Ports handed out to translated flows
+    pub ports: RangeInclusive<u16>,
+    /// Flows that saw no packet for this long are forgotten
+    pub idle_timeout: Duration,
+    /// Flows closed or reset by the endpoints are forgotten after this long
+    pub closing_timeout: Duration,
+}
+
+impl NatConfig {
+    pub fn new(public_addr: Ipv4Addr) -> Self {
+        Self {
+            public_addr,
+            ports: 49152..=65535,
+            // Same as Linux's `nf_conntrack_tcp_timeout_established` and
+            // `nf_conntrack_tcp_timeout_time_wait`
+            idle_timeout: Duration::from_secs(5 * 24 * 60 * 60),
+            closing_timeout: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+/// Translated flow
+#[derive(Clone, Debug)]
+struct Flow {
+    inside: Endpoint,
+    remote: Endpoint,
+    last_seen: Instant,
+    fin_out: bool,
+    fin_in: bool,
+    reset: bool,
+}
+
+impl Flow {
+    fn is_closing(&self) -> bool {
+        self.reset || (self.fin_out && self.fin_in)
+    }
+}
+
+/// Port translation table
+pub struct Nat {
+    config: NatConfig,
+    /// (inside, remote) -> public port
+    outbound: HashMap<(Endpoint, Endpoint), u16>,
+    /// Public port -> flow
+    flows: HashMap<u16, Flow>,
+    /// Where to start looking for a free port
+    next_port: u16,
+}
+
+impl Nat {
+    pub fn new(config: NatConfig) -> Self {
+        Self {
+            next_port: *config.ports.start(),
+            config,
+            outbound: HashMap::new(),
+            flows: HashMap::new(),
+        }
+    }
+
+    /// Number of flows being translated
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Rewrites a packet leaving the inside network. Only a SYN can open a
+    /// new flow. Returns `false` when the packet can't be translated
+    /// and must be dropped.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::nat::{Nat, NatConfig};
+    /// # use std::{net::Ipv4Addr, time::Instant};
+    /// let mut nat = Nat::new(NatConfig::new(Ipv4Addr::new(10, 0, 0, 1)));
+    ///
+    /// let mut syn = Vec::new();
+    /// etherparse::PacketBuilder::ipv4([192, 168, 0, 2], [1, 1, 1, 1], 64)
+    ///     .tcp(4321, 80, 0, 1024)
+    ///     .syn()
+    ///     .write(&mut syn, &[])
+    ///     .unwrap();
+    /// assert!(nat.outbound(&mut syn, Instant::now()));
+    ///
+    /// let (ip, rest) = etherparse::Ipv4Header::read_from_slice(&syn).unwrap();
+    /// let (tcp, _) = etherparse::TcpHeader::read_from_slice(rest).unwrap();
+    /// assert_eq!(ip.source, [10, 0, 0, 1]);
+    ///
+    /// // The reply finds its way back to the inside endpoint
+    /// let mut syn_ack = Vec::new();
+    /// etherparse::PacketBuilder::ipv4([1, 1, 1, 1], [10, 0, 0, 1], 64)
+    ///     .tcp(80, tcp.source_port, 0, 1024)
+    ///     .syn()
+    ///     .ack(1)
+    ///     .write(&mut syn_ack, &[])
+    ///     .unwrap();
+    /// assert!(nat.inbound(&mut syn_ack, Instant::now()));
+    ///
+    /// let (ip, rest) = etherparse::Ipv4Header::read_from_slice(&syn_ack).unwrap();
+    /// let (tcp, _) = etherparse::TcpHeader::read_from_slice(rest).unwrap();
+    /// assert_eq!((ip.destination, tcp.destination_port), ([192, 168, 0, 2], 4321));
+    /// ```
+    pub fn outbound(&mut self, packet: &mut [u8], now: Instant) -> bool {
+        let (ip, tcp) = match parse(packet) {
+            Some(headers) => headers,
+            None => return false,
+        };
+
+        let inside = (Ipv4Addr::from(ip.source), tcp.source_port);
+        let remote = (Ipv4Addr::from(ip.destination), tcp.destination_port);
+
+        let port = match self.outbound.get(&(inside, remote)) {
+            Some(&port) => port,
+            None if tcp.syn && !tcp.ack => match self.allocate(inside, remote, now) {
+                Some(port) => port,
+                None => return false,
+            },
+            None => return false,
+        };
+
+        let flow = self.flows.get_mut(&port).unwrap();
+        flow.last_seen = now;
+        flow.fin_out |= tcp.fin;
+        flow.reset |= tcp.rst;
+
+        rewrite(packet, ip, tcp, Some((self.config.public_addr, port)), None)
+    }
+
+    /// Rewrites a packet coming back to the public address. Returns `false`
+    /// when it doesn't belong to any translated flow and must be dropped.
+    pub fn inbound(&mut self, packet: &mut [u8], now: Instant) -> bool {
+        let (ip, tcp) = match parse(packet) {
+            Some(headers) => headers,
+            None => return false,
+        };
+
+        if Ipv4Addr::from(ip.destination) != self.config.public_addr {
+            return false;
+        }
+
+        let remote = (Ipv4Addr::from(ip.source), tcp.source_port);
+        let flow = match self.flows.get_mut(&tcp.destination_port) {
+            // Only the remote end the flow was opened to can answer
+            Some(flow) if flow.remote == remote => flow,
+            _ => return false,
+        };
+
+        flow.last_seen = now;
+        flow.fin_in |= tcp.fin;
+        flow.reset |= tcp.rst;
+
+        let inside = flow.inside;
+        rewrite(packet, ip, tcp, None, Some(inside))
+    }
+
+    /// Forgets the flows that timed out.
+    pub fn expire(&mut self, now: Instant) {
+        let config = &self.config;
+        let outbound = &mut self.outbound;
+        self.flows.retain(|_, flow| {
+            let timeout = if flow.is_closing() {
+                config.closing_timeout
+            } else {
+                config.idle_timeout
+            };
+
+            let alive = now.saturating_duration_since(flow.last_seen) < timeout;
+            if !alive {
+                outbound.remove(&(flow.inside, flow.remote));
+            }
+            alive
+        });
+    }
+
+    /// Forwards packets between two devices until one of them fails,
+    /// translating whatever goes from `inside` to `outside` and back.
+    pub fn run(&mut self, inside: &dyn Device, outside: &dyn Device) -> io::Result<()> {
+        let mut buf = [0u8; 1504];
+        let mut last_expiry = Instant::now();
+
+        loop {
+            let mut pfd = [
+                nix::poll::PollFd::new(inside.as_raw_fd(), nix::poll::PollFlags::POLLIN),
+                nix::poll::PollFd::new(outside.as_raw_fd(), nix::poll::PollFlags::POLLIN),
+            ];
+            nix::poll::poll(&mut pfd[..], 1000).map_err(|e| e.as_errno().unwrap())?;
+
+            let now = Instant::now();
+            if now.duration_since(last_expiry) >= Duration::from_secs(1) {
+                self.expire(now);
+                last_expiry = now;
+            }
+
+            let ready = |pfd: &nix::poll::PollFd| {
+                pfd.revents()
+                    .is_some_and(|r| r.contains(nix::poll::PollFlags::POLLIN))
+            };
+
+            if ready(&pfd[0]) {
+                let n = inside.recv(&mut buf[..])?;
+                if self.outbound(&mut buf[..n], now) {
+                    outside.send(&buf[..n])?;
+                }
+            }
+
+            if ready(&pfd[1]) {
+                let n = outside.recv(&mut buf[..])?;
+                if self.inbound(&mut buf[..n], now) {
+                    inside.send(&buf[..n])?;
+                }
+            }
+        }
+    }
+
+    fn allocate(&mut self, inside: Endpoint, remote: Endpoint, now: Instant) -> Option<u16> {
+        let (first, last) = (*self.config.ports.start(), *self.config.ports.end());
+        let n = (last as u32 + 1).checked_sub(first as u32)?;
+
+        let port = (0..n)
+            .map(|i| (first as u32 + (self.next_port as u32 - first as u32 + i) % n) as u16)
+            .find(|port| !self.flows.contains_key(port))?;
+
+        self.next_port = if port == last { first } else { port + 1 };
+        self.outbound.insert((inside, remote), port);
+        self.flows.insert(
+            port,
+            Flow {
+                inside,
+                remote,
+                last_seen: now,
+                fin_out: false,
+                fin_in: false,
+                reset: false,
+            },
+        );
+        Some(port)
+    }
+}
+
+fn parse(packet: &[u8]) -> Option<(Ipv4Header, TcpHeader)> {
+    let (ip, rest) = Ipv4Header::read_from_slice(packet).ok()?;
+    if ip.protocol != etherparse::IpTrafficClass::Tcp as u8 {
+        return None;
+    }
+    let (tcp, _) = TcpHeader::read_from_slice(rest).ok()?;
+    Some((ip, tcp))
+}
+
+/// Replaces the source and/or destination of a packet, fixing the checksums.
+fn rewrite(
+    packet: &mut [u8],
+    mut ip: Ipv4Header,
+    mut tcp: TcpHeader,
+    src: Option<Endpoint>,
+    dst: Option<Endpoint>,
+) -> bool {
+    if let Some((addr, port)) = src {
+        ip.source = addr.octets();
+        tcp.source_port = port;
+    }
+    if let Some((addr, port)) = dst {
+        ip.destination = addr.octets();
+        tcp.destination_port = port;
+    }
+
+    let ip_len = ip.header_len();
+    let tcp_len = tcp.header_len() as usize;
+    let payload_end = ip_len + ip.payload_len as usize;
+    if payload_end > packet.len() || ip_len + tcp_len > payload_end {
+        return false;
+    }
+
+    tcp.checksum = match tcp.calc_checksum_ipv4(&ip, &packet[ip_len + tcp_len..payload_end]) {
+        Ok(checksum) => checksum,
+        Err(_) => return false,
+    };
+
+    // Writing the IPv4 header recomputes its checksum too
+    ip.write(&mut &mut packet[..ip_len]).is_ok()
+        && tcp
+            .write(&mut &mut packet[ip_len..ip_len + tcp_len])
+            .is_ok()
+}
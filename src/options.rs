@@ -0,0 +1,100 @@
+//! Hooks for TCP options the stack doesn't know about, e.g. the
+//! experimental kinds 253 and 254 (RFC 6994), see
+//! [`crate::TcpStream::add_option_hook`].
+use std::sync::{Arc, Mutex};
+
+/// Room for options in a TCP header
+const MAX_OPTIONS_LEN: usize = 40;
+
+/// Segment about to be sent, for a hook to decide what goes in its option
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutgoingSegment {
+    pub seq: u32,
+    pub ack: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+}
+
+/// Writes and reads one kind of option on a connection.
+pub trait OptionHook: Send {
+    fn kind(&self) -> u8;
+
+    /// ExID the option data starts with, to tell experiments sharing an
+    /// experimental kind apart. The hook only sees the data past it.
+    fn exid(&self) -> Option<u16> {
+        None
+    }
+
+    /// Data of the option to put in `segment`, without the kind, length
+    /// or ExID. `None` leaves the option out. Options that don't fit in
+    /// the header anymore are left out too.
+    fn on_send(&mut self, segment: &OutgoingSegment) -> Option<Vec<u8>>;
+
+    /// Called with the data of every option of this kind, and ExID if
+    /// any, the peer sends
+    fn on_receive(&mut self, data: &[u8]);
+}
+
+pub(crate) type Hooks = Vec<Arc<Mutex<dyn OptionHook>>>;
+
+/// Options of the hooks for `segment` after those of the stack, padded to a
+/// multiple of 4 bytes
+pub(crate) fn encode(hooks: &Hooks, segment: &OutgoingSegment, mut options: Vec<u8>) -> Vec<u8> {
+    for hook in hooks {
+        let mut hook = hook.lock().unwrap();
+        let data = match hook.on_send(segment) {
+            Some(data) => data,
+            None => continue,
+        };
+        let exid = hook.exid().map(u16::to_be_bytes);
+        let len = 2 + exid.map_or(0, |e| e.len()) + data.len();
+        if options.len() + len > MAX_OPTIONS_LEN {
+            continue;
+        }
+
+        options.extend([hook.kind(), len as u8]);
+        options.extend(exid.iter().flatten());
+        options.extend(&data);
+    }
+    // End of option list
+    options.resize(options.len().div_ceil(4) * 4, 0);
+    options
+}
+
+/// Hands the options of a received segment to the hooks of their kind
+pub(crate) fn dispatch(hooks: &Hooks, mut options: &[u8]) {
+    while let Some(&kind) = options.first() {
+        let len = match kind {
+            // End of option list
+            0 => break,
+            // No-operation
+            1 => 1,
+            _ => match options.get(1) {
+                Some(&len) if len >= 2 && len as usize <= options.len() => len as usize,
+                // Nothing past a malformed option can be trusted
+                _ => break,
+            },
+        };
+        let (option, rest) = options.split_at(len);
+        options = rest;
+        if len < 2 {
+            continue;
+        }
+
+        for hook in hooks {
+            let mut hook = hook.lock().unwrap();
+            if hook.kind() != kind {
+                continue;
+            }
+            let data = &option[2..];
+            match hook.exid() {
+                None => hook.on_receive(data),
+                Some(exid) if data.get(..2) == Some(&exid.to_be_bytes()[..]) => {
+                    hook.on_receive(&data[2..])
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! OpenTelemetry spans of the connection lifecycles, see
+//! [`crate::Interface::export_spans`].
+//!
+//! Every connection gets a `tcp.connection` span, from its SYN to its
+//! removal, with children for the handshake, the data transfer, each
+//! retransmission episode and the close. They're written once the
+//! connection is gone, as OTLP/JSON: one `ExportTraceServiceRequest` per
+//! line, the format the collector's `otlpjsonfile` receiver reads.
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Write as _,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::SocketAddrV4,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::json::{self, Object};
+
+/// Trace the spans of a connection belong to, and the span they hang from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub parent_span_id: Option<[u8; 8]>,
+}
+
+/// Retransmission timeouts in a row, until an ACK makes progress again
+#[derive(Clone, Debug)]
+struct Episode {
+    start: SystemTime,
+    end: Option<SystemTime>,
+    retransmits: u32,
+}
+
+/// What happened to a connection so far
+#[derive(Clone, Debug)]
+pub(crate) struct Lifecycle {
+    context: TraceContext,
+    syn: SystemTime,
+    established: Option<SystemTime>,
+    closing: Option<SystemTime>,
+    episodes: Vec<Episode>,
+    /// Payload bytes accepted from the peer
+    pub(crate) received: u64,
+}
+
+impl Lifecycle {
+    pub(crate) fn new() -> Self {
+        let mut trace_id = [0u8; 16];
+        trace_id[..8].copy_from_slice(&random_id());
+        trace_id[8..].copy_from_slice(&random_id());
+        Self {
+            context: TraceContext {
+                trace_id,
+                parent_span_id: None,
+            },
+            syn: SystemTime::now(),
+            established: None,
+            closing: None,
+            episodes: Vec::new(),
+            received: 0,
+        }
+    }
+
+    pub(crate) fn set_context(&mut self, context: TraceContext) {
+        self.context = context;
+    }
+
+    pub(crate) fn on_established(&mut self) {
+        self.established.get_or_insert_with(SystemTime::now);
+    }
+
+    pub(crate) fn on_retransmit(&mut self) {
+        match self.episodes.last_mut() {
+            Some(e) if e.end.is_none() => e.retransmits += 1,
+            _ => self.episodes.push(Episode {
+                start: SystemTime::now(),
+                end: None,
+                retransmits: 1,
+            }),
+        }
+    }
+
+    /// An ACK acknowledged new data
+    pub(crate) fn on_progress(&mut self) {
+        if let Some(e) = self.episodes.last_mut().filter(|e| e.end.is_none()) {
+            e.end = Some(SystemTime::now());
+        }
+    }
+
+    pub(crate) fn on_close(&mut self) {
+        self.closing.get_or_insert_with(SystemTime::now);
+    }
+}
+
+/// Connection the spans describe
+pub(crate) struct Ends {
+    pub(crate) local: SocketAddrV4,
+    pub(crate) remote: SocketAddrV4,
+    /// Bytes acknowledged by the peer
+    pub(crate) delivered: u64,
+}
+
+/// Writes the spans of a connection that just went away as one line
+pub(crate) fn export(out: &mut dyn io::Write, life: &Lifecycle, ends: &Ends) -> io::Result<()> {
+    let now = SystemTime::now();
+    let root = random_id();
+    let mut spans = Vec::new();
+
+    let attributes = [
+        ("net.host.ip", Value::Str(ends.local.ip().to_string())),
+        ("net.host.port", Value::Int(ends.local.port() as u64)),
+        ("net.peer.ip", Value::Str(ends.remote.ip().to_string())),
+        ("net.peer.port", Value::Int(ends.remote.port() as u64)),
+        ("tcp.bytes_sent", Value::Int(ends.delivered)),
+        ("tcp.bytes_received", Value::Int(life.received)),
+        (
+            "tcp.retransmission_episodes",
+            Value::Int(life.episodes.len() as u64),
+        ),
+    ];
+    spans.push(span(
+        &life.context,
+        root,
+        life.context.parent_span_id,
+        "tcp.connection",
+        life.syn,
+        now,
+        &attributes,
+    ));
+
+    let established = life.established.unwrap_or(now);
+    spans.push(span(
+        &life.context,
+        random_id(),
+        Some(root),
+        "tcp.handshake",
+        life.syn,
+        established,
+        &[],
+    ));
+    if let Some(at) = life.established {
+        spans.push(span(
+            &life.context,
+            random_id(),
+            Some(root),
+            "tcp.transfer",
+            at,
+            life.closing.unwrap_or(now),
+            &[],
+        ));
+    }
+    for e in &life.episodes {
+        spans.push(span(
+            &life.context,
+            random_id(),
+            Some(root),
+            "tcp.retransmission",
+            e.start,
+            e.end.unwrap_or(now),
+            &[("tcp.retransmits", Value::Int(e.retransmits as u64))],
+        ));
+    }
+    if let Some(at) = life.closing {
+        spans.push(span(
+            &life.context,
+            random_id(),
+            Some(root),
+            "tcp.close",
+            at,
+            now,
+            &[],
+        ));
+    }
+
+    let resource = Object::new().raw(
+        "attributes",
+        json::array([attribute(
+            "service.name",
+            &Value::Str(String::from("tcp_rust")),
+        )]),
+    );
+    let scope = Object::new()
+        .raw("scope", Object::new().str("name", "tcp_rust").finish())
+        .raw("spans", json::array(spans));
+    let resource_spans = Object::new()
+        .raw("resource", resource.finish())
+        .raw("scopeSpans", json::array([scope.finish()]));
+    let request = Object::new().raw("resourceSpans", json::array([resource_spans.finish()]));
+    writeln!(out, "{}", request.finish())
+}
+
+enum Value {
+    Str(String),
+    Int(u64),
+}
+
+fn attribute(key: &str, value: &Value) -> String {
+    let value = match value {
+        Value::Str(s) => Object::new().str("stringValue", s),
+        // 64 bit integers are strings in OTLP/JSON
+        Value::Int(i) => Object::new().str("intValue", i),
+    };
+    Object::new()
+        .str("key", key)
+        .raw("value", value.finish())
+        .finish()
+}
+
+fn span(
+    context: &TraceContext,
+    id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    name: &str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: &[(&str, Value)],
+) -> String {
+    let mut object = Object::new()
+        .str("traceId", hex(&context.trace_id))
+        .str("spanId", hex(&id));
+    if let Some(parent) = parent {
+        object = object.str("parentSpanId", hex(&parent));
+    }
+    object
+        .str("name", name)
+        .raw("kind", 1)
+        .str("startTimeUnixNano", unix_nanos(start))
+        .str("endTimeUnixNano", unix_nanos(end.max(start)))
+        .raw(
+            "attributes",
+            json::array(attributes.iter().map(|(k, v)| attribute(k, v))),
+        )
+        .finish()
+}
+
+fn unix_nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+/// Span ID from std's randomly keyed hasher, as good as needed for
+/// identifiers and without pulling in a random number generator
+fn random_id() -> [u8; 8] {
+    let mut h = RandomState::new().build_hasher();
+    h.write_u128(unix_nanos(SystemTime::now()));
+    h.finish().to_be_bytes()
+}
@@ -0,0 +1,213 @@
+//! Receivers that misbehave with their window, to see how the send path
+//! copes: the windows the peer advertises are shrunk or held back on their
+//! way in, following a schedule, e.g. "a zero window for 2s" or "window
+//! updates 200ms late".
+//!
+//! ```
+//! # use tcp_rust::{device::Loopback, Interface, InterfaceConfig};
+//! # use tcp_rust::peer_window::{Phase, WindowAction, WindowSchedule, WindowShaper};
+//! # use std::time::Duration;
+//! # fn main() -> std::io::Result<()> {
+//! # let (nic, _peer) = Loopback::pair()?;
+//! let schedule = WindowSchedule::new();
+//! // A zero window for 2s, then window updates 200ms late
+//! schedule.push(Phase::new(Duration::from_secs(2), WindowAction::Clamp(0)));
+//! schedule.push(Phase::new(Duration::from_secs(10), WindowAction::Delay(Duration::from_millis(200))));
+//! let nic = WindowShaper::new(nic, &schedule);
+//! let iface = Interface::with_device(nic, InterfaceConfig::default())?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddrV4,
+    os::unix::prelude::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bpf::Program,
+    device::{Capabilities, Device},
+    segment::InboundSegment,
+};
+
+/// What the window of the peer looks like to the stack during a [`Phase`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowAction {
+    /// As advertised
+    Pass,
+    /// No larger than this, `0` for a receiver that stops reading
+    Clamp(u16),
+    /// As advertised this long ago, so that updates arrive late
+    Delay(Duration),
+}
+
+/// How long an action lasts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Phase {
+    pub duration: Duration,
+    pub action: WindowAction,
+}
+
+impl Phase {
+    pub fn new(duration: Duration, action: WindowAction) -> Self {
+        Self { duration, action }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    phases: Vec<Phase>,
+    /// When the first phase started, with the first segment after
+    /// [`WindowSchedule::clear`]
+    started: Option<Instant>,
+    /// Windows each peer advertised lately, oldest first
+    history: HashMap<SocketAddrV4, VecDeque<(Instant, u16)>>,
+    rewritten: usize,
+}
+
+impl Inner {
+    fn action(&mut self, now: Instant) -> WindowAction {
+        let mut elapsed = now - *self.started.get_or_insert(now);
+        for phase in &self.phases {
+            if elapsed < phase.duration {
+                return phase.action;
+            }
+            elapsed -= phase.duration;
+        }
+        WindowAction::Pass
+    }
+
+    /// The window `peer` advertised `delay` ago, or the oldest one known
+    fn delayed(&mut self, peer: SocketAddrV4, window: u16, now: Instant, delay: Duration) -> u16 {
+        let history = self.history.entry(peer).or_default();
+        history.push_back((now, window));
+        // Keep the newest of the windows that are old enough
+        while history.len() > 1 && now - history[1].0 >= delay {
+            history.pop_front();
+        }
+        history[0].1
+    }
+}
+
+/// Phases of a [`WindowShaper`], run one after the other. Windows go
+/// through untouched once they're over. They can still change once the
+/// device is handed to the interface.
+#[derive(Clone, Default)]
+pub struct WindowSchedule(Arc<Mutex<Inner>>);
+
+impl WindowSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a phase after the others
+    pub fn push(&self, phase: Phase) {
+        self.0.lock().unwrap().phases.push(phase);
+    }
+
+    /// Removes every phase, the next ones start over from the next segment
+    pub fn clear(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.phases.clear();
+        inner.started = None;
+        inner.history.clear();
+    }
+
+    /// Segments whose window was changed so far
+    pub fn rewritten(&self) -> usize {
+        self.0.lock().unwrap().rewritten
+    }
+
+    fn apply(&self, packet: &mut [u8]) {
+        let (peer, window, at) = match InboundSegment::parse(packet) {
+            Some(seg) => (seg.src(), seg.tcph.window_size(), seg.iph.slice().len()),
+            None => return,
+        };
+        let now = Instant::now();
+        let mut inner = self.0.lock().unwrap();
+        let seen = match inner.action(now) {
+            WindowAction::Pass => window,
+            WindowAction::Clamp(max) => window.min(max),
+            WindowAction::Delay(delay) => inner.delayed(peer, window, now, delay),
+        };
+        if seen != window {
+            inner.rewritten += 1;
+            set_window(&mut packet[at..], window, seen);
+        }
+    }
+}
+
+/// Rewrites the window of a TCP header, updating its checksum on the way
+/// (RFC 1624 S3)
+fn set_window(tcp: &mut [u8], old: u16, new: u16) {
+    let checksum = u16::from_be_bytes([tcp[16], tcp[17]]);
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    tcp[14..16].copy_from_slice(&new.to_be_bytes());
+    tcp[16..18].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+/// Device applying a [`WindowSchedule`] to the packets it receives. What
+/// the stack sends goes through untouched, see [`crate::shaping`] for that.
+pub struct WindowShaper<D> {
+    inner: D,
+    schedule: WindowSchedule,
+}
+
+impl<D: Device> WindowShaper<D> {
+    pub fn new(inner: D, schedule: &WindowSchedule) -> Self {
+        Self {
+            inner,
+            schedule: schedule.clone(),
+        }
+    }
+}
+
+impl<D: AsRawFd> AsRawFd for WindowShaper<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<D: AsFd> AsFd for WindowShaper<D> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+impl<D: Device> Device for WindowShaper<D> {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.inner.send(packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.recv(buf)?;
+        self.schedule.apply(&mut buf[..n]);
+        Ok(n)
+    }
+
+    fn gso_max_len(&self) -> Option<usize> {
+        self.inner.gso_max_len()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn attach_filter(&self, program: Option<&Program>) -> io::Result<()> {
+        self.inner.attach_filter(program)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn send_offloaded(&self, packet: &[u8], mss: Option<u16>) -> io::Result<usize> {
+        self.inner.send_offloaded(packet, mss)
+    }
+}
@@ -0,0 +1,32 @@
+//! What most programs need, brought in with `use tcp_rust::prelude::*`.
+//!
+//! This, along with the re-exports at the crate root, is the surface meant
+//! to stay put from one release to the next. The connection internals of
+//! [`crate::tcp`] keep changing and are better left alone. Errors are all
+//! [`std::io::Error`]s, their kinds are documented with each method.
+//!
+//! ```
+//! use tcp_rust::prelude::*;
+//! # use tcp_rust::device::Loopback;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let (nic, _peer) = Loopback::pair()?;
+//! let mut iface = Interface::with_device(nic, InterfaceConfig::default())?;
+//! let listener: TcpListener = iface.bind(80)?;
+//! listener.set_stream_options(StreamOptions {
+//!     read_shutdown: ReadShutdown::Reset,
+//!     ..Default::default()
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+pub use std::{
+    io::{Read, Write},
+    net::Shutdown,
+};
+
+pub use crate::{
+    Admission, ConfigFile, ConnectionInfo, EventLoop, Feature, FeatureReport, Interface,
+    InterfaceConfig, InterfaceSet, InterfaceStats, PeerOptions, ReadMode, ReadShutdown, Server,
+    ShutdownHandle, State, StreamOptions, SynInfo, TcpListener, TcpStream, TimerInfo, TunSetup,
+};
@@ -0,0 +1,32 @@
+//! Letting go of root once the TUN device is set up, see
+//! [`crate::Interface::with_setup`].
+use std::io;
+
+/// Switches the process to `uid` and `gid`, without supplementary groups.
+/// Leaving root clears every capability, and `PR_SET_NO_NEW_PRIVS` keeps
+/// setuid binaries from giving them back.
+pub(crate) fn drop_to(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    // The groups go first, changing them needs the privileges about to go
+    // SAFETY: an empty group list, nothing is read from the pointer
+    if unsafe { libc::setgroups(0, std::ptr::null()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: plain syscalls without pointers
+    unsafe {
+        if libc::setgid(gid) < 0 || libc::setuid(uid) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // SAFETY: plain syscall without pointers
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Root privileges could be regained",
+        ));
+    }
+    Ok(())
+}
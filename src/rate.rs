@@ -0,0 +1,113 @@
+//! Delivery rate estimation (draft-cheng-iccrg-delivery-rate-estimation):
+//! every ACK samples the rate the peer got data at over the time the
+//! segment it acknowledged was in flight. The samples are smoothed into
+//! the goodput, and their max over the last rounds is the bandwidth.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Round trips the bandwidth is the max of the samples over, as BBR's
+/// filter
+const BW_WINDOW: u64 = 10;
+
+/// Gain of the smoothed goodput
+const GOODPUT_GAIN: f64 = 1.0 / 8.0;
+
+/// What a segment remembers of the deliveries when it's sent
+#[derive(Clone, Copy)]
+pub(crate) struct Stamp {
+    /// Bytes delivered to the peer
+    pub(crate) delivered: u64,
+    /// When the last of them was
+    pub(crate) delivered_at: Instant,
+    /// When the segment acknowledged last was sent
+    pub(crate) first_sent_at: Instant,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Estimator {
+    /// C.delivered_time, when the last ACK delivered something
+    delivered_at: Option<Instant>,
+    /// C.first_sent_time, send time of the segment acknowledged last
+    first_sent_at: Option<Instant>,
+    /// Round-trip counting as BBR does: a round ends once a segment sent
+    /// after its start gets acknowledged
+    round: u64,
+    next_round_delivered: u64,
+    latest: Option<f64>,
+    smoothed: Option<f64>,
+    /// Samples of the last rounds, for the max filter
+    samples: VecDeque<(u64, f64)>,
+}
+
+impl Estimator {
+    /// Stamps a segment sent at `now` with `delivered` bytes delivered so
+    /// far. The intervals start over once nothing was in flight.
+    pub(crate) fn on_send(&mut self, now: Instant, delivered: u64, idle: bool) -> Stamp {
+        if idle {
+            self.delivered_at = Some(now);
+            self.first_sent_at = Some(now);
+        }
+        Stamp {
+            delivered,
+            delivered_at: *self.delivered_at.get_or_insert(now),
+            first_sent_at: *self.first_sent_at.get_or_insert(now),
+        }
+    }
+
+    /// Takes an ACK that brought `delivered` up, `sent` being the segment
+    /// sent last of those it acknowledged. Returns the rate sample, in
+    /// bytes per second, unless its interval is too short to tell: below
+    /// `min_rtt` the ACKs came back compressed (S3.3).
+    pub(crate) fn on_ack(
+        &mut self,
+        now: Instant,
+        delivered: u64,
+        sent_at: Instant,
+        sent: Stamp,
+        min_rtt: Option<Duration>,
+    ) -> Option<f64> {
+        self.delivered_at = Some(now);
+        self.first_sent_at = Some(sent_at);
+        if sent.delivered >= self.next_round_delivered {
+            self.round += 1;
+            self.next_round_delivered = delivered;
+        }
+
+        // The slower of the sending and the acknowledging
+        let send_elapsed = sent_at.saturating_duration_since(sent.first_sent_at);
+        let ack_elapsed = now.saturating_duration_since(sent.delivered_at);
+        let interval = send_elapsed.max(ack_elapsed);
+        if interval.is_zero() || min_rtt.is_some_and(|min| interval < min) {
+            return None;
+        }
+        let rate = (delivered - sent.delivered) as f64 / interval.as_secs_f64();
+
+        self.latest = Some(rate);
+        self.smoothed = Some(
+            self.smoothed
+                .map_or(rate, |s| s + GOODPUT_GAIN * (rate - s)),
+        );
+        self.samples.push_back((self.round, rate));
+        let round = self.round;
+        self.samples
+            .retain(|&(r, _)| round.saturating_sub(r) < BW_WINDOW);
+        Some(rate)
+    }
+
+    /// Latest sample
+    pub(crate) fn latest(&self) -> Option<f64> {
+        self.latest
+    }
+
+    /// Samples smoothed
+    pub(crate) fn goodput(&self) -> Option<f64> {
+        self.smoothed
+    }
+
+    /// Max of the samples over the last rounds
+    pub(crate) fn bandwidth(&self) -> Option<f64> {
+        self.samples.iter().map(|&(_, bw)| bw).reduce(f64::max)
+    }
+}
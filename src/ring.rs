@@ -0,0 +1,104 @@
+//! Byte queue of a stream, the data received and not read yet or written
+//! and not acknowledged yet. Its bytes may wrap around the end of the
+//! storage, the helpers take care of the two halves.
+//!
+//! ```
+//! # use tcp_rust::ring::RingBuffer;
+//! // Every layout of up to 8 bytes, wrapped at every point
+//! for start in 0..8 {
+//!     for len in 0..=8 {
+//!         let data: Vec<u8> = (1..=len as u8).collect();
+//!         let mut ring = RingBuffer::with_capacity(8);
+//!         ring.extend_from(&vec![0; start]);
+//!         ring.consume(start);
+//!         ring.extend_from(&data);
+//!         assert_eq!(ring.len(), len);
+//!
+//!         for from in 0..=len {
+//!             for to in from..=len {
+//!                 let (head, tail) = ring.slices(from..to);
+//!                 assert_eq!([head, tail].concat(), &data[from..to]);
+//!             }
+//!         }
+//!         for n in 0..=len + 1 {
+//!             let mut buf = vec![0; n];
+//!             let copied = ring.copy_to(&mut buf);
+//!             assert_eq!(copied, n.min(len));
+//!             assert_eq!(&buf[..copied], &data[..copied]);
+//!         }
+//!     }
+//! }
+//! ```
+use std::{collections::VecDeque, ops::Range};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RingBuffer(VecDeque<u8>);
+
+impl RingBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self(VecDeque::with_capacity(capacity))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Appends `data` at the back
+    pub fn extend_from(&mut self, data: &[u8]) {
+        self.0.extend(data);
+    }
+
+    /// Drops the first `n` bytes, or all of them if there are fewer
+    pub fn consume(&mut self, n: usize) {
+        drop(self.0.drain(..n.min(self.0.len())));
+    }
+
+    /// Copies as many bytes from the front as fit in `buf`, leaving them
+    /// in the queue. Returns how many were copied.
+    pub fn copy_to(&self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.len());
+        let (head, tail) = self.slices(0..n);
+        buf[..head.len()].copy_from_slice(head);
+        buf[head.len()..n].copy_from_slice(tail);
+        n
+    }
+
+    /// The bytes of `range`, in order: the first slice, then the second.
+    /// Either can be empty.
+    ///
+    /// # Panics
+    ///
+    /// If `range` goes past the end of the queue.
+    pub fn slices(&self, range: Range<usize>) -> (&[u8], &[u8]) {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "Range out of the queue"
+        );
+        let (head, tail) = self.0.as_slices();
+        if range.end <= head.len() {
+            (&head[range], &[])
+        } else if range.start >= head.len() {
+            (&tail[range.start - head.len()..range.end - head.len()], &[])
+        } else {
+            (&head[range.start..], &tail[..range.end - head.len()])
+        }
+    }
+}
+
+impl From<Vec<u8>> for RingBuffer {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data.into())
+    }
+}
@@ -0,0 +1,268 @@
+//! Selective acknowledgments (RFC 2018): data arriving past a hole is held
+//! on to and reported in our ACKs, and the blocks the peer reports in its
+//! own let the holes alone be retransmitted (RFC 6675). Data arriving twice
+//! is reported too, in a D-SACK block (RFC 2883).
+use std::cmp;
+
+use crate::tcp::Wrap;
+
+/// Duplicate ACKs, or segments SACKed past a hole, that tell a loss from
+/// reordering (RFC 6675 S2)
+pub(crate) const DUP_THRESH: usize = 3;
+
+/// Most blocks an ACK carries, what fits in the options without timestamps
+pub(crate) const MAX_BLOCKS: usize = 4;
+
+/// Data received past RCV.NXT, waiting for the holes before it to fill
+#[derive(Clone, Default)]
+pub(crate) struct Reassembly {
+    /// Disjoint and never adjacent, in no particular order
+    ranges: Vec<Held>,
+    /// Bumped on every insert, to tell which range changed last
+    inserts: u64,
+    /// Data that arrived again, reported once in the first block (RFC 2883
+    /// S4)
+    duplicate: Option<(u32, u32)>,
+}
+
+#[derive(Clone)]
+struct Held {
+    seq: u32,
+    data: Vec<u8>,
+    /// [`Reassembly::inserts`] when it last grew
+    touched: u64,
+}
+
+impl Held {
+    fn end(&self) -> u32 {
+        self.seq.wrapping_add(self.data.len() as u32)
+    }
+}
+
+impl Reassembly {
+    /// Holds on to the part of `data`, starting at `seq` past RCV.NXT, that
+    /// fits in the window `[nxt, nxt+wnd)`
+    pub(crate) fn insert(&mut self, nxt: u32, wnd: u32, seq: u32, data: &[u8]) {
+        let offset = |seq: u32| seq.wrapping_sub(nxt) as usize;
+        let start = offset(seq);
+        let end = cmp::min(start + data.len(), wnd as usize);
+        if start >= end {
+            return;
+        }
+
+        // Merged with the ranges it overlaps or touches
+        let (touching, mut rest): (Vec<Held>, Vec<Held>) = self
+            .ranges
+            .drain(..)
+            .partition(|r| offset(r.seq) <= end && start <= offset(r.seq) + r.data.len());
+        // What it brings again of the data held
+        let overlap = touching.iter().find_map(|r| {
+            let lo = cmp::max(start, offset(r.seq));
+            let hi = cmp::min(end, offset(r.seq) + r.data.len());
+            (lo < hi).then(|| (nxt.wrapping_add(lo as u32), nxt.wrapping_add(hi as u32)))
+        });
+        if overlap.is_some() {
+            self.duplicate = overlap;
+        }
+        let lo = touching.iter().map(|r| offset(r.seq)).fold(start, cmp::min);
+        let hi = touching
+            .iter()
+            .map(|r| offset(r.seq) + r.data.len())
+            .fold(end, cmp::max);
+        let mut merged = vec![0; hi - lo];
+        for r in &touching {
+            let at = offset(r.seq) - lo;
+            merged[at..at + r.data.len()].copy_from_slice(&r.data);
+        }
+        merged[start - lo..end - lo].copy_from_slice(&data[..end - start]);
+
+        self.inserts += 1;
+        rest.push(Held {
+            seq: nxt.wrapping_add(lo as u32),
+            data: merged,
+            touched: self.inserts,
+        });
+        self.ranges = rest;
+    }
+
+    /// Takes what lines up with RCV.NXT once a hole got filled, and drops
+    /// what is behind it
+    pub(crate) fn pull(&mut self, nxt: u32) -> Vec<u8> {
+        let mut ready = Vec::new();
+        self.ranges.retain_mut(|r| {
+            if !nxt.wrapping_lt(r.end()) {
+                return false;
+            }
+            if !nxt.wrapping_lt(r.seq) {
+                ready = r.data.split_off(nxt.wrapping_sub(r.seq) as usize);
+                return false;
+            }
+            true
+        });
+        ready
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.ranges.clear();
+        self.duplicate = None;
+    }
+
+    /// Notes that `[seq, end)` arrived again, for the next ACK to report
+    pub(crate) fn duplicate(&mut self, seq: u32, end: u32) {
+        self.duplicate = Some((seq, end));
+    }
+
+    /// Edges of the ranges held, the one that changed last first (RFC 2018
+    /// S4), after the duplicate if one arrived since the last call. A
+    /// duplicate of held data comes right before the range holding it.
+    pub(crate) fn blocks(&mut self) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<&Held> = self.ranges.iter().collect();
+        ranges.sort_by_key(|r| cmp::Reverse(r.touched));
+        self.duplicate
+            .take()
+            .into_iter()
+            .chain(ranges.iter().map(|r| (r.seq, r.end())))
+            .collect()
+    }
+}
+
+/// Whether the first of the blocks of an ACK reports a duplicate rather
+/// than data held: it's below the ACK, or inside the second block (RFC 2883
+/// S4)
+pub(crate) fn is_dsack(ack: u32, blocks: &[(u32, u32)]) -> bool {
+    let (left, right) = match blocks.first() {
+        Some(&first) => first,
+        None => return false,
+    };
+    let inside = |&(l, r): &(u32, u32)| !left.wrapping_lt(l) && !r.wrapping_lt(right);
+    !ack.wrapping_lt(right) || blocks.get(1).is_some_and(inside)
+}
+
+/// What the peer's SACK blocks tell about the data past SND.UNA
+#[derive(Clone, Default)]
+pub(crate) struct Scoreboard {
+    /// Disjoint ranges the peer holds, in no particular order
+    sacked: Vec<(u32, u32)>,
+    pub(crate) recovery: Option<Recovery>,
+}
+
+/// Loss recovery under way
+#[derive(Clone, Copy)]
+pub(crate) struct Recovery {
+    /// RecoveryPoint, SND.NXT when it started: it's over once that's ACKed
+    pub(crate) point: u32,
+    /// HighRxt, how far the retransmission of the holes got
+    pub(crate) high_rxt: u32,
+    /// Started by the retransmission timer rather than by the SACK blocks
+    pub(crate) timeout: bool,
+}
+
+impl Scoreboard {
+    /// Takes in the blocks of an ACK once SND.UNA moved up to it. Blocks
+    /// outside of `[una, nxt]` are dropped.
+    pub(crate) fn update(&mut self, una: u32, nxt: u32, blocks: &[(u32, u32)]) {
+        let offset = |seq: u32| seq.wrapping_sub(una);
+        for &(left, right) in blocks {
+            if offset(left) < offset(right) && offset(right) <= offset(nxt) {
+                self.add(una, left, right);
+            }
+        }
+        self.sacked.retain_mut(|(left, right)| {
+            if offset(*right) == 0 || offset(*right) > offset(nxt) {
+                return false;
+            }
+            if offset(*left) > offset(*right) {
+                *left = una;
+            }
+            true
+        });
+        if let Some(recovery) = self.recovery {
+            if !una.wrapping_lt(recovery.point) {
+                self.recovery = None;
+            }
+        }
+    }
+
+    fn add(&mut self, una: u32, left: u32, right: u32) {
+        let offset = |seq: u32| seq.wrapping_sub(una);
+        let (mut lo, mut hi) = (left, right);
+        self.sacked.retain(|&(l, r)| {
+            let touching = offset(l) <= offset(hi) && offset(lo) <= offset(r);
+            if touching {
+                lo = cmp::min_by_key(lo, l, |&s| offset(s));
+                hi = cmp::max_by_key(hi, r, |&s| offset(s));
+            }
+            !touching
+        });
+        self.sacked.push((lo, hi));
+    }
+
+    /// Forgets the blocks, the peer may have dropped what they covered
+    /// (RFC 2018 S8)
+    pub(crate) fn on_timeout(&mut self, recovery: Recovery) {
+        self.sacked.clear();
+        self.recovery = Some(recovery);
+    }
+
+    /// Bytes the peer holds past SND.UNA
+    pub(crate) fn sacked(&self) -> usize {
+        self.sacked
+            .iter()
+            .map(|&(left, right)| right.wrapping_sub(left) as usize)
+            .sum()
+    }
+
+    /// Right edge of the highest block
+    fn high(&self, una: u32) -> Option<u32> {
+        self.sacked
+            .iter()
+            .map(|&(_, right)| right)
+            .max_by_key(|&right| right.wrapping_sub(una))
+    }
+
+    /// First hole at or past `from` and below the highest block, as its
+    /// edges
+    pub(crate) fn next_hole(&self, una: u32, from: u32) -> Option<(u32, u32)> {
+        let offset = |seq: u32| seq.wrapping_sub(una);
+        let mut sacked = self.sacked.clone();
+        sacked.sort_by_key(|&(left, _)| offset(left));
+        let mut at = cmp::max_by_key(una, from, |&s| offset(s));
+        for (left, right) in sacked {
+            if offset(at) < offset(left) {
+                return Some((at, left));
+            }
+            at = cmp::max_by_key(at, right, |&s| offset(s));
+        }
+        None
+    }
+
+    /// Bytes thought to be in the network out of the `in_flight` past
+    /// SND.UNA: every hole below the highest block counts as lost unless
+    /// it was retransmitted (RFC 6675 S4, `pipe`)
+    pub(crate) fn pipe(&self, una: u32, in_flight: usize) -> usize {
+        let high = match self.high(una) {
+            Some(high) => high,
+            None => return in_flight,
+        };
+        let past_high = in_flight.saturating_sub(high.wrapping_sub(una) as usize);
+        let retransmitted = self.recovery.map_or(0, |r| {
+            let upto = cmp::min_by_key(r.high_rxt, high, |&s| s.wrapping_sub(una));
+            let sacked_below: usize = self
+                .sacked
+                .iter()
+                .map(|&(left, right)| {
+                    let right = cmp::min_by_key(right, upto, |&s| s.wrapping_sub(una));
+                    right
+                        .wrapping_sub(una)
+                        .saturating_sub(left.wrapping_sub(una)) as usize
+                })
+                .sum();
+            (upto.wrapping_sub(una) as usize).saturating_sub(sacked_below)
+        });
+        past_high + retransmitted
+    }
+}
@@ -0,0 +1,133 @@
+//! Received TCP/IPv4 packets, parsed once, see [`InboundSegment`].
+use std::net::SocketAddrV4;
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::{Quad, TCP_PROTO_NO};
+
+/// Headers and payload of a received segment, borrowed from the packet.
+/// The packet loop parses it once and hands it down to the connection, and
+/// it can be made out of any packet, e.g. to see where a synthetic segment
+/// would go.
+///
+/// ```
+/// # use tcp_rust::segment::InboundSegment;
+/// # use etherparse::PacketBuilder;
+/// let mut packet = Vec::new();
+/// PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64)
+///     .tcp(4000, 80, 100, 1024)
+///     .syn()
+///     .write(&mut packet, b"hi")
+///     .unwrap();
+///
+/// let seg = InboundSegment::parse(&packet).unwrap();
+/// assert_eq!(seg.src(), "10.0.0.2:4000".parse().unwrap());
+/// assert_eq!(seg.dst(), "10.0.0.1:80".parse().unwrap());
+/// assert_eq!(seg.data, b"hi");
+/// // The SYN takes a sequence number too
+/// assert_eq!(seg.seg_len(), 3);
+/// assert_eq!(seg.headers_len() + seg.data.len(), packet.len());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InboundSegment<'a> {
+    pub iph: Ipv4HeaderSlice<'a>,
+    pub tcph: TcpHeaderSlice<'a>,
+    /// Everything after the TCP header, up to the IP total length: the
+    /// padding of a short frame isn't data
+    pub data: &'a [u8],
+}
+
+impl<'a> InboundSegment<'a> {
+    /// `None` for anything but TCP over IPv4 with headers that parse and
+    /// fit in the IP total length
+    pub fn parse(packet: &'a [u8]) -> Option<Self> {
+        let iph = Ipv4HeaderSlice::from_slice(packet).ok()?;
+        if iph.protocol() != TCP_PROTO_NO {
+            return None;
+        }
+        let end = (iph.total_len() as usize).min(packet.len());
+        let tcp = packet.get(iph.slice().len()..end)?;
+        let tcph = TcpHeaderSlice::from_slice(tcp).ok()?;
+        let data = &tcp[tcph.slice().len()..];
+        Some(Self { iph, tcph, data })
+    }
+
+    pub fn src(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.iph.source_addr(), self.tcph.source_port())
+    }
+
+    pub fn dst(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.iph.destination_addr(), self.tcph.destination_port())
+    }
+
+    pub(crate) fn quad(&self) -> Quad {
+        Quad {
+            src: (self.iph.source_addr(), self.tcph.source_port()),
+            dst: (self.iph.destination_addr(), self.tcph.destination_port()),
+        }
+    }
+
+    /// Bytes of the IP and TCP headers, options included
+    pub fn headers_len(&self) -> usize {
+        self.iph.slice().len() + self.tcph.slice().len()
+    }
+
+    /// SEG.LEN, the sequence numbers the segment takes: one per byte of
+    /// data, and one each for the SYN and the FIN
+    pub fn seg_len(&self) -> u32 {
+        self.data.len() as u32 + self.tcph.syn() as u32 + self.tcph.fin() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::Ipv4Addr};
+
+    use etherparse::PacketBuilder;
+
+    use super::*;
+
+    fn packet(src_port: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64)
+            .tcp(src_port, 80, 100, 1024)
+            .write(&mut packet, data)
+            .unwrap();
+        packet
+    }
+
+    #[test]
+    fn segments_demux_by_quad() {
+        let (a, b) = (packet(4000, b"a"), packet(4001, b"b"));
+        let (a, b) = (
+            InboundSegment::parse(&a).unwrap(),
+            InboundSegment::parse(&b).unwrap(),
+        );
+        let quad = a.quad();
+        assert_eq!(quad.src, (Ipv4Addr::new(10, 0, 0, 2), 4000));
+        assert_eq!(quad.dst, (Ipv4Addr::new(10, 0, 0, 1), 80));
+
+        let connections: HashMap<Quad, &str> = [(quad, "a"), (b.quad(), "b")].into();
+        let again = packet(4000, b"again");
+        let again = InboundSegment::parse(&again).unwrap();
+        assert_eq!(connections[&again.quad()], "a");
+        assert_eq!(connections[&b.quad()], "b");
+    }
+
+    #[test]
+    fn padding_past_the_total_length_is_not_data() {
+        let mut padded = packet(4000, b"hi");
+        padded.extend([0; 6]);
+        let seg = InboundSegment::parse(&padded).unwrap();
+        assert_eq!(seg.data, b"hi");
+        assert_eq!(seg.headers_len() + seg.data.len(), padded.len() - 6);
+    }
+
+    #[test]
+    fn total_length_short_of_the_headers_is_refused() {
+        let mut short = packet(4000, b"");
+        // Room for the IP header only
+        short[2..4].copy_from_slice(&20u16.to_be_bytes());
+        assert!(InboundSegment::parse(&short).is_none());
+    }
+}
@@ -0,0 +1,116 @@
+//! Pool of threads serving the connections of a listener, see
+//! [`crate::Interface::serve`].
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{TcpListener, TcpStream};
+
+/// Handle of a running server, stopping it when dropped.
+///
+/// It serves as many connections at once as it has threads. Up to as many
+/// more wait to be picked up by one, after that the acceptor stops taking
+/// them and they queue up in the listener, up to its backlog.
+///
+/// Its listener keeps the interface alive, so drop it before the
+/// [`crate::Interface`].
+pub struct Server {
+    stop: Arc<AtomicBool>,
+    acceptor: Option<thread::JoinHandle<io::Result<()>>>,
+    pool: Vec<thread::JoinHandle<()>>,
+}
+
+impl Server {
+    pub(crate) fn spawn<F>(mut listener: TcpListener, threads: usize, handler: F) -> Self
+    where
+        F: Fn(&mut TcpStream) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::sync_channel::<TcpStream>(threads);
+        let rx = Arc::new(Mutex::new(rx));
+        let handler = Arc::new(handler);
+
+        let pool = (0..threads)
+            .map(|_| {
+                let (rx, handler) = (rx.clone(), handler.clone());
+                thread::spawn(move || loop {
+                    // The lock is only held while waiting, not while serving
+                    let mut stream = match rx.lock().unwrap().recv() {
+                        Ok(stream) => stream,
+                        Err(_) => break,
+                    };
+                    // A failing or panicking handler only ends its own
+                    // connection, the thread goes on with the next one
+                    match panic::catch_unwind(AssertUnwindSafe(|| handler(&mut stream))) {
+                        // Dropping the stream sends our FIN
+                        Ok(Ok(())) => drop(stream),
+                        // The peer gets a RST rather than a clean close
+                        // of whatever the handler left half done
+                        _ => {
+                            let _ = stream.reset();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let acceptor = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    match listener.accept_timeout(Duration::from_millis(100)) {
+                        Ok(stream) => {
+                            if tx.send(stream).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            })
+        };
+
+        Self {
+            stop,
+            acceptor: Some(acceptor),
+            pool,
+        }
+    }
+
+    /// Serves until accepting fails, then waits for the connections being
+    /// served to be done with.
+    pub fn join(mut self) -> io::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let res = match self.acceptor.take() {
+            Some(jh) => jh.join().unwrap(),
+            None => Ok(()),
+        };
+        // The acceptor dropped the sending end, so the pool winds down
+        // once the connections it was handed are served
+        for jh in self.pool.drain(..) {
+            let _ = jh.join();
+        }
+        res
+    }
+}
+
+impl Drop for Server {
+    /// Stops accepting, and waits for the connections already accepted to
+    /// be served.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.finish();
+    }
+}
@@ -0,0 +1,333 @@
+//! Deliberate drops and delays of the packets the stack sends, to walk a
+//! test through a specific recovery path, e.g. "drop the 3rd data segment"
+//! or "delay every ACK by 50ms".
+//!
+//! ```
+//! # use tcp_rust::{device::Loopback, shaping::{Rules, Shaper}, Interface, InterfaceConfig};
+//! # fn main() -> std::io::Result<()> {
+//! # let (nic, _peer) = Loopback::pair()?;
+//! let rules = Rules::new();
+//! rules.push("drop syn #1".parse()?);
+//! rules.push("delay 50ms ack".parse()?);
+//! let iface = Interface::with_device(Shaper::new(nic, &rules), InterfaceConfig::default())?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fmt, io,
+    net::SocketAddrV4,
+    os::unix::prelude::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    bpf::Program,
+    device::{Capabilities, Device},
+};
+
+/// Which of the packets a [`Rule`] looks at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Segments {
+    All,
+    /// Segments carrying data
+    Data,
+    /// Segments with neither data nor SYN, FIN or RST
+    PureAcks,
+    Syn,
+    Fin,
+    Rst,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Drop,
+    /// Sent late, possibly after the packets that followed it
+    Delay(Duration),
+}
+
+/// What to do with some of the packets.
+///
+/// As text: `<drop | delay <ms>ms> <all | data | ack | syn | fin | rst>
+/// [#<n>] [to <addr:port>]`, e.g. `drop data #3` or `delay 50ms ack`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub segments: Segments,
+    /// Only the `n`th of the matching packets, counting from 1, rather
+    /// than every one of them
+    pub nth: Option<usize>,
+    /// Only the packets going to this peer
+    pub peer: Option<SocketAddrV4>,
+    pub action: Action,
+}
+
+impl Rule {
+    pub fn new(segments: Segments, action: Action) -> Self {
+        Self {
+            segments,
+            nth: None,
+            peer: None,
+            action,
+        }
+    }
+
+    pub fn nth(mut self, n: usize) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    pub fn to(mut self, peer: SocketAddrV4) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    fn matches(&self, packet: &[u8]) -> bool {
+        if self.segments == Segments::All && self.peer.is_none() {
+            return true;
+        }
+        let (iph, tcph) = match parse(packet) {
+            Some(headers) => headers,
+            None => return false,
+        };
+        if let Some(peer) = self.peer {
+            let dst = SocketAddrV4::new(iph.destination_addr(), tcph.destination_port());
+            if dst != peer {
+                return false;
+            }
+        }
+
+        let data = iph.payload_len() as usize > tcph.slice().len();
+        match self.segments {
+            Segments::All => true,
+            Segments::Data => data,
+            Segments::PureAcks => !(data || tcph.syn() || tcph.fin() || tcph.rst()),
+            Segments::Syn => tcph.syn(),
+            Segments::Fin => tcph.fin(),
+            Segments::Rst => tcph.rst(),
+        }
+    }
+}
+
+fn parse(
+    packet: &[u8],
+) -> Option<(
+    etherparse::Ipv4HeaderSlice<'_>,
+    etherparse::TcpHeaderSlice<'_>,
+)> {
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(packet).ok()?;
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).ok()?;
+    Some((iph, tcph))
+}
+
+impl FromStr for Rule {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid rule {:?}: {}", s, what),
+            )
+        };
+
+        let mut words = s.split_whitespace();
+        let action = match words.next() {
+            Some("drop") => Action::Drop,
+            Some("delay") => {
+                let ms = words
+                    .next()
+                    .and_then(|w| w.strip_suffix("ms"))
+                    .and_then(|ms| ms.parse().ok())
+                    .ok_or_else(|| invalid("expected a delay such as 50ms"))?;
+                Action::Delay(Duration::from_millis(ms))
+            }
+            _ => return Err(invalid("expected drop or delay")),
+        };
+        let segments = match words.next() {
+            Some("all") => Segments::All,
+            Some("data") => Segments::Data,
+            Some("ack") => Segments::PureAcks,
+            Some("syn") => Segments::Syn,
+            Some("fin") => Segments::Fin,
+            Some("rst") => Segments::Rst,
+            _ => return Err(invalid("expected all, data, ack, syn, fin or rst")),
+        };
+
+        let mut rule = Rule::new(segments, action);
+        while let Some(word) = words.next() {
+            if let Some(n) = word.strip_prefix('#') {
+                rule.nth = Some(n.parse().map_err(|_| invalid("bad packet number"))?);
+            } else if word == "to" {
+                let peer = words.next().and_then(|w| w.parse().ok());
+                rule.peer = Some(peer.ok_or_else(|| invalid("bad peer address"))?);
+            } else {
+                return Err(invalid("unexpected trailing words"));
+            }
+        }
+        Ok(rule)
+    }
+}
+
+/// The same text the rule is parsed from
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            Action::Drop => f.write_str("drop")?,
+            Action::Delay(d) => write!(f, "delay {}ms", d.as_millis())?,
+        }
+        let segments = match self.segments {
+            Segments::All => "all",
+            Segments::Data => "data",
+            Segments::PureAcks => "ack",
+            Segments::Syn => "syn",
+            Segments::Fin => "fin",
+            Segments::Rst => "rst",
+        };
+        write!(f, " {}", segments)?;
+        if let Some(n) = self.nth {
+            write!(f, " #{}", n)?;
+        }
+        if let Some(peer) = self.peer {
+            write!(f, " to {}", peer)?;
+        }
+        Ok(())
+    }
+}
+
+struct Entry {
+    rule: Rule,
+    /// Packets that matched so far
+    seen: usize,
+    /// Packets the rule was applied to
+    hits: usize,
+}
+
+/// Rules of a [`Shaper`], which can still change once the device is
+/// handed to the interface. The first rule that applies to a packet wins.
+#[derive(Clone, Default)]
+pub struct Rules(Arc<Mutex<Vec<Entry>>>);
+
+impl Rules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, rule: Rule) {
+        self.0.lock().unwrap().push(Entry {
+            rule,
+            seen: 0,
+            hits: 0,
+        });
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    /// Packets the `i`th rule dropped or delayed so far
+    pub fn hits(&self, i: usize) -> usize {
+        self.0.lock().unwrap().get(i).map_or(0, |e| e.hits)
+    }
+
+    /// The rules in order, with their hits
+    pub fn to_vec(&self) -> Vec<(Rule, usize)> {
+        let entries = self.0.lock().unwrap();
+        entries.iter().map(|e| (e.rule, e.hits)).collect()
+    }
+
+    fn action(&self, packet: &[u8]) -> Option<Action> {
+        let mut entries = self.0.lock().unwrap();
+        for e in entries.iter_mut() {
+            if !e.rule.matches(packet) {
+                continue;
+            }
+            e.seen += 1;
+            if e.rule.nth.is_none_or(|n| n == e.seen) {
+                e.hits += 1;
+                return Some(e.rule.action);
+            }
+        }
+        None
+    }
+}
+
+/// Device applying [`Rules`] to the packets sent through it. What it
+/// receives goes through untouched, the test peer already controls that.
+pub struct Shaper<D> {
+    inner: Arc<D>,
+    rules: Rules,
+}
+
+impl<D: Device + 'static> Shaper<D> {
+    pub fn new(inner: D, rules: &Rules) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            rules: rules.clone(),
+        }
+    }
+
+    fn shape(
+        &self,
+        packet: &[u8],
+        send: fn(&D, &[u8], Option<u16>) -> io::Result<usize>,
+        mss: Option<u16>,
+    ) -> io::Result<usize> {
+        match self.rules.action(packet) {
+            None => send(&self.inner, packet, mss),
+            Some(Action::Drop) => Ok(packet.len()),
+            Some(Action::Delay(delay)) => {
+                let (inner, late) = (self.inner.clone(), packet.to_vec());
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    // Nobody to tell if it fails, it's as good as dropped
+                    let _ = send(&inner, &late, mss);
+                });
+                Ok(packet.len())
+            }
+        }
+    }
+}
+
+impl<D: AsRawFd> AsRawFd for Shaper<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<D: AsFd> AsFd for Shaper<D> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+impl<D: Device + 'static> Device for Shaper<D> {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.shape(packet, |d, p, _| d.send(p), None)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(buf)
+    }
+
+    fn gso_max_len(&self) -> Option<usize> {
+        self.inner.gso_max_len()
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn attach_filter(&self, program: Option<&Program>) -> io::Result<()> {
+        self.inner.attach_filter(program)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn send_offloaded(&self, packet: &[u8], mss: Option<u16>) -> io::Result<usize> {
+        self.shape(packet, |d, p, mss| d.send_offloaded(p, mss), mss)
+    }
+}
@@ -0,0 +1,153 @@
+//! Connections split by quad into shards, each behind its own lock, so that
+//! the workers of [`crate::InterfaceConfig::workers`] run the segments of
+//! the connections they own side by side.
+//!
+//! The lock of a shard is only ever taken with the manager's held or with
+//! no other lock held, never the other way around, and one shard at a time
+//! but for [`Shards::lock_all`].
+use std::{
+    collections::hash_map::{DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::Ipv4Addr,
+    ops::{Deref, DerefMut},
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::{config::InterfaceConfig, tcp::Connection, Quad, QuadHashBuilder};
+
+pub(crate) struct Shard {
+    pub(crate) connections: HashMap<Quad, Connection, QuadHashBuilder>,
+    /// Copies of the manager's, for a worker to run segments without it
+    pub(crate) config: InterfaceConfig,
+    pub(crate) addresses: Option<Vec<Ipv4Addr>>,
+}
+
+pub(crate) struct Shards(Vec<Mutex<Shard>>);
+
+/// A connection, with its shard locked
+pub(crate) struct Locked<'a> {
+    shard: MutexGuard<'a, Shard>,
+    quad: Quad,
+}
+
+impl Deref for Locked<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.shard.connections[&self.quad]
+    }
+}
+
+impl DerefMut for Locked<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.shard.connections.get_mut(&self.quad).unwrap()
+    }
+}
+
+impl Default for Shards {
+    fn default() -> Self {
+        Self::new(1, &InterfaceConfig::default())
+    }
+}
+
+impl Shards {
+    pub(crate) fn new(n: usize, config: &InterfaceConfig) -> Self {
+        let n = n.max(1);
+        let shard = || {
+            Mutex::new(Shard {
+                connections: HashMap::with_capacity_and_hasher(
+                    config.expected_connections / n,
+                    QuadHashBuilder::new(config.connection_hasher),
+                ),
+                config: config.clone(),
+                addresses: (!config.addresses.is_empty()).then(|| config.addresses.clone()),
+            })
+        };
+        Self((0..n).map(|_| shard()).collect())
+    }
+
+    /// Shard owning `quad`, the worker segments of `quad` are handed to
+    pub(crate) fn index(&self, quad: &Quad) -> usize {
+        let mut hasher = DefaultHasher::new();
+        quad.hash(&mut hasher);
+        hasher.finish() as usize % self.0.len()
+    }
+
+    pub(crate) fn lock(&self, index: usize) -> MutexGuard<'_, Shard> {
+        self.0[index].lock().unwrap()
+    }
+
+    /// Every shard, in order
+    pub(crate) fn lock_all(&self) -> Vec<MutexGuard<'_, Shard>> {
+        self.0.iter().map(|s| s.lock().unwrap()).collect()
+    }
+
+    pub(crate) fn get(&self, quad: &Quad) -> Option<Locked<'_>> {
+        let shard = self.lock(self.index(quad));
+        shard
+            .connections
+            .contains_key(quad)
+            .then(|| Locked { shard, quad: *quad })
+    }
+
+    pub(crate) fn contains(&self, quad: &Quad) -> bool {
+        self.lock(self.index(quad)).connections.contains_key(quad)
+    }
+
+    pub(crate) fn insert(&self, quad: Quad, c: Connection) {
+        self.lock(self.index(&quad)).connections.insert(quad, c);
+    }
+
+    pub(crate) fn remove(&self, quad: &Quad) -> Option<Connection> {
+        self.lock(self.index(quad)).connections.remove(quad)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lock_all().iter().map(|s| s.connections.len()).sum()
+    }
+
+    /// Quads of the connections `f` picks
+    pub(crate) fn quads(&self, mut f: impl FnMut(&Connection) -> bool) -> Vec<Quad> {
+        self.lock_all()
+            .iter()
+            .flat_map(|s| s.connections.iter())
+            .filter(|(_, c)| f(c))
+            .map(|(quad, _)| *quad)
+            .collect()
+    }
+
+    /// Runs `f` on every connection, a shard at a time
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&Quad, &mut Connection)) {
+        for mut shard in self.lock_all() {
+            for (quad, c) in shard.connections.iter_mut() {
+                f(quad, c);
+            }
+        }
+    }
+
+    /// Puts new protocol parameters in place, rebuilding the maps if the
+    /// hash function changed
+    pub(crate) fn set_config(&self, config: &InterfaceConfig) {
+        for mut shard in self.lock_all() {
+            if config.connection_hasher != shard.config.connection_hasher {
+                let mut connections = HashMap::with_capacity_and_hasher(
+                    (config.expected_connections / self.0.len()).max(shard.connections.len()),
+                    QuadHashBuilder::new(config.connection_hasher),
+                );
+                connections.extend(shard.connections.drain());
+                shard.connections = connections;
+            } else {
+                let additional = (config.expected_connections / self.0.len())
+                    .saturating_sub(shard.connections.len());
+                shard.connections.reserve(additional);
+            }
+            shard.config = config.clone();
+        }
+    }
+
+    pub(crate) fn set_addresses(&self, addresses: &Option<Vec<Ipv4Addr>>) {
+        for mut shard in self.lock_all() {
+            shard.addresses = addresses.clone();
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Remote debugging service running on the stack itself.
+//!
+//! `stackd` listens on a port of an [`Interface`] and answers one command
+//! per line with one JSON object per line. The first line of every session
+//! must be the token the service was started with, anything else closes it.
+//!
+//! | Command              | Answer                                                   |
+//! |----------------------|----------------------------------------------------------|
+//! | `stats`              | the [`InterfaceStats`]                                   |
+//! | `connections`        | every connection, with its [`ConnectionInfo`]            |
+//! | `timers`             | every connection, with its [`TimerInfo`]                 |
+//! | `log <level>`        | the [`Level`] of the events now, `off`, `info` or `debug` |
+//! | `mirror <on \| off>` | the mirror resumed or paused, see [`Interface::pause_mirror`] |
+//! | `rules`              | the impairments and their hits, see `Stackd::shape`      |
+//! | `rule <rule>`        | the same, `<rule>` added last                            |
+//! | `rules clear`        | the same, none left                                      |
+//! | `quit`               | nothing, the session is closed                           |
+//!
+//! The `rule` commands need the `testing` feature, without it there are no
+//! rules to change.
+//!
+//! Changes made in a session outlive it, so a long run can be looked into
+//! more closely and left as it was without restarting it.
+use std::{
+    io::{self, Write},
+    net::{Shutdown, SocketAddrV4},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    events::Level,
+    json::{self, Object},
+    stats::Histogram,
+    ConnectionInfo, Interface, InterfaceHandle, InterfaceStats, Quad, TcpStream, TimerInfo,
+};
+
+/// Handle of a running service, stopping it when dropped.
+///
+/// Its listener keeps the interface alive, so drop it before the
+/// [`Interface`].
+pub struct Stackd {
+    ih: InterfaceHandle,
+    stop: Arc<AtomicBool>,
+    /// Session being served
+    session: Arc<Mutex<Option<Quad>>>,
+    /// Impairments the sessions can change
+    #[cfg(feature = "testing")]
+    rules: Arc<Mutex<Option<Rules>>>,
+    jh: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "testing")]
+use crate::shaping::Rules;
+
+/// Stands in for [`crate::shaping::Rules`], which only the `testing`
+/// feature has
+#[cfg(not(feature = "testing"))]
+#[derive(Clone)]
+enum Rules {}
+
+#[cfg(feature = "testing")]
+impl Stackd {
+    /// Lets the sessions list and change `rules`, those of a
+    /// [`crate::shaping::Shaper`] of the interface.
+    pub fn shape(&self, rules: &Rules) {
+        *self.rules.lock().unwrap() = Some(rules.clone());
+    }
+}
+
+impl Drop for Stackd {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wakes the session up if it's waiting for the client
+        if let Some(quad) = *self.session.lock().unwrap() {
+            let _ = crate::reset(&self.ih, quad);
+        }
+        if let Some(jh) = self.jh.take() {
+            jh.join().unwrap();
+        }
+    }
+}
+
+/// Starts serving `iface` on `port`. Sessions are served one at a time,
+/// and the one in progress is reset when the service stops. A client gets
+/// [`AUTH_TIMEOUT`] to send the token, so that one keeping quiet doesn't
+/// hold the others off. The token can't be empty.
+/// # Examples
+/// ```no_run
+/// # use tcp_rust::{stackd, Interface};
+/// # fn main() -> std::io::Result<()> {
+/// let mut iface = Interface::new()?;
+/// let _stackd = stackd::spawn(&mut iface, 7777, "s3cr3t")?;
+/// // $ printf 's3cr3t\nstats\n' | nc 192.168.0.2 7777
+/// // $ printf 's3cr3t\nlog info\n' | nc 192.168.0.2 7777
+/// # Ok(())
+/// # }
+/// ```
+pub fn spawn(iface: &mut Interface, port: u16, token: &str) -> io::Result<Stackd> {
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The token can't be empty",
+        ));
+    }
+    let mut listener = iface.bind(port)?;
+    let ih = iface.ih.as_ref().unwrap().clone();
+    let token = token.to_owned();
+    let stop = Arc::new(AtomicBool::new(false));
+    let current: Arc<Mutex<Option<Quad>>> = Default::default();
+    let rules: Arc<Mutex<Option<Rules>>> = Default::default();
+
+    let jh = {
+        let (ih, stop, current, rules) = (ih.clone(), stop.clone(), current.clone(), rules.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                match listener.accept_timeout(Duration::from_millis(100)) {
+                    Ok(stream) => {
+                        *current.lock().unwrap() = Some(stream.quad);
+                        if stop.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        // A misbehaving client only ends its own session
+                        let _ = session(&ih, &rules, stream, &token);
+                        *current.lock().unwrap() = None;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                    Err(_) => break,
+                }
+            }
+        })
+    };
+
+    Ok(Stackd {
+        ih,
+        stop,
+        session: current,
+        #[cfg(feature = "testing")]
+        rules,
+        jh: Some(jh),
+    })
+}
+
+fn session(
+    ih: &InterfaceHandle,
+    rules: &Mutex<Option<Rules>>,
+    mut stream: TcpStream,
+    token: &str,
+) -> io::Result<()> {
+    let mut pending = Vec::new();
+
+    let deadline = Instant::now() + AUTH_TIMEOUT;
+    let authenticated = match next_line(&mut stream, &mut pending, Some(deadline))? {
+        Some(line) => constant_time_eq(line.trim_end().as_bytes(), token.as_bytes()),
+        None => false,
+    };
+    if !authenticated {
+        stream.write_all(error("unauthorized").as_bytes())?;
+        stream.write_all(b"\n")?;
+        return stream.shutdown(Shutdown::Write);
+    }
+
+    while let Some(line) = next_line(&mut stream, &mut pending, None)? {
+        let mut words = line.split_whitespace();
+        let answer = match (words.next(), words.next(), words.next()) {
+            (Some("stats"), None, _) => stats_json(&ih.manager.lock().unwrap().stats),
+            (Some("connections"), None, _) => connections_json(&crate::connections(ih)),
+            (Some("timers"), None, _) => timers_json(ih),
+            (Some("log"), Some(level), None) => set_level(ih, level),
+            (Some("mirror"), Some(on), None) => match on {
+                "on" | "off" if ih.nic.pause(on == "off") => {
+                    Object::new().str("mirror", on).finish()
+                }
+                "on" | "off" => error("no mirror"),
+                _ => error("expected on or off"),
+            },
+            (Some("rule" | "rules"), ..) => match &*rules.lock().unwrap() {
+                Some(rules) => shape(rules, line.trim()),
+                None => error("no rules"),
+            },
+            (Some("quit"), None, _) => break,
+            _ => error("unknown command"),
+        };
+        stream.write_all(answer.as_bytes())?;
+        stream.write_all(b"\n")?;
+    }
+    stream.shutdown(Shutdown::Write)
+}
+
+fn error(what: &str) -> String {
+    Object::new().str("error", what).finish()
+}
+
+fn set_level(ih: &InterfaceHandle, name: &str) -> String {
+    let level = match name {
+        "off" => Level::Off,
+        "info" => Level::Info,
+        "debug" => Level::Debug,
+        _ => return error("expected off, info or debug"),
+    };
+    ih.manager.lock().unwrap().set_event_level(level);
+    Object::new().str("log", name).finish()
+}
+
+#[cfg(not(feature = "testing"))]
+fn shape(rules: &Rules, _: &str) -> String {
+    match *rules {}
+}
+
+/// Runs one of the `rules`, `rule <rule>` or `rules clear` commands
+#[cfg(feature = "testing")]
+fn shape(rules: &Rules, command: &str) -> String {
+    match command.split_once(char::is_whitespace) {
+        Some(("rule", rule)) => match rule.parse() {
+            Ok(rule) => rules.push(rule),
+            Err(e) => return error(&e.to_string()),
+        },
+        Some(("rules", "clear")) => rules.clear(),
+        None if command == "rules" => {}
+        _ => return error("unknown command"),
+    }
+
+    json::array(
+        rules
+            .to_vec()
+            .iter()
+            .map(|(rule, hits)| Object::new().str("rule", rule).raw("hits", hits).finish()),
+    )
+}
+
+/// Longest command line accepted
+const MAX_LINE: usize = 1024;
+
+/// How long a client has to send the token before its session is closed
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads up to the next newline, `pending` holding what was read past it,
+/// failing with [`io::ErrorKind::TimedOut`] once `deadline` passes.
+/// Returns `None` at the end of the stream.
+fn next_line(
+    stream: &mut TcpStream,
+    pending: &mut Vec<u8>,
+    deadline: Option<Instant>,
+) -> io::Result<Option<String>> {
+    let mut buf = [0u8; 512];
+    loop {
+        if let Some(at) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=at).collect();
+            return Ok(Some(String::from_utf8_lossy(&line[..at]).into_owned()));
+        }
+        if pending.len() > MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+        }
+
+        let n = stream.recv(&mut buf, deadline)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        pending.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// Compares without bailing out on the first difference, so the time taken
+/// doesn't tell how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn micros(d: Option<Duration>) -> String {
+    d.map_or(String::from("null"), |d| d.as_micros().to_string())
+}
+
+fn histogram_json(h: &Histogram) -> String {
+    Object::new()
+        .raw("count", h.count())
+        .raw("mean_us", micros(h.mean()))
+        .raw("p50_us", micros(h.quantile(0.5)))
+        .raw("p99_us", micros(h.quantile(0.99)))
+        .raw("max_us", h.max().as_micros())
+        .finish()
+}
+
+fn stats_json(stats: &InterfaceStats) -> String {
+    let c = &stats.coalesce;
+    let coalesce = Object::new()
+        .raw("segments", c.segments)
+        .raw("bytes", c.bytes)
+        .raw("super_packets", c.super_packets)
+        .raw("super_packet_bytes", c.super_packet_bytes)
+        .finish();
+    let m = &stats.malformed;
+    let malformed = Object::new()
+        .raw("ip_header", m.ip_header)
+        .raw("tcp_header", m.tcp_header)
+        .raw("reserved_bits", m.reserved_bits)
+        .raw("illegal_flags", m.illegal_flags)
+        .finish();
+    let reverse_path = Object::new()
+        .raw("wrong_destination", stats.reverse_path.wrong_destination)
+        .raw("spoofed_source", stats.reverse_path.spoofed_source)
+        .finish();
+    Object::new()
+        .raw(
+            "handshake_latency",
+            histogram_json(&stats.handshake_latency),
+        )
+        .raw("accept_wait", histogram_json(&stats.accept_wait))
+        .raw("coalesce", coalesce)
+        .raw("checksum_errors", stats.checksum_errors)
+        .raw("quota_refused", stats.quota_refused)
+        .raw("malformed", malformed)
+        .raw("reverse_path", reverse_path)
+        .finish()
+}
+
+fn timers_json(ih: &InterfaceHandle) -> String {
+    let mut timers: Vec<(Quad, TimerInfo)> = Vec::new();
+    {
+        let cm = ih.manager.lock().unwrap();
+        let config = &cm.config;
+        cm.shards
+            .for_each(|quad, c| timers.push((*quad, c.timers(config))));
+    }
+    json::array(timers.iter().map(|(quad, t)| {
+        Object::new()
+            .str("local", quad.local())
+            .str("remote", quad.remote())
+            .raw("retransmission_us", micros(t.retransmission))
+            .raw("zero_window", t.zero_window)
+            .raw("persist_us", micros(t.persist))
+            .raw("persist_probes", t.persist_probes)
+            .raw("keepalive_us", micros(t.keepalive))
+            .raw("keepalive_probes", t.keepalive_probes)
+            .raw("time_wait_us", micros(t.time_wait))
+            .raw("fin_wait2_us", micros(t.fin_wait2))
+            .raw("frozen", t.frozen)
+            .finish()
+    }))
+}
+
+fn connections_json(connections: &[(SocketAddrV4, SocketAddrV4, ConnectionInfo)]) -> String {
+    json::array(connections.iter().map(|(local, remote, info)| {
+        Object::new()
+            .str("local", local)
+            .str("remote", remote)
+            .str("state", format!("{:?}", info.state))
+            .raw("handshake_latency_us", micros(info.handshake_latency))
+            .raw("accept_wait_us", micros(info.accept_wait))
+            .raw("cwnd", info.cwnd)
+            .raw("peer_window", info.peer_window)
+            .finish()
+    }))
+}
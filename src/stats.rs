@@ -0,0 +1,332 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io,
+    time::{Duration, Instant},
+};
+
+use crate::device::Device;
+
+const BUCKETS: usize = 32;
+
+/// Latency histogram with power-of-two microsecond buckets.
+///
+/// Bucket `i` counts the samples below `2^i` microseconds (and at least
+/// `2^(i-1)`), the last one also takes everything that doesn't fit anywhere
+/// else. Cheap enough to be updated on the packet path.
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, sample: Duration) {
+        let us = sample.as_micros();
+        let i = (128 - us.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.sum += sample;
+        self.max = self.max.max(sample);
+    }
+
+    /// Number of recorded samples
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.sum / self.count as u32)
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Upper bound of the bucket holding the `q`-quantile (`0.0 <= q <= 1.0`).
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::stats::Histogram;
+    /// # use std::time::Duration;
+    /// let mut h = Histogram::default();
+    /// assert_eq!(h.quantile(0.5), None);
+    ///
+    /// for us in [3, 5, 6, 7, 900] {
+    ///     h.record(Duration::from_micros(us));
+    /// }
+    /// // 5, 6 and 7 all fall in the [4, 8) bucket
+    /// assert_eq!(h.quantile(0.5), Some(Duration::from_micros(8)));
+    /// assert_eq!(h.quantile(1.0), Some(Duration::from_micros(1024)));
+    /// ```
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (upper, n) in self.buckets() {
+            seen += n;
+            if seen >= rank {
+                return Some(upper);
+            }
+        }
+        unreachable!("rank is never above the sample count")
+    }
+
+    /// Adds all the samples of `other` to this histogram
+    pub fn merge(&mut self, other: &Histogram) {
+        for (mine, theirs) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *mine += theirs;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    /// Iterates over the `(upper bound, samples)` pairs of every bucket
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (Duration::from_micros(1 << i), *n))
+    }
+}
+
+/// Aggregated statistics of an [`crate::Interface`]
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceStats {
+    /// Time from the SYN to the connection becoming ESTABLISHED
+    pub handshake_latency: Histogram,
+    /// Time established connections spent waiting for `accept()`
+    pub accept_wait: Histogram,
+    /// Per-stage timings, only filled in with the `profiling` feature
+    pub packet_path: PacketPathStats,
+    /// Receive coalescing counters
+    pub coalesce: CoalesceStats,
+    /// Received segments dropped because of a wrong TCP checksum
+    pub checksum_errors: u64,
+    /// Packets with broken or odd headers
+    pub malformed: MalformedStats,
+    /// SYNs turned away as their source already held as many connections
+    /// as [`crate::InterfaceConfig::ip_quota`] allows
+    pub quota_refused: u64,
+    /// Packets dropped by [`crate::InterfaceConfig::reverse_path`]
+    pub reverse_path: ReversePathStats,
+}
+
+/// What was wrong with a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Malformed {
+    /// The IPv4 header couldn't be parsed
+    IpHeader,
+    /// The TCP header couldn't be parsed, e.g. its data offset is off
+    TcpHeader,
+    /// Some of the reserved bits of the TCP header are set
+    ReservedBits,
+    /// SYN with FIN or RST, or neither SYN, ACK nor RST
+    IllegalFlags,
+}
+
+/// Start of a malformed packet, for a look at what's sending them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MalformedSample {
+    pub reason: Malformed,
+    /// Up to the first [`MALFORMED_SAMPLE_LEN`] bytes of the packet
+    pub header: Vec<u8>,
+}
+
+/// Bytes kept of a malformed packet
+pub const MALFORMED_SAMPLE_LEN: usize = 64;
+/// Malformed packets kept, the oldest ones go first
+const MALFORMED_SAMPLES: usize = 16;
+
+/// Packets with broken or odd headers, see [`crate::ParsePolicy`]
+#[derive(Clone, Debug, Default)]
+pub struct MalformedStats {
+    pub ip_header: u64,
+    pub tcp_header: u64,
+    pub reserved_bits: u64,
+    pub illegal_flags: u64,
+    /// The last few of them, oldest first
+    pub samples: VecDeque<MalformedSample>,
+}
+
+impl MalformedStats {
+    pub(crate) fn record(&mut self, reason: Malformed, packet: &[u8]) {
+        *match reason {
+            Malformed::IpHeader => &mut self.ip_header,
+            Malformed::TcpHeader => &mut self.tcp_header,
+            Malformed::ReservedBits => &mut self.reserved_bits,
+            Malformed::IllegalFlags => &mut self.illegal_flags,
+        } += 1;
+
+        if self.samples.len() >= MALFORMED_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(MalformedSample {
+            reason,
+            header: packet[..packet.len().min(MALFORMED_SAMPLE_LEN)].to_vec(),
+        });
+    }
+}
+
+/// Packets whose addresses don't add up, see
+/// [`crate::InterfaceConfig::reverse_path`]
+/// # Examples
+/// ```
+/// # use tcp_rust::{device::Loopback, Interface, InterfaceConfig};
+/// # fn main() -> std::io::Result<()> {
+/// # let (nic, _peer) = Loopback::pair()?;
+/// let config = InterfaceConfig {
+///     addresses: vec![[10, 0, 0, 1].into()],
+///     reverse_path: true,
+///     ..Default::default()
+/// };
+/// let iface = Interface::with_device(nic, config)?;
+/// let stats = iface.stats().reverse_path;
+/// println!("{} spoofed, {} misdirected", stats.spoofed_source, stats.wrong_destination);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReversePathStats {
+    /// Not addressed to any of the interface's addresses
+    pub wrong_destination: u64,
+    /// Coming from one of the interface's own addresses
+    pub spoofed_source: u64,
+}
+
+/// Congestion control state of a connection at some point, see
+/// [`crate::InterfaceConfig::cc_sample_interval`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CcSample {
+    /// Time since the connection's SYN
+    pub at: Duration,
+    /// Congestion window, in bytes
+    pub cwnd: usize,
+    /// Slow start threshold, if slow start ended already
+    pub ssthresh: Option<usize>,
+    /// Smoothed round-trip time
+    pub srtt: Duration,
+    /// Bytes sent and not acknowledged yet
+    pub in_flight: usize,
+    /// Bytes acknowledged by the peer so far
+    pub delivered: u64,
+}
+
+/// Totals over the connections sharing a tag, see
+/// [`crate::Interface::group_stats`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GroupStats {
+    pub connections: usize,
+    /// Bytes acknowledged by the peers so far
+    pub delivered: u64,
+    /// Bytes written and not acknowledged yet
+    pub unacked: usize,
+    /// Bytes received and not read yet
+    pub unread: usize,
+}
+
+/// How much of the received data reached the connections in bursts
+#[derive(Clone, Debug, Default)]
+pub struct CoalesceStats {
+    /// Segments merged into the one before them by the stack
+    pub segments: u64,
+    /// Payload bytes of the segments merged by the stack
+    pub bytes: u64,
+    /// Packets larger than a segment handed over by the device
+    pub super_packets: u64,
+    /// Payload bytes of those packets
+    pub super_packet_bytes: u64,
+}
+
+/// Time spent in each stage of the packet path
+#[derive(Clone, Debug, Default)]
+pub struct PacketPathStats {
+    /// Parsing the IP and TCP headers
+    pub parse: Histogram,
+    /// Waiting for the connection manager lock
+    pub lock: Histogram,
+    /// Running the connection state machine, sends included
+    pub on_packet: Histogram,
+    /// Handing a single packet to the device
+    pub send: Histogram,
+}
+
+/// Times a stage of the packet path. Does nothing unless the `profiling`
+/// feature is enabled.
+#[derive(Clone, Copy)]
+pub(crate) struct Stopwatch(Option<Instant>);
+
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Self(if cfg!(feature = "profiling") {
+            Some(Instant::now())
+        } else {
+            None
+        })
+    }
+
+    /// Freezes the elapsed time, for when the histogram isn't reachable yet
+    pub(crate) fn stop(self) -> Lap {
+        Lap(self.0.map(|t| t.elapsed()))
+    }
+
+    pub(crate) fn record(self, histogram: &mut Histogram) {
+        self.stop().record(histogram);
+    }
+}
+
+/// Elapsed time of a stopped [`Stopwatch`]
+#[derive(Clone, Copy)]
+pub(crate) struct Lap(Option<Duration>);
+
+impl Lap {
+    pub(crate) fn record(self, histogram: &mut Histogram) {
+        if let Some(elapsed) = self.0 {
+            histogram.record(elapsed);
+        }
+    }
+}
+
+thread_local! {
+    /// Sends happen deep in the connection code, away from the stats. Each
+    /// thread keeps its own timings until whoever holds the manager lock
+    /// merges them with [`flush_sends`].
+    static SENDS: RefCell<Histogram> = RefCell::new(Histogram::default());
+}
+
+/// Sends a packet through the device, timing it with the `profiling` feature.
+/// When `offload` is set, the device is left to complete the checksum and,
+/// given an MSS, to segment the packet.
+pub(crate) fn timed_send(
+    nic: &dyn Device,
+    buf: &[u8],
+    offload: Option<Option<u16>>,
+) -> io::Result<usize> {
+    let sw = Stopwatch::start();
+    let n = match offload {
+        Some(mss) => nic.send_offloaded(buf, mss)?,
+        None => nic.send(buf)?,
+    };
+    SENDS.with(|h| sw.record(&mut h.borrow_mut()));
+    Ok(n)
+}
+
+/// Merges the send timings of the current thread into the interface stats
+pub(crate) fn flush_sends(stats: &mut InterfaceStats) {
+    if cfg!(feature = "profiling") {
+        SENDS.with(|h| {
+            stats
+                .packet_path
+                .send
+                .merge(&h.replace(Histogram::default()))
+        });
+    }
+}
@@ -1,10 +1,35 @@
 use bitflags::bitflags;
-use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use etherparse::{TcpHeaderSlice, TcpOptionElement};
 use std::{
+    cmp,
     collections::{BTreeMap, VecDeque},
-    io, time, u32, usize,
+    io,
+    sync::Arc,
+    time,
 };
 
+use std::net::SocketAddrV4;
+
+use crate::{
+    cc::{AckSample, Controller},
+    clock::Clock,
+    config::{InterfaceConfig, ReadShutdown, StreamOptions, SynPolicy, KEEPALIVE_PROBES},
+    device::{self, Capabilities, Device},
+    entropy::EntropySource,
+    options::{self, OutgoingSegment},
+    rate,
+    ring::RingBuffer,
+    sack::{self, Reassembly, Recovery, Scoreboard},
+    segment::InboundSegment,
+    stats,
+};
+
+/// Largest packet sent without segmentation offload, headers included
+pub(crate) const MAX_PACKET_LEN: usize = 1504;
+
+/// MSS of a peer whose SYN has no MSS option (RFC 9293 S3.7.1)
+const DEFAULT_MSS: u16 = 536;
+
 bitflags! {
     pub(crate) struct Available: u8 {
         const READ = 0b000000001;
@@ -14,13 +39,39 @@ bitflags! {
 }
 
 /// TCP connection states
-#[derive(Clone, Debug)]
+/// # Examples
+/// ```no_run
+/// # use tcp_rust::State;
+/// # use std::{io, net::Shutdown};
+/// # fn main() -> io::Result<()> {
+/// let mut iface = tcp_rust::Interface::new()?;
+/// let mut listener = iface.bind(80)?;
+/// let stream = listener.accept()?;
+/// stream.shutdown(Shutdown::Write)?;
+/// // Both ends closing at once go through CLOSING rather than FIN-WAIT-2
+/// if let State::FinWait2 | State::Closing = stream.info()?.state {
+///     println!("our FIN is out");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum State {
+    /// Our SYN is out, waiting for the peer's
+    SynSent,
     SynRecvd,
     Estab,
     FinWait1,
     FinWait2,
+    /// Both FINs crossed, waiting for ours to be ACKed (RFC 9293 S3.6)
+    Closing,
+    /// The peer closed its side, ours is still open
+    CloseWait,
+    /// Our FIN went out after the peer's, waiting for it to be ACKed
+    LastAck,
     TimeWait,
+    /// The connection is over and can be reaped
+    Closed,
 }
 // TCB - transmition control block
 #[derive(Clone)]
@@ -34,24 +85,365 @@ pub struct Connection {
     send: SendSequenceSpace,
     recv: ReceiveSequenceSpace,
     timers: Timers,
-    pub(crate) incoming: VecDeque<u8>,
-    pub(crate) unacked: VecDeque<u8>,
+    pub(crate) incoming: RingBuffer,
+    pub(crate) unacked: RingBuffer,
+    /// The send queue filled up and hasn't drained to the low watermark yet
+    pub(crate) send_full: bool,
 
     pub(crate) closed: bool,
     closed_at: Option<u32>,
+    /// Closed by a RST, ours or the peer's, rather than by the two FINs
+    pub(crate) reset: bool,
+    /// The application won't read anymore, see [`ReadShutdown`]
+    pub(crate) read_shutdown: bool,
+    /// The receive window grew enough to tell the peer, on the next
+    /// [`Self::transmit`]
+    window_update: bool,
+    /// The data waiting to be read went up to the high watermark, and not
+    /// back down to the low one yet
+    recv_pressured: bool,
+    /// When [`crate::TcpStream::freeze`] stopped the connection
+    frozen_at: Option<time::Instant>,
+
+    /// When the SYN was received
+    syn_at: time::Instant,
+    /// When the handshake completed
+    established_at: Option<time::Instant>,
+    /// When the application accepted the connection
+    accepted_at: Option<time::Instant>,
+
+    /// Congestion controller, see [`InterfaceConfig::congestion_control`]
+    cc: Controller,
+    /// Bytes acknowledged by the peer so far
+    delivered: u64,
+    /// Rate they got delivered at
+    rate: rate::Estimator,
+    /// Options the peer sent with its SYN
+    peer: PeerOptions,
+    /// Extensions we offered or would agree to
+    offered: PeerOptions,
+    /// Once both ends agreed to timestamps, or while our SYN offers them
+    timestamps: Option<Timestamps>,
+    /// Data past a hole, kept once both ends agreed to SACK
+    reassembly: Reassembly,
+    /// What the peer's SACK blocks say it holds
+    scoreboard: Scoreboard,
+    /// Buffer sizes and keep-alive, see [`StreamOptions`]
+    pub(crate) options: StreamOptions,
+    /// Group the application put the connection in, see
+    /// [`crate::TcpStream::set_tag`]
+    pub(crate) tag: Option<String>,
+    /// See [`crate::TcpStream::add_option_hook`]
+    pub(crate) option_hooks: options::Hooks,
+    /// What the timers go by, see [`InterfaceConfig::clock`]
+    clock: Arc<dyn Clock>,
+    /// Oldest first, see [`InterfaceConfig::cc_sample_interval`]
+    pub(crate) cc_samples: VecDeque<stats::CcSample>,
+    acks: AckStats,
+    /// Spans of the connection, see [`crate::Interface::export_spans`]
+    #[cfg(feature = "otel")]
+    pub(crate) lifecycle: crate::otel::Lifecycle,
+}
+
+/// TCP options offered by the peer in its SYN. Only the MSS, timestamps and
+/// SACK are in use: the segments we send are no larger than the MSS, and
+/// the other two are taken up once both ends offered them. Windows are
+/// never scaled.
+///
+/// ```no_run
+/// # use std::io;
+/// # fn main() -> io::Result<()> {
+/// let mut iface = tcp_rust::Interface::new()?;
+/// let mut listener = iface.bind(80)?;
+/// let stream = listener.accept()?;
+/// // The segments we send are no larger than this
+/// println!("{:?}", stream.info()?.peer_options.mss);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerOptions {
+    /// Largest segment the peer is willing to receive (RFC 9293 S3.7.1)
+    pub mss: Option<u16>,
+    /// Shift count of the peer's window (RFC 7323 S2)
+    pub window_scale: Option<u8>,
+    /// Whether the peer can do selective acknowledgments (RFC 2018)
+    pub sack_permitted: bool,
+    /// Whether the peer sent timestamps (RFC 7323 S3)
+    pub timestamps: bool,
+    /// Whether the SYN asked for ECN, with ECE and CWR set (RFC 3168 S6.1.1)
+    pub ecn: bool,
+}
+
+impl PeerOptions {
+    fn parse(tcph: &TcpHeaderSlice) -> Self {
+        let mut options = Self {
+            ecn: tcph.ece() && tcph.cwr(),
+            ..Self::default()
+        };
+        for option in tcph.options_iterator() {
+            match option {
+                Ok(TcpOptionElement::MaximumSegmentSize(mss)) => options.mss = Some(mss),
+                Ok(TcpOptionElement::WindowScale(shift)) => options.window_scale = Some(shift),
+                Ok(TcpOptionElement::SelectiveAcknowledgementPermitted) => {
+                    options.sack_permitted = true
+                }
+                Ok(TcpOptionElement::Timestamp(..)) => options.timestamps = true,
+                Ok(_) => {}
+                // Nothing past a malformed option can be trusted
+                Err(_) => break,
+            }
+        }
+        options
+    }
+}
+
+/// Extensions our SYN or SYN-ACK would agree to. Our MSS isn't negotiated,
+/// and ECN isn't implemented.
+fn offered(config: &InterfaceConfig) -> PeerOptions {
+    PeerOptions {
+        sack_permitted: config.sack,
+        timestamps: config.timestamps,
+        ..PeerOptions::default()
+    }
+}
+
+/// Edges of the SACK blocks of a segment (RFC 2018 S3)
+fn sack_blocks(tcph: &TcpHeaderSlice) -> Vec<(u32, u32)> {
+    tcph.options_iterator()
+        .map_while(Result::ok)
+        .find_map(|option| match option {
+            TcpOptionElement::SelectiveAcknowledgement(first, rest) => Some(
+                std::iter::once(first)
+                    .chain(rest.iter().flatten().copied())
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// TSval and TSecr of a segment (RFC 7323 S3.2)
+fn timestamp(tcph: &TcpHeaderSlice) -> Option<(u32, u32)> {
+    tcph.options_iterator()
+        .map_while(Result::ok)
+        .find_map(|option| match option {
+            TcpOptionElement::Timestamp(val, ecr) => Some((val, ecr)),
+            _ => None,
+        })
+}
+
+/// What became of a TCP extension on a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// Not enabled on our side, whatever the peer offered
+    Off,
+    /// Enabled on our side, but the peer doesn't support it
+    Downgraded,
+    /// Both ends agreed to it during the handshake
+    Active,
+}
+
+impl Feature {
+    fn negotiate(ours: bool, theirs: bool) -> Self {
+        match (ours, theirs) {
+            (false, _) => Self::Off,
+            (true, false) => Self::Downgraded,
+            (true, true) => Self::Active,
+        }
+    }
+}
+
+/// Which TCP extensions are actually in use on a connection
+///
+/// ```no_run
+/// # use tcp_rust::Feature;
+/// # use std::io;
+/// # fn main() -> io::Result<()> {
+/// let mut iface = tcp_rust::Interface::new()?;
+/// let mut listener = iface.bind(80)?;
+/// let stream = listener.accept()?;
+/// if stream.info()?.features.sack == Feature::Downgraded {
+///     println!("the peer doesn't do SACK");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Once SACK is agreed to, data past a hole is held on to and reported, and
+/// so are the duplicates below the ACK (RFC 2883).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeatureReport {
+    /// Explicit congestion notification (RFC 3168)
+    pub ecn: Feature,
+    /// Selective acknowledgments (RFC 2018)
+    pub sack: Feature,
+    /// Timestamps (RFC 7323 S3)
+    pub timestamps: Feature,
+    /// Window scaling (RFC 7323 S2)
+    pub window_scale: Feature,
+}
+
+impl FeatureReport {
+    fn negotiate(ours: &PeerOptions, theirs: &PeerOptions) -> Self {
+        Self {
+            ecn: Feature::negotiate(ours.ecn, theirs.ecn),
+            sack: Feature::negotiate(ours.sack_permitted, theirs.sack_permitted),
+            timestamps: Feature::negotiate(ours.timestamps, theirs.timestamps),
+            window_scale: Feature::negotiate(
+                ours.window_scale.is_some(),
+                theirs.window_scale.is_some(),
+            ),
+        }
+    }
+}
+
+/// Snapshot of a connection's state, see [`crate::TcpStream::info`]
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub state: State,
+    /// Time from the SYN to the connection becoming ESTABLISHED
+    pub handshake_latency: Option<time::Duration>,
+    /// Time the established connection waited in the accept queue
+    pub accept_wait: Option<time::Duration>,
+    /// Congestion window, in bytes
+    pub cwnd: usize,
+    /// Rate the segments are paced at, in bytes per second
+    pub pacing_rate: Option<f64>,
+    /// Last window advertised by the peer, in bytes
+    pub peer_window: u16,
+    /// Options the peer offered during the handshake
+    pub peer_options: PeerOptions,
+    /// Extensions in use, after the handshake's negotiation
+    pub features: FeatureReport,
+    /// Bytes acknowledged by the peer so far
+    pub delivered: u64,
+    /// Bytes per second the peer got data at over the round trip of the
+    /// segment acknowledged last: the latest sample of the delivery rate
+    pub delivery_rate: Option<f64>,
+    /// Delivery rate samples smoothed, in bytes per second. Retransmitted
+    /// data counts once, and while the application doesn't keep the
+    /// window full it measures the application rather than the path.
+    pub goodput: Option<f64>,
+    /// Highest delivery rate of the last 10 round trips, the estimate of
+    /// the path's bandwidth, in bytes per second
+    pub bandwidth: Option<f64>,
+    /// Group the connection belongs to, if any
+    pub tag: Option<String>,
+    /// ACKs received that didn't acknowledge anything new while data was
+    /// outstanding (RFC 5681 S2)
+    pub dup_acks: u64,
+    /// Retransmissions the original segments got acknowledged for, as the
+    /// ACK came back sooner than any round trip so far
+    pub spurious_retransmits: u64,
+    /// Segments the peer reported getting twice in a D-SACK block (RFC
+    /// 2883), whether retransmitted needlessly or duplicated on the way
+    pub dsacks: u64,
+    /// Reordering extent, in segments: the most duplicate ACKs seen before
+    /// an ACK made progress without anything being retransmitted
+    pub reordering: u32,
+}
+
+/// Time left on the timers of a connection, `None` for those that aren't
+/// running, see [`crate::TcpStream::timers`]. ACKs are never delayed, so
+/// there's no timer for them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimerInfo {
+    /// Until the oldest segment in flight, or the SYN, is sent again. Zero
+    /// if it's overdue and goes out on the next tick.
+    pub retransmission: Option<time::Duration>,
+    /// The peer's window is closed with data waiting
+    pub zero_window: bool,
+    /// Until the next probe of the peer's closed window, which backs off
+    /// from the retransmission timeout (RFC 9293 S3.8.6.1)
+    pub persist: Option<time::Duration>,
+    /// Window probes sent since the window closed
+    pub persist_probes: u32,
+    /// Until the next keep-alive probe of an idle connection
+    pub keepalive: Option<time::Duration>,
+    /// Keep-alive probes left unanswered so far
+    pub keepalive_probes: u32,
+    /// Until TIME-WAIT is over
+    pub time_wait: Option<time::Duration>,
+    /// Until FIN-WAIT-2 gives up on the peer's FIN
+    pub fin_wait2: Option<time::Duration>,
+    /// Nothing runs until the stream is thawed, see
+    /// [`crate::TcpStream::freeze`]
+    pub frozen: bool,
+}
+
+/// What the ACKs tell about duplicates, reordering and retransmissions
+#[derive(Clone, Default)]
+struct AckStats {
+    dup_acks: u64,
+    /// Duplicate ACKs since SND.UNA last moved
+    in_a_row: u32,
+    spurious_retransmits: u64,
+    dsacks: u64,
+    /// Most duplicate ACKs seen before SND.UNA moved without a
+    /// retransmission, i.e. how many segments overtook a delayed one
+    reordering: u32,
+    /// When SND.UNA was retransmitted, until it moves
+    retransmitted_at: Option<time::Instant>,
+    /// Shortest RTT sampled outside of retransmissions
+    min_rtt: Option<time::Duration>,
+}
+
+/// Segment in flight
+#[derive(Clone, Copy)]
+struct Sent {
+    at: time::Instant,
+    /// What the deliveries were when it was sent
+    stamp: rate::Stamp,
+}
+
+/// What the timestamps option needs to remember (RFC 7323 S4.3)
+#[derive(Clone, Copy, Default)]
+struct Timestamps {
+    /// TS.Recent, the TSval our segments echo
+    recent: u32,
+    /// Last.ACK.sent, the RCV.NXT of our latest ACK
+    last_ack_sent: u32,
 }
 
 #[derive(Clone)]
 struct Timers {
-    send_times: BTreeMap<u32, time::Instant>,
+    send_times: BTreeMap<u32, Sent>,
     pub(crate) srtt: f64,
+    /// When the connection entered TIME-WAIT
+    time_wait: Option<time::Instant>,
+    /// When our FIN got ACKed and we started waiting for the peer's
+    fin_wait2: Option<time::Instant>,
+    /// How many times the SYN or SYN-ACK has been retransmitted
+    syn_retries: u32,
+    /// When new data was last sent, to pace the next segments
+    paced_at: Option<time::Instant>,
+    /// When the last segment arrived from the peer
+    last_recv: Option<time::Instant>,
+    /// Keep-alive probes sent since then
+    keepalive_probes: u32,
+    /// When the peer's window was found closed, or last probed
+    persist_at: Option<time::Instant>,
+    /// Window probes sent since it closed
+    persist_probes: u32,
+    /// When the last congestion control sample was taken
+    sampled_at: Option<time::Instant>,
 }
 
 impl Default for Timers {
     fn default() -> Self {
         Self {
             send_times: Default::default(),
-            srtt: time::Duration::from_secs(1 * 60).as_secs_f64(),
+            srtt: time::Duration::from_secs(60).as_secs_f64(),
+            time_wait: None,
+            fin_wait2: None,
+            syn_retries: 0,
+            paced_at: None,
+            last_recv: None,
+            keepalive_probes: 0,
+            persist_at: None,
+            persist_probes: 0,
+            sampled_at: None,
         }
     }
 }
@@ -68,6 +460,7 @@ impl Default for Timers {
 /// 4 - future sequence numbers which are not yet allowed
 /// ```
 #[derive(Clone)]
+#[allow(dead_code)]
 pub struct SendSequenceSpace {
     /// Send Unacknowledged
     una: u32,
@@ -97,6 +490,7 @@ pub struct SendSequenceSpace {
 /// 3 - future sequence numbers which are not yet allowed
 /// ```
 #[derive(Clone)]
+#[allow(dead_code)]
 pub struct ReceiveSequenceSpace {
     /// Receive Next
     nxt: u32,
@@ -110,8 +504,12 @@ pub struct ReceiveSequenceSpace {
 
 impl Connection {
     fn availability(&self) -> Available {
+        // Whoever waits gets the error at once
+        if self.reset {
+            return Available::all();
+        }
         let mut a = Available::empty();
-        if self.is_recv_closed() || !self.incoming.is_empty() {
+        if self.is_recv_closed() || self.read_shutdown || !self.incoming.is_empty() {
             a |= Available::READ;
         };
 
@@ -119,99 +517,379 @@ impl Connection {
             a |= Available::FLUSH;
         };
 
-        // TODO: set available::WRITE
+        if self.send_room() > 0 {
+            a |= Available::WRITE;
+        };
+
         a
     }
 
-    pub fn on_tick(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        if let State::FinWait2 | State::TimeWait = self.state {
+    /// Bytes writes can queue now, none from the time the queue filled up
+    /// until it drains to the low watermark
+    pub(crate) fn send_room(&self) -> usize {
+        match self.options.send_low_watermark {
+            Some(low) if self.send_full && self.unacked.len() > low => 0,
+            _ => self.options.send_buffer.saturating_sub(self.unacked.len()),
+        }
+    }
+
+    pub fn on_tick(&mut self, nic: &dyn Device, config: &InterfaceConfig) -> io::Result<()> {
+        if self.frozen_at.is_some() {
+            return Ok(());
+        }
+        if let (
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::CloseWait
+            | State::LastAck,
+            Some(every),
+        ) = (self.state, config.cc_sample_interval)
+        {
+            if self
+                .timers
+                .sampled_at
+                .is_none_or(|at| self.clock.since(at) >= every)
+            {
+                self.sample_cc(config.cc_sample_capacity);
+            }
+        }
+
+        if let State::TimeWait = self.state {
+            // Wait 2*MSL so that any of the peer's retransmissions die out
+            if let Some(since) = self.timers.time_wait {
+                if self.clock.since(since) >= config.msl * 2 {
+                    self.state = State::Closed;
+                }
+            }
+            return Ok(());
+        }
+
+        if let State::FinWait2 = self.state {
             // we have shutdown our write side and the other side acked, no need to (re)transmit anything
+            // but don't hold on to the TCB forever if the peer never sends its FIN
+            if let Some(since) = self.timers.fin_wait2 {
+                if self.clock.since(since) >= config.fin_wait2_timeout {
+                    self.state = State::Closed;
+                }
+            }
             return Ok(());
         }
 
-        let n_unacked: usize = self
-            .closed_at
-            .unwrap_or(self.send.nxt)
-            .wrapping_sub(self.send.una) as usize;
-        let unsent: usize = self.unacked.len() - n_unacked as usize;
+        if let State::Closed = self.state {
+            return Ok(());
+        }
+
+        if let State::SynSent | State::SynRecvd = self.state {
+            // The SYN or SYN-ACK is the only thing in flight
+            let rto = self.syn_rto(config);
+            let retries = match self.state {
+                State::SynSent => config.syn_retries,
+                _ => config.syn_ack_retries,
+            };
+
+            let waited = self
+                .timers
+                .send_times
+                .get(&self.send.iss)
+                .map(|t| self.clock.since(t.at));
+
+            if let Some(waited) = waited {
+                if waited > rto {
+                    if self.timers.syn_retries >= retries {
+                        // The peer never answered, drop the half-open connection
+                        self.state = State::Closed;
+                    } else {
+                        self.timers.syn_retries += 1;
+                        self.tcp.syn = true;
+                        self.write(nic, self.send.iss, 0)?;
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        if let (State::Estab, Some(idle)) = (self.state, self.options.keepalive) {
+            let since = self
+                .clock
+                .since(self.timers.last_recv.unwrap_or(self.syn_at));
+            let quiet = self.send.una == self.send.nxt && self.unacked.is_empty();
+            if quiet && since >= idle * (self.timers.keepalive_probes + 1) {
+                if self.timers.keepalive_probes >= KEEPALIVE_PROBES {
+                    // The peer is gone
+                    self.send_rst(nic)?;
+                    self.state = State::Closed;
+                    self.reset = true;
+                    return Ok(());
+                }
+                // <SEQ=SND.UNA-1>, an old sequence number the peer ACKs right away
+                self.timers.keepalive_probes += 1;
+                self.write(nic, self.send.una.wrapping_sub(1), 0)?;
+                return Ok(());
+            }
+        }
 
-        let one_sec = time::Duration::from_secs_f64(1.0);
+        if self.persisting() {
+            let since = *self.timers.persist_at.get_or_insert(self.clock.now());
+            if self.clock.since(since) >= self.persist_timeout(config) {
+                // <SEQ=SND.UNA-1>, the peer's ACK carries its window, so a
+                // lost window update doesn't leave us waiting forever
+                self.timers.persist_probes += 1;
+                self.timers.persist_at = Some(self.clock.now());
+                self.write(nic, self.send.una.wrapping_sub(1), 0)?;
+            }
+        } else {
+            self.timers.persist_at = None;
+            self.timers.persist_probes = 0;
+        }
 
-        let waited_secs = self
-            .timers
-            .send_times
-            .range(self.send.una..)
-            .next()
-            .map(|(_s, t)| t.elapsed());
+        let n_unacked = self.in_flight();
+        let rto = self.rto(config);
+        let waited_secs = self.oldest_sent().map(|at| self.clock.since(at));
 
         let should_retransmit = if let Some(waited_secs) = waited_secs {
-            waited_secs > one_sec && waited_secs.as_secs_f64() > 1.5 * self.timers.srtt
+            waited_secs > rto
         } else {
             false
         };
 
         if should_retransmit {
+            self.cc.on_timeout(n_unacked);
+            self.acks.retransmitted_at = Some(self.clock.now());
+            #[cfg(feature = "otel")]
+            self.lifecycle.on_retransmit();
             let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
             if resend < self.send.wnd as u32 && self.closed {
                 self.tcp.fin = true;
                 self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
             }
 
-            self.write(nic, self.send.una, resend as usize)?;
-        } else {
-            // TODO: send new data if we have new data and space in the window
-            if unsent.eq(&0) && self.closed_at.is_some() {
-                // Nothing to retransmit
-                return Ok(());
+            let sent = self.write(nic, self.send.una, resend as usize)?;
+            if self.sack() {
+                // The holes left go out as the SACK blocks come back
+                self.scoreboard.on_timeout(Recovery {
+                    point: self.send.nxt,
+                    high_rxt: self.send.una.wrapping_add(sent as u32),
+                    timeout: true,
+                });
             }
+        } else {
+            self.transmit(nic)?;
+        }
 
-            let allowed: usize = self.send.wnd as usize - n_unacked;
+        Ok(())
+    }
 
-            // Can't send any data
-            if allowed == 0 {
-                return Ok(());
-            }
+    /// Retransmission timeout of the SYN or SYN-ACK, from 1 second doubled
+    /// on every retry (RFC 6298 S2.1 and S5.5)
+    fn syn_rto(&self, config: &InterfaceConfig) -> time::Duration {
+        config
+            .clamp_rto(time::Duration::from_secs(1) * 2u32.saturating_pow(self.timers.syn_retries))
+    }
 
-            let send = std::cmp::min(unsent, allowed);
-            if send < allowed && self.closed && self.closed_at.is_none() {
-                // If we are allowed to send more than we're sending
-                // And we're supposed to send the fin
-                // Than send the fin
-                self.tcp.fin = true;
-                self.closed_at = Some(self.send.nxt.wrapping_add(unsent as u32));
-            }
+    fn rto(&self, config: &InterfaceConfig) -> time::Duration {
+        config.clamp_rto(time::Duration::from_secs_f64(1.5 * self.timers.srtt))
+    }
+
+    /// Whether the persist timer runs: the peer's window is closed with data
+    /// waiting, and nothing in flight would bring a window update back
+    fn persisting(&self) -> bool {
+        self.send_window_closed()
+            && self.in_flight() == 0
+            && matches!(
+                self.state,
+                State::Estab | State::FinWait1 | State::CloseWait | State::LastAck
+            )
+    }
+
+    /// Interval between window probes, from the retransmission timeout
+    /// doubled on every probe (RFC 9293 S3.8.6.1)
+    fn persist_timeout(&self, config: &InterfaceConfig) -> time::Duration {
+        config.clamp_rto(self.rto(config) * 2u32.saturating_pow(self.timers.persist_probes))
+    }
+
+    /// When the oldest segment still in flight was sent, the keys wrap
+    /// around past SND.UNA
+    fn oldest_sent(&self) -> Option<time::Instant> {
+        let t = &self.timers.send_times;
+        let mut sent = t.range(self.send.una..).chain(t.range(..self.send.una));
+        sent.next().map(|(_, sent)| sent.at)
+    }
+
+    /// Bytes sent and not acknowledged yet
+    fn in_flight(&self) -> usize {
+        self.closed_at
+            .unwrap_or(self.send.nxt)
+            .wrapping_sub(self.send.una) as usize
+    }
+
+    /// Sends the queued data the windows allow, and the FIN once it's all
+    /// out. This is the send path of every tick, run early by
+    /// [`crate::TcpStream::kick`].
+    pub(crate) fn transmit(&mut self, nic: &dyn Device) -> io::Result<()> {
+        if self.frozen_at.is_some() {
+            return Ok(());
+        }
+        if std::mem::take(&mut self.window_update) {
+            // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+            self.write(nic, self.send.nxt, 0)?;
+        }
+        if !matches!(
+            self.state,
+            State::Estab | State::FinWait1 | State::Closing | State::CloseWait | State::LastAck
+        ) {
+            return Ok(());
+        }
+        let n_unacked = self.in_flight();
+        let unsent = self.unacked.len() - n_unacked;
+
+        if unsent.eq(&0) && self.closed_at.is_some() {
+            // Nothing to retransmit
+            return Ok(());
+        }
+
+        // What the peer holds past a hole has left the network (RFC 6675 S5)
+        let pipe = self.scoreboard.pipe(self.send.una, n_unacked);
+        let mut allowed = std::cmp::min(
+            (self.send.wnd as usize).saturating_sub(n_unacked),
+            self.cc.cwnd().saturating_sub(pipe),
+        );
+
+        if let Some(rate) = self.cc.pacing_rate() {
+            // Only send what the rate allows since the last send, but
+            // at least a segment so that the connection keeps going
+            let now = self.clock.now();
+            let mss = self.send_mss(nic);
+            let budget = self
+                .timers
+                .paced_at
+                .map_or(usize::MAX, |at| (rate * (now - at).as_secs_f64()) as usize);
+            allowed = std::cmp::min(allowed, budget.max(mss));
+        }
+
+        // Can't send any data
+        if allowed == 0 {
+            return Ok(());
+        }
+
+        let send = std::cmp::min(unsent, allowed);
+        if send < allowed && self.closed && self.closed_at.is_none() {
+            // If we are allowed to send more than we're sending
+            // And we're supposed to send the fin
+            // Than send the fin
+            self.tcp.fin = true;
+            self.closed_at = Some(self.send.nxt.wrapping_add(unsent as u32));
+        }
 
-            self.write(nic, self.send.nxt, send)?;
+        if send == 0 && !self.tcp.fin {
+            // Nothing new to send, incoming data gets ACKed on arrival
+            return Ok(());
+        }
+
+        if send > 0 {
+            self.timers.paced_at = Some(self.clock.now());
         }
+        self.write(nic, self.send.nxt, send)?;
 
         Ok(())
     }
 
     /// Accepts a new incoming connection, setting the initial handshake,
     /// receiving the SYN and returning an ACK and a SYN.
-    /// The 'a here is the lifetime of the packet itself,
-    /// which is the lifetime of the buffer at [`crate::TcpSocket::run`].
-    pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
-        iph: Ipv4HeaderSlice<'a>,
-        tcph: TcpHeaderSlice<'a>,
-        _data: &'a [u8],
+    pub fn accept(
+        nic: &dyn Device,
+        entropy: &mut dyn EntropySource,
+        config: &InterfaceConfig,
+        options: StreamOptions,
+        seg: &InboundSegment,
     ) -> io::Result<Option<Self>> {
+        let (tcph, data) = (&seg.tcph, seg.data);
         // Expect a packet that has the SYN bit set
         if !tcph.syn() {
             return Ok(None);
         }
 
-        let iss = 0;
-        let wnd_size = 1024;
-        let mut c = Self {
-            state: State::SynRecvd,
+        let iss = entropy.next_u32()?;
+        #[cfg(feature = "testing")]
+        let iss = config.initial_sequence.unwrap_or(iss);
+        let mut c = Self::new(
+            nic,
+            config,
+            options,
+            State::SynRecvd,
+            seg.dst(),
+            seg.src(),
+            iss,
+        );
+        c.on_syn(tcph);
+        if config.timestamps {
+            c.timestamps = timestamp(tcph).map(|(val, _)| Timestamps {
+                recent: val,
+                last_ack_sent: c.recv.nxt,
+            });
+        }
+
+        if config.compliance.syn_data && !data.is_empty() {
+            // Held until the connection is accepted
+            let taken = std::cmp::min(data.len(), c.recv.wnd as usize);
+            c.incoming.extend_from(&data[..taken]);
+            c.recv.nxt = c.recv.nxt.wrapping_add(taken as u32);
+            c.recv.wnd = c.receive_space();
+        }
+
+        c.tcp.syn = true;
+        c.tcp.ack = true;
+
+        c.write(nic, c.send.nxt, 0)?;
+
+        Ok(Some(c))
+    }
+
+    /// Opens a connection from `local` to `remote` by sending a SYN. It
+    /// stays in SYN-SENT until the peer answers with its own (RFC 9293
+    /// S3.5).
+    pub(crate) fn connect(
+        nic: &dyn Device,
+        entropy: &mut dyn EntropySource,
+        config: &InterfaceConfig,
+        options: StreamOptions,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+    ) -> io::Result<Self> {
+        let iss = entropy.next_u32()?;
+        #[cfg(feature = "testing")]
+        let iss = config.initial_sequence.unwrap_or(iss);
+        let mut c = Self::new(nic, config, options, State::SynSent, local, remote, iss);
+        if config.timestamps {
+            c.timestamps = Some(Timestamps::default());
+        }
+
+        // <SEQ=ISS><CTL=SYN>
+        c.tcp.syn = true;
+        c.write(nic, c.send.nxt, 0)?;
+
+        Ok(c)
+    }
+
+    /// A connection that hasn't heard from the peer yet
+    fn new(
+        nic: &dyn Device,
+        config: &InterfaceConfig,
+        options: StreamOptions,
+        state: State,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        iss: u32,
+    ) -> Self {
+        let wnd_size = options.recv_buffer as u16;
+        Self {
+            state,
             timers: Default::default(),
             recv: ReceiveSequenceSpace {
-                // Keep track of sender info
-                irs: tcph.sequence_number(),
-                nxt: tcph.sequence_number() + 1,
-                wnd: tcph.window_size(),
+                irs: 0,
+                nxt: 0,
+                wnd: wnd_size,
                 up: false,
             },
             send: SendSequenceSpace {
@@ -219,97 +897,242 @@ impl Connection {
                 iss,
                 una: iss,
                 nxt: iss,
-                wnd: wnd_size,
+                wnd: 0,
                 up: false,
                 wl1: 0,
-                wl2: 0,
+                wl2: iss,
             },
             ip: etherparse::Ipv4Header::new(
                 0,
                 64,
                 etherparse::IpTrafficClass::Tcp,
-                iph.destination_addr().octets(),
-                iph.source_addr().octets(),
-            ),
-
-            // Construct a new TCP header to send the acknowledgment
-            tcp: etherparse::TcpHeader::new(
-                tcph.destination_port(),
-                tcph.source_port(),
-                iss,
-                wnd_size,
+                local.ip().octets(),
+                remote.ip().octets(),
             ),
+            tcp: etherparse::TcpHeader::new(local.port(), remote.port(), iss, wnd_size),
 
             incoming: Default::default(),
             unacked: Default::default(),
             closed: false,
             closed_at: None,
-        };
+            reset: false,
+            read_shutdown: false,
+            window_update: false,
+            recv_pressured: false,
+            frozen_at: None,
+            send_full: false,
+
+            syn_at: config.clock.now(),
+            established_at: None,
+            accepted_at: None,
+
+            cc: Controller::new(config, nic.mtu() - 40),
+            delivered: 0,
+            rate: Default::default(),
+            peer: PeerOptions::default(),
+            offered: offered(config),
+            timestamps: None,
+            reassembly: Reassembly::default(),
+            scoreboard: Scoreboard::default(),
+            options,
+            tag: None,
+            option_hooks: Vec::new(),
+            clock: config.clock.clone(),
+            cc_samples: VecDeque::new(),
+            acks: AckStats::default(),
+            #[cfg(feature = "otel")]
+            lifecycle: crate::otel::Lifecycle::new(),
+        }
+    }
 
-        c.tcp.syn = true;
-        c.tcp.ack = true;
+    /// Keeps track of the peer's side from its SYN
+    fn on_syn(&mut self, tcph: &TcpHeaderSlice) {
+        self.recv.irs = tcph.sequence_number();
+        self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        self.send.wnd = tcph.window_size();
+        self.send.wl1 = tcph.sequence_number();
+        self.peer = PeerOptions::parse(tcph);
+    }
 
-        c.write(nic, c.send.nxt, 0)?;
+    /// Handles the answer to our SYN (RFC 9293 S3.10.7.3)
+    fn on_syn_sent(&mut self, nic: &dyn Device, seg: &InboundSegment) -> io::Result<Available> {
+        let (tcph, data) = (&seg.tcph, seg.data);
+        // ISS < SEG.ACK <= SND.NXT, the peer saw our SYN
+        let ackn = tcph.acknowledgment_number();
+        let acceptable = ackn.is_between_wrapped(self.send.iss, self.send.nxt.wrapping_add(1));
+        if tcph.ack() && !acceptable {
+            // <SEQ=SEG.ACK><CTL=RST>, for an old duplicate of a connection
+            send_rst_reply(nic, seg)?;
+            return Ok(self.availability());
+        }
 
-        Ok(Some(c))
+        if tcph.rst() {
+            // Refused, unless it doesn't even answer our SYN
+            if tcph.ack() {
+                self.state = State::Closed;
+                self.reset = true;
+            }
+            return Ok(self.availability());
+        }
+
+        if !tcph.syn() {
+            return Ok(self.availability());
+        }
+
+        self.on_syn(tcph);
+        // Only in use if the peer's SYN carries them too (RFC 7323 S3.2)
+        let recv_nxt = self.recv.nxt;
+        self.timestamps = self
+            .timestamps
+            .and(timestamp(tcph))
+            .map(|(val, _)| Timestamps {
+                recent: val,
+                last_ack_sent: recv_nxt,
+            });
+        self.tcp.ack = true;
+        if !data.is_empty() {
+            let taken = std::cmp::min(data.len(), self.recv.wnd as usize);
+            self.incoming.extend_from(&data[..taken]);
+            self.recv.nxt = self.recv.nxt.wrapping_add(taken as u32);
+            self.recv.wnd = self.receive_space();
+        }
+
+        if !tcph.ack() {
+            // Both ends opened at once, our SYN is answered along with theirs
+            self.state = State::SynRecvd;
+            self.tcp.syn = true;
+            self.write(nic, self.send.iss, 0)?;
+            return Ok(self.availability());
+        }
+
+        let sent = self.timers.send_times.remove(&self.send.iss);
+        // Unless retransmitted, the RTT of the SYN is the first sample. The
+        // echoed timestamp tells which of the SYNs got answered.
+        let rtt = self.echoed_rtt(tcph).or_else(|| {
+            sent.filter(|_| self.timers.syn_retries == 0)
+                .map(|sent| self.clock.since(sent.at))
+        });
+        if let Some(rtt) = rtt {
+            self.timers.srtt = rtt.as_secs_f64();
+            self.acks.min_rtt = Some(rtt);
+        }
+        self.send.una = ackn;
+        self.send.wl2 = ackn;
+        self.state = State::Estab;
+        self.established_at = Some(self.clock.now());
+        #[cfg(feature = "otel")]
+        self.lifecycle.on_established();
+
+        // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+        self.write(nic, self.send.nxt, 0)?;
+        Ok(self.availability())
     }
 
     /// Gets called when the connection is already known.
     /// Expecting an ACK for the SYN we sent on [`Connection::accept()`].
-    pub(crate) fn on_packet<'a>(
+    pub(crate) fn on_packet(
         &mut self,
-        nic: &mut tun_tap::Iface,
-        _iph: Ipv4HeaderSlice<'a>,
-        tcph: TcpHeaderSlice<'a>,
-        data: &'a [u8],
+        nic: &dyn Device,
+        config: &InterfaceConfig,
+        seg: &InboundSegment,
     ) -> io::Result<Available> {
-        // Is this packet even worth looking into?
-        // Valid segment check
-        // RCV.NXT =< SEG.SEQ < RCV.NXT + RCV.WND // First bit
-        // RCV.NXT =< SEG.SEQ + SEG.LEN - 1 < RCV.NXT + RCV.WND // Last bit
-        let seqn = tcph.sequence_number();
-        let w_end = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
-        let mut slen = data.len() as u32;
+        let (tcph, data) = (&seg.tcph, seg.data);
+        if self.frozen_at.is_some() {
+            // Nothing moves, the peer only hears where we stopped
+            if seg.seg_len() > 0 {
+                self.write(nic, self.send.nxt, 0)?;
+            }
+            return Ok(self.availability());
+        }
+        // The peer is still there
+        self.timers.last_recv = Some(self.clock.now());
+        self.timers.keepalive_probes = 0;
+        if !self.option_hooks.is_empty() {
+            options::dispatch(&self.option_hooks, tcph.options());
+        }
 
-        if tcph.syn() {
-            slen += 1;
-        };
+        if let State::SynSent = self.state {
+            return self.on_syn_sent(nic, seg);
+        }
 
-        if tcph.fin() {
-            slen += 1;
-        };
+        // Is this packet even worth looking into?
+        let seqn = tcph.sequence_number();
+        let okay = segment_acceptable(seqn, seg.seg_len(), self.recv.nxt, self.recv.wnd as u32);
+
+        if tcph.rst() && config.compliance.honor_rst {
+            // Unacceptable RSTs are dropped without an answer
+            if okay {
+                if seqn == self.recv.nxt {
+                    self.incoming.clear();
+                    self.unacked.clear();
+                    self.state = State::Closed;
+                    self.reset = true;
+                } else {
+                    // Could be blind, make the peer prove it (RFC 5961 S3.2)
+                    self.write(nic, self.send.nxt, 0)?;
+                }
+            }
+            return Ok(self.availability());
+        }
 
-        let okay = if slen.eq(&0) {
-            // Zero-length segment and zero-length window
-            if self.recv.wnd.eq(&0) {
-                // In this case, the seq number must be equal to nxt
-                seqn.eq(&self.recv.nxt)
-            } else {
-                seqn.is_between_wrapped(self.recv.nxt.wrapping_sub(1), w_end)
+        if let State::TimeWait = self.state {
+            // The peer's FIN can only come again if our ACK of it got lost
+            let fin_again =
+                tcph.fin() && seqn.wrapping_add(data.len() as u32).wrapping_add(1) == self.recv.nxt;
+            if fin_again && config.time_wait_ack {
+                self.timers.time_wait = Some(self.clock.now());
+                self.write(nic, self.send.nxt, 0)?;
             }
-        } else {
-            // If window is 0 than its not acceptable
-            !self.recv.wnd.eq(&0) ||
-            // if !(!false and !false) return false
-            !(!seqn.is_between_wrapped(self.recv.nxt.wrapping_sub(1), w_end)
-                && !seqn
-                    .wrapping_add(slen - 1)
-                    .is_between_wrapped(self.recv.nxt.wrapping_sub(1), w_end))
-        };
+            return Ok(self.availability());
+        }
+
+        // The peer's SYN again, our SYN-ACK must have been lost
+        let syn_again =
+            tcph.syn() && matches!(self.state, State::SynRecvd) && seqn == self.recv.irs;
+        if syn_again && !tcph.ack() {
+            // What data it carries was taken along with the first one, or
+            // is dropped until the peer sends it again
+            self.tcp.syn = true;
+            self.write(nic, self.send.iss, 0)?;
+            return Ok(self.availability());
+        }
 
         if !okay {
+            self.note_duplicate(seqn, data.len());
             self.write(nic, self.send.nxt, 0)?;
             return Ok(self.availability());
         }
 
-        if !tcph.ack() {
-            if tcph.syn() {
-                assert!(data.is_empty());
-                self.recv.nxt = seqn.wrapping_add(1);
+        // Echo the latest of the segments that reached the left edge of the
+        // window (RFC 7323 S4.3)
+        if let (Some(ts), Some((val, _))) = (self.timestamps.as_mut(), timestamp(tcph)) {
+            if !ts.last_ack_sent.wrapping_lt(seqn) && !val.wrapping_lt(ts.recent) {
+                ts.recent = val;
+            }
+        }
+
+        // The peer's SYN-ACK of a simultaneous open is ACK processed below
+        if tcph.syn() && !syn_again {
+            // A SYN of another sequence number is either an old duplicate
+            // or an attempt to inject a reset, never a legitimate segment
+            match config.in_window_syn {
+                SynPolicy::ChallengeAck => {
+                    // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+                    self.write(nic, self.send.nxt, 0)?;
+                }
+                SynPolicy::Reset => {
+                    self.send_rst(nic)?;
+                    self.state = State::Closed;
+                    self.reset = true;
+                }
             }
             return Ok(self.availability());
         }
 
+        if !tcph.ack() {
+            return Ok(self.availability());
+        }
+
         // Acceptable ACK check
         // SND.UNA < SEG.ACK <= SND.NEXT
         let ackn = tcph.acknowledgment_number();
@@ -317,41 +1140,151 @@ impl Connection {
             if ackn.is_between_wrapped(self.send.una.wrapping_sub(1), self.send.nxt.wrapping_add(1))
             {
                 self.state = State::Estab;
-            } else {
-                // TODO: RESET <SEQ=SEG.ACK> <CTL=RST>
+                self.established_at = Some(self.clock.now());
+                #[cfg(feature = "otel")]
+                self.lifecycle.on_established();
+            } else if config.compliance.reset_unacceptable_ack {
+                // <SEQ=SEG.ACK><CTL=RST>, the connection stays as it is
+                self.tcp.ack = false;
+                self.tcp.rst = true;
+                let res = self.write(nic, ackn, 0);
+                self.tcp.rst = false;
+                self.tcp.ack = true;
+                res?;
+                return Ok(self.availability());
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            if ackn.is_between_wrapped(self.send.una, self.send.nxt.wrapping_add(1)) {
-                if !self.unacked.is_empty() {
-                    // send.una hasn't been updated yet with ACK for our SYN, so data starts just beyond it
-                    let data_start = self
-                        .send
-                        .una
-                        .wrapping_add((self.send.una == self.send.iss).into());
-
-                    let acked_data_end =
-                        std::cmp::min(ackn.wrapping_sub(data_start) as usize, self.unacked.len());
-
-                    self.unacked.drain(..acked_data_end);
-
-                    let una = self.send.una;
-                    let srtt = &mut self.timers.srtt;
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::Closing
+        | State::CloseWait
+        | State::LastAck = self.state
+        {
+            // Duplicate ACK (RFC 5681 S2)
+            if ackn == self.send.una
+                && self.send.una != self.send.nxt
+                && data.is_empty()
+                && !tcph.fin()
+                && tcph.window_size() == self.send.wnd
+            {
+                self.acks.dup_acks += 1;
+                self.acks.in_a_row += 1;
+            }
 
-                    self.timers.send_times.retain(|&seq, sent| {
-                        if seq.is_between_wrapped(una, ackn) {
-                            *srtt = 0.8 * *srtt + (1.0 - 0.8) * sent.elapsed().as_secs_f64();
-                            return false;
+            if ackn.is_between_wrapped(self.send.una, self.send.nxt.wrapping_add(1)) {
+                let retransmitted_at = self.acks.retransmitted_at.take();
+                match retransmitted_at {
+                    // Too early to answer the retransmission
+                    Some(at)
+                        if self
+                            .acks
+                            .min_rtt
+                            .is_some_and(|min| self.clock.since(at) < min) =>
+                    {
+                        self.acks.spurious_retransmits += 1;
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.acks.reordering = self.acks.reordering.max(self.acks.in_a_row);
+                    }
+                }
+                self.acks.in_a_row = 0;
+                // send.una hasn't been updated yet with ACK for our SYN, so data starts just beyond it
+                let data_start = self
+                    .send
+                    .una
+                    .wrapping_add((self.send.una == self.send.iss).into());
+
+                let acked_data_end =
+                    std::cmp::min(ackn.wrapping_sub(data_start) as usize, self.unacked.len());
+
+                self.unacked.consume(acked_data_end);
+
+                let una = self.send.una;
+                // Timed off the echo rather than the send times once in use
+                let echoed = self.echoed_rtt(tcph);
+                let srtt = &mut self.timers.srtt;
+                // Most recently sent of the acknowledged segments
+                let mut latest: Option<Sent> = None;
+
+                let now = self.clock.now();
+                self.timers.send_times.retain(|&seq, sent| {
+                    // SND.UNA <= seq < SEG.ACK
+                    if seq.wrapping_sub(una) < ackn.wrapping_sub(una) {
+                        let rtt = now.saturating_duration_since(sent.at);
+                        if echoed.is_none() {
+                            *srtt = 0.8 * *srtt + (1.0 - 0.8) * rtt.as_secs_f64();
+                        }
+                        if latest.is_none_or(|l| sent.at > l.at) {
+                            latest = Some(*sent);
                         }
-                        true
-                    });
+                        return false;
+                    }
+                    true
+                });
+
+                if let Some(rtt) = echoed {
+                    // One sample per ACK (RFC 7323 S4.2)
+                    *srtt = 0.8 * *srtt + (1.0 - 0.8) * rtt.as_secs_f64();
+                }
+
+                self.delivered += acked_data_end as u64;
+                let rtt = latest.map(|l| now - l.at);
+                // Retransmitted segments can't tell which copy got ACKed
+                // (Karn), unless the echo does
+                let unambiguous = match retransmitted_at {
+                    None => rtt,
+                    Some(_) => echoed,
+                };
+                if let Some(rtt) = unambiguous {
+                    self.acks.min_rtt = Some(self.acks.min_rtt.map_or(rtt, |min| min.min(rtt)));
+                }
+                let delivery_rate = latest.filter(|_| acked_data_end > 0).and_then(|l| {
+                    let (delivered, min_rtt) = (self.delivered, self.acks.min_rtt);
+                    self.rate.on_ack(now, delivered, l.at, l.stamp, min_rtt)
+                });
+                let sample = AckSample {
+                    now,
+                    acked: acked_data_end,
+                    in_flight: self.send.nxt.wrapping_sub(ackn) as usize,
+                    rtt,
+                    delivery_rate,
+                    delivered: self.delivered,
+                    prior_delivered: latest.map_or(0, |l| l.stamp.delivered),
+                    recovering: self.scoreboard.recovery.is_some_and(|r| !r.timeout),
+                };
+                if acked_data_end > 0 {
+                    self.cc.on_ack(&sample);
+                    #[cfg(feature = "otel")]
+                    self.lifecycle.on_progress();
                 }
 
                 self.send.una = ackn;
             }
 
-            // TODO: update window
+            // Take the peer's window from the most recent segment, unless this
+            // one is older than the last update (RFC 9293 S3.10.7.4)
+            if ackn == self.send.una
+                && (self.send.wl1.wrapping_lt(seqn)
+                    || (self.send.wl1 == seqn && !ackn.wrapping_lt(self.send.wl2)))
+            {
+                self.send.wnd = tcph.window_size();
+                self.send.wl1 = seqn;
+                self.send.wl2 = ackn;
+            }
+
+            if self.sack() {
+                let blocks = sack_blocks(tcph);
+                let dsack = sack::is_dsack(ackn, &blocks);
+                if dsack {
+                    self.acks.dsacks += 1;
+                }
+                self.scoreboard
+                    .update(self.send.una, self.send.nxt, &blocks[dsack as usize..]);
+                self.recover(nic)?;
+            }
         }
 
         if let State::FinWait1 = self.state {
@@ -359,20 +1292,50 @@ impl Connection {
                 if self.send.una == closed_at.wrapping_add(1) {
                     // our FIN has been ACKed!
                     self.state = State::FinWait2;
+                    self.timers.fin_wait2 = Some(self.clock.now());
                 }
             }
         }
 
+        if let State::Closing = self.state {
+            if self.closed_at.map(|at| at.wrapping_add(1)) == Some(self.send.una) {
+                // The peer got our FIN, and we had its FIN already
+                self.state = State::TimeWait;
+                self.timers.time_wait = Some(self.clock.now());
+            }
+        }
+
+        if let State::LastAck = self.state {
+            if self.closed_at.map(|at| at.wrapping_add(1)) == Some(self.send.una) {
+                // Our FIN is ACKed, nothing is left of the connection
+                self.state = State::Closed;
+            }
+        }
+
+        // Data and FIN arriving together are acknowledged at once
+        let mut ack = false;
         if !data.is_empty() {
             if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-                let mut unread_data_at = self.recv.nxt.wrapping_sub(seqn) as usize;
-
-                if unread_data_at > data.len() {
-                    assert_eq!(unread_data_at, data.len() + 1);
-                    unread_data_at = 0;
+                let new_data = trim_segment(seqn, data.len(), self.recv.nxt, self.recv.wnd as u32);
+                self.note_duplicate(seqn, data.len());
+                if self.read_shutdown
+                    && self.options.read_shutdown == ReadShutdown::Reset
+                    && !new_data.is_empty()
+                {
+                    self.reset(nic)?;
+                    return Ok(self.availability());
                 }
 
-                self.incoming.extend(&data[unread_data_at..]);
+                if !self.read_shutdown {
+                    self.incoming.extend_from(&data[new_data.clone()]);
+                }
+                let past_hole = self.recv.nxt.wrapping_lt(seqn);
+                if past_hole && self.sack() && !self.read_shutdown {
+                    // Until the hole fills, and reported meanwhile
+                    let wnd = self.recv.wnd as u32;
+                    self.reassembly.insert(self.recv.nxt, wnd, seqn, data);
+                }
+                self.recv.wnd = self.receive_space();
 
                 /*
                 Once the TCP takes responsibility for the data, it advances
@@ -380,33 +1343,120 @@ impl Connection {
                 appropriate   to   the   current    buffer    availability.
                 The total of RCV.NXT and RCV.WND  should  not  be  reduced.
                 */
-                self.recv.nxt = seqn.wrapping_add(data.len() as u32);
-
-                // Send an Ack of the form: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
-                self.write(nic, self.send.nxt, 0)?;
+                self.recv.nxt = self.recv.nxt.wrapping_add(new_data.len() as u32);
+                #[cfg(feature = "otel")]
+                {
+                    self.lifecycle.received += new_data.len() as u64;
+                }
+                if !new_data.is_empty() && !self.reassembly.is_empty() {
+                    // What was held past the hole that just filled
+                    let held = self.reassembly.pull(self.recv.nxt);
+                    self.incoming.extend_from(&held);
+                    self.recv.nxt = self.recv.nxt.wrapping_add(held.len() as u32);
+                    self.recv.wnd = self.receive_space();
+                }
+                self.update_recv_pressure();
+                ack = true;
             };
         }
 
-        if tcph.fin() {
-            if let State::FinWait2 = self.state {
-                // We're done with the connection
-                // Client has FINed
-                self.recv.nxt = self.recv.nxt.wrapping_add(1);
-                self.write(nic, self.send.nxt, 0)?;
-                self.state = State::TimeWait;
+        // The FIN only counts once everything before it arrived
+        let fin_at = seqn.wrapping_add(data.len() as u32);
+        if tcph.fin() && fin_at == self.recv.nxt {
+            match self.state {
+                State::Estab => {
+                    // The peer is done sending, the application may not be
+                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                    self.state = State::CloseWait;
+                    ack = true;
+                }
+                State::FinWait1 => {
+                    // Simultaneous close, our FIN is still unacknowledged
+                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                    self.state = State::Closing;
+                    ack = true;
+                }
+                State::FinWait2 => {
+                    // We're done with the connection
+                    // Client has FINed
+                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                    self.state = State::TimeWait;
+                    self.timers.time_wait = Some(self.clock.now());
+                    ack = true;
+                }
+                _ => {}
             }
         }
 
+        if ack {
+            // Send an Ack of the form: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+            self.write(nic, self.send.nxt, 0)?;
+        }
+
         Ok(self.availability())
     }
 
-    /// Sends a chunk of data through the tun_tap interface.
-    pub fn write(&mut self, nic: &mut tun_tap::Iface, seq: u32, limit: usize) -> io::Result<usize> {
-        let mut buf = [0u8; 1504];
+    /// Sends a chunk of data through the network device. Chunks larger
+    /// than a segment go out as a single packet when the device can
+    /// segment them itself.
+    pub fn write(&mut self, nic: &dyn Device, seq: u32, limit: usize) -> io::Result<usize> {
         self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+        self.tcp.window_size = self.recv.wnd;
+
+        // Our MSS goes in the SYN only, what the device takes without the
+        // fixed headers (RFC 9293 S3.7.1)
+        let mut raw = Vec::new();
+        if self.tcp.syn {
+            let mss = cmp::min(nic.mtu() - 40, u16::MAX as usize) as u16;
+            raw.extend([2, 4]);
+            raw.extend(mss.to_be_bytes());
+            // Offered in our SYN, agreed to in the SYN-ACK (RFC 2018 S2)
+            if self.offered.sack_permitted && (self.peer.sack_permitted || !self.tcp.ack) {
+                raw.extend([1, 1, 4, 2]);
+            }
+        }
+        let ts_val = self.ts_val();
+        if let Some(ts) = self.timestamps.as_mut() {
+            // In every segment once agreed, after two NOPs to keep the
+            // values aligned (RFC 7323 S3.2 and Appendix A)
+            raw.extend([1, 1, 8, 10]);
+            raw.extend(ts_val.to_be_bytes());
+            raw.extend(ts.recent.to_be_bytes());
+            if self.tcp.ack {
+                ts.last_ack_sent = self.recv.nxt;
+            }
+        }
+        if self.tcp.ack && !self.tcp.syn && self.sack() {
+            // As many blocks as there is room for after two NOPs (RFC 2018
+            // S3 and S4)
+            let room = (40 - raw.len()).saturating_sub(4) / 8;
+            let blocks = self.reassembly.blocks();
+            let blocks = &blocks[..cmp::min(blocks.len(), cmp::min(room, sack::MAX_BLOCKS))];
+            if !blocks.is_empty() {
+                raw.extend([1, 1, 5, 2 + 8 * blocks.len() as u8]);
+                for (left, right) in blocks {
+                    raw.extend(left.to_be_bytes());
+                    raw.extend(right.to_be_bytes());
+                }
+            }
+        }
+        if !self.option_hooks.is_empty() {
+            let segment = OutgoingSegment {
+                seq,
+                ack: self.recv.nxt,
+                syn: self.tcp.syn,
+                fin: self.tcp.fin,
+                rst: self.tcp.rst,
+            };
+            raw = options::encode(&self.option_hooks, &segment, raw);
+        }
+        // encode() keeps within the room there is
+        self.tcp.set_options_raw(&raw).unwrap();
 
-        let mut offset = seq.wrapping_sub(self.send.una) as usize;
+        // Keep-alive probes start right before SND.UNA and carry nothing
+        let mut offset =
+            std::cmp::min(seq.wrapping_sub(self.send.una) as usize, self.unacked.len());
 
         if let Some(closed_at) = self.closed_at {
             if seq == closed_at.wrapping_add(1) {
@@ -414,30 +1464,22 @@ impl Connection {
             }
         };
 
-        // we want self.unacked[n_unacked..]
-        let (mut head, mut tail) = self.unacked.as_slices();
-        if head.len() >= offset {
-            head = &head[offset..];
-        } else {
-            let skipped = head.len();
-            head = &[];
-            tail = &tail[(offset - skipped)..];
-        }
-
-        let max_data = std::cmp::min(limit, head.len() + tail.len());
+        let max_data = std::cmp::min(limit, self.unacked.len() - offset);
 
-        let size = std::cmp::min(
-            buf.len(),
-            self.ip.header_len() + self.tcp.header_len() as usize + max_data,
-        );
+        let headers_len = self.ip.header_len() + self.tcp.header_len() as usize;
+        let mss = self.send_mss(nic);
+        let gso = nic.gso_max_len().filter(|_| max_data > mss);
+        let offload = gso.is_some() || nic.capabilities().contains(Capabilities::TX_CSUM);
+        let size = match gso {
+            Some(max_len) => cmp::min(max_len, headers_len + max_data),
+            None => headers_len + cmp::min(max_data, mss),
+        };
+        let mut buf = vec![0u8; size];
 
         self.ip
             .set_payload_len(size - self.ip.header_len())
-            .or_else(|_e| {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Error calculating checksum",
-                ))
+            .map_err(|_e| {
+                io::Error::new(io::ErrorKind::InvalidData, "Error calculating checksum")
             })?;
 
         // Write headers to buffer
@@ -453,31 +1495,36 @@ impl Connection {
 
         // write the payload to the in-memory buffer
         let payload_bytes = {
-            let mut written = 0;
-            let mut limit = max_data;
-
-            // write as much as we can from payload1
-            let p1l = std::cmp::min(limit, head.len());
-            written += unwritten.write(&head[..p1l])?;
-            limit -= written;
-
-            // then, write more (if we can) from payload2
-            let p2l = std::cmp::min(limit, tail.len());
-            written += unwritten.write(&tail[..p2l])?;
-            written
+            let (head, tail) = self.unacked.slices(offset..offset + max_data);
+            unwritten.write(head)? + unwritten.write(tail)?
         };
 
         let payload_end = buf_len - unwritten.len();
 
-        self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, &buf[tcph_end..payload_end])
-            .or_else(|_e| {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Error calculating IPV4 checksum",
-                ))
-            })?;
+        // The FIN goes on the segment that ends the data: one cut short by
+        // the MSS leaves it to a later one, as if it had never been queued
+        if let Some(closed_at) = self.closed_at.filter(|_| self.tcp.fin) {
+            if seq.wrapping_add(payload_bytes as u32) != closed_at {
+                self.tcp.fin = false;
+                if self.send.nxt != closed_at.wrapping_add(1) {
+                    self.closed_at = None;
+                }
+            }
+        }
+
+        self.tcp.checksum = if offload {
+            // Completed by the device
+            device::pseudo_header_sum(self.ip.source, self.ip.destination, payload_end - iph_end)
+        } else {
+            self.tcp
+                .calc_checksum_ipv4(&self.ip, &buf[tcph_end..payload_end])
+                .map_err(|_e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Error calculating IPV4 checksum",
+                    )
+                })?
+        };
 
         let mut tcph_buf = &mut buf[iph_end..tcph_end];
         self.tcp.write(&mut tcph_buf).unwrap();
@@ -498,40 +1545,487 @@ impl Connection {
             self.send.nxt = next_seq;
         }
 
-        self.timers.send_times.insert(seq, time::Instant::now());
+        // Only what takes up sequence space gets acknowledged
+        if next_seq != seq {
+            let now = self.clock.now();
+            let idle = self.timers.send_times.is_empty();
+            self.timers.send_times.insert(
+                seq,
+                Sent {
+                    at: now,
+                    stamp: self.rate.on_send(now, self.delivered, idle),
+                },
+            );
+        }
 
         // Send the data back through the the network interface
-        nic.send(&buf[..payload_end])?;
+        let offload = Some(gso.map(|_| mss as u16)).filter(|_| offload);
+        stats::timed_send(nic, &buf[..payload_end], offload)?;
 
         Ok(payload_bytes)
     }
 
-    /*
-    Helper function that sends a reset packet back to the client (not used)
-    pub fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> Result<(), Box<dyn Error>> {
-        self.tcp.rst = true;
-        self.tcp.acknowledgment_number = 0;
-        self.tcp.sequence_number = 0;
-        self.write(nic, self.send.nxt, 0)?;
+    /// Whether both ends agreed to selective acknowledgments
+    fn sack(&self) -> bool {
+        self.offered.sack_permitted && self.peer.sack_permitted
+    }
+
+    /// Notes the part of a segment below RCV.NXT, received already, for
+    /// the next ACK to report (RFC 2883 S4)
+    fn note_duplicate(&mut self, seqn: u32, len: usize) {
+        if !self.sack() || len == 0 || !seqn.wrapping_lt(self.recv.nxt) {
+            return;
+        }
+        let end = seqn.wrapping_add(len as u32);
+        let end = if self.recv.nxt.wrapping_lt(end) {
+            self.recv.nxt
+        } else {
+            end
+        };
+        self.reassembly.duplicate(seqn, end);
+    }
+
+    /// Retransmits the holes the peer's SACK blocks point at, as far as the
+    /// congestion window goes. Recovery starts on three duplicate ACKs or
+    /// three segments SACKed past a hole (RFC 6675 S5).
+    fn recover(&mut self, nic: &dyn Device) -> io::Result<()> {
+        let mss = self.send_mss(nic);
+        let in_flight = self.in_flight();
+        if self.scoreboard.recovery.is_none() {
+            let lost = self.acks.in_a_row as usize >= sack::DUP_THRESH
+                || self.scoreboard.sacked() >= sack::DUP_THRESH * mss;
+            if !lost || in_flight == 0 {
+                return Ok(());
+            }
+            self.cc.on_loss(in_flight);
+            self.scoreboard.recovery = Some(Recovery {
+                point: self.send.nxt,
+                high_rxt: self.send.una,
+                timeout: false,
+            });
+            // The first hole goes out whatever the window (step 4.3)
+            if !self.retransmit_hole(nic, mss)? {
+                return Ok(());
+            }
+        }
+        while self.scoreboard.pipe(self.send.una, in_flight) + mss <= self.cc.cwnd() {
+            if !self.retransmit_hole(nic, mss)? {
+                break;
+            }
+        }
         Ok(())
     }
-    */
 
-    pub(crate) fn is_recv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // PTPD: CloseWait, LastAck, Closed, Closing
-            return true;
+    /// Sends a segment of the next hole past HighRxt, the one at SND.UNA
+    /// if the peer sent no blocks. `false` once there is none left.
+    fn retransmit_hole(&mut self, nic: &dyn Device, mss: usize) -> io::Result<bool> {
+        let recovery = match self.scoreboard.recovery {
+            Some(recovery) => recovery,
+            None => return Ok(false),
+        };
+        let una = self.send.una;
+        let hole = match self.scoreboard.next_hole(una, recovery.high_rxt) {
+            Some((start, end)) => Some((start, end.wrapping_sub(start) as usize)),
+            None if recovery.high_rxt == una => Some((una, mss)),
+            None => None,
+        };
+        let (start, len) = match hole {
+            Some(hole) => hole,
+            None => return Ok(false),
+        };
+        self.acks.retransmitted_at = Some(self.clock.now());
+        let sent = self.write(nic, start, cmp::min(len, mss))?;
+        if let Some(recovery) = self.scoreboard.recovery.as_mut() {
+            recovery.high_rxt = start.wrapping_add(sent as u32);
         }
-        false
+        Ok(sent > 0)
+    }
+
+    /// Our timestamp clock, in milliseconds since the connection opened and
+    /// from 1, so that the echo of a timestamp is never 0
+    fn ts_val(&self) -> u32 {
+        (self.clock.since(self.syn_at).as_millis() as u32).wrapping_add(1)
+    }
+
+    /// Round trip of the segment whose timestamp `tcph` echoes, if it
+    /// echoes one of ours (RFC 7323 S4.1)
+    fn echoed_rtt(&self, tcph: &TcpHeaderSlice) -> Option<time::Duration> {
+        self.timestamps?;
+        let (_, ecr) = timestamp(tcph).filter(|_| tcph.ack())?;
+        let now = self.ts_val();
+        if now.wrapping_lt(ecr) {
+            return None;
+        }
+        Some(time::Duration::from_millis(now.wrapping_sub(ecr) as u64))
+    }
+
+    /// Largest payload of the segments we send: what fits in the MTU of the
+    /// device, and what the peer said it takes (RFC 9293 S3.7.1), options
+    /// included (RFC 6691 S2)
+    fn send_mss(&self, nic: &dyn Device) -> usize {
+        let headers_len = self.ip.header_len() + self.tcp.header_len() as usize;
+        let options_len = headers_len - 40;
+        let peer = self.peer.mss.unwrap_or(DEFAULT_MSS) as usize;
+        cmp::min(nic.mtu() - headers_len, peer.saturating_sub(options_len)).max(1)
+    }
+
+    /// Room left in the receive queue, which is the window we advertise
+    fn receive_space(&self) -> u16 {
+        if self.read_shutdown && self.options.read_shutdown == ReadShutdown::CloseWindow {
+            return 0;
+        }
+        self.options.recv_buffer.saturating_sub(self.incoming.len()) as u16
+    }
+
+    /// Stops taking data for the application, see [`ReadShutdown`]
+    pub(crate) fn shutdown_read(&mut self) {
+        self.read_shutdown = true;
+        self.incoming.clear();
+        self.reassembly.clear();
+        self.recv.wnd = self.receive_space();
+    }
+
+    fn sample_cc(&mut self, capacity: usize) {
+        let now = self.clock.now();
+        self.timers.sampled_at = Some(now);
+        if capacity == 0 {
+            return;
+        }
+        if self.cc_samples.len() >= capacity {
+            self.cc_samples.pop_front();
+        }
+        self.cc_samples.push_back(stats::CcSample {
+            at: now - self.syn_at,
+            cwnd: self.cc.cwnd(),
+            ssthresh: self.cc.ssthresh(),
+            srtt: time::Duration::from_secs_f64(self.timers.srtt),
+            in_flight: self.send.nxt.wrapping_sub(self.send.una) as usize,
+            delivered: self.delivered,
+        });
+    }
+
+    /// Reopens the receive window once the application read some data,
+    /// telling the peer when it grew by at least half the queue or a
+    /// segment (RFC 1122 S4.2.3.3). Returns whether it's due, the update
+    /// goes out on the next [`Self::transmit`] so that it can't overtake the
+    /// ACKs the packet loop is about to send.
+    pub(crate) fn on_read(&mut self, nic: &dyn Device) -> bool {
+        if self.frozen_at.is_some() {
+            // Caught up with on thaw
+            return false;
+        }
+        self.update_recv_pressure();
+        let wnd = self.receive_space();
+        // Against the window the peer was told last, so that small reads add
+        // up to an update (RFC 9293 S3.8.6.2.2)
+        let grown = wnd.saturating_sub(self.recv.wnd) as usize;
+
+        let mss = nic.mtu() - self.ip.header_len() - self.tcp.header_len() as usize;
+        let synchronized = matches!(self.state, State::Estab | State::FinWait1 | State::FinWait2);
+        if synchronized && grown >= std::cmp::min(self.options.recv_buffer / 2, mss) {
+            self.recv.wnd = wnd;
+            self.window_update = true;
+        }
+        self.window_update
+    }
+
+    /// Follows the data waiting to be read against the watermarks of
+    /// [`StreamOptions::recv_high_watermark`]
+    fn update_recv_pressure(&mut self) {
+        let high = match self.options.recv_high_watermark {
+            Some(high) => high,
+            None => {
+                self.recv_pressured = false;
+                return;
+            }
+        };
+        let waiting = self.incoming.len();
+        self.recv_pressured = match self.options.recv_low_watermark {
+            _ if !self.recv_pressured => waiting >= high,
+            Some(low) => waiting > low,
+            None => waiting >= high,
+        };
+    }
+
+    pub(crate) fn recv_pressured(&self) -> bool {
+        self.recv_pressured
+    }
+
+    /// Bytes queued that weren't sent yet
+    pub(crate) fn unsent(&self) -> usize {
+        self.unacked.len().saturating_sub(self.in_flight())
+    }
+
+    /// The peer's window is closed with data waiting to be sent
+    pub(crate) fn send_window_closed(&self) -> bool {
+        self.send.wnd == 0 && self.unsent() > 0
+    }
+
+    /// Sends a keepalive-style probe right away, <SEQ=SND.UNA-1><CTL=ACK>,
+    /// which the peer answers with an ACK of its own. It doesn't count
+    /// towards the keepalive probes, nor waits for the connection to idle.
+    pub(crate) fn send_probe(&mut self, nic: &dyn Device) -> io::Result<()> {
+        if !matches!(
+            self.state,
+            State::Estab
+                | State::FinWait1
+                | State::FinWait2
+                | State::Closing
+                | State::CloseWait
+                | State::LastAck
+        ) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Connection isn't synchronized",
+            ));
+        }
+        self.write(nic, self.send.una.wrapping_sub(1), 0)
+            .map(|_| ())
+    }
+
+    /// Sends a reset packet back to the client: <SEQ=SND.NXT><CTL=RST,ACK>
+    pub(crate) fn send_rst(&mut self, nic: &dyn Device) -> io::Result<()> {
+        self.tcp.rst = true;
+        let res = self.write(nic, self.send.nxt, 0);
+        self.tcp.rst = false;
+        res.map(|_| ())
+    }
+
+    /// Aborts the connection, sending a RST and discarding both queues.
+    pub(crate) fn reset(&mut self, nic: &dyn Device) -> io::Result<()> {
+        let res = if let State::Closed = self.state {
+            Ok(())
+        } else {
+            self.send_rst(nic)
+        };
+
+        self.incoming.clear();
+        self.unacked.clear();
+        self.state = State::Closed;
+        self.reset = true;
+        res
+    }
+
+    pub(crate) fn is_handshaking(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynRecvd)
+    }
+
+    /// Whether our SYN is still unanswered
+    pub(crate) fn is_connecting(&self) -> bool {
+        matches!(self.state, State::SynSent)
+    }
+
+    /// Time it took to go from the SYN to ESTABLISHED
+    pub(crate) fn handshake_latency(&self) -> Option<time::Duration> {
+        self.established_at.map(|t| t.duration_since(self.syn_at))
+    }
+
+    /// Time spent in the accept queue once established
+    pub(crate) fn accept_wait(&self) -> Option<time::Duration> {
+        let accepted_at = self.accepted_at?;
+        Some(self.established_at.map_or(time::Duration::ZERO, |t| {
+            accepted_at.saturating_duration_since(t)
+        }))
+    }
+
+    /// Picks up where the timers were before the link went down `down` ago,
+    /// so that the outage counts neither towards a timeout nor the RTT
+    pub(crate) fn on_link_up(&mut self, down: time::Duration) {
+        if self.frozen_at.is_some() {
+            // The whole freeze gets skipped on thaw
+            return;
+        }
+        self.shift_timers(down);
+    }
+
+    fn shift_timers(&mut self, down: time::Duration) {
+        let t = &mut self.timers;
+        for sent in t.send_times.values_mut() {
+            sent.at += down;
+        }
+        for at in [
+            &mut t.time_wait,
+            &mut t.fin_wait2,
+            &mut t.paced_at,
+            &mut t.last_recv,
+            &mut t.persist_at,
+            &mut t.sampled_at,
+        ]
+        .iter_mut()
+        .filter_map(|at| at.as_mut())
+        {
+            *at += down;
+        }
+    }
+
+    /// Stops sending and running the timers, see [`crate::TcpStream::freeze`]
+    pub(crate) fn freeze(&mut self) {
+        if self.frozen_at.is_none() {
+            self.frozen_at = Some(self.clock.now());
+        }
+    }
+
+    /// Picks up where [`Self::freeze`] left off, as if no time had passed
+    pub(crate) fn thaw(&mut self, nic: &dyn Device) {
+        if let Some(frozen_at) = self.frozen_at.take() {
+            self.shift_timers(self.clock.since(frozen_at));
+            self.on_read(nic);
+        }
+    }
+
+    /// Marks the connection as handed out to the application
+    pub(crate) fn on_accept(&mut self) -> Option<time::Duration> {
+        self.accepted_at = Some(self.clock.now());
+        self.accept_wait()
+    }
+
+    /// What the timers are up to, see [`crate::TcpStream::timers`]
+    pub fn timers(&self, config: &InterfaceConfig) -> TimerInfo {
+        let left = |since: time::Instant, timeout: time::Duration| {
+            timeout.saturating_sub(self.clock.since(since))
+        };
+        let retransmission = match self.state {
+            State::SynSent | State::SynRecvd => self
+                .timers
+                .send_times
+                .get(&self.send.iss)
+                .map(|sent| left(sent.at, self.syn_rto(config))),
+            State::Estab | State::FinWait1 | State::Closing | State::CloseWait | State::LastAck => {
+                self.oldest_sent().map(|at| left(at, self.rto(config)))
+            }
+            _ => None,
+        };
+
+        let quiet = self.send.una == self.send.nxt && self.unacked.is_empty();
+        let keepalive = match (self.state, self.options.keepalive) {
+            (State::Estab, Some(idle)) if quiet => Some(left(
+                self.timers.last_recv.unwrap_or(self.syn_at),
+                idle * (self.timers.keepalive_probes + 1),
+            )),
+            _ => None,
+        };
+
+        TimerInfo {
+            retransmission,
+            zero_window: self.send_window_closed(),
+            persist: self.persisting().then(|| {
+                let since = self.timers.persist_at.unwrap_or(self.clock.now());
+                left(since, self.persist_timeout(config))
+            }),
+            persist_probes: self.timers.persist_probes,
+            keepalive,
+            keepalive_probes: self.timers.keepalive_probes,
+            time_wait: match self.state {
+                State::TimeWait => self.timers.time_wait.map(|at| left(at, config.msl * 2)),
+                _ => None,
+            },
+            fin_wait2: match self.state {
+                State::FinWait2 => self
+                    .timers
+                    .fin_wait2
+                    .map(|at| left(at, config.fin_wait2_timeout)),
+                _ => None,
+            },
+            frozen: self.frozen_at.is_some(),
+        }
+    }
+
+    /// Time until [`Connection::on_tick`] has something to do, `None` if
+    /// only a segment or the application can change that
+    pub(crate) fn next_timer(&self, config: &InterfaceConfig) -> Option<time::Duration> {
+        if self.frozen_at.is_some() {
+            return None;
+        }
+        let timers = self.timers(config);
+        let sample = match (self.state, config.cc_sample_interval) {
+            (
+                State::Estab
+                | State::FinWait1
+                | State::FinWait2
+                | State::Closing
+                | State::CloseWait
+                | State::LastAck,
+                Some(every),
+            ) => Some(self.timers.sampled_at.map_or(time::Duration::ZERO, |at| {
+                every.saturating_sub(self.clock.since(at))
+            })),
+            _ => None,
+        };
+        // Paced data goes out as the ticks refill the budget
+        let paced = (self.cc.pacing_rate().is_some() && self.unacked.len() > self.in_flight())
+            .then_some(time::Duration::ZERO);
+        [
+            timers.retransmission,
+            timers.persist,
+            timers.keepalive,
+            timers.time_wait,
+            timers.fin_wait2,
+            sample,
+            paced,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            state: self.state,
+            handshake_latency: self.handshake_latency(),
+            accept_wait: self.accept_wait(),
+            cwnd: self.cc.cwnd(),
+            pacing_rate: self.cc.pacing_rate(),
+            peer_window: self.send.wnd,
+            peer_options: self.peer,
+            features: FeatureReport::negotiate(&self.offered, &self.peer),
+            delivered: self.delivered,
+            delivery_rate: self.rate.latest(),
+            goodput: self.rate.goodput(),
+            bandwidth: self.rate.bandwidth(),
+            tag: self.tag.clone(),
+            dup_acks: self.acks.dup_acks,
+            spurious_retransmits: self.acks.spurious_retransmits,
+            dsacks: self.acks.dsacks,
+            reordering: self.acks.reordering,
+        }
+    }
+
+    pub(crate) fn is_recv_closed(&self) -> bool {
+        // The peer's FIN arrived
+        matches!(
+            self.state,
+            State::Closing | State::CloseWait | State::LastAck | State::TimeWait | State::Closed
+        )
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    /// Whether nothing is left to exchange with the peer, TIME-WAIT only
+    /// lingers to answer a retransmitted FIN
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(self.state, State::TimeWait | State::Closed)
     }
 
     pub(crate) fn close(&mut self) -> io::Result<()> {
         self.closed = true;
+        #[cfg(feature = "otel")]
+        self.lifecycle.on_close();
         match self.state {
             State::SynRecvd | State::Estab => {
                 self.state = State::FinWait1;
             }
-            State::FinWait1 | State::FinWait2 => {}
+            // Our FIN goes out once the queued data has
+            State::CloseWait => {
+                self.state = State::LastAck;
+            }
+            // Nothing to say goodbye to yet
+            State::SynSent => {
+                self.state = State::Closed;
+            }
+            State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck => {}
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::NotConnected,
@@ -543,6 +2037,415 @@ impl Connection {
     }
 }
 
+/// Crafts a [`Connection`] in any state, see [`Connection::builder`]
+#[cfg(feature = "testing")]
+#[derive(Clone, Debug)]
+pub struct ConnectionBuilder {
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    state: State,
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u16,
+    irs: u32,
+    rcv_nxt: u32,
+    rcv_wnd: u16,
+    incoming: Vec<u8>,
+    unacked: Vec<u8>,
+    peer: PeerOptions,
+    options: StreamOptions,
+    config: InterfaceConfig,
+}
+
+#[cfg(feature = "testing")]
+impl Connection {
+    /// Starts building an ESTABLISHED connection between `local` and
+    /// `remote`, with both initial sequence numbers at 0, nothing exchanged
+    /// past the handshake and 1024 bytes windows.
+    ///
+    /// Only available with the `testing` feature.
+    /// # Examples
+    /// ```
+    /// # use tcp_rust::tcp::{Connection, State};
+    /// let c = Connection::builder(
+    ///     "10.0.0.1:80".parse().unwrap(),
+    ///     "10.0.0.2:4000".parse().unwrap(),
+    /// )
+    /// .iss(u32::MAX - 1)
+    /// .unacked(b"wrapping")
+    /// .build();
+    /// assert_eq!(c.info().state, State::Estab);
+    /// ```
+    pub fn builder(local: SocketAddrV4, remote: SocketAddrV4) -> ConnectionBuilder {
+        ConnectionBuilder {
+            local,
+            remote,
+            state: State::Estab,
+            iss: 0,
+            snd_una: 1,
+            snd_nxt: 1,
+            snd_wnd: 1024,
+            irs: 0,
+            rcv_nxt: 1,
+            rcv_wnd: 1024,
+            incoming: Vec::new(),
+            unacked: Vec::new(),
+            peer: PeerOptions::default(),
+            options: StreamOptions::default(),
+            config: InterfaceConfig::default(),
+        }
+    }
+
+    /// Processes an IPv4 packet as if the interface had received it for
+    /// this connection
+    pub fn on_segment(
+        &mut self,
+        nic: &dyn Device,
+        config: &InterfaceConfig,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        let seg = InboundSegment::parse(packet)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not a TCP/IPv4 packet"))?;
+        self.on_packet(nic, config, &seg).map(|_| ())
+    }
+
+    /// SND.UNA, the oldest of our sequence numbers yet to be acknowledged
+    pub fn snd_una(&self) -> u32 {
+        self.send.una
+    }
+
+    /// RCV.NXT, the next sequence number expected from the peer
+    pub fn rcv_nxt(&self) -> u32 {
+        self.recv.nxt
+    }
+}
+
+#[cfg(feature = "testing")]
+impl ConnectionBuilder {
+    pub fn state(mut self, state: State) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Our initial sequence number, SND.UNA and SND.NXT follow right after it
+    pub fn iss(mut self, iss: u32) -> Self {
+        self.iss = iss;
+        self.snd_una = iss.wrapping_add(1);
+        self.snd_nxt = iss.wrapping_add(1);
+        self
+    }
+
+    pub fn snd_una(mut self, una: u32) -> Self {
+        self.snd_una = una;
+        self
+    }
+
+    pub fn snd_nxt(mut self, nxt: u32) -> Self {
+        self.snd_nxt = nxt;
+        self
+    }
+
+    pub fn snd_wnd(mut self, wnd: u16) -> Self {
+        self.snd_wnd = wnd;
+        self
+    }
+
+    /// The peer's initial sequence number, RCV.NXT follows right after it
+    pub fn irs(mut self, irs: u32) -> Self {
+        self.irs = irs;
+        self.rcv_nxt = irs.wrapping_add(1);
+        self
+    }
+
+    pub fn rcv_nxt(mut self, nxt: u32) -> Self {
+        self.rcv_nxt = nxt;
+        self
+    }
+
+    pub fn rcv_wnd(mut self, wnd: u16) -> Self {
+        self.rcv_wnd = wnd;
+        self
+    }
+
+    /// Data received but not read by the application yet
+    pub fn incoming(mut self, data: &[u8]) -> Self {
+        self.incoming = data.to_vec();
+        self
+    }
+
+    /// Data written by the application, starting at SND.UNA. Whatever lies
+    /// past SND.NXT is yet to be sent.
+    pub fn unacked(mut self, data: &[u8]) -> Self {
+        self.unacked = data.to_vec();
+        self
+    }
+
+    /// Options as if the peer had sent them in its SYN
+    pub fn peer_options(mut self, options: PeerOptions) -> Self {
+        self.peer = options;
+        self
+    }
+
+    /// Buffer sizes and keep-alive of the connection
+    pub fn options(mut self, options: StreamOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Protocol parameters the connection is set up with
+    pub fn config(mut self, config: InterfaceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> Connection {
+        let now = self.config.clock.now();
+        // Past ESTABLISHED, our FIN went out right before SND.NXT
+        let closing = matches!(
+            self.state,
+            State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck | State::TimeWait
+        );
+
+        let mut timers = Timers::default();
+        match self.state {
+            State::FinWait2 => timers.fin_wait2 = Some(now),
+            State::TimeWait => timers.time_wait = Some(now),
+            _ => {}
+        }
+
+        Connection {
+            state: self.state,
+            timers,
+            recv: ReceiveSequenceSpace {
+                irs: self.irs,
+                nxt: self.rcv_nxt,
+                wnd: self.rcv_wnd,
+                up: false,
+            },
+            send: SendSequenceSpace {
+                iss: self.iss,
+                una: self.snd_una,
+                nxt: self.snd_nxt,
+                wnd: self.snd_wnd,
+                up: false,
+                wl1: self.irs,
+                wl2: self.iss,
+            },
+            ip: etherparse::Ipv4Header::new(
+                0,
+                64,
+                etherparse::IpTrafficClass::Tcp,
+                self.local.ip().octets(),
+                self.remote.ip().octets(),
+            ),
+            tcp: {
+                let mut tcp = etherparse::TcpHeader::new(
+                    self.local.port(),
+                    self.remote.port(),
+                    self.snd_nxt,
+                    self.rcv_wnd,
+                );
+                tcp.ack = !matches!(self.state, State::SynSent | State::SynRecvd);
+                tcp
+            },
+
+            incoming: self.incoming.into(),
+            unacked: self.unacked.into(),
+            closed: closing,
+            closed_at: if closing {
+                Some(self.snd_nxt.wrapping_sub(1))
+            } else {
+                None
+            },
+            reset: false,
+            read_shutdown: false,
+            window_update: false,
+            recv_pressured: false,
+            frozen_at: None,
+            send_full: false,
+
+            syn_at: now,
+            established_at: if let State::SynSent | State::SynRecvd = self.state {
+                None
+            } else {
+                Some(now)
+            },
+            accepted_at: None,
+
+            cc: Controller::new(&self.config, MAX_PACKET_LEN - 40),
+            delivered: 0,
+            rate: Default::default(),
+            peer: self.peer,
+            offered: offered(&self.config),
+            timestamps: (self.config.timestamps && self.peer.timestamps).then_some(Timestamps {
+                recent: 0,
+                last_ack_sent: self.rcv_nxt,
+            }),
+            reassembly: Reassembly::default(),
+            scoreboard: Scoreboard::default(),
+            options: self.options,
+            tag: None,
+            option_hooks: Vec::new(),
+            clock: self.config.clock.clone(),
+            cc_samples: VecDeque::new(),
+            acks: AckStats::default(),
+            #[cfg(feature = "otel")]
+            lifecycle: crate::otel::Lifecycle::new(),
+        }
+    }
+}
+
+/// Answers a segment that doesn't belong to any connection with a RST
+/// (RFC 793 S3.4 "Reset Generation"):
+/// - if the segment has an ACK, `<SEQ=SEG.ACK><CTL=RST>`
+/// - otherwise `<SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>`
+pub(crate) fn send_rst_reply(nic: &dyn Device, seg: &InboundSegment) -> io::Result<()> {
+    let (iph, tcph) = (&seg.iph, &seg.tcph);
+    // Never answer a reset with a reset
+    if tcph.rst() {
+        return Ok(());
+    }
+
+    let mut tcp = etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), 0, 0);
+    tcp.rst = true;
+    if tcph.ack() {
+        tcp.sequence_number = tcph.acknowledgment_number();
+    } else {
+        tcp.ack = true;
+        tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(seg.seg_len());
+    }
+
+    let ip = etherparse::Ipv4Header::new(
+        tcp.header_len(),
+        64,
+        etherparse::IpTrafficClass::Tcp,
+        iph.destination_addr().octets(),
+        iph.source_addr().octets(),
+    );
+
+    tcp.checksum = tcp.calc_checksum_ipv4(&ip, &[]).map_err(|_e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Error calculating IPV4 checksum",
+        )
+    })?;
+
+    let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len() as usize);
+    ip.write(&mut buf).unwrap();
+    tcp.write(&mut buf)?;
+    stats::timed_send(nic, &buf, None)?;
+    Ok(())
+}
+
+/// Segment acceptability test (RFC 793 S3.3).
+///
+/// ```md
+/// Segment Receive  Test
+/// Length  Window
+/// ------- -------  -------------------------------------------
+///    0       0     SEG.SEQ = RCV.NXT
+///    0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+///   >0       0     not acceptable
+///   >0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+///               or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
+/// ```
+/// `len` is the segment length in sequence space, SYN and FIN included.
+/// Unlike the literal RFC test, a segment that starts before RCV.NXT and
+/// ends beyond the window is accepted too, since it carries RCV.NXT.
+/// # Examples
+/// ```
+/// # use tcp_rust::tcp::segment_acceptable;
+/// // Zero-length segment, zero window: only RCV.NXT itself
+/// assert!(segment_acceptable(100, 0, 100, 0));
+/// assert!(!segment_acceptable(99, 0, 100, 0));
+/// assert!(!segment_acceptable(101, 0, 100, 0));
+///
+/// // Zero-length segment, open window: anywhere in [RCV.NXT, RCV.NXT+RCV.WND)
+/// assert!(segment_acceptable(100, 0, 100, 10));
+/// assert!(segment_acceptable(109, 0, 100, 10));
+/// assert!(!segment_acceptable(99, 0, 100, 10));
+/// assert!(!segment_acceptable(110, 0, 100, 10));
+///
+/// // Data, zero window: never
+/// assert!(!segment_acceptable(100, 1, 100, 0));
+/// assert!(!segment_acceptable(100, 10, 100, 0));
+///
+/// // Data, open window: the first or the last byte must be in the window
+/// assert!(segment_acceptable(100, 5, 100, 10)); // fully inside
+/// assert!(segment_acceptable(95, 10, 100, 10)); // head already received
+/// assert!(segment_acceptable(105, 10, 100, 10)); // tail beyond the window
+/// assert!(segment_acceptable(95, 20, 100, 10)); // covers the whole window
+/// assert!(!segment_acceptable(90, 10, 100, 10)); // old duplicate
+/// assert!(!segment_acceptable(110, 5, 100, 10)); // entirely in the future
+/// ```
+///
+/// ```
+/// # use tcp_rust::tcp::segment_acceptable;
+/// // The same rules hold when the window wraps around the sequence space
+/// let nxt = u32::MAX - 4;
+/// assert!(segment_acceptable(nxt, 0, nxt, 0));
+/// assert!(segment_acceptable(2, 0, nxt, 10));
+/// assert!(!segment_acceptable(5, 0, nxt, 10));
+/// assert!(segment_acceptable(u32::MAX, 3, nxt, 10));
+/// assert!(segment_acceptable(nxt - 5, 8, nxt, 10));
+/// assert!(segment_acceptable(4, 5, nxt, 10));
+/// assert!(!segment_acceptable(nxt - 10, 10, nxt, 10));
+/// assert!(!segment_acceptable(5, 1, nxt, 10));
+/// assert!(!segment_acceptable(nxt, 1, nxt, 0));
+/// ```
+pub fn segment_acceptable(seq: u32, len: u32, rcv_nxt: u32, rcv_wnd: u32) -> bool {
+    let start = rcv_nxt.wrapping_sub(1);
+    let end = rcv_nxt.wrapping_add(rcv_wnd);
+
+    match (len, rcv_wnd) {
+        (0, 0) => seq == rcv_nxt,
+        (0, _) => seq.is_between_wrapped(start, end),
+        (_, 0) => false,
+        (_, _) => {
+            let last = seq.wrapping_add(len - 1);
+            seq.is_between_wrapped(start, end)
+                || last.is_between_wrapped(start, end)
+                || rcv_nxt.is_between_wrapped(seq.wrapping_sub(1), last.wrapping_add(1))
+        }
+    }
+}
+
+/// Part of the `len` bytes of data starting at `seq` that is new and fits
+/// in the receive window `[RCV.NXT, RCV.NXT+RCV.WND)`, as a range of the
+/// segment's data. Whatever was already received is trimmed off the head
+/// and whatever goes beyond the window off the tail (RFC 9293 S3.10.7.4).
+/// Data starting past RCV.NXT leaves a gap, so nothing of it can be taken.
+/// # Examples
+/// ```
+/// # use tcp_rust::tcp::trim_segment;
+/// assert_eq!(trim_segment(100, 5, 100, 10), 0..5); // fully inside
+/// assert_eq!(trim_segment(100, 10, 100, 10), 0..10); // exactly the window
+/// assert_eq!(trim_segment(95, 10, 100, 10), 5..10); // head already received
+/// assert_eq!(trim_segment(105, 10, 100, 10), 0..0); // starts past RCV.NXT
+/// assert_eq!(trim_segment(100, 15, 100, 10), 0..10); // tail beyond the window
+/// assert_eq!(trim_segment(95, 20, 100, 10), 5..15); // covers the whole window
+/// assert_eq!(trim_segment(90, 10, 100, 10), 10..10); // old duplicate
+/// assert_eq!(trim_segment(100, 5, 100, 0), 0..0); // zero window
+/// ```
+///
+/// ```
+/// # use tcp_rust::tcp::trim_segment;
+/// // Same thing across the wraparound
+/// let nxt = 2;
+/// assert_eq!(trim_segment(u32::MAX - 2, 10, nxt, 10), 5..10);
+/// assert_eq!(trim_segment(u32::MAX - 2, 20, nxt, 10), 5..15);
+/// assert_eq!(trim_segment(u32::MAX - 10, 5, nxt, 10), 5..5);
+/// assert_eq!(trim_segment(5, 5, nxt, 10), 0..0);
+/// ```
+pub fn trim_segment(seq: u32, len: usize, rcv_nxt: u32, rcv_wnd: u32) -> std::ops::Range<usize> {
+    if rcv_nxt.wrapping_lt(seq) {
+        return 0..0;
+    }
+    let start = std::cmp::min(rcv_nxt.wrapping_sub(seq) as usize, len);
+    start..std::cmp::min(len, start + rcv_wnd as usize)
+}
+
 /// Trait to deal with comparison of wrapping numbers.
 pub trait Wrap {
     fn wrapping_lt(&self, rhs: u32) -> bool;
@@ -556,8 +2459,8 @@ impl Wrap for u32 {
     /// Compare numbers taking into consideration that they can be
     /// wrapped.
     /// # Examples
-    /// ```
-    /// # use tcp_rust::connection::Wrap;
+    /// ```ignore
+    /// # use tcp_rust::tcp::Wrap;
     /// // Tests this case (X > S)
     /// //  0 |------E-----S---------X-------------| OK
     /// //           10    30        50
@@ -568,8 +2471,8 @@ impl Wrap for u32 {
     /// assert!(x.is_between_wrapped(start, end.wrapping_add(1)));
     /// ```
     ///
-    /// ```
-    /// # use tcp_rust::connection::Wrap;
+    /// ```ignore
+    /// # use tcp_rust::tcp::Wrap;
     /// // Tests this case (X < S)
     /// //  0 |------X-----E---------S------------| OK
     /// //           10    20        50
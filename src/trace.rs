@@ -0,0 +1,527 @@
+//! Packet captures of a workload, and how two of them compare, e.g. before
+//! and after a congestion control change.
+//!
+//! Captures are pcap files, taken by tcpdump or written by [`PcapWriter`]
+//! off [`crate::Interface::mirror`]. `tcp_rust trace-diff a.pcap b.pcap`
+//! prints the [`Comparison`] of two of them.
+//!
+//! ```
+//! # use etherparse::PacketBuilder;
+//! # use std::{io, time::Duration};
+//! use tcp_rust::trace::{self, PcapWriter, Trace};
+//!
+//! # fn main() -> io::Result<()> {
+//! # let segment = |client: bool, seq: u32, ack: Option<u32>, syn: bool, data: &[u8]| {
+//! #     let (from, to, ports) = match client {
+//! #         true => ([10, 0, 0, 2], [10, 0, 0, 1], (4000, 80)),
+//! #         false => ([10, 0, 0, 1], [10, 0, 0, 2], (80, 4000)),
+//! #     };
+//! #     let mut builder = PacketBuilder::ipv4(from, to, 64).tcp(ports.0, ports.1, seq, 1024);
+//! #     if syn {
+//! #         builder = builder.syn();
+//! #     }
+//! #     if let Some(ack) = ack {
+//! #         builder = builder.ack(ack);
+//! #     }
+//! #     let mut packet = Vec::new();
+//! #     builder.write(&mut packet, data).unwrap();
+//! #     packet
+//! # };
+//! let capture = |lost: bool| -> io::Result<Trace> {
+//!     let ms = Duration::from_millis;
+//!     let mut pcap = PcapWriter::new(Vec::new())?;
+//!     pcap.write_packet(ms(0), &segment(true, 100, None, true, &[]))?;
+//!     pcap.write_packet(ms(0), &segment(false, 0, Some(101), true, &[]))?;
+//!     pcap.write_packet(ms(1), &segment(true, 101, Some(1), false, &[]))?;
+//!     pcap.write_packet(ms(2), &segment(true, 101, Some(1), false, b"hello"))?;
+//!     if lost {
+//!         // Nothing tells about the loss, the timer resends it
+//!         pcap.write_packet(ms(300), &segment(true, 101, Some(1), false, b"hello"))?;
+//!     }
+//!     pcap.write_packet(ms(301), &segment(false, 1, Some(106), false, &[]))?;
+//!     Trace::from_pcap(&pcap.into_inner())
+//! };
+//!
+//! let comparison = trace::compare(&capture(true)?, &capture(false)?, Duration::from_millis(100));
+//! assert_eq!((comparison.a.retransmits, comparison.a.rto_events), (1, 1));
+//! assert_eq!((comparison.b.retransmits, comparison.b.rto_events), (0, 0));
+//! assert_eq!(comparison.b.handshakes, [Duration::from_millis(1)]);
+//! assert_eq!(comparison.b.throughput, [5, 0, 0, 0]);
+//! println!("{}", comparison);
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    cmp,
+    collections::HashMap,
+    convert::TryInto,
+    fmt, fs,
+    io::{self, Write},
+    net::SocketAddrV4,
+    path::Path,
+    time::Duration,
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice, TcpOptionElement};
+
+use crate::{sack, tcp::Wrap};
+
+/// Link layers the captures can be taken on, tcpdump's `LINKTYPE_*`
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+/// Writes a capture of raw IPv4 packets in the pcap format, with
+/// microsecond timestamps
+pub struct PcapWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Starts the capture with the file header
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend(0xa1b2_c3d4u32.to_le_bytes());
+        // Version 2.4, in UTC, as accurate as the timestamps are
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        header.extend([0; 8]);
+        header.extend(u32::from(u16::MAX).to_le_bytes());
+        header.extend(LINKTYPE_RAW.to_le_bytes());
+        inner.write_all(&header)?;
+        Ok(Self { inner })
+    }
+
+    /// Adds a packet, `at` being the time since the capture started
+    pub fn write_packet(&mut self, at: Duration, data: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(16 + data.len());
+        record.extend((at.as_secs() as u32).to_le_bytes());
+        record.extend(at.subsec_micros().to_le_bytes());
+        record.extend((data.len() as u32).to_le_bytes());
+        record.extend((data.len() as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        self.inner.write_all(&record)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// TCP segments of a capture, in the order they were captured
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug)]
+struct Segment {
+    /// Time since the first packet of the capture
+    at: Duration,
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    seq: u32,
+    ack: Option<u32>,
+    syn: bool,
+    fin: bool,
+    /// Payload length, off the IP header so that the snap length doesn't
+    /// cut it short
+    len: usize,
+    /// Carries SACK blocks
+    sack: bool,
+}
+
+impl Segment {
+    fn parse(at: Duration, packet: &[u8]) -> Option<Self> {
+        let iph = Ipv4HeaderSlice::from_slice(packet).ok()?;
+        if iph.protocol() != 6 || iph.more_fragments() || iph.fragments_offset() != 0 {
+            return None;
+        }
+        let ip_len = usize::from(iph.ihl()) * 4;
+        let tcph = TcpHeaderSlice::from_slice(packet.get(ip_len..)?).ok()?;
+        let headers_len = ip_len + usize::from(tcph.data_offset()) * 4;
+        Some(Self {
+            at,
+            source: SocketAddrV4::new(iph.source_addr(), tcph.source_port()),
+            destination: SocketAddrV4::new(iph.destination_addr(), tcph.destination_port()),
+            seq: tcph.sequence_number(),
+            ack: tcph.ack().then(|| tcph.acknowledgment_number()),
+            syn: tcph.syn(),
+            fin: tcph.fin(),
+            len: usize::from(iph.total_len()).saturating_sub(headers_len),
+            sack: tcph
+                .options_iterator()
+                .any(|option| matches!(option, Ok(TcpOptionElement::SelectiveAcknowledgement(..)))),
+        })
+    }
+}
+
+/// IPv4 packet in a frame of the link layer, if it holds one
+fn ip_packet(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+    let ethertype = |at: usize| {
+        frame
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 => Some(frame),
+        // The address family is in the byte order of the capturing host,
+        // AF_INET is 2 everywhere
+        LINKTYPE_NULL => match frame.get(..4)? {
+            [2, 0, 0, 0] | [0, 0, 0, 2] => frame.get(4..),
+            _ => None,
+        },
+        LINKTYPE_ETHERNET => match ethertype(12)? {
+            ETHERTYPE_IPV4 => frame.get(14..),
+            ETHERTYPE_VLAN if ethertype(16)? == ETHERTYPE_IPV4 => frame.get(18..),
+            _ => None,
+        },
+        LINKTYPE_LINUX_SLL if ethertype(14)? == ETHERTYPE_IPV4 => frame.get(16..),
+        _ => None,
+    }
+}
+
+impl Trace {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_pcap(&fs::read(path)?)
+    }
+
+    /// Reads a pcap capture, in either byte order and timestamp precision.
+    /// What isn't TCP over IPv4 is skipped.
+    pub fn from_pcap(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let header = bytes
+            .get(..24)
+            .ok_or_else(|| invalid("Truncated pcap header"))?;
+        let (big_endian, nanos) = match header[..4] {
+            [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+            [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+            [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+            [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+            _ => return Err(invalid("Not a pcap capture")),
+        };
+        let u32_at = |bytes: &[u8], at: usize| {
+            let bytes = bytes[at..at + 4].try_into().unwrap();
+            match big_endian {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            }
+        };
+        // The upper bits tell about the frame check sequences
+        let link_type = u32_at(header, 20) & 0xffff;
+        if ![
+            LINKTYPE_NULL,
+            LINKTYPE_ETHERNET,
+            LINKTYPE_RAW,
+            LINKTYPE_LINUX_SLL,
+            LINKTYPE_IPV4,
+        ]
+        .contains(&link_type)
+        {
+            return Err(invalid("Unsupported pcap link type"));
+        }
+
+        let mut trace = Self::default();
+        let mut first = None;
+        let mut rest = &bytes[24..];
+        while !rest.is_empty() {
+            let truncated = || invalid("Truncated pcap record");
+            let record = rest.get(..16).ok_or_else(truncated)?;
+            let captured = u32_at(record, 8) as usize;
+            let frame = rest.get(16..16 + captured).ok_or_else(truncated)?;
+            rest = &rest[16 + captured..];
+
+            let fraction = u32_at(record, 4);
+            let at = Duration::new(
+                u64::from(u32_at(record, 0)),
+                if nanos { fraction } else { fraction * 1000 },
+            );
+            let at = at.saturating_sub(*first.get_or_insert(at));
+            if let Some(segment) = ip_packet(link_type, frame).and_then(|p| Segment::parse(at, p)) {
+                trace.segments.push(segment);
+            }
+        }
+        Ok(trace)
+    }
+
+    /// Number of TCP segments captured
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Goes through the capture, with the throughput in buckets of
+    /// `interval`
+    pub fn summary(&self, interval: Duration) -> Summary {
+        let mut summary = Summary {
+            segments: self.segments.len(),
+            duration: self.segments.last().map_or(Duration::ZERO, |s| s.at),
+            interval,
+            ..Default::default()
+        };
+        // Up to the last packet, quiet ones included
+        let bucket = |at: Duration| (at.as_nanos() / cmp::max(interval.as_nanos(), 1)) as usize;
+        if !self.segments.is_empty() {
+            summary.throughput = vec![0; bucket(summary.duration) + 1];
+        }
+        let mut flows: HashMap<(SocketAddrV4, SocketAddrV4), Flow> = HashMap::new();
+        let mut ways: HashMap<(SocketAddrV4, SocketAddrV4), Way> = HashMap::new();
+        for s in &self.segments {
+            let flow = flows
+                .entry((
+                    cmp::min(s.source, s.destination),
+                    cmp::max(s.source, s.destination),
+                ))
+                .or_default();
+
+            // What it tells about the other way first
+            let back = ways.entry((s.destination, s.source)).or_default();
+            back.heard_at = Some(s.at);
+            if let Some(ack) = s.ack {
+                match back.acked {
+                    Some(acked) if acked == ack && s.len == 0 && !s.syn && !s.fin => {
+                        back.dupacks += 1
+                    }
+                    Some(acked) if !acked.wrapping_lt(ack) => {}
+                    _ => {
+                        back.acked = Some(ack);
+                        back.dupacks = 0;
+                    }
+                }
+                back.sacked = s.sack;
+                let fin_acked =
+                    matches!(back.fin, Some(fin) if !ack.wrapping_lt(fin.wrapping_add(1)));
+                if fin_acked && back.fin_acked_at.is_none() {
+                    back.fin_acked_at = Some(s.at);
+                }
+            }
+
+            match (s.syn, s.ack) {
+                (true, None) if flow.syn_at.is_none() => {
+                    summary.connections += 1;
+                    flow.syn_at = Some(s.at);
+                    flow.client = Some(s.source);
+                }
+                (true, Some(_)) => flow.syn_acked = true,
+                (false, Some(_))
+                    if flow.syn_acked
+                        && flow.handshake.is_none()
+                        && flow.client == Some(s.source) =>
+                {
+                    let took = s.at.saturating_sub(flow.syn_at.unwrap_or_default());
+                    flow.handshake = Some(took);
+                    summary.handshakes.push(took);
+                }
+                _ => {}
+            }
+
+            let way = ways.entry((s.source, s.destination)).or_default();
+            let seq_len = s.len as u32 + s.syn as u32 + s.fin as u32;
+            if seq_len == 0 {
+                continue;
+            }
+            let data_start = s.seq.wrapping_add(s.syn as u32);
+            let data_end = data_start.wrapping_add(s.len as u32);
+            let mut fresh_from = data_start;
+            if let Some(high) = way.high {
+                if s.seq.wrapping_lt(high) {
+                    summary.retransmits += 1;
+                    // Left to the timer, and not clocked by an ACK either
+                    let fast = way.dupacks >= sack::DUP_THRESH || way.sacked;
+                    let quiet = way.heard_at.is_none_or(|heard| {
+                        s.at.saturating_sub(heard) >= flow.handshake.unwrap_or_default()
+                    });
+                    if !fast && quiet {
+                        summary.rto_events += 1;
+                    }
+                }
+                if data_start.wrapping_lt(high) {
+                    fresh_from = high;
+                }
+            }
+            if fresh_from.wrapping_lt(data_end) {
+                let fresh = u64::from(data_end.wrapping_sub(fresh_from));
+                summary.throughput[bucket(s.at)] += fresh;
+                summary.data_bytes += fresh;
+            }
+            let end = s.seq.wrapping_add(seq_len);
+            if way.high.is_none_or(|high| high.wrapping_lt(end)) {
+                way.high = Some(end);
+            }
+            if s.fin && way.fin.is_none() {
+                way.fin = Some(data_end);
+                flow.fin_at.get_or_insert(s.at);
+            }
+        }
+
+        // Closed once both FINs were ACKed, in the order they were
+        let acked = |from, to| ways.get(&(from, to)).and_then(|w: &Way| w.fin_acked_at);
+        let mut closes: Vec<(Duration, Duration)> = flows
+            .iter()
+            .filter_map(|(&(a, b), flow)| {
+                let closed_at = cmp::max(acked(a, b)?, acked(b, a)?);
+                Some((closed_at, closed_at.saturating_sub(flow.fin_at?)))
+            })
+            .collect();
+        closes.sort();
+        summary.closes = closes.into_iter().map(|(_, took)| took).collect();
+        summary
+    }
+}
+
+/// One way of a connection, as its sender sees it
+#[derive(Default)]
+struct Way {
+    /// End of the sequence space sent so far
+    high: Option<u32>,
+    /// Highest ACK back, and the duplicates of it that came in a row
+    acked: Option<u32>,
+    dupacks: usize,
+    /// The last ACK back carried SACK blocks
+    sacked: bool,
+    /// When the last segment back came in
+    heard_at: Option<Duration>,
+    /// Sequence number of the FIN, and when it was ACKed
+    fin: Option<u32>,
+    fin_acked_at: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Flow {
+    /// Sender of the first SYN, and when it went out
+    client: Option<SocketAddrV4>,
+    syn_at: Option<Duration>,
+    syn_acked: bool,
+    /// SYN to the ACK of the SYN-ACK, a round trip wherever the capture
+    /// was taken
+    handshake: Option<Duration>,
+    fin_at: Option<Duration>,
+}
+
+/// What a capture tells about the workload, see [`Trace::summary`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Connections opened by a SYN in the capture
+    pub connections: usize,
+    pub segments: usize,
+    /// Bytes of data sent for the first time, both ways
+    pub data_bytes: u64,
+    /// Segments sending sequence space again, SYNs and FINs included
+    pub retransmits: usize,
+    /// Retransmissions the timer sent: no duplicate ACKs nor SACK blocks
+    /// told about the loss, and the sender heard nothing back for at least
+    /// the handshake's round trip before sending them
+    pub rto_events: usize,
+    /// SYN to the ACK completing the handshake, per connection
+    pub handshakes: Vec<Duration>,
+    /// First FIN to the ACK of the second one, per connection
+    pub closes: Vec<Duration>,
+    /// First packet to the last one
+    pub duration: Duration,
+    /// Width of the buckets of `throughput`
+    pub interval: Duration,
+    /// Bytes of new data sent in every `interval` since the first packet
+    pub throughput: Vec<u64>,
+}
+
+/// Summaries of two captures side by side, see [`compare`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comparison {
+    pub a: Summary,
+    pub b: Summary,
+}
+
+/// Compares two captures of the same workload, their throughput in
+/// buckets of `interval`
+pub fn compare(a: &Trace, b: &Trace, interval: Duration) -> Comparison {
+    Comparison {
+        a: a.summary(interval),
+        b: b.summary(interval),
+    }
+}
+
+/// Change from `a` to `b`, in percents of `a`
+fn change(a: f64, b: f64) -> String {
+    if a == b {
+        String::from("=")
+    } else if a == 0.0 {
+        String::from("-")
+    } else {
+        format!("{:+.1}%", (b - a) / a * 100.0)
+    }
+}
+
+fn mean(durations: &[Duration]) -> Option<Duration> {
+    let sum: Duration = durations.iter().sum();
+    (!durations.is_empty()).then(|| sum / durations.len() as u32)
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, b) = (&self.a, &self.b);
+        let row = |f: &mut fmt::Formatter, name: &str, x: String, y: String, change: String| {
+            writeln!(f, "{:<16}{:>14}{:>14}{:>10}", name, x, y, change)
+        };
+        let count = |f: &mut fmt::Formatter, name: &str, x: u64, y: u64| {
+            row(
+                f,
+                name,
+                x.to_string(),
+                y.to_string(),
+                change(x as f64, y as f64),
+            )
+        };
+        let time =
+            |f: &mut fmt::Formatter, name: &str, x: Option<Duration>, y: Option<Duration>| {
+                let show =
+                    |d: Option<Duration>| d.map_or(String::from("-"), |d| format!("{:.1?}", d));
+                let changed = match (x, y) {
+                    (Some(x), Some(y)) => change(x.as_secs_f64(), y.as_secs_f64()),
+                    _ => String::from("-"),
+                };
+                row(f, name, show(x), show(y), changed)
+            };
+
+        row(
+            f,
+            "",
+            String::from("a"),
+            String::from("b"),
+            String::from("change"),
+        )?;
+        count(f, "connections", a.connections as u64, b.connections as u64)?;
+        count(f, "segments", a.segments as u64, b.segments as u64)?;
+        count(f, "data bytes", a.data_bytes, b.data_bytes)?;
+        count(f, "retransmits", a.retransmits as u64, b.retransmits as u64)?;
+        count(f, "rto events", a.rto_events as u64, b.rto_events as u64)?;
+        time(
+            f,
+            "handshake mean",
+            mean(&a.handshakes),
+            mean(&b.handshakes),
+        )?;
+        let max = |durations: &[Duration]| durations.iter().max().copied();
+        time(f, "handshake max", max(&a.handshakes), max(&b.handshakes))?;
+        time(f, "close mean", mean(&a.closes), mean(&b.closes))?;
+        time(f, "close max", max(&a.closes), max(&b.closes))?;
+        time(f, "duration", Some(a.duration), Some(b.duration))?;
+
+        writeln!(f, "bytes per {:?}", a.interval)?;
+        let buckets = cmp::max(a.throughput.len(), b.throughput.len());
+        for i in 0..buckets {
+            let at = format!("{:.1}s", (a.interval * i as u32).as_secs_f64());
+            let x = a.throughput.get(i).copied().unwrap_or(0);
+            let y = b.throughput.get(i).copied().unwrap_or(0);
+            count(f, &format!("  {}", at), x, y)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+//! Wakes the packet loop out of its `poll` when a stream has new data to
+//! send, rather than leaving it queued until the next tick (eventfd(2)).
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::prelude::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd},
+};
+
+pub(crate) struct Wake {
+    fd: File,
+}
+
+impl Wake {
+    pub(crate) fn new() -> io::Result<Self> {
+        // SAFETY: plain syscall without pointers
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        Ok(Self {
+            fd: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    /// Makes the descriptor readable until [`Wake::clear`]
+    pub(crate) fn notify(&self) -> io::Result<()> {
+        match (&self.fd).write(&1u64.to_ne_bytes()) {
+            // The counter is about to overflow, so it is readable already
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    pub(crate) fn clear(&self) -> io::Result<()> {
+        match (&self.fd).read(&mut [0u8; 8]) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+}
+
+impl AsRawFd for Wake {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Wake {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
@@ -0,0 +1,32 @@
+//! Connections crafted in a given state with `Connection::builder`
+use std::io;
+
+use etherparse::{Ipv4Header, TcpHeader};
+use tcp_rust::{
+    device::{Device, Loopback},
+    tcp::{Connection, State},
+    InterfaceConfig,
+};
+
+#[test]
+fn built_connection_sends_across_the_wrap() -> io::Result<()> {
+    let (nic, peer) = Loopback::pair()?;
+    let mut c = Connection::builder(
+        "10.0.0.1:80".parse().unwrap(),
+        "10.0.0.2:4000".parse().unwrap(),
+    )
+    .iss(u32::MAX - 1)
+    .unacked(b"wrapping")
+    .build();
+    assert_eq!(c.info().state, State::Estab);
+
+    // The queued data goes out on the next tick, right across the wrap
+    c.on_tick(&nic, &InterfaceConfig::default())?;
+    let mut buf = [0u8; 1504];
+    let n = peer.recv(&mut buf)?;
+    let (_, rest) = Ipv4Header::read_from_slice(&buf[..n]).unwrap();
+    let (tcph, data) = TcpHeader::read_from_slice(rest).unwrap();
+    assert_eq!(tcph.sequence_number, u32::MAX);
+    assert_eq!(data, b"wrapping");
+    Ok(())
+}
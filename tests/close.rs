@@ -0,0 +1,96 @@
+//! Closing connections, and what is left of them afterwards
+mod common;
+
+use std::{
+    io::{self, Read},
+    net::Shutdown,
+    time::Duration,
+};
+
+use common::{
+    events, handshake, handshake_polled, interface, virtual_time, wait_event, wait_state,
+};
+use tcp_rust::{events::EventKind, InterfaceConfig, State};
+
+#[test]
+fn dropped_stream_in_close_wait_is_removed() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let events = events(&iface);
+    let mut listener = iface.bind(80)?;
+    let (stream, seq, ack) = handshake(&peer, &mut listener)?;
+
+    peer.send(peer.tcp(seq).ack(ack).fin(), &[])?;
+    assert_eq!(peer.recv()?.tcph.acknowledgment_number, seq + 1);
+    wait_state(&events, State::CloseWait);
+
+    drop(stream);
+    let fin = peer.recv()?.tcph;
+    assert!(fin.fin);
+    assert_eq!(fin.sequence_number, ack);
+    wait_state(&events, State::LastAck);
+
+    peer.send(peer.tcp(seq + 1).ack(ack + 1), &[])?;
+    wait_event(&events, |e| match e {
+        EventKind::Removed { .. } => Some(()),
+        _ => None,
+    });
+    Ok(())
+}
+
+#[test]
+fn dropped_stream_sends_fin() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (stream, _, ack) = handshake(&peer, &mut listener)?;
+
+    drop(stream);
+    let fin = peer.recv()?.tcph;
+    assert!(fin.fin);
+    assert_eq!(fin.sequence_number, ack);
+    Ok(())
+}
+
+#[test]
+fn time_wait_passes_on_the_virtual_clock() -> io::Result<()> {
+    let (config, clock) = virtual_time(InterfaceConfig::default());
+    let msl = config.msl;
+    let (mut iface, peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake_polled(&peer, &iface, &mut listener)?;
+
+    stream.shutdown(Shutdown::Write)?;
+    // The FIN goes out on the next tick
+    clock.advance(&iface, Duration::from_millis(10))?;
+    assert!(peer.recv()?.tcph.fin);
+    // Acknowledged, along with the peer's FIN
+    peer.send(peer.tcp(seq).ack(ack + 1).fin(), &[])?;
+    iface.poll(Duration::ZERO)?;
+    assert_eq!(stream.read(&mut [0u8; 8])?, 0);
+    assert_eq!(iface.connections().len(), 1);
+
+    // Four minutes in TIME-WAIT, in a blink
+    clock.advance(&iface, msl * 2)?;
+    assert!(iface.connections().is_empty());
+    Ok(())
+}
+
+#[test]
+fn simultaneous_close_goes_through_closing() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let events = events(&iface);
+    let mut listener = iface.bind(80)?;
+    let (stream, seq, ack) = handshake(&peer, &mut listener)?;
+    stream.shutdown(Shutdown::Write)?;
+    assert!(peer.recv()?.tcph.fin);
+
+    // The peer's FIN, sent before ours arrived
+    peer.send(peer.tcp(seq).ack(ack).fin(), &[])?;
+    assert_eq!(peer.recv()?.tcph.acknowledgment_number, seq + 1);
+    assert_eq!(stream.info()?.state, State::Closing);
+
+    // And the ACK of ours
+    peer.send(peer.tcp(seq + 1).ack(ack + 1), &[])?;
+    wait_state(&events, State::TimeWait);
+    assert_eq!(stream.info()?.state, State::TimeWait);
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! Packets larger than a segment, merged by the device or by the stack
+mod common;
+
+use std::{
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    time::Duration,
+};
+
+use common::{interface_on, PEER_ISS};
+use tcp_rust::{
+    device::{Device, Loopback},
+    EventLoop, InterfaceConfig,
+};
+
+/// Device that hands over packets of up to 64 KiB, as one doing GRO would
+struct Gro(Loopback);
+
+impl AsRawFd for Gro {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Device for Gro {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.0.send(packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn gso_max_len(&self) -> Option<usize> {
+        Some(65535)
+    }
+}
+
+#[test]
+fn only_packets_oversized_on_arrival_are_super_packets() -> io::Result<()> {
+    let config = InterfaceConfig {
+        event_loop: EventLoop::Manual,
+        ..Default::default()
+    };
+    let (mut iface, peer) = interface_on(config, Gro)?;
+    let wait = Duration::from_millis(10);
+    let mut listener = iface.bind(80)?;
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    iface.poll(wait)?;
+    let ack = peer.recv()?.tcph.sequence_number + 1;
+    let seq = PEER_ISS + 1;
+    peer.send(peer.tcp(seq).ack(ack), &[])?;
+    iface.poll(wait)?;
+    let _stream = listener.try_accept()?.unwrap();
+
+    // Read in the same batch, and merged into one larger than the MTU
+    let chunk = [7u8; 1000];
+    peer.send_data(seq, ack, &chunk)?;
+    peer.send_data(seq + 1000, ack, &chunk)?;
+    iface.poll(wait)?;
+    let stats = iface.stats().coalesce;
+    assert_eq!(stats.segments, 1);
+    assert_eq!(stats.super_packets, 0);
+
+    peer.send_data(seq + 2000, ack, &[7u8; 3000])?;
+    iface.poll(wait)?;
+    let stats = iface.stats().coalesce;
+    assert_eq!(stats.super_packets, 1);
+    assert_eq!(stats.super_packet_bytes, 3000);
+    Ok(())
+}
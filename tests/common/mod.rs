@@ -0,0 +1,250 @@
+//! Hand-crafted peer the integration tests run the stack against, over a
+//! [`Loopback`] pair. The interface is at 10.0.0.1, the peer at 10.0.0.2.
+#![allow(dead_code)]
+
+use std::{
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use etherparse::{Ipv4Header, PacketBuilder, PacketBuilderStep, TcpHeader};
+use tcp_rust::{
+    bpf::Program,
+    clock::VirtualClock,
+    device::{Capabilities, Device, Loopback},
+    entropy,
+    events::{Event, EventKind},
+    EventLoop, Interface, InterfaceConfig, State, TcpListener, TcpStream,
+};
+
+/// What the peer waits for a segment at most, so that a test that misses
+/// one fails rather than hangs
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sequence number of the peer's SYN, its data starts right after
+pub const PEER_ISS: u32 = 100;
+
+pub struct Peer {
+    /// Shared with the interface's end, so that it outlives the packet loop
+    pub dev: Arc<Loopback>,
+    /// Port of the interface it talks to
+    pub port: u16,
+    /// Port it talks from
+    pub src_port: u16,
+    /// Window it advertises
+    pub window: u16,
+}
+
+/// Segment the interface sent
+#[derive(Debug)]
+pub struct Segment {
+    pub tcph: TcpHeader,
+    pub data: Vec<u8>,
+}
+
+/// Interface with a fixed ISS of 0, and the peer on the other side of it
+pub fn interface(config: InterfaceConfig) -> io::Result<(Interface, Peer)> {
+    interface_on(config, |nic| nic)
+}
+
+/// [`interface`] on the device `wrap` makes out of its end of the pair
+pub fn interface_on<D: Device + 'static>(
+    config: InterfaceConfig,
+    wrap: impl FnOnce(Loopback) -> D,
+) -> io::Result<(Interface, Peer)> {
+    let (nic, peer) = wire(wrap)?;
+    let iface = Interface::with_device(nic, config)?;
+    iface.set_entropy_source(entropy::Fixed(0));
+    Ok((iface, peer))
+}
+
+/// Interface driven by hand, on a clock that only moves when told to
+pub fn virtual_time(config: InterfaceConfig) -> (InterfaceConfig, VirtualClock) {
+    let clock = VirtualClock::new();
+    let config = InterfaceConfig {
+        clock: Arc::new(clock.clone()),
+        event_loop: EventLoop::Manual,
+        ..config
+    };
+    (config, clock)
+}
+
+/// Device for an interface to run on, made by `wrap` out of its end of the
+/// pair, and the peer on the other end
+pub fn wire<D: Device>(wrap: impl FnOnce(Loopback) -> D) -> io::Result<(Wired<D>, Peer)> {
+    let (nic, dev) = Loopback::pair()?;
+    dev.set_read_timeout(Some(RECV_TIMEOUT))?;
+    let dev = Arc::new(dev);
+    let nic = Wired {
+        nic: wrap(nic),
+        _peer: dev.clone(),
+    };
+    let peer = Peer {
+        dev,
+        port: 80,
+        src_port: 4000,
+        window: 1024,
+    };
+    Ok((nic, peer))
+}
+
+/// Device of the interface, which keeps the peer's end open until the
+/// interface is gone: whatever the stack sends after a test is over, say the
+/// FIN of a dropped stream, must not fail and stop the packet loop
+pub struct Wired<D> {
+    nic: D,
+    _peer: Arc<Loopback>,
+}
+
+impl<D: AsRawFd> AsRawFd for Wired<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.nic.as_raw_fd()
+    }
+}
+
+impl<D: Device> Device for Wired<D> {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.nic.send(packet)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.nic.recv(buf)
+    }
+
+    fn gso_max_len(&self) -> Option<usize> {
+        self.nic.gso_max_len()
+    }
+
+    fn mtu(&self) -> usize {
+        self.nic.mtu()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.nic.capabilities()
+    }
+
+    fn send_offloaded(&self, packet: &[u8], mss: Option<u16>) -> io::Result<usize> {
+        self.nic.send_offloaded(packet, mss)
+    }
+
+    fn attach_filter(&self, program: Option<&Program>) -> io::Result<()> {
+        self.nic.attach_filter(program)
+    }
+}
+
+/// Events of `iface` from now on
+pub fn events(iface: &Interface) -> mpsc::Receiver<Event> {
+    let (tx, rx) = mpsc::channel();
+    iface.on_event(move |event| {
+        let _ = tx.send(event.clone());
+    });
+    rx
+}
+
+/// Waits for the first event `f` picks something out of
+pub fn wait_event<T>(
+    events: &mpsc::Receiver<Event>,
+    mut f: impl FnMut(&EventKind) -> Option<T>,
+) -> T {
+    loop {
+        let event = events
+            .recv_timeout(RECV_TIMEOUT)
+            .expect("no such event came");
+        if let Some(t) = f(&event.kind) {
+            return t;
+        }
+    }
+}
+
+/// Waits for a connection to get to `state`
+pub fn wait_state(events: &mpsc::Receiver<Event>, state: State) {
+    wait_event(events, |e| match e {
+        EventKind::State { to, .. } => (*to == state).then_some(()),
+        _ => None,
+    });
+}
+
+impl Peer {
+    /// Segment from the peer at `seq`, for the caller to set the flags of
+    pub fn tcp(&self, seq: u32) -> PacketBuilderStep<TcpHeader> {
+        self.tcp_to([10, 0, 0, 1], seq)
+    }
+
+    /// [`Self::tcp`], to another address of the interface
+    pub fn tcp_to(&self, dst: [u8; 4], seq: u32) -> PacketBuilderStep<TcpHeader> {
+        PacketBuilder::ipv4([10, 0, 0, 2], dst, 64).tcp(self.src_port, self.port, seq, self.window)
+    }
+
+    pub fn send(&self, tcp: PacketBuilderStep<TcpHeader>, data: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::new();
+        tcp.write(&mut packet, data).unwrap();
+        self.dev.send(&packet).map(drop)
+    }
+
+    /// ACK carrying `data`
+    pub fn send_data(&self, seq: u32, ack: u32, data: &[u8]) -> io::Result<()> {
+        self.send(self.tcp(seq).ack(ack), data)
+    }
+
+    /// Next segment to [`Self::src_port`], those left over from the
+    /// connections of other ports are skipped
+    pub fn recv(&self) -> io::Result<Segment> {
+        let mut buf = [0u8; 65536];
+        loop {
+            let n = self.dev.recv(&mut buf)?;
+            let (_, rest) = Ipv4Header::read_from_slice(&buf[..n]).unwrap();
+            let (tcph, data) = TcpHeader::read_from_slice(rest).unwrap();
+            if tcph.destination_port == self.src_port {
+                return Ok(Segment {
+                    tcph,
+                    data: data.to_vec(),
+                });
+            }
+        }
+    }
+
+    /// Whether nothing arrives for `wait`
+    pub fn is_quiet(&self, wait: Duration) -> io::Result<bool> {
+        self.dev.set_read_timeout(Some(wait))?;
+        let quiet = self.recv().is_err();
+        self.dev.set_read_timeout(Some(RECV_TIMEOUT))?;
+        Ok(quiet)
+    }
+}
+
+/// Opens a connection to the interface's port. Returns the peer's next
+/// sequence number and the one it has to ACK.
+pub fn connect(peer: &Peer) -> io::Result<(u32, u32)> {
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    let syn_ack = peer.recv()?.tcph;
+    assert!(syn_ack.syn && syn_ack.ack);
+    let (seq, ack) = (PEER_ISS + 1, syn_ack.sequence_number.wrapping_add(1));
+    peer.send(peer.tcp(seq).ack(ack), &[])?;
+    Ok((seq, ack))
+}
+
+/// [`connect`]s to `listener` and accepts the connection
+pub fn handshake(peer: &Peer, listener: &mut TcpListener) -> io::Result<(TcpStream, u32, u32)> {
+    let (seq, ack) = connect(peer)?;
+    Ok((listener.accept()?, seq, ack))
+}
+
+/// [`handshake`] with an interface driven by hand, see
+/// [`tcp_rust::EventLoop::Manual`]: it's polled once each segment is in
+pub fn handshake_polled(
+    peer: &Peer,
+    iface: &Interface,
+    listener: &mut TcpListener,
+) -> io::Result<(TcpStream, u32, u32)> {
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    iface.poll(Duration::ZERO)?;
+    let syn_ack = peer.recv()?.tcph;
+    assert!(syn_ack.syn && syn_ack.ack);
+    let (seq, ack) = (PEER_ISS + 1, syn_ack.sequence_number.wrapping_add(1));
+    peer.send(peer.tcp(seq).ack(ack), &[])?;
+    iface.poll(Duration::ZERO)?;
+    let stream = listener.try_accept()?.expect("handshake over");
+    Ok((stream, seq, ack))
+}
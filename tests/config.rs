@@ -0,0 +1,199 @@
+//! Protocol parameters of `InterfaceConfig` and `StreamOptions`
+mod common;
+
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{handshake, interface, PEER_ISS};
+use etherparse::PacketBuilder;
+use tcp_rust::{
+    stats::Malformed, Compliance, InterfaceConfig, IpQuota, ParsePolicy, ReadShutdown,
+    StreamOptions,
+};
+
+#[test]
+fn ip_quota_resets_connections_over_the_cap() -> io::Result<()> {
+    let config = InterfaceConfig {
+        ip_quota: Some(IpQuota {
+            max_connections: 1,
+            reset: true,
+        }),
+        ..Default::default()
+    };
+    let (mut iface, mut peer) = interface(config)?;
+    let _listener = iface.bind(80)?;
+
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    let syn_ack = peer.recv()?.tcph;
+    assert!(syn_ack.syn && syn_ack.ack);
+
+    // Same address, another connection
+    peer.src_port += 1;
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    assert!(peer.recv()?.tcph.rst);
+    assert_eq!(iface.stats().quota_refused, 1);
+    Ok(())
+}
+
+#[test]
+fn rfc9293_compliance_skips_the_shortcuts() -> io::Result<()> {
+    let config = InterfaceConfig {
+        compliance: Compliance::rfc9293(),
+        ..Default::default()
+    };
+    let (mut iface, peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+
+    // Data in the SYN is kept, and acknowledged along with it
+    peer.send(peer.tcp(PEER_ISS).syn(), b"early")?;
+    let syn_ack = peer.recv()?.tcph;
+    let seq = PEER_ISS + 6;
+    assert_eq!(syn_ack.acknowledgment_number, seq);
+
+    // An ACK for something never sent is answered with <SEQ=SEG.ACK><CTL=RST>
+    peer.send(peer.tcp(seq).ack(500), &[])?;
+    let rst = peer.recv()?.tcph;
+    assert!(rst.rst && !rst.ack);
+    assert_eq!(rst.sequence_number, 500);
+
+    peer.send(peer.tcp(seq).ack(1), &[])?;
+    let mut stream = listener.accept()?;
+    let mut data = [0u8; 5];
+    stream.read_exact(&mut data)?;
+    assert_eq!(&data, b"early");
+
+    // A RST right at RCV.NXT aborts the connection, waking the reader
+    peer.send(peer.tcp(seq).rst(), &[])?;
+    let err = stream.read(&mut data).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    Ok(())
+}
+
+#[test]
+fn strict_parsing_drops_illegal_flags() -> io::Result<()> {
+    let config = InterfaceConfig {
+        parsing: ParsePolicy::Strict,
+        ..Default::default()
+    };
+    let (mut iface, peer) = interface(config)?;
+    let _listener = iface.bind(80)?;
+
+    // No answer to that one, the only reply is to the proper SYN
+    peer.send(peer.tcp(PEER_ISS).syn().fin(), &[])?;
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    let syn_ack = peer.recv()?.tcph;
+    assert!(syn_ack.syn && !syn_ack.fin);
+    assert!(peer.is_quiet(Duration::from_millis(50))?);
+
+    let malformed = iface.stats().malformed;
+    assert_eq!(malformed.illegal_flags, 1);
+    assert_eq!(malformed.samples[0].reason, Malformed::IllegalFlags);
+    Ok(())
+}
+
+#[test]
+fn writers_wait_for_the_low_watermark() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+    stream.set_options(StreamOptions {
+        send_buffer: 8,
+        send_low_watermark: Some(2),
+        ..Default::default()
+    })?;
+    let soon = || Instant::now() + Duration::from_millis(50);
+
+    stream.write_all(b"12345678")?;
+    assert_eq!(peer.recv()?.data, b"12345678");
+    // Half of it acknowledged, that's not enough room yet
+    peer.send_data(seq, ack + 4, &[])?;
+    assert_eq!(stream.write_all_deadline(b"9", soon())?, 0);
+    peer.send_data(seq, ack + 6, &[])?;
+    assert_eq!(stream.write_all_deadline(b"9", soon())?, 1);
+    Ok(())
+}
+
+#[test]
+fn write_all_deadline_tells_how_much_was_queued() -> io::Result<()> {
+    let config = InterfaceConfig {
+        compliance: Compliance::rfc9293(),
+        ..Default::default()
+    };
+    let (mut iface, peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+    stream.set_options(StreamOptions {
+        send_buffer: 8,
+        ..Default::default()
+    })?;
+    let soon = || Instant::now() + Duration::from_millis(50);
+
+    stream.write_all(b"12345678")?;
+    assert_eq!(peer.recv()?.data, b"12345678");
+    // Room for 3 of the 5 bytes by the deadline
+    peer.send_data(seq, ack + 3, &[])?;
+    assert_eq!(stream.write_all_deadline(b"abcde", soon())?, 3);
+    assert_eq!(peer.recv()?.data, b"abc");
+
+    // Room for 3 again, then a RST while waiting for more: the 3 are
+    // counted, the error comes next
+    peer.send_data(seq, ack + 6, &[])?;
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(100));
+            peer.send(peer.tcp(seq).rst(), &[])
+        });
+        let later = Instant::now() + Duration::from_secs(5);
+        assert_eq!(stream.write_all_deadline(b"vwxyz", later)?, 3);
+        io::Result::Ok(())
+    })?;
+    assert!(stream.write_all_deadline(b"yz", soon()).is_err());
+    Ok(())
+}
+
+#[test]
+fn data_after_a_read_shutdown_resets() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (stream, seq, ack) = handshake(&peer, &mut listener)?;
+    stream.set_options(StreamOptions {
+        read_shutdown: ReadShutdown::Reset,
+        ..Default::default()
+    })?;
+    stream.shutdown(Shutdown::Read)?;
+
+    peer.send_data(seq, ack, b"nobody reads this")?;
+    assert!(peer.recv()?.tcph.rst);
+    Ok(())
+}
+
+#[test]
+fn reverse_path_drops_what_cant_be_ours() -> io::Result<()> {
+    let config = InterfaceConfig {
+        addresses: vec![[10, 0, 0, 1].into()],
+        reverse_path: true,
+        ..Default::default()
+    };
+    let (mut iface, peer) = interface(config)?;
+    let _listener = iface.bind(80)?;
+    let syn = |src, dst| {
+        PacketBuilder::ipv4(src, dst, 64)
+            .tcp(peer.src_port, 80, PEER_ISS, 1024)
+            .syn()
+    };
+
+    // Our own address coming back from the outside
+    peer.send(syn([10, 0, 0, 1], [10, 0, 0, 1]), &[])?;
+    // For someone else
+    peer.send(syn([10, 0, 0, 2], [10, 0, 0, 9]), &[])?;
+
+    // Neither of them gets an answer
+    assert!(peer.is_quiet(Duration::from_millis(100))?);
+    let stats = iface.stats().reverse_path;
+    assert_eq!((stats.spoofed_source, stats.wrong_destination), (1, 1));
+    Ok(())
+}
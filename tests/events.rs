@@ -0,0 +1,94 @@
+//! Events of an interface and its connections
+mod common;
+
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use common::{events, handshake, interface, wait_event, wait_state, PEER_ISS};
+use tcp_rust::{
+    events::{EventKind, Level},
+    InterfaceConfig, State, StreamOptions,
+};
+
+/// Log the test reads back what the interface wrote to
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(b)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn events_are_logged_as_json_lines() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let log = Shared::default();
+    iface.log_events(log.clone());
+    let _listener = iface.bind(80)?;
+
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    peer.recv()?;
+    // The event is written once the interface is unlocked
+    iface.stats();
+
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    assert!(log.lines().next().unwrap().ends_with(
+        r#""event":"state","local":"10.0.0.1:80","remote":"10.0.0.2:4000","from":null,"to":"SynRecvd"}"#
+    ));
+    Ok(())
+}
+
+#[test]
+fn event_level_filters_the_debug_events() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let events = events(&iface);
+    iface.set_event_level(Level::Info);
+    let _listener = iface.bind(80)?;
+
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    peer.recv()?;
+
+    // The new connection's state, but not its congestion window
+    wait_state(&events, State::SynRecvd);
+    iface.stats();
+    let rest: Vec<_> = events.try_iter().map(|e| e.kind).collect();
+    assert!(rest.is_empty(), "{:?}", rest);
+    Ok(())
+}
+
+#[test]
+fn recv_pressure_follows_the_watermarks() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let events = events(&iface);
+    let mut listener = iface.bind(80)?;
+    listener.set_stream_options(StreamOptions {
+        recv_high_watermark: Some(8),
+        recv_low_watermark: Some(2),
+        ..Default::default()
+    })?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+    let pressure = || {
+        wait_event(&events, |e| match *e {
+            EventKind::RecvPressure {
+                pressured, waiting, ..
+            } => Some((pressured, waiting)),
+            _ => None,
+        })
+    };
+
+    peer.send_data(seq, ack, b"0123456789")?;
+    assert_eq!(pressure(), (true, 10));
+
+    // Still 3 bytes waiting, more than the low watermark
+    stream.read_exact(&mut [0; 7])?;
+    stream.read_exact(&mut [0; 1])?;
+    assert_eq!(pressure(), (false, 2));
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! What an `Interface` does for all its connections
+mod common;
+
+use std::{
+    io::{self, Read},
+    net::Ipv4Addr,
+    os::unix::io::{AsFd, AsRawFd},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use common::{handshake, interface, wire, PEER_ISS};
+use nix::poll::{poll, PollFd, PollFlags};
+use tcp_rust::{
+    bpf::Program, device::Device, entropy::Fixed, mirror::Direction, EventLoop, InterfaceConfig,
+    InterfaceSet,
+};
+
+/// Interface whose packet loop only runs when polled
+fn manual() -> InterfaceConfig {
+    InterfaceConfig {
+        event_loop: EventLoop::Manual,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn manual_loop_only_runs_when_polled() -> io::Result<()> {
+    let (mut iface, peer) = interface(manual())?;
+    let mut listener = iface.bind(80)?;
+    let wait = Duration::from_millis(10);
+
+    // Nothing answers the SYN until the loop runs
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    assert!(peer.is_quiet(Duration::from_millis(50))?);
+    iface.poll(wait)?;
+    let syn_ack = peer.recv()?.tcph;
+    assert!(syn_ack.syn && syn_ack.ack);
+
+    peer.send_data(PEER_ISS + 1, syn_ack.sequence_number + 1, &[])?;
+    assert!(listener.try_accept()?.is_none());
+    iface.poll(wait)?;
+    assert!(listener.try_accept()?.is_some());
+    Ok(())
+}
+
+#[test]
+fn mirror_copies_both_directions() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let _listener = iface.bind(80)?;
+    let (tx, rx) = mpsc::channel();
+    iface.mirror(tx);
+
+    let mut syn = Vec::new();
+    peer.tcp(PEER_ISS).syn().write(&mut syn, &[]).unwrap();
+    peer.dev.send(&syn)?;
+
+    let inbound = rx.recv().unwrap();
+    assert_eq!((inbound.direction, inbound.data), (Direction::Inbound, syn));
+    // The SYN-ACK
+    assert_eq!(rx.recv().unwrap().direction, Direction::Outbound);
+    Ok(())
+}
+
+#[test]
+fn set_runs_every_interface_from_one_thread() -> io::Result<()> {
+    let set = InterfaceSet::new();
+    let (nic_a, peer_a) = wire(|nic| nic)?;
+    let (nic_b, peer_b) = wire(|nic| nic)?;
+    let mut a = set.add(nic_a, InterfaceConfig::default())?;
+    let mut b = set.add(nic_b, InterfaceConfig::default())?;
+    let (_la, _lb) = (a.bind(80)?, b.bind(80)?);
+
+    for peer in [&peer_a, &peer_b] {
+        peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+        let syn_ack = peer.recv()?.tcph;
+        assert!(syn_ack.syn && syn_ack.ack);
+    }
+    Ok(())
+}
+
+#[test]
+fn close_group_resets_the_tagged_connections() -> io::Result<()> {
+    let (mut iface, mut peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (mut tagged, _, _) = handshake(&peer, &mut listener)?;
+    tagged.set_tag(Some("tenant-a"))?;
+    peer.src_port += 1;
+    let (other, _, _) = handshake(&peer, &mut listener)?;
+    assert_eq!(iface.group_stats("tenant-a").connections, 1);
+
+    assert_eq!(iface.close_group("tenant-a"), 1);
+    assert_eq!(iface.group_stats("tenant-a").connections, 0);
+    let err = tagged.read(&mut [0u8; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    assert!(other.info().is_ok());
+    Ok(())
+}
+
+#[test]
+fn entropy_source_picks_the_iss() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    iface.set_entropy_source(Fixed(1000));
+    let _listener = iface.bind(80)?;
+
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    assert_eq!(peer.recv()?.tcph.sequence_number, 1000);
+    Ok(())
+}
+
+/// Interface answering on 10.0.0.1 only
+fn one_address() -> InterfaceConfig {
+    InterfaceConfig {
+        addresses: vec![Ipv4Addr::new(10, 0, 0, 1)],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn added_address_is_answered() -> io::Result<()> {
+    let (mut iface, peer) = interface(one_address())?;
+    let _listener = iface.bind(80)?;
+
+    // Not ours, no answer
+    peer.send(peer.tcp_to([10, 0, 0, 9], PEER_ISS).syn(), &[])?;
+    assert!(peer.is_quiet(Duration::from_millis(100))?);
+
+    iface.add_addr([10, 0, 0, 9].into())?;
+    peer.send(peer.tcp_to([10, 0, 0, 9], PEER_ISS).syn(), &[])?;
+    let syn_ack = peer.recv()?.tcph;
+    assert!(syn_ack.syn && syn_ack.ack);
+    assert_eq!(iface.local_addrs().len(), 2);
+    Ok(())
+}
+
+#[test]
+fn removed_address_fails_its_streams() -> io::Result<()> {
+    let (mut iface, peer) = interface(one_address())?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, _, _) = handshake(&peer, &mut listener)?;
+
+    iface.remove_addr([10, 0, 0, 1].into())?;
+    let err = stream.read(&mut [0u8; 8]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    Ok(())
+}
+
+#[test]
+fn link_down_fails_blocking_reads() -> io::Result<()> {
+    let config = InterfaceConfig {
+        link_down_grace: Duration::ZERO,
+        ..Default::default()
+    };
+    let (mut iface, peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, _, _) = handshake(&peer, &mut listener)?;
+
+    iface.set_link_state(false);
+    let err = stream.read(&mut [0u8; 8]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NetworkDown);
+    Ok(())
+}
+
+#[test]
+fn filter_keeps_other_ports_from_the_stack() -> io::Result<()> {
+    let (mut iface, mut peer) = interface(InterfaceConfig::default())?;
+    let (_http, _other) = (iface.bind(80)?, iface.bind(81)?);
+    iface.attach_filter(&Program::tcp_ports(&[80])?)?;
+
+    // Never seen by the stack, although a listener waits for it
+    peer.port = 81;
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    assert!(peer.is_quiet(Duration::from_millis(100))?);
+
+    peer.port = 80;
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    assert!(peer.recv()?.tcph.syn);
+    Ok(())
+}
+
+#[test]
+fn connect_is_refused_by_a_rst() -> io::Result<()> {
+    let (mut iface, mut peer) = interface(one_address())?;
+
+    // The peer listens on port 80, and refuses connections to 81
+    let server = thread::spawn(move || {
+        for port in [80, 81] {
+            peer.src_port = port;
+            let syn = peer.recv()?.tcph;
+            assert!(syn.syn);
+            peer.port = syn.source_port;
+            let reply = peer.tcp(300).ack(syn.sequence_number.wrapping_add(1));
+            match port {
+                80 => peer.send(reply.syn(), &[])?,
+                _ => peer.send(reply.rst(), &[])?,
+            }
+        }
+        io::Result::Ok(peer)
+    });
+
+    let stream = iface.connect("10.0.0.2:80".parse().unwrap())?;
+    assert_eq!(stream.peer_addr(), "10.0.0.2:80".parse().unwrap());
+    assert!(stream.local_addr().port() >= 49152);
+    let refused = iface.connect("10.0.0.2:81".parse().unwrap());
+    assert_eq!(
+        refused.err().unwrap().kind(),
+        io::ErrorKind::ConnectionRefused
+    );
+    server.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn shutdown_waits_for_the_fins() -> io::Result<()> {
+    let (mut iface, mut peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+    let handle = iface.shutdown();
+
+    // Our FIN is out, the peer still has to answer it
+    assert!(peer.recv()?.tcph.fin);
+    assert!(handle.wait(Duration::from_millis(50)).is_err());
+    peer.send(peer.tcp(seq).ack(ack + 1).fin(), &[])?;
+    handle.wait(Duration::from_secs(1))?;
+    assert_eq!(stream.read(&mut [0u8; 8])?, 0);
+
+    // No new connections from then on
+    peer.src_port += 1;
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    assert!(peer.recv()?.tcph.rst);
+    Ok(())
+}
+
+#[test]
+fn descriptors_tell_an_outside_loop_when_to_poll() -> io::Result<()> {
+    let (mut iface, peer) = interface(manual())?;
+    let _listener = iface.bind(80)?;
+    let mut fds = [
+        PollFd::new(iface.as_fd().as_raw_fd(), PollFlags::POLLIN),
+        PollFd::new(iface.wake_fd().as_raw_fd(), PollFlags::POLLIN),
+    ];
+    assert_eq!(poll(&mut fds, 0).unwrap(), 0);
+
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    assert_eq!(poll(&mut fds, 1000).unwrap(), 1);
+    iface.poll(Duration::ZERO)?;
+    assert!(peer.recv()?.tcph.syn);
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! Connections handed out by a `TcpListener`
+mod common;
+
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+
+use common::{connect, events, interface, wait_state, PEER_ISS};
+use tcp_rust::{Compliance, InterfaceConfig, State, StreamOptions};
+
+#[test]
+fn try_accept_waits_for_the_handshake() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let events = events(&iface);
+    let mut listener = iface.bind(80)?;
+
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    let syn_ack = peer.recv()?.tcph;
+    assert!(listener.try_accept()?.is_none());
+
+    peer.send(peer.tcp(PEER_ISS + 1).ack(syn_ack.sequence_number + 1), &[])?;
+    wait_state(&events, State::Estab);
+    assert!(listener.try_accept()?.is_some());
+    Ok(())
+}
+
+#[test]
+fn accepted_streams_get_the_listener_options() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let options = StreamOptions {
+        recv_buffer: 4096,
+        keepalive: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    listener.set_stream_options(options)?;
+
+    // The SYN-ACK already advertises the larger window
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    let syn_ack = peer.recv()?.tcph;
+    assert_eq!(syn_ack.window_size, 4096);
+    peer.send_data(PEER_ISS + 1, syn_ack.sequence_number + 1, &[])?;
+    assert_eq!(listener.accept()?.options()?, options);
+
+    // Streams accepted before keep theirs
+    listener.set_stream_options(StreamOptions::default())?;
+    let mut other = peer;
+    other.src_port += 1;
+    connect(&other)?;
+    assert_eq!(listener.accept()?.options()?, StreamOptions::default());
+    Ok(())
+}
+
+#[test]
+fn dropped_listener_resets_what_it_never_accepted() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let events = events(&iface);
+    let listener = iface.bind(80)?;
+    connect(&peer)?;
+    wait_state(&events, State::Estab);
+
+    // Established, but never accepted
+    drop(listener);
+    assert!(peer.recv()?.tcph.rst);
+    Ok(())
+}
+
+#[test]
+fn segments_nobody_listens_for_are_reset() -> io::Result<()> {
+    let (mut iface, mut peer) = interface(InterfaceConfig::default())?;
+    let _listener = iface.bind(80)?;
+
+    peer.port = 81;
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    let rst = peer.recv()?.tcph;
+    assert!(rst.rst && rst.ack);
+    assert_eq!(rst.acknowledgment_number, PEER_ISS + 1);
+
+    // A connection that never was, on a port that is open
+    peer.port = 80;
+    peer.send(peer.tcp(PEER_ISS).ack(5000), b"hi")?;
+    let rst = peer.recv()?.tcph;
+    assert!(rst.rst && !rst.ack);
+    assert_eq!(rst.sequence_number, 5000);
+    Ok(())
+}
+
+#[test]
+fn route_splits_by_the_first_bytes() -> io::Result<()> {
+    let (mut iface, mut peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let mut http = listener.route(4, |first| first == b"GET ")?;
+
+    for (port, first) in [(4000, &b"SSH-2.0"[..]), (4001, b"GET / HTTP/1.1")] {
+        peer.src_port = port;
+        let (seq, ack) = connect(&peer)?;
+        peer.send_data(seq, ack, first)?;
+        assert_eq!(
+            peer.recv()?.tcph.acknowledgment_number,
+            seq + first.len() as u32
+        );
+    }
+
+    assert_eq!(http.accept()?.peer_addr().port(), 4001);
+    assert_eq!(listener.accept()?.peer_addr().port(), 4000);
+    Ok(())
+}
+
+#[test]
+fn retransmitted_syn_gets_the_syn_ack_again() -> io::Result<()> {
+    for syn_data in [false, true] {
+        let config = InterfaceConfig {
+            compliance: Compliance {
+                syn_data,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut iface, peer) = interface(config)?;
+        let mut listener = iface.bind(80)?;
+
+        // The SYN-ACK gets lost, the SYN and its data come again
+        peer.send(peer.tcp(PEER_ISS).syn(), b"early")?;
+        let syn_ack = peer.recv()?.tcph;
+        peer.send(peer.tcp(PEER_ISS).syn(), b"early")?;
+        let again = peer.recv()?.tcph;
+        assert!(again.syn && again.ack);
+        assert_eq!(again.sequence_number, syn_ack.sequence_number);
+        assert_eq!(again.acknowledgment_number, syn_ack.acknowledgment_number);
+
+        // Data not taken with the SYN is sent again after the handshake
+        let (seq, ack) = (PEER_ISS + 1, syn_ack.sequence_number + 1);
+        match syn_data {
+            true => peer.send_data(seq + 5, ack, &[])?,
+            false => peer.send_data(seq, ack, b"early")?,
+        }
+        let mut stream = listener.accept()?;
+        let mut data = [0u8; 5];
+        stream.read_exact(&mut data)?;
+        assert_eq!(&data, b"early");
+        if !syn_data {
+            assert_eq!(peer.recv()?.tcph.acknowledgment_number, seq + 5);
+        }
+        // Taken once only
+        assert!(peer.is_quiet(Duration::from_millis(50))?);
+        assert_eq!(
+            stream
+                .read_timeout(&mut data, Duration::from_millis(50))
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! Probing a peer's closed window (RFC 9293 S3.8.6.1)
+mod common;
+
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use common::{handshake, interface};
+use tcp_rust::InterfaceConfig;
+
+#[test]
+fn closed_window_is_probed_with_backoff() -> io::Result<()> {
+    let config = InterfaceConfig {
+        rto_min: Duration::from_millis(20),
+        rto_max: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let (mut iface, mut peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+
+    // Once the byte is read, the window that came with it is in place
+    peer.window = 0;
+    peer.send_data(seq, ack, b"x")?;
+    stream.read_exact(&mut [0u8; 1])?;
+    assert_eq!(peer.recv()?.tcph.acknowledgment_number, seq + 1);
+    let seq = seq + 1;
+    stream.write_all(b"stuck")?;
+    let first = peer.recv()?;
+    assert!(first.data.is_empty());
+    assert_eq!(first.tcph.sequence_number, ack.wrapping_sub(1));
+    let timers = stream.timers()?;
+    assert!(timers.zero_window);
+    assert_eq!(timers.persist_probes, 1);
+    assert!(timers.persist.unwrap() <= Duration::from_millis(100));
+
+    // Still closed: the next probe waits twice as long, up to the cap
+    peer.send_data(seq, ack, &[])?;
+    let second = peer.recv()?;
+    assert!(second.data.is_empty());
+    assert_eq!(stream.timers()?.persist_probes, 2);
+
+    peer.window = 1024;
+    peer.send_data(seq, ack, &[])?;
+    let data = peer.recv()?;
+    assert_eq!(data.data, b"stuck");
+    let timers = stream.timers()?;
+    assert_eq!(timers.persist, None);
+    assert_eq!(timers.persist_probes, 0);
+
+    drop(stream);
+    assert!(peer.recv()?.tcph.fin);
+    Ok(())
+}
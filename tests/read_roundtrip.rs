@@ -0,0 +1,138 @@
+//! Randomized round trips through the read and write paths of streams.
+//!
+//! Two interfaces talk over a `Loopback` pair: one echoes back whatever it
+//! reads, the other writes chunks of random bytes and sizes and reads the
+//! echo back into buffers of random sizes, checking every byte. The receive
+//! queues are small and half the reads take only a few bytes, so that data
+//! piles up behind them and wraps around the end of the storage, and reads
+//! copy out of both halves.
+//!
+//! ```text
+//! ROUNDTRIP_ROUNDS=1000 ROUNDTRIP_SEED=42 cargo test --release --test read_roundtrip
+//! ```
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddrV4},
+    thread,
+    time::Duration,
+};
+
+use tcp_rust::{
+    device::Loopback,
+    entropy::{EntropySource, Seeded},
+    Interface, InterfaceConfig, StreamOptions,
+};
+
+/// Receive queue of both ends, smaller than most chunks
+const RECV_BUFFER: usize = 1500;
+/// Largest chunk written at once, the echo holds on to all of it
+const MAX_CHUNK: usize = 4000;
+/// Longest a read waits for the echo
+const STALL: Duration = Duration::from_secs(5);
+/// Rounds run unless `ROUNDTRIP_ROUNDS` says otherwise
+const ROUNDS: usize = 50;
+/// Seed unless `ROUNDTRIP_SEED` says otherwise
+const SEED: u64 = 0x7c9_5eed;
+
+/// Random sizes and coin tosses off a [`Seeded`] source, so that a seed is
+/// all it takes to replay a run
+struct Rng(Seeded);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.0.fill(&mut bytes).unwrap();
+        u64::from_ne_bytes(bytes)
+    }
+
+    fn coin(&mut self) -> bool {
+        self.next() & 1 == 0
+    }
+
+    /// In `1..=max`
+    fn size(&mut self, max: usize) -> usize {
+        1 + (self.next() % max as u64) as usize
+    }
+}
+
+fn config(address: [u8; 4]) -> InterfaceConfig {
+    InterfaceConfig {
+        addresses: vec![address.into()],
+        ..Default::default()
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).map_or(default, |v| v.parse().ok().expect(name))
+}
+
+#[test]
+fn echoed_bytes_come_back_intact() -> io::Result<()> {
+    let rounds = env_or("ROUNDTRIP_ROUNDS", ROUNDS);
+    let seed = env_or("ROUNDTRIP_SEED", SEED);
+    let mut rng = Rng(Seeded::new(seed));
+    let mut echo_rng = Rng(Seeded::new(rng.next()));
+
+    let (a, b) = Loopback::pair()?;
+    let mut server = Interface::with_device(a, config([10, 0, 0, 1]))?;
+    let mut client = Interface::with_device(b, config([10, 0, 0, 2]))?;
+    let options = StreamOptions {
+        recv_buffer: RECV_BUFFER,
+        ..Default::default()
+    };
+
+    let mut listener = server.bind(7)?;
+    listener.set_stream_options(options)?;
+    let echo = thread::spawn(move || -> io::Result<()> {
+        let mut stream = listener.accept()?;
+        let mut buf = vec![0u8; MAX_CHUNK];
+        loop {
+            let max = if echo_rng.coin() { 64 } else { buf.len() };
+            let len = echo_rng.size(max);
+            match stream.read(&mut buf[..len])? {
+                0 => return stream.shutdown(Shutdown::Write),
+                n => stream.write_all(&buf[..n])?,
+            }
+        }
+    });
+
+    let mut stream = client.connect(SocketAddrV4::new([10, 0, 0, 1].into(), 7))?;
+    stream.set_options(options)?;
+    let mut echoed = vec![0u8; MAX_CHUNK];
+    for round in 0..rounds {
+        let chunk: Vec<u8> = (0..rng.size(MAX_CHUNK)).map(|_| rng.next() as u8).collect();
+        stream.write_all(&chunk)?;
+        // Reads outpace the echo, let it fill the receive queue first so
+        // that what arrives next wraps around
+        thread::sleep(Duration::from_millis(5));
+
+        let mut got = 0;
+        while got < chunk.len() {
+            let left = chunk.len() - got;
+            let max = if rng.coin() { left.min(64) } else { left };
+            let len = rng.size(max);
+            // Rather than hang on a stalled connection
+            match stream.read_timeout(&mut echoed[got..got + len], STALL) {
+                Ok(0) => panic!("round {} of seed {}: the echo hung up", round, seed),
+                Ok(n) => got += n,
+                Err(e) => panic!(
+                    "round {} of seed {}: {} after {} bytes",
+                    round, seed, e, got
+                ),
+            }
+        }
+        if let Some(at) = (0..got).find(|&i| echoed[i] != chunk[i]) {
+            panic!(
+                "round {} of seed {}: byte {} of {} came back wrong",
+                round,
+                seed,
+                at,
+                chunk.len()
+            );
+        }
+    }
+
+    stream.shutdown(Shutdown::Write)?;
+    assert_eq!(stream.read(&mut echoed)?, 0);
+    echo.join().unwrap()
+}
@@ -0,0 +1,61 @@
+//! Connections served by `Interface::serve`
+mod common;
+
+use std::io::{self, Read, Write};
+
+use common::{connect, interface};
+use tcp_rust::InterfaceConfig;
+
+#[test]
+fn serves_and_closes() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let server = iface.serve(80, |stream| {
+        let mut buf = [0u8; 16];
+        let n = stream.read(&mut buf)?;
+        stream.write_all(&buf[..n])
+    })?;
+    let (seq, ack) = connect(&peer)?;
+
+    peer.send_data(seq, ack, b"ping")?;
+    let mut echoed = Vec::new();
+    let fin = loop {
+        let segment = peer.recv()?;
+        echoed.extend(segment.data);
+        if segment.tcph.fin {
+            break segment.tcph;
+        }
+    };
+    assert_eq!(echoed, b"ping");
+    assert!(!fin.rst);
+
+    drop(server);
+    Ok(())
+}
+
+#[test]
+fn resets_when_the_handler_fails() -> io::Result<()> {
+    let (mut iface, mut peer) = interface(InterfaceConfig::default())?;
+    let server = iface.serve(80, |stream| {
+        let mut buf = [0u8; 16];
+        match stream.read(&mut buf)? {
+            1 => Err(io::Error::new(io::ErrorKind::InvalidData, "bad request")),
+            _ => panic!("worse request"),
+        }
+    })?;
+
+    for request in [&b"?"[..], b"??"] {
+        peer.src_port += 1;
+        let (seq, ack) = connect(&peer)?;
+        peer.send_data(seq, ack, request)?;
+        let rst = loop {
+            let tcph = peer.recv()?.tcph;
+            if !tcph.ack || tcph.rst {
+                break tcph;
+            }
+        };
+        assert!(rst.rst);
+    }
+
+    drop(server);
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Segments dropped on purpose by a `Shaper`, and windows shrunk by a
+//! `WindowShaper`
+mod common;
+
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use common::{handshake, interface_on, PEER_ISS};
+use tcp_rust::{
+    peer_window::{Phase, WindowAction, WindowSchedule, WindowShaper},
+    shaping::{Rules, Shaper},
+    InterfaceConfig,
+};
+
+#[test]
+fn dropped_syn_ack_is_retransmitted() -> io::Result<()> {
+    let rules = Rules::new();
+    rules.push("drop syn #1".parse()?);
+    let (mut iface, peer) =
+        interface_on(InterfaceConfig::default(), |nic| Shaper::new(nic, &rules))?;
+    let _listener = iface.bind(80)?;
+
+    // The SYN-ACK is lost, the peer only sees its retransmission
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    assert!(peer.is_quiet(Duration::from_millis(200))?);
+    assert_eq!(rules.hits(0), 1);
+    assert!(peer.recv()?.tcph.syn);
+    assert_eq!(rules.to_vec()[0].0.to_string(), "drop syn #1");
+    Ok(())
+}
+
+#[test]
+fn clamped_window_holds_the_data_back() -> io::Result<()> {
+    let schedule = WindowSchedule::new();
+    schedule.push(Phase::new(Duration::from_secs(10), WindowAction::Clamp(0)));
+    let (mut iface, peer) = interface_on(InterfaceConfig::default(), |nic| {
+        WindowShaper::new(nic, &schedule)
+    })?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+    stream.write_all(b"ping")?;
+    stream.kick()?;
+
+    // The stack saw a zero window
+    assert!(peer.is_quiet(Duration::from_millis(200))?);
+    assert_eq!(stream.info()?.peer_window, 0);
+
+    // Until the peer's window gets through
+    schedule.clear();
+    peer.send_data(seq, ack, &[])?;
+    assert_eq!(peer.recv()?.data, b"ping");
+    assert!(schedule.rewritten() >= 1);
+    Ok(())
+}
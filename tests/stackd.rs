@@ -0,0 +1,37 @@
+//! The remote debugging service
+mod common;
+
+use std::io;
+
+use common::{connect, interface};
+use tcp_rust::{stackd, InterfaceConfig};
+
+#[test]
+fn empty_token_is_refused() -> io::Result<()> {
+    let (mut iface, _peer) = interface(InterfaceConfig::default())?;
+    let e = stackd::spawn(&mut iface, 80, "").err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn wrong_token_closes_the_session() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let stackd = stackd::spawn(&mut iface, 80, "s3cr3t")?;
+    let (seq, ack) = connect(&peer)?;
+
+    peer.send_data(seq, ack, b"guess\n")?;
+    let mut answer = Vec::new();
+    let fin = loop {
+        let segment = peer.recv()?;
+        answer.extend(segment.data);
+        if segment.tcph.fin {
+            break segment.tcph;
+        }
+    };
+    assert_eq!(answer, b"{\"error\":\"unauthorized\"}\n");
+    assert!(!fin.rst);
+
+    drop(stackd);
+    Ok(())
+}
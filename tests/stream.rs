@@ -0,0 +1,392 @@
+//! What a `TcpStream` does to the connection under it
+mod common;
+
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use common::{connect, handshake, handshake_polled, interface, virtual_time, PEER_ISS};
+use etherparse::{TcpHeader, TcpOptionElement};
+use tcp_rust::{
+    options::{OptionHook, OutgoingSegment},
+    Feature, InterfaceConfig, PeerOptions, ReadMode, StreamOptions,
+};
+
+#[test]
+fn shutdown_closes_each_half() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (seq, ack) = connect(&peer)?;
+    peer.send_data(seq, ack, b"hi")?;
+    assert_eq!(peer.recv()?.tcph.acknowledgment_number, seq + 2);
+    let mut stream = listener.accept()?;
+
+    // Nothing is sent for an empty write
+    assert_eq!(stream.write(&[])?, 0);
+    assert!(peer.is_quiet(Duration::from_millis(50))?);
+
+    // "hi" was received, but won't be read anymore
+    stream.shutdown(Shutdown::Read)?;
+    assert_eq!(stream.read(&mut [0u8; 16])?, 0);
+
+    // Nothing was queued, the FIN goes out at once
+    stream.shutdown(Shutdown::Write)?;
+    let fin = peer.recv()?.tcph;
+    assert!(fin.fin);
+    assert_eq!(fin.sequence_number, ack);
+    let err = stream.write(b"too late").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    Ok(())
+}
+
+#[test]
+fn reading_reopens_the_window() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (seq, ack) = connect(&peer)?;
+
+    // More than the window, only 1024 bytes are taken
+    peer.send_data(seq, ack, &[7; 1200])?;
+    let full = peer.recv()?.tcph;
+    assert_eq!(full.acknowledgment_number, seq + 1024);
+    assert_eq!(full.window_size, 0);
+
+    let mut stream = listener.accept()?;
+    assert_eq!(stream.read(&mut [0u8; 2048])?, 1024);
+    assert_eq!(peer.recv()?.tcph.window_size, 1024);
+    Ok(())
+}
+
+#[test]
+fn data_with_the_fin_is_read_first() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (seq, ack) = connect(&peer)?;
+    let mut stream = listener.accept()?;
+    stream.shutdown(Shutdown::Write)?;
+    assert!(peer.recv()?.tcph.fin);
+    // Our FIN is acknowledged
+    peer.send_data(seq, ack + 1, &[])?;
+
+    // "bye" and a FIN in the same segment, both acknowledged at once
+    peer.send(peer.tcp(seq).ack(ack + 1).fin(), b"bye")?;
+    assert_eq!(peer.recv()?.tcph.acknowledgment_number, seq + 3 + 1);
+
+    let mut data = [0u8; 16];
+    assert_eq!(stream.read(&mut data)?, 3);
+    assert_eq!(&data[..3], b"bye");
+    assert_eq!(stream.read(&mut data)?, 0);
+    Ok(())
+}
+
+#[test]
+fn info_reports_what_the_peer_offered() -> io::Result<()> {
+    let (mut iface, mut peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let options = [
+        TcpOptionElement::MaximumSegmentSize(536),
+        TcpOptionElement::WindowScale(7),
+    ];
+    peer.send(peer.tcp(PEER_ISS).syn().options(&options).unwrap(), &[])?;
+    let ack = peer.recv()?.tcph.sequence_number + 1;
+    peer.window = 512;
+    peer.send_data(PEER_ISS + 1, ack, &[])?;
+    let stream = listener.accept()?;
+
+    let info = stream.info()?;
+    assert_eq!(info.peer_window, 512);
+    assert_eq!(
+        info.peer_options,
+        PeerOptions {
+            mss: Some(536),
+            window_scale: Some(7),
+            ..Default::default()
+        }
+    );
+    // The SYN-ACK didn't scale its window, so neither end does
+    assert_eq!(info.features.window_scale, Feature::Off);
+    Ok(())
+}
+
+#[test]
+fn cc_samples_are_taken_at_every_interval() -> io::Result<()> {
+    let (config, clock) = virtual_time(InterfaceConfig {
+        cc_sample_interval: Some(Duration::from_millis(10)),
+        ..Default::default()
+    });
+    let (mut iface, peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+    let (stream, _, _) = handshake_polled(&peer, &iface, &mut listener)?;
+    clock.advance(&iface, Duration::from_millis(100))?;
+
+    let samples = stream.take_cc_samples()?;
+    assert!(samples.len() >= 10);
+    assert!(samples.windows(2).all(|w| w[0].at < w[1].at));
+    assert_eq!(samples[0].in_flight, 0);
+    // Taken, and forgotten
+    assert!(stream.take_cc_samples()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn probe_is_an_empty_ack_of_an_old_sequence_number() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (stream, seq, ack) = handshake(&peer, &mut listener)?;
+
+    stream.send_probe()?;
+    let probe = peer.recv()?;
+    assert_eq!(probe.tcph.sequence_number, ack.wrapping_sub(1));
+    assert_eq!(probe.tcph.acknowledgment_number, seq);
+    assert!(probe.tcph.ack && probe.data.is_empty());
+    Ok(())
+}
+
+/// Echoes the peer's nonce back, in experimental option 253
+struct Echo(Arc<Mutex<Vec<u8>>>);
+
+impl OptionHook for Echo {
+    fn kind(&self) -> u8 {
+        253
+    }
+
+    fn exid(&self) -> Option<u16> {
+        Some(0xbeef)
+    }
+
+    fn on_send(&mut self, _: &OutgoingSegment) -> Option<Vec<u8>> {
+        Some(self.0.lock().unwrap().clone())
+    }
+
+    fn on_receive(&mut self, data: &[u8]) {
+        *self.0.lock().unwrap() = data.to_vec();
+    }
+}
+
+#[test]
+fn option_hook_reads_and_writes_its_option() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+    let nonce = Arc::new(Mutex::new(Vec::new()));
+    stream.add_option_hook(Echo(nonce.clone()))?;
+
+    let tcp = peer
+        .tcp(seq)
+        .ack(ack)
+        .options_raw(&[253, 6, 0xbe, 0xef, 4, 2])
+        .unwrap();
+    peer.send(tcp, b"hi")?;
+    stream.read_exact(&mut [0u8; 2])?;
+    assert_eq!(*nonce.lock().unwrap(), [4, 2]);
+
+    // The ACK of "hi" carries the nonce back
+    let reply = peer.recv()?.tcph;
+    assert_eq!(reply.acknowledgment_number, seq + 2);
+    assert_eq!(reply.options(), &[253, 6, 0xbe, 0xef, 4, 2, 0, 0]);
+    Ok(())
+}
+
+#[test]
+fn fill_mode_waits_for_the_whole_buffer() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+    stream.set_read_mode(ReadMode::Fill)?;
+
+    // A frame of 8 bytes, coming in two segments
+    let reader = thread::spawn(move || {
+        let mut frame = [0u8; 8];
+        let n = stream.read(&mut frame)?;
+        io::Result::Ok((n, frame))
+    });
+    peer.send_data(seq, ack, b"head")?;
+    assert_eq!(peer.recv()?.tcph.acknowledgment_number, seq + 4);
+    peer.send_data(seq + 4, ack, b"tail")?;
+
+    let (n, frame) = reader.join().unwrap()?;
+    assert_eq!(n, 8);
+    assert_eq!(&frame, b"headtail");
+    Ok(())
+}
+
+#[test]
+fn kick_sends_without_waiting_for_the_loop() -> io::Result<()> {
+    let (config, _clock) = virtual_time(InterfaceConfig::default());
+    let (mut iface, peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, _, _) = handshake_polled(&peer, &iface, &mut listener)?;
+
+    // The loop isn't polled again, only the kick sends it
+    stream.write_all(b"ping")?;
+    assert!(peer.is_quiet(Duration::from_millis(50))?);
+    stream.kick()?;
+    assert_eq!(peer.recv()?.data, b"ping");
+    Ok(())
+}
+
+#[test]
+fn frozen_connection_sends_nothing_until_thawed() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+    stream.freeze()?;
+    stream.write_all(b"ping")?;
+    stream.kick()?;
+
+    // Not even "ping" went out, and "hi" isn't acknowledged
+    peer.send_data(seq, ack, b"hi")?;
+    let reply = peer.recv()?;
+    assert_eq!(reply.tcph.acknowledgment_number, seq);
+    assert!(reply.data.is_empty());
+
+    stream.thaw()?;
+    assert_eq!(peer.recv()?.data, b"ping");
+    Ok(())
+}
+
+#[test]
+fn reset_forgets_the_connection_at_once() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, _, _) = handshake(&peer, &mut listener)?;
+
+    stream.reset()?;
+    let rst = peer.recv()?;
+    assert!(rst.tcph.rst && rst.data.is_empty());
+    assert!(iface.connections().is_empty());
+    assert!(stream.write(b"too late").is_err());
+    Ok(())
+}
+
+#[test]
+fn idle_connection_waits_for_its_keepalive() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let (stream, _, _) = handshake(&peer, &mut listener)?;
+    stream.set_options(StreamOptions {
+        keepalive: Some(Duration::from_secs(60)),
+        ..Default::default()
+    })?;
+
+    let timers = stream.timers()?;
+    // Nothing in flight, the connection idles until the first probe
+    assert_eq!(timers.retransmission, None);
+    assert!(timers.keepalive.unwrap() > Duration::from_secs(50));
+    assert_eq!(timers.keepalive_probes, 0);
+    Ok(())
+}
+
+#[test]
+fn segments_fit_the_peer_mss() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let mss = [TcpOptionElement::MaximumSegmentSize(100)];
+    peer.send(peer.tcp(PEER_ISS).syn().options(&mss).unwrap(), &[])?;
+
+    // Ours is what fits in the MTU of the device
+    let syn_ack = peer.recv()?.tcph;
+    let ours = syn_ack.options_iterator().next().unwrap().unwrap();
+    assert_eq!(ours, TcpOptionElement::MaximumSegmentSize(1504 - 40));
+    let ack = syn_ack.sequence_number + 1;
+    peer.send_data(PEER_ISS + 1, ack, &[])?;
+
+    let mut stream = listener.accept()?;
+    stream.write_all(&[0; 250])?;
+    for expected in [100, 100, 50] {
+        assert_eq!(peer.recv()?.data.len(), expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn timestamps_are_echoed_once_offered() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let ts = [TcpOptionElement::Timestamp(7, 0)];
+    peer.send(peer.tcp(PEER_ISS).syn().options(&ts).unwrap(), &[])?;
+
+    // The SYN-ACK echoes the peer's timestamp
+    let syn_ack = peer.recv()?.tcph;
+    let echo = syn_ack.options_iterator().find_map(|option| match option {
+        Ok(TcpOptionElement::Timestamp(_, ecr)) => Some(ecr),
+        _ => None,
+    });
+    assert_eq!(echo, Some(7));
+    let ts = [TcpOptionElement::Timestamp(8, 1)];
+    let ack = peer.tcp(PEER_ISS + 1).ack(syn_ack.sequence_number + 1);
+    peer.send(ack.options(&ts).unwrap(), &[])?;
+
+    let stream = listener.accept()?;
+    let features = stream.info()?.features;
+    assert_eq!(features.timestamps, Feature::Active);
+    assert_eq!(features.sack, Feature::Downgraded);
+    Ok(())
+}
+
+/// First SACK block of `tcph`
+fn first_sack_block(tcph: &TcpHeader) -> Option<(u32, u32)> {
+    tcph.options_iterator().find_map(|option| match option {
+        Ok(TcpOptionElement::SelectiveAcknowledgement(first, _)) => Some(first),
+        _ => None,
+    })
+}
+
+#[test]
+fn sack_reports_holes_and_duplicates() -> io::Result<()> {
+    let (mut iface, peer) = interface(InterfaceConfig::default())?;
+    let mut listener = iface.bind(80)?;
+    let sack = [TcpOptionElement::SelectiveAcknowledgementPermitted];
+    peer.send(peer.tcp(PEER_ISS).syn().options(&sack).unwrap(), &[])?;
+    let ack = peer.recv()?.tcph.sequence_number + 1;
+    let seq = PEER_ISS + 1;
+    peer.send_data(seq, ack, &[])?;
+    let mut stream = listener.accept()?;
+    assert_eq!(stream.info()?.features.sack, Feature::Active);
+
+    // The first 4 bytes went missing
+    peer.send_data(seq + 4, ack, b"world")?;
+    let reply = peer.recv()?.tcph;
+    assert_eq!(reply.acknowledgment_number, seq);
+    assert_eq!(first_sack_block(&reply), Some((seq + 4, seq + 9)));
+
+    peer.send_data(seq, ack, b"hell")?;
+    assert_eq!(peer.recv()?.tcph.acknowledgment_number, seq + 9);
+    let mut read = [0; 9];
+    stream.read_exact(&mut read)?;
+    assert_eq!(&read, b"hellworld");
+
+    // Once more, reported as a duplicate below the ACK (RFC 2883)
+    peer.send_data(seq, ack, b"hell")?;
+    let reply = peer.recv()?.tcph;
+    assert_eq!(reply.acknowledgment_number, seq + 9);
+    assert_eq!(first_sack_block(&reply), Some((seq, seq + 4)));
+    Ok(())
+}
+
+#[test]
+fn goodput_is_sampled_by_the_acks() -> io::Result<()> {
+    let (config, clock) = virtual_time(InterfaceConfig::default());
+    let (mut iface, peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+    let (mut stream, seq, ack) = handshake_polled(&peer, &iface, &mut listener)?;
+    assert_eq!(stream.info()?.goodput, None);
+
+    stream.write_all(&[0; 100])?;
+    iface.poll(Duration::ZERO)?;
+    assert_eq!(peer.recv()?.data.len(), 100);
+    clock.advance(&iface, Duration::from_millis(10))?;
+    peer.send_data(seq, ack + 100, &[])?;
+    iface.poll(Duration::ZERO)?;
+
+    // 100 bytes in 10ms at least
+    let info = stream.info()?;
+    let goodput = info.goodput.unwrap();
+    assert!(goodput > 0.0 && goodput <= 10_000.0, "{}", goodput);
+    assert_eq!(info.bandwidth, info.delivery_rate);
+    Ok(())
+}
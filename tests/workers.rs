@@ -0,0 +1,36 @@
+//! Interfaces handing their segments to worker threads
+mod common;
+
+use std::io::{self, Read, Write};
+
+use common::{handshake, interface, PEER_ISS};
+use tcp_rust::InterfaceConfig;
+
+#[test]
+fn workers_run_the_segments_of_every_connection() -> io::Result<()> {
+    let config = InterfaceConfig {
+        workers: 2,
+        ..Default::default()
+    };
+    let (mut iface, mut peer) = interface(config)?;
+    let mut listener = iface.bind(80)?;
+
+    for _ in 0..4 {
+        peer.src_port += 1;
+        let (mut stream, seq, ack) = handshake(&peer, &mut listener)?;
+        peer.send_data(seq, ack, b"ping")?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"ping");
+        assert_eq!(peer.recv()?.tcph.acknowledgment_number, PEER_ISS + 5);
+
+        stream.write_all(b"pong")?;
+        let segment = peer.recv()?;
+        assert_eq!(segment.data, b"pong");
+        peer.send_data(seq + 4, ack + 4, &[])?;
+        stream.flush()?;
+        stream.reset()?;
+        peer.recv()?;
+    }
+    Ok(())
+}
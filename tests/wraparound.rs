@@ -0,0 +1,133 @@
+//! Sequence number wraparound: both ends of a connection start right below
+//! `u32::MAX`. The peer sends a request that wraps its sequence numbers,
+//! then the stack streams back the requested amount of data, wrapping its
+//! own on the first segment, and every 4 GiB for a long enough soak. Every
+//! byte is checked on arrival.
+//!
+//! ```text
+//! WRAPAROUND_MIB=8192 cargo test --release --features testing --test wraparound
+//! ```
+mod common;
+
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+    thread,
+};
+
+use common::interface;
+use tcp_rust::InterfaceConfig;
+
+const PEER_ISS: u32 = u32::MAX - 100;
+const STACK_ISS: u32 = u32::MAX - 10;
+/// Bytes sent by the peer, enough to wrap its sequence numbers
+const REQUEST_LEN: usize = 8 * 1024;
+const SEGMENT_LEN: usize = 1024;
+/// Bytes streamed back unless `WRAPAROUND_MIB` says otherwise
+const RESPONSE_LEN: u64 = 256 * 1024;
+
+/// What the stream carries at `offset`
+fn pattern(offset: u64) -> u8 {
+    (offset % 251) as u8
+}
+
+#[test]
+fn data_survives_the_wraparound() -> io::Result<()> {
+    let total = std::env::var("WRAPAROUND_MIB")
+        .map(|mib| mib.parse::<u64>().expect("size in MiB") * 1024 * 1024)
+        .unwrap_or(RESPONSE_LEN);
+    let config = InterfaceConfig {
+        initial_sequence: Some(STACK_ISS),
+        ..Default::default()
+    };
+    let (mut iface, mut peer) = interface(config)?;
+    peer.window = u16::MAX;
+    let mut listener = iface.bind(80)?;
+
+    let server = thread::spawn(move || -> io::Result<()> {
+        let mut stream = listener.accept()?;
+        let mut request = vec![0u8; REQUEST_LEN];
+        stream.read_exact(&mut request)?;
+        assert!((0..REQUEST_LEN).all(|i| request[i] == pattern(i as u64)));
+
+        let mut chunk = [0u8; 4096];
+        let mut offset = 0;
+        while offset < total {
+            let n = std::cmp::min(chunk.len() as u64, total - offset) as usize;
+            for (i, b) in chunk[..n].iter_mut().enumerate() {
+                *b = pattern(offset + i as u64);
+            }
+            stream.write_all(&chunk[..n])?;
+            offset += n as u64;
+        }
+        stream.shutdown(Shutdown::Write)
+    });
+
+    peer.send(peer.tcp(PEER_ISS).syn(), &[])?;
+    let syn_ack = peer.recv()?.tcph;
+    assert!(syn_ack.syn && syn_ack.ack);
+    assert_eq!(syn_ack.sequence_number, STACK_ISS);
+    let mut seq = PEER_ISS.wrapping_add(1);
+    let mut expected = STACK_ISS.wrapping_add(1);
+    peer.send_data(seq, expected, &[])?;
+
+    // Request, one segment at a time as the stack only has room for that.
+    // A segment that didn't fit is sent again once the window reopens.
+    let request: Vec<u8> = (0..REQUEST_LEN as u64).map(pattern).collect();
+    for segment in request.chunks(SEGMENT_LEN) {
+        let end = seq.wrapping_add(segment.len() as u32);
+        'segment: loop {
+            peer.send_data(seq, expected, segment)?;
+            loop {
+                let ack = peer.recv()?.tcph;
+                if ack.acknowledgment_number == end {
+                    break 'segment;
+                }
+                if ack.window_size as usize >= segment.len() {
+                    break;
+                }
+            }
+        }
+        seq = end;
+    }
+
+    // Response, ACKing as it comes and checking every byte
+    let (mut received, mut wraps) = (0u64, 0u64);
+    loop {
+        let segment = peer.recv()?;
+        if segment.tcph.sequence_number != expected {
+            // Retransmission of what already arrived
+            peer.send_data(seq, expected, &[])?;
+            continue;
+        }
+
+        for (i, &b) in segment.data.iter().enumerate() {
+            assert_eq!(
+                b,
+                pattern(received + i as u64),
+                "byte {}",
+                received + i as u64
+            );
+        }
+        received += segment.data.len() as u64;
+        let next = expected.wrapping_add(segment.data.len() as u32);
+        if next < expected {
+            wraps += 1;
+        }
+        expected = next;
+
+        if segment.tcph.fin {
+            expected = expected.wrapping_add(1);
+            peer.send_data(seq, expected, &[])?;
+            break;
+        }
+        if !segment.data.is_empty() {
+            peer.send_data(seq, expected, &[])?;
+        }
+    }
+
+    server.join().unwrap()?;
+    assert_eq!(received, total);
+    assert!(wraps >= 1);
+    Ok(())
+}